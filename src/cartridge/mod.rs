@@ -9,7 +9,10 @@ use std::io::{Read, Seek, SeekFrom, Write};
 use std::iter::repeat;
 use std::path::{Path, PathBuf};
 
+use savestate::{self, Snapshot, StateReader, StateWriter};
+
 mod models;
+mod rtc;
 
 /// Common state for all cartridge types
 pub struct Cartridge {
@@ -18,9 +21,9 @@ pub struct Cartridge {
     /// Cartridge RAM data
     ram: Vec<u8>,
     /// Total number of ROM banks in this cart
-    rom_banks: u8,
+    rom_banks: u16,
     /// Current number of the rom bank mapped at [0x4000, 0x7fff]
-    rom_bank: u8,
+    rom_bank: u16,
     /// Current bank offset for the bank mapped at [0x4000, 0x7fff].
     /// This value is added to ROM register addresses when they're in
     /// that range.
@@ -32,6 +35,15 @@ pub struct Cartridge {
     /// Certain cartridges allow banking either the RAM or ROM
     /// depending on the value of this flag.
     bank_ram: bool,
+    /// State of the rumble motor on carts that have one
+    rumble: bool,
+    /// Set when the rumble motor is turned on, cleared by
+    /// `take_rumble`. Games drive the motor with a PWM signal so we
+    /// need to remember that it's been active even if it's off when
+    /// the UI polls it.
+    rumble_latch: bool,
+    /// Real time clock, only present on some MBC3 carts
+    rtc: Option<rtc::Rtc>,
     /// struct used to handle model specific functions
     model: models::Model,
     /// Path to the ROM image for this cartridge
@@ -55,6 +67,11 @@ impl Cartridge {
 
         let model = models::from_id(rom[offsets::TYPE]);
 
+        let rtc = match models::has_rtc(rom[offsets::TYPE]) {
+            true => Some(rtc::Rtc::new()),
+            false => None,
+        };
+
         let mut cartridge = Cartridge {
             rom: rom,
             ram: Vec::new(),
@@ -65,6 +82,9 @@ impl Cartridge {
             ram_offset: 0,
             ram_wp: true,
             bank_ram: false,
+            rumble: false,
+            rumble_latch: false,
+            rtc: rtc,
             model: model,
             path: PathBuf::from(rom_path),
             save_file: None,
@@ -109,13 +129,13 @@ impl Cartridge {
 
         let ramsize = rambanks * banksize;
 
-        if ramsize == 0 {
+        if ramsize == 0 && self.rtc.is_none() {
             // No RAM on this cartridge, we're done
             return Ok(());
         }
 
-        // We have some RAM, open the save file or create it if it
-        // doesn't exist yet
+        // We have some RAM or an RTC, open the save file or create
+        // it if it doesn't exist yet
         let mut savepath = self.path.clone();
         savepath.set_extension("sav");
 
@@ -134,11 +154,23 @@ impl Cartridge {
             // Then fill the file with the right amount of 0s
             // to reserve enough space for saving later.
             try!(save_file.write_all(&self.ram));
-        } else if save_size == (ramsize as u64) {
+        } else if self.valid_save_size(ramsize, save_size) {
             // The file contains a RAM image
             try!((&mut save_file)
                 .take(ramsize as u64)
                 .read_to_end(&mut self.ram));
+
+            // Followed by the RTC state if the cartridge has one and
+            // it's been saved already
+            if let Some(rtc) = self.rtc.as_mut() {
+                let mut footer = Vec::new();
+
+                try!(save_file.read_to_end(&mut footer));
+
+                if !footer.is_empty() {
+                    rtc.load_footer(&footer);
+                }
+            }
         } else {
             panic!(
                 "Unexpected save file size for {}: expected {} got {}",
@@ -154,6 +186,21 @@ impl Cartridge {
         Ok(())
     }
 
+    /// Return `true` if `save_size` is valid for a save file
+    /// containing `ramsize` bytes of RAM. Carts with an RTC can have
+    /// a footer appended to the RAM image.
+    fn valid_save_size(&self, ramsize: usize, save_size: u64) -> bool {
+        let footer = match save_size.checked_sub(ramsize as u64) {
+            Some(f) => f as usize,
+            None => return false,
+        };
+
+        match self.rtc {
+            Some(_) => footer == 0 || footer == rtc::FOOTER_LEN || footer == rtc::FOOTER_LEN_SHORT,
+            None => footer == 0,
+        }
+    }
+
     /// Update the save file
     pub fn save_ram(&mut self) -> IoResult<()> {
         if let Some(f) = self.save_file.as_mut() {
@@ -163,16 +210,73 @@ impl Cartridge {
 
             try!(f.seek(SeekFrom::Start(0)));
             try!(f.write_all(&self.ram));
+
+            let mut len = self.ram.len();
+
+            if let Some(ref rtc) = self.rtc {
+                let footer = rtc.footer();
+
+                try!(f.write_all(&footer));
+                len += footer.len();
+            }
+
+            // Drop any leftover from an older, longer footer
+            try!(f.set_len(len as u64));
         }
 
         Ok(())
     }
 
+    /// Stop updating the save file, the RAM will be lost when the
+    /// cartridge is dropped. Used when the RAM contents don't belong
+    /// to the player, like when replaying a movie.
+    pub fn detach_save_file(&mut self) {
+        self.save_file = None;
+    }
+
+    /// Called at each tick of the system clock
+    pub fn step(&mut self) {
+        if let Some(ref mut rtc) = self.rtc {
+            rtc.step();
+        }
+    }
+
+    /// Return the real time clock if this cartridge has one
+    fn rtc(&self) -> Option<&rtc::Rtc> {
+        self.rtc.as_ref()
+    }
+
+    /// Return the real time clock if this cartridge has one
+    fn rtc_mut(&mut self) -> Option<&mut rtc::Rtc> {
+        self.rtc.as_mut()
+    }
+
+    /// Return the registers of the real time clock (see
+    /// `Rtc::registers`) if this cartridge has one
+    pub fn rtc_registers(&self) -> Option<[u8; 10]> {
+        self.rtc().map(|rtc| rtc.registers())
+    }
+
+    /// Overwrite the registers of the real time clock. Does nothing
+    /// if this cartridge doesn't have one.
+    pub fn set_rtc_registers(&mut self, regs: &[u8; 10]) {
+        if let Some(rtc) = self.rtc_mut() {
+            rtc.set_registers(regs);
+        }
+    }
+
     /// Attempt to retreive the rom's name
     pub fn name(&self) -> Option<String> {
         let mut name = String::with_capacity(16);
 
-        for i in 0..16 {
+        // On CGB cartridges the last byte of the title is used for
+        // the CGB flag
+        let len = match self.cgb() {
+            true => 15,
+            false => 16,
+        };
+
+        for i in 0..len {
             let c = match self.rom[offsets::TITLE + i].to_ascii() {
                 Ok(c) => c,
                 _ => return None,
@@ -196,9 +300,31 @@ impl Cartridge {
         Some(name)
     }
 
+    /// Return `true` if the header says the cartridge supports the
+    /// Game Boy Color. CGB-only and CGB-enhanced cartridges both run
+    /// in CGB mode.
+    pub fn cgb(&self) -> bool {
+        self.rom[offsets::CGB_FLAG] & 0x80 != 0
+    }
+
+    /// Return the global checksum stored in the ROM header. It's not
+    /// verified by the hardware but it's good enough to tell ROMs
+    /// apart.
+    pub fn checksum(&self) -> u16 {
+        let hi = self.rom[offsets::GLOBAL_CHECKSUM] as u16;
+        let lo = self.rom[offsets::GLOBAL_CHECKSUM + 1] as u16;
+
+        (hi << 8) | lo
+    }
+
+    /// Return the path of the ROM image
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
     /// Return the number of ROM banks declared in the header. Each
     /// bank is 16KB.
-    fn parse_rom_banks(&self) -> Option<u8> {
+    fn parse_rom_banks(&self) -> Option<u16> {
         let id = self.rom_byte(offsets::ROM_SIZE as u16);
 
         let nbanks = match id {
@@ -209,6 +335,8 @@ impl Cartridge {
             0x04 => 32,
             0x05 => 64,
             0x06 => 128,
+            0x07 => 256,
+            0x08 => 512,
             0x52 => 72,
             0x53 => 80,
             0x54 => 96,
@@ -247,6 +375,8 @@ impl Cartridge {
         Some((nbanks, bank_size_kb * 1024))
     }
 
+    /// Return the value of ROM byte at `offset` in the CPU address
+    /// space, taking the current bank into account
     pub fn rom_byte(&self, offset: u16) -> u8 {
         let off = offset as i32;
 
@@ -257,6 +387,8 @@ impl Cartridge {
         }
     }
 
+    /// Handle a write to the ROM address space. Those are used to
+    /// control the memory bank controller.
     pub fn set_rom_byte(&mut self, offset: u16, val: u8) {
         (self.model.write_rom)(self, offset, val)
     }
@@ -291,23 +423,58 @@ impl Cartridge {
         (self.model.write_ram)(self, addr, val);
     }
 
+    /// Return the whole ROM image
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    /// Return the whole ROM image for patching
+    pub fn rom_mut(&mut self) -> &mut [u8] {
+        &mut self.rom
+    }
+
+    /// Return the whole RAM
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    /// Return the whole RAM for patching
+    pub fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
     /// Retreive the number of ROM banks in the cartridge
-    pub fn rom_banks(&self) -> u8 {
+    pub fn rom_banks(&self) -> u16 {
         self.rom_banks
     }
 
     /// Retrieve current ROM bank number for the bankable range at
     /// [0x4000, 0x7fff]
-    pub fn rom_bank(&self) -> u8 {
+    pub fn rom_bank(&self) -> u16 {
         self.rom_bank
     }
 
     /// Set new ROM bank number for the bankable range at
     /// [0x4000, 0x7fff]
-    pub fn set_rom_bank(&mut self, bank: u8) {
+    pub fn set_rom_bank(&mut self, bank: u16) {
+        self.rom_bank = bank;
+    }
+
+    /// Map ROM `bank` in the bankable range bypassing the memory bank
+    /// controller. Used by the debugger. Like the real controllers
+    /// only MBC5 can map bank 0 there, the others map bank 1 instead.
+    pub fn force_rom_bank(&mut self, bank: u16) {
+        let bank = match bank {
+            0 if !self.model.rom_bank0_mappable => 1,
+            b => b,
+        };
+
         self.rom_bank = bank;
+        // The CPU address already contains a one bank offset
+        self.rom_offset = ROM_BANK_SIZE * (bank as i32 - 1);
     }
 
+    /// Set the offset added to CPU accesses in the bankable ROM range
     pub fn set_rom_offset(&mut self, offset: i32) {
         self.rom_offset = offset;
     }
@@ -327,28 +494,76 @@ impl Cartridge {
         self.bank_ram = v
     }
 
+    /// Return the RAM bank currently mapped
+    pub fn ram_bank(&self) -> u8 {
+        (self.ram_offset / (8 * 1024)) as u8
+    }
+
     /// Set new RAM bank number
     pub fn set_ram_bank(&mut self, bank: u8) {
         // Bankable RAM is always 8KB per bank
         self.ram_offset = bank as u32 * 8 * 1024;
     }
 
+    /// Turn the rumble motor on or off
+    pub fn set_rumble(&mut self, on: bool) {
+        self.rumble = on;
+        self.rumble_latch |= on;
+    }
+
+    /// Return `true` if the rumble motor is on or has been turned on
+    /// since the last call
+    pub fn take_rumble(&mut self) -> bool {
+        let r = self.rumble || self.rumble_latch;
+
+        self.rumble_latch = false;
+
+        r
+    }
+
+    /// Create a Cartridge from a ROM image built in memory, such as
+    /// the one generated to play GBS files. Uses the model and RAM
+    /// size declared in the header but the RAM is volatile: there's
+    /// no save file. `path` is only used to name related files, like
+    /// save states.
+    pub fn from_rom(rom: Vec<u8>, path: &Path) -> Cartridge {
+        let mut cartridge = Cartridge::without_ram(rom, path);
+
+        if let Some((banks, bank_size)) = cartridge.parse_ram_banks() {
+            cartridge.ram = vec![0; banks * bank_size];
+        }
+
+        cartridge
+    }
+
     /// Create a Cartridge instance from a ROM provided in a
-    /// Vec<u8>. Usefull for tests. Creates a MBC0 model without
-    /// banking.
+    /// Vec<u8>. Usefull for tests. Uses the model declared in the
+    /// header (MBC0 for an empty ROM) but never has any RAM.
     #[cfg(test)]
     pub fn from_vec(rom: Vec<u8>) -> Cartridge {
+        Cartridge::without_ram(rom, Path::new("dummy"))
+    }
+
+    /// Build a Cartridge for an in-memory `rom` image, with the
+    /// model declared in its header and no RAM
+    fn without_ram(rom: Vec<u8>, path: &Path) -> Cartridge {
+        let model = models::from_id(rom[offsets::TYPE]);
+        let rom_banks = (rom.len() / ROM_BANK_SIZE as usize) as u16;
+
         Cartridge {
             rom: rom,
             ram: Vec::new(),
             rom_bank: 1,
-            rom_banks: 2,
+            rom_banks: rom_banks,
             rom_offset: 0,
             ram_offset: 0,
             ram_wp: true,
             bank_ram: false,
-            model: models::from_id(0x00),
-            path: PathBuf::from("dummy"),
+            rumble: false,
+            rumble_latch: false,
+            rtc: None,
+            model: model,
+            path: PathBuf::from(path),
             save_file: None,
         }
     }
@@ -365,6 +580,57 @@ impl Drop for Cartridge {
     }
 }
 
+impl Snapshot for Cartridge {
+    fn snapshot(&self, w: &mut StateWriter) {
+        w.write_u16(self.rom_bank);
+        w.write_i32(self.rom_offset);
+        w.write_u32(self.ram_offset);
+        w.write_bool(self.ram_wp);
+        w.write_bool(self.bank_ram);
+        w.write_bool(self.rumble);
+        w.write_u32(self.ram.len() as u32);
+        w.write_bytes(&self.ram);
+
+        w.write_bool(self.rtc.is_some());
+        if let Some(ref rtc) = self.rtc {
+            rtc.snapshot(w);
+        }
+    }
+
+    fn restore(&mut self, r: &mut StateReader) -> IoResult<()> {
+        self.rom_bank = try!(r.read_u16());
+        self.rom_offset = try!(r.read_i32());
+
+        // Make sure the whole bankable range stays within the ROM
+        let first = self.rom_offset + ROM_BANK_SIZE;
+        let last = first + ROM_BANK_SIZE - 1;
+
+        if first < 0 || last as usize >= self.rom.len() {
+            return savestate::invalid("ROM bank out of range");
+        }
+
+        self.ram_offset = try!(r.read_u32());
+        self.ram_wp = try!(r.read_bool());
+        self.bank_ram = try!(r.read_bool());
+        self.rumble = try!(r.read_bool());
+
+        if try!(r.read_u32()) as usize != self.ram.len() {
+            return savestate::invalid("cartridge RAM size mismatch");
+        }
+
+        try!(r.read_bytes(&mut self.ram));
+
+        if try!(r.read_bool()) != self.rtc.is_some() {
+            return savestate::invalid("cartridge RTC mismatch");
+        }
+
+        match self.rtc {
+            Some(ref mut rtc) => rtc.restore(r),
+            None => Ok(()),
+        }
+    }
+}
+
 impl Debug for Cartridge {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
         let name = match self.name() {
@@ -384,8 +650,14 @@ impl Debug for Cartridge {
             "'{}' (Model: {}, \
              ROM banks: {}, \
              RAM banks: {}, \
-             RAM bank size: {}B)",
-            name, self.model.name, rombanks, rambanks, rambanksize
+             RAM bank size: {}B, \
+             CGB: {})",
+            name,
+            self.model.name,
+            rombanks,
+            rambanks,
+            rambanksize,
+            self.cgb()
         ));
 
         Ok(())
@@ -400,8 +672,101 @@ mod offsets {
 
     /// Title. Upper case ASCII 16bytes long, padded with 0s if shorter
     pub const TITLE: usize = 0x134;
+    /// CGB flag: 0x80 if the game supports CGB functions, 0xc0 if
+    /// it's CGB only. Overlaps with the last byte of the title on
+    /// older cartridges.
+    pub const CGB_FLAG: usize = 0x143;
     /// Cartridge type
     pub const TYPE: usize = 0x147;
     pub const ROM_SIZE: usize = 0x148;
     pub const RAM_SIZE: usize = 0x149;
+    /// Big endian 16bit sum of all the ROM bytes (except for the
+    /// checksum itself)
+    pub const GLOBAL_CHECKSUM: usize = 0x14e;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{offsets, Cartridge, ROM_BANK_SIZE};
+
+    /// Build a cart of type `model` (MBC1, MBC5...) with `banks` ROM
+    /// banks. The first two bytes of each bank contain its number.
+    fn banked_cart(model: u8, banks: usize) -> Cartridge {
+        let mut rom = vec![0; banks * ROM_BANK_SIZE as usize];
+
+        for b in 0..banks {
+            let off = b * ROM_BANK_SIZE as usize;
+
+            rom[off] = b as u8;
+            rom[off + 1] = (b >> 8) as u8;
+        }
+
+        rom[offsets::TYPE] = model;
+
+        Cartridge::from_vec(rom)
+    }
+
+    fn mapped_bank(cart: &Cartridge) -> usize {
+        let lo = cart.rom_byte(0x4000) as usize;
+        let hi = cart.rom_byte(0x4001) as usize;
+
+        (hi << 8) | lo
+    }
+
+    #[test]
+    fn mbc5_rom_banking() {
+        let mut cart = banked_cart(0x19, 512);
+
+        cart.set_rom_byte(0x2000, 0x42);
+        assert!(mapped_bank(&cart) == 0x42);
+
+        // Bit 8 of the bank number
+        cart.set_rom_byte(0x3000, 0x01);
+        assert!(mapped_bank(&cart) == 0x142);
+
+        // Bank 0 can be mapped in the bankable region
+        cart.set_rom_byte(0x3000, 0x00);
+        cart.set_rom_byte(0x2000, 0x00);
+        assert!(mapped_bank(&cart) == 0);
+        assert!(cart.rom_bank() == 0);
+
+        // Banks past the end of an odd-sized ROM wrap around
+        let mut cart = banked_cart(0x19, 6);
+
+        cart.set_rom_byte(0x2000, 0x07);
+        assert!(mapped_bank(&cart) == 1);
+    }
+
+    #[test]
+    fn force_rom_bank() {
+        let mut cart = banked_cart(0x19, 4);
+
+        cart.force_rom_bank(0);
+        assert!(mapped_bank(&cart) == 0);
+
+        // Same layout, MBC1 controller
+        let mut cart = banked_cart(0x01, 4);
+
+        cart.force_rom_bank(0);
+        assert!(mapped_bank(&cart) == 1);
+        cart.force_rom_bank(3);
+        assert!(mapped_bank(&cart) == 3);
+    }
+
+    #[test]
+    fn mbc5_rumble() {
+        let mut cart = banked_cart(0x1c, 4);
+
+        assert!(!cart.take_rumble());
+
+        // Pulse the motor, the UI must still see it
+        cart.set_rom_byte(0x4000, 0x08);
+        cart.set_rom_byte(0x4000, 0x00);
+        assert!(cart.take_rumble());
+        assert!(!cart.take_rumble());
+
+        cart.set_rom_byte(0x4000, 0x0b);
+        assert!(cart.take_rumble());
+        assert!(cart.take_rumble());
+    }
 }
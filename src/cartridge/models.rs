@@ -13,6 +13,9 @@ pub struct Model {
     pub write_ram: fn(cart: &mut Cartridge, addr: u32, val: u8),
     /// Handle RAM read
     pub read_ram: fn(cart: &Cartridge, addr: u32) -> u8,
+    /// `true` if bank 0 can be mapped in the bankable ROM range,
+    /// otherwise selecting it maps bank 1
+    pub rom_bank0_mappable: bool,
 }
 
 impl ::std::clone::Clone for Model {
@@ -22,6 +25,7 @@ impl ::std::clone::Clone for Model {
             write_rom: self.write_rom,
             write_ram: self.write_ram,
             read_ram: self.read_ram,
+            rom_bank0_mappable: self.rom_bank0_mappable,
         }
     }
 }
@@ -39,7 +43,7 @@ fn read_ram(cart: &Cartridge, addr: u32) -> u8 {
 }
 
 /// Default implementation of bank reconfiguration
-fn set_rom_bank(cart: &mut Cartridge, bank: u8) {
+fn set_rom_bank(cart: &mut Cartridge, bank: u16) {
     cart.set_rom_bank(bank);
 
     let rom_offset = ROM_BANK_SIZE
@@ -69,6 +73,7 @@ mod mbc0 {
         write_rom: write_rom,
         write_ram: super::write_ram,
         read_ram: super::read_ram,
+        rom_bank0_mappable: false,
     };
 }
 
@@ -89,7 +94,7 @@ mod mbc1 {
                 // Select a new ROM bank, bits [4:0]
                 let cur_bank = cart.rom_bank() & !0x1f;
 
-                let bank = cur_bank | (val & 0x1f);
+                let bank = cur_bank | (val & 0x1f) as u16;
 
                 set_rom_bank(cart, bank);
             }
@@ -101,7 +106,7 @@ mod mbc1 {
                     // Select a new ROM bank, bits [6:5]
                     let cur_bank = cart.rom_bank() & !0x60;
 
-                    let bank = cur_bank | ((val << 5) & 0x60);
+                    let bank = cur_bank | ((val << 5) & 0x60) as u16;
 
                     set_rom_bank(cart, bank);
                 }
@@ -119,7 +124,7 @@ mod mbc1 {
     /// that documented anywhere but The Legend of Zelda crashes at
     /// certain points if this is not accurate. I took the algorithm
     /// from gambatte.
-    fn set_rom_bank(cart: &mut Cartridge, bank: u8) {
+    fn set_rom_bank(cart: &mut Cartridge, bank: u16) {
         cart.set_rom_bank(bank);
 
         // I don't really understand this part, I know that bank can't
@@ -146,6 +151,7 @@ mod mbc1 {
         write_rom: write_rom,
         write_ram: super::write_ram,
         read_ram: super::read_ram,
+        rom_bank0_mappable: false,
     };
 }
 
@@ -163,7 +169,7 @@ mod mbc2 {
                 cart.set_ram_wp(val & 0xf != 0xa)
             }
             0x2000...0x3fff => {
-                super::set_rom_bank(cart, val & 0xf);
+                super::set_rom_bank(cart, (val & 0xf) as u16);
             }
             _ => debug!("Unhandled ROM write: {:04x} {:02x}", offset, val),
         }
@@ -174,6 +180,7 @@ mod mbc2 {
         write_rom: write_rom,
         write_ram: super::write_ram,
         read_ram: super::read_ram,
+        rom_bank0_mappable: false,
     };
 }
 
@@ -193,24 +200,146 @@ mod mbc3 {
             0x2000...0x3fff =>
             // Select a new ROM bank
             {
-                super::set_rom_bank(cart, val & 0x7f)
+                super::set_rom_bank(cart, (val & 0x7f) as u16)
+            }
+            0x4000...0x5fff => {
+                // Select a new RAM bank (0x00 to 0x07) or map one of
+                // the RTC registers (0x08 to 0x0c) in the RAM range
+                if let Some(rtc) = cart.rtc_mut() {
+                    rtc.select(val);
+                }
+
+                if val < 8 {
+                    cart.set_ram_bank(val);
+                }
+            }
+            0x6000...0x7fff => {
+                // Latch the RTC registers
+                match cart.rtc_mut() {
+                    Some(rtc) => rtc.write_latch(val),
+                    None => debug!("RTC latch on a cart without RTC"),
+                }
+            }
+            _ => debug!("Unhandled ROM write: {:04x} {:02x}", offset, val),
+        }
+    }
+
+    fn write_ram(cart: &mut Cartridge, addr: u32, val: u8) {
+        if let Some(rtc) = cart.rtc_mut() {
+            if rtc.selected() {
+                return rtc.write(val);
+            }
+        }
+
+        super::write_ram(cart, addr, val)
+    }
+
+    fn read_ram(cart: &Cartridge, addr: u32) -> u8 {
+        if let Some(rtc) = cart.rtc() {
+            if rtc.selected() {
+                return rtc.read();
+            }
+        }
+
+        super::read_ram(cart, addr)
+    }
+
+    pub static MODEL: Model = Model {
+        name: "MBC3",
+        write_rom: write_rom,
+        write_ram: write_ram,
+        read_ram: read_ram,
+        rom_bank0_mappable: false,
+    };
+}
+
+mod mbc5 {
+    use super::Model;
+    use cartridge::{Cartridge, ROM_BANK_SIZE};
+
+    fn write_rom(cart: &mut Cartridge, offset: u16, val: u8) {
+        match offset {
+            0x0000...0x1fff =>
+            // Writing a low nibble 0xa to anywhere in that
+            // address range removes RAM write protect, All other
+            // values enable it.
+            {
+                cart.set_ram_wp(val & 0xf != 0xa)
+            }
+            0x2000...0x2fff => {
+                // Select a new ROM bank, bits [7:0]
+                let bank = (cart.rom_bank() & 0x100) | val as u16;
+
+                set_rom_bank(cart, bank);
+            }
+            0x3000...0x3fff => {
+                // Select a new ROM bank, bit 8
+                let bank = (cart.rom_bank() & 0xff) | ((val as u16 & 1) << 8);
+
+                set_rom_bank(cart, bank);
             }
             0x4000...0x5fff =>
             // Select a new RAM bank
             {
-                cart.set_ram_bank(val)
+                cart.set_ram_bank(val & 0xf)
             }
-            0x6000...0x7fff => debug!("Unhandled RTC access"),
             _ => debug!("Unhandled ROM write: {:04x} {:02x}", offset, val),
         }
     }
 
+    /// Rumble carts use bit 3 of the RAM bank register to drive the
+    /// motor, which leaves them with only 8 RAM banks.
+    fn write_rom_rumble(cart: &mut Cartridge, offset: u16, val: u8) {
+        match offset {
+            0x4000...0x5fff => {
+                cart.set_rumble(val & 0x8 != 0);
+                cart.set_ram_bank(val & 0x7);
+            }
+            _ => write_rom(cart, offset, val),
+        }
+    }
+
+    /// Unlike the older MBCs bank 0 can be mapped in the bankable
+    /// range.
+    fn set_rom_bank(cart: &mut Cartridge, bank: u16) {
+        cart.set_rom_bank(bank);
+
+        // The bank number wraps around if the ROM is smaller than
+        // 512 banks. Real MBC5 carts have a power of two number of
+        // banks but overdumps and homebrew ROMs don't always.
+        let bank = bank % ::std::cmp::max(cart.rom_banks(), 1);
+
+        // Same as super::set_rom_bank: we already have a one bank
+        // offset in the CPU address when accessing bankable ROM. For
+        // bank 0 this gives a negative offset.
+        let bank = (bank as i32) - 1;
+
+        cart.set_rom_offset(ROM_BANK_SIZE * bank);
+    }
+
     pub static MODEL: Model = Model {
-        name: "MBC3",
+        name: "MBC5",
         write_rom: write_rom,
         write_ram: super::write_ram,
         read_ram: super::read_ram,
+        rom_bank0_mappable: true,
     };
+
+    pub static MODEL_RUMBLE: Model = Model {
+        name: "MBC5+RUMBLE",
+        write_rom: write_rom_rumble,
+        write_ram: super::write_ram,
+        read_ram: super::read_ram,
+        rom_bank0_mappable: true,
+    };
+}
+
+/// Return `true` if the cartridge type has an MBC3 real time clock
+pub fn has_rtc(id: u8) -> bool {
+    match id {
+        0x0f | 0x10 => true,
+        _ => false,
+    }
 }
 
 /// Return a cartridge instance for a given cartridge type
@@ -220,6 +349,8 @@ pub fn from_id(id: u8) -> Model {
         0x01...0x03 => mbc1::MODEL,
         0x05...0x06 => mbc2::MODEL,
         0x0f...0x13 => mbc3::MODEL,
+        0x19...0x1b => mbc5::MODEL,
+        0x1c...0x1e => mbc5::MODEL_RUMBLE,
         _ => panic!("Unknown cartridge model 0x{:02x}", id),
     }
 }
@@ -0,0 +1,482 @@
+//! MBC3 Real Time Clock emulation.
+//!
+//! The clock is made of 5 registers (seconds, minutes, hours and a 9
+//! bit day counter with halt and carry flags) which the game can't
+//! read directly: it has to latch them first by writing 0x00 then
+//! 0x01 to [0x6000, 0x7fff], reads then return the latched copy.
+//!
+//! While the emulator runs the clock advances with the emulated
+//! system clock. Between sessions it catches up using the wall clock
+//! timestamp stored in the save file footer.
+
+use std::io::Result as IoResult;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use savestate::{self, Snapshot, StateReader, StateWriter};
+
+/// RTC state
+pub struct Rtc {
+    /// Seconds counter, 6 bits
+    seconds: u8,
+    /// Minutes counter, 6 bits
+    minutes: u8,
+    /// Hours counter, 5 bits
+    hours: u8,
+    /// Day counter, 9 bits
+    days: u16,
+    /// If `true` the clock is stopped
+    halt: bool,
+    /// Set when the day counter overflows, cleared by the game
+    carry: bool,
+    /// Copy of the registers taken at the last latch, in register
+    /// order. That's what the game reads.
+    latched: [u8; 5],
+    /// `true` if the last write to the latch register was 0x00, the
+    /// next write of 0x01 will latch the registers
+    latch_armed: bool,
+    /// Register currently mapped in the cartridge RAM range (0 for
+    /// seconds through 4 for the day high register) or `None` if a
+    /// RAM bank is mapped instead
+    selected: Option<usize>,
+    /// System clock ticks elapsed since the last second
+    subsecond: u32,
+}
+
+impl Rtc {
+    /// Create a new RTC starting at day 0, 00:00:00
+    pub fn new() -> Rtc {
+        Rtc {
+            seconds: 0,
+            minutes: 0,
+            hours: 0,
+            days: 0,
+            halt: false,
+            carry: false,
+            latched: [0; 5],
+            latch_armed: false,
+            selected: None,
+            subsecond: 0,
+        }
+    }
+
+    /// Called at each tick of the system clock
+    pub fn step(&mut self) {
+        if self.halt {
+            return;
+        }
+
+        self.subsecond += 1;
+
+        if self.subsecond == ::GAMEBOY_SYSTEM_CLOCK_FREQUENCY as u32 {
+            self.subsecond = 0;
+            self.tick();
+        }
+    }
+
+    /// Handle a write to the latch register
+    pub fn write_latch(&mut self, val: u8) {
+        if self.latch_armed && val == 1 {
+            for reg in 0..self.latched.len() {
+                self.latched[reg] = self.register(reg);
+            }
+        }
+
+        self.latch_armed = val == 0;
+    }
+
+    /// Map RTC register `reg` (0x08 to 0x0c) in the cartridge RAM
+    /// range. Any other value maps RAM back.
+    pub fn select(&mut self, reg: u8) {
+        self.selected = match reg {
+            0x08...0x0c => Some((reg - 0x08) as usize),
+            _ => None,
+        };
+    }
+
+    /// Return `true` if an RTC register is mapped instead of RAM
+    pub fn selected(&self) -> bool {
+        self.selected.is_some()
+    }
+
+    /// Read the latched value of the selected register
+    pub fn read(&self) -> u8 {
+        match self.selected {
+            Some(reg) => self.latched[reg],
+            None => 0xff,
+        }
+    }
+
+    /// Write to the selected register. Unused bits are discarded.
+    pub fn write(&mut self, val: u8) {
+        let reg = match self.selected {
+            Some(reg) => reg,
+            None => return,
+        };
+
+        self.set_register(reg, val);
+
+        // Writes are visible without having to latch again
+        self.latched[reg] = self.register(reg);
+    }
+
+    /// Return the live registers followed by the latched ones, the
+    /// whole state of a clock that's just been loaded
+    pub fn registers(&self) -> [u8; 10] {
+        let mut regs = [0; 10];
+
+        for reg in 0..5 {
+            regs[reg] = self.register(reg);
+            regs[5 + reg] = self.latched[reg];
+        }
+
+        regs
+    }
+
+    /// Overwrite the registers with values returned by `registers`
+    pub fn set_registers(&mut self, regs: &[u8; 10]) {
+        for reg in 0..5 {
+            self.set_register(reg, regs[reg]);
+            self.latched[reg] = regs[5 + reg];
+        }
+    }
+
+    /// Return the live value of register `reg`
+    fn register(&self, reg: usize) -> u8 {
+        match reg {
+            0 => self.seconds,
+            1 => self.minutes,
+            2 => self.hours,
+            3 => self.days as u8,
+            4 => {
+                let mut r = (self.days >> 8) as u8;
+
+                r |= (self.halt as u8) << 6;
+                r |= (self.carry as u8) << 7;
+
+                r
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Set the live value of register `reg`
+    fn set_register(&mut self, reg: usize, val: u8) {
+        match reg {
+            0 => {
+                self.seconds = val & 0x3f;
+                // Writing the seconds resets the prescaler
+                self.subsecond = 0;
+            }
+            1 => self.minutes = val & 0x3f,
+            2 => self.hours = val & 0x1f,
+            3 => self.days = (self.days & 0x100) | val as u16,
+            4 => {
+                self.days = (self.days & 0xff) | ((val as u16 & 1) << 8);
+                self.halt = val & 0x40 != 0;
+                self.carry = val & 0x80 != 0;
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Advance the clock by one second. The counters can be set to
+    /// out of range values by the game, in which case they keep
+    /// counting until they wrap around at their bit width without
+    /// carrying into the next one.
+    fn tick(&mut self) {
+        self.seconds = (self.seconds + 1) & 0x3f;
+        if self.seconds != 60 {
+            return;
+        }
+        self.seconds = 0;
+
+        self.minutes = (self.minutes + 1) & 0x3f;
+        if self.minutes != 60 {
+            return;
+        }
+        self.minutes = 0;
+
+        self.hours = (self.hours + 1) & 0x1f;
+        if self.hours != 24 {
+            return;
+        }
+        self.hours = 0;
+
+        self.days += 1;
+        if self.days == 0x200 {
+            self.days = 0;
+            self.carry = true;
+        }
+    }
+
+    /// Advance the clock by `secs` seconds
+    fn advance(&mut self, mut secs: u64) {
+        if self.halt {
+            return;
+        }
+
+        // Out of range values don't follow the regular arithmetic,
+        // step them one second at a time until they're back in
+        // range. It can't take more than a couple of days.
+        while secs > 0 && !(self.seconds < 60 && self.minutes < 60 && self.hours < 24) {
+            self.tick();
+            secs -= 1;
+        }
+
+        let total = secs
+            + self.seconds as u64
+            + self.minutes as u64 * 60
+            + self.hours as u64 * 3600
+            + self.days as u64 * 86400;
+
+        let days = total / 86400;
+
+        if days >= 0x200 {
+            self.carry = true;
+        }
+
+        self.seconds = (total % 60) as u8;
+        self.minutes = ((total / 60) % 60) as u8;
+        self.hours = ((total / 3600) % 24) as u8;
+        self.days = (days % 0x200) as u16;
+    }
+
+    /// Serialize the clock in the footer format appended to `.sav`
+    /// files by most emulators (VBA-M, BGB...): the 5 live registers
+    /// and the 5 latched registers as little endian 32bit values
+    /// followed by a 64bit UNIX timestamp.
+    pub fn footer(&self) -> Vec<u8> {
+        let mut footer = Vec::with_capacity(FOOTER_LEN);
+
+        for reg in 0..5 {
+            push_le(&mut footer, self.register(reg) as u64, 4);
+        }
+
+        for reg in 0..5 {
+            push_le(&mut footer, self.latched[reg] as u64, 4);
+        }
+
+        push_le(&mut footer, unix_time(), 8);
+
+        footer
+    }
+
+    /// Restore the clock from a save file footer and advance it by
+    /// the time elapsed since it was written. Some emulators only
+    /// store a 32bit timestamp, so `footer` can be either
+    /// `FOOTER_LEN` or `FOOTER_LEN_SHORT` bytes long.
+    pub fn load_footer(&mut self, footer: &[u8]) {
+        for reg in 0..5 {
+            let v = read_le(&footer[reg * 4..], 4) as u8;
+
+            self.set_register(reg, v);
+            self.latched[reg] = read_le(&footer[20 + reg * 4..], 4) as u8;
+        }
+
+        let timestamp_len = footer.len() - 40;
+        let timestamp = read_le(&footer[40..], timestamp_len);
+
+        let now = unix_time();
+
+        if now > timestamp {
+            self.advance(now - timestamp);
+        }
+    }
+}
+
+impl Snapshot for Rtc {
+    fn snapshot(&self, w: &mut StateWriter) {
+        for reg in 0..5 {
+            w.write_u8(self.register(reg));
+        }
+
+        w.write_bytes(&self.latched);
+        w.write_bool(self.latch_armed);
+        w.write_u8(match self.selected {
+            Some(reg) => reg as u8,
+            None => 0xff,
+        });
+        w.write_u32(self.subsecond);
+    }
+
+    fn restore(&mut self, r: &mut StateReader) -> IoResult<()> {
+        for reg in 0..5 {
+            let v = try!(r.read_u8());
+
+            self.set_register(reg, v);
+        }
+
+        try!(r.read_bytes(&mut self.latched));
+        self.latch_armed = try!(r.read_bool());
+        self.selected = match try!(r.read_u8()) {
+            0xff => None,
+            n if n < 5 => Some(n as usize),
+            _ => return savestate::invalid("RTC register select"),
+        };
+
+        self.subsecond = try!(r.read_u32());
+        if self.subsecond >= ::GAMEBOY_SYSTEM_CLOCK_FREQUENCY as u32 {
+            return savestate::invalid("RTC prescaler");
+        }
+
+        Ok(())
+    }
+}
+
+/// Length of the RTC footer with a 64bit timestamp
+pub const FOOTER_LEN: usize = 48;
+
+/// Length of the RTC footer with a 32bit timestamp
+pub const FOOTER_LEN_SHORT: usize = 44;
+
+/// Return the current UNIX time in seconds
+fn unix_time() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs(),
+        Err(_) => 0,
+    }
+}
+
+/// Append the `len` low bytes of `v` in little endian
+fn push_le(buf: &mut Vec<u8>, v: u64, len: usize) {
+    for i in 0..len {
+        buf.push((v >> (i * 8)) as u8);
+    }
+}
+
+/// Read a `len` bytes long little endian value
+fn read_le(buf: &[u8], len: usize) -> u64 {
+    let mut v = 0;
+
+    for i in 0..len {
+        v |= (buf[i] as u64) << (i * 8);
+    }
+
+    v
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Rtc;
+
+    fn rtc_at(days: u16, hours: u8, minutes: u8, seconds: u8) -> Rtc {
+        let mut rtc = Rtc::new();
+
+        rtc.days = days;
+        rtc.hours = hours;
+        rtc.minutes = minutes;
+        rtc.seconds = seconds;
+
+        rtc
+    }
+
+    #[test]
+    fn latch() {
+        let mut rtc = rtc_at(0x1ab, 23, 59, 58);
+
+        rtc.select(0x08);
+        assert!(rtc.read() == 0);
+
+        rtc.write_latch(0);
+        rtc.write_latch(1);
+        assert!(rtc.read() == 58);
+
+        // Latched values don't change until the next latch
+        rtc.tick();
+        assert!(rtc.read() == 58);
+
+        // Writing 1 without 0 first doesn't latch
+        rtc.write_latch(1);
+        assert!(rtc.read() == 58);
+
+        rtc.write_latch(0);
+        rtc.write_latch(1);
+        assert!(rtc.read() == 59);
+
+        rtc.select(0x0b);
+        assert!(rtc.read() == 0xab);
+        rtc.select(0x0c);
+        assert!(rtc.read() == 0x01);
+    }
+
+    #[test]
+    fn day_carry() {
+        let mut rtc = rtc_at(0x1ff, 23, 59, 59);
+
+        rtc.tick();
+
+        assert!(rtc.days == 0);
+        assert!(rtc.carry);
+        assert!(rtc.register(4) == 0x80);
+    }
+
+    #[test]
+    fn halt() {
+        let mut rtc = Rtc::new();
+
+        rtc.select(0x0c);
+        rtc.write(0x40);
+
+        for _ in 0..(2 * ::GAMEBOY_SYSTEM_CLOCK_FREQUENCY) {
+            rtc.step();
+        }
+
+        rtc.advance(1000);
+
+        assert!(rtc.seconds == 0);
+    }
+
+    #[test]
+    fn out_of_range() {
+        // Out of range seconds wrap at 64 without incrementing the
+        // minutes
+        let mut rtc = rtc_at(0, 0, 0, 62);
+
+        rtc.tick();
+        rtc.tick();
+
+        assert!(rtc.seconds == 0);
+        assert!(rtc.minutes == 0);
+    }
+
+    #[test]
+    fn advance() {
+        let mut rtc = rtc_at(10, 22, 30, 15);
+
+        rtc.advance(2 * 86400 + 3600 + 50);
+
+        assert!(rtc.days == 12);
+        assert!(rtc.hours == 23);
+        assert!(rtc.minutes == 31);
+        assert!(rtc.seconds == 5);
+        assert!(!rtc.carry);
+
+        rtc.advance(600 * 86400);
+        assert!(rtc.carry);
+        assert!(rtc.days == (12 + 600) % 512);
+    }
+
+    #[test]
+    fn footer() {
+        let mut rtc = rtc_at(300, 12, 34, 56);
+        rtc.write_latch(0);
+        rtc.write_latch(1);
+
+        let footer = rtc.footer();
+        assert!(footer.len() == super::FOOTER_LEN);
+
+        let mut other = Rtc::new();
+        other.load_footer(&footer);
+
+        assert!(other.days == 300);
+        assert!(other.hours == 12);
+        assert!(other.minutes == 34);
+        assert!(other.latched == rtc.latched);
+
+        // 32bit timestamp variant
+        let mut other = Rtc::new();
+        other.load_footer(&footer[..super::FOOTER_LEN_SHORT]);
+
+        assert!(other.days == 300);
+    }
+}
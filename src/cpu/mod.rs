@@ -1,33 +1,68 @@
 extern crate bit_vec;
 
 use self::bit_vec::BitVec;
+use std::io::Result as IoResult;
 
 use cpu::instructions::split_into_halves;
 use cpu::instructions::INSTRUCTIONS_PIPELINE;
 use io::{Interconnect, Interrupt};
+use savestate::{Snapshot, StateReader, StateWriter};
 
 mod cpu_test;
 mod instructions;
 
-pub trait CanRunInstruction {
+/// Interface shared by the CPU cores. Both cores save their state
+/// using the same layout (AF, BC, DE, HL, SP, PC, IME, pending IME,
+/// HALT followed by the interconnect) so a save state made with one
+/// can be loaded in the other.
+pub trait CanRunInstruction: Snapshot {
     fn run_next_instruction(&mut self) -> u8;
+
+    /// Return the current value of the CPU registers
+    fn registers(&self) -> Registers;
+
+    /// Overwrite the CPU registers
+    fn set_registers(&mut self, regs: &Registers);
+
+    /// Return the interconnect driven by this CPU
+    fn interconnect(&self) -> &Interconnect;
+
+    /// Return the interconnect driven by this CPU
+    fn interconnect_mut(&mut self) -> &mut Interconnect;
 }
 
-pub struct Cpu<'a> {
+/// Value of the CPU registers, in the same format for both cores
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Registers {
+    /// Accumulator and flags. The low nibble of F always reads as 0.
+    pub af: u16,
+    /// BC register pair
+    pub bc: u16,
+    /// DE register pair
+    pub de: u16,
+    /// HL register pair
+    pub hl: u16,
+    /// Stack pointer
+    pub sp: u16,
+    /// Program counter
+    pub pc: u16,
+}
+
+pub struct Cpu {
     pub simple_registers: [u8; 6],
     pub accumulator: u8,
     pub flags: [bool; 8], // [0,0,0,0,C,H,N,Z]
     pub stack_pointer: u16,
     pub program_counter: u16,
-    pub memory_map: Interconnect<'a>,
+    pub memory_map: Interconnect,
     is_interrupts_enabled: bool,
     has_to_enable_interrupts_next: bool,
     halted: bool,
     instruction_cycles: u8,
 }
 
-impl<'a> Cpu<'a> {
-    pub fn new<'n>(inter: Interconnect<'n>) -> Cpu<'n> {
+impl Cpu {
+    pub fn new(inter: Interconnect) -> Cpu {
         Cpu {
             simple_registers: [0; 6], //[B, C, D, E, H, L]
             accumulator: 0,
@@ -153,6 +188,7 @@ impl<'a> Cpu<'a> {
             Interrupt::VBlank => 0x40,
             Interrupt::Lcdc => 0x48,
             Interrupt::Timer => 0x50,
+            Interrupt::Serial => 0x58,
             Interrupt::Button => 0x60,
         };
 
@@ -164,8 +200,10 @@ impl<'a> Cpu<'a> {
         self.program_counter = handler_addr;
     }
 
+    /// Stop the system clock until a button is pressed. On the CGB
+    /// STOP is also used to switch the CPU speed.
     pub fn stop(&mut self) {
-        panic!("STOP is not implemented");
+        self.memory_map.stop();
     }
 
     fn delay(&mut self, machine_cycles: u8) {
@@ -203,10 +241,17 @@ impl<'a> Cpu<'a> {
     }
 }
 
-impl<'n> CanRunInstruction for Cpu<'n> {
+impl CanRunInstruction for Cpu {
     fn run_next_instruction(&mut self) -> u8 {
         self.instruction_cycles = 0;
 
+        if self.memory_map.stopped() {
+            // Nothing runs until a button is pressed, just let the
+            // time pass
+            self.instruction_cycles = 4;
+            return self.instruction_cycles;
+        }
+
         if self.is_interrupts_enabled {
             if let Some(it) = self.memory_map.next_interrupt_ack() {
                 // We have a pending interrupt!
@@ -235,12 +280,7 @@ impl<'n> CanRunInstruction for Cpu<'n> {
             }
         }
 
-        trace!(
-            "about to read instruction at pc {:x}\n",
-            self.program_counter
-        );
         let instruction_code = self.read_and_advance_program_counter();
-        trace!("about to run instruction {:x}\n", instruction_code);
         let (first_half, second_half) = split_into_halves(instruction_code);
         for instruction in INSTRUCTIONS_PIPELINE.iter() {
             if instruction(self, instruction_code, first_half, second_half) {
@@ -252,4 +292,65 @@ impl<'n> CanRunInstruction for Cpu<'n> {
             instruction_code, self.program_counter
         );
     }
+
+    fn registers(&self) -> Registers {
+        Registers {
+            af: self.read_combined_register(6),
+            bc: self.read_combined_register(0),
+            de: self.read_combined_register(2),
+            hl: self.read_combined_register(4),
+            sp: self.stack_pointer,
+            pc: self.program_counter,
+        }
+    }
+
+    fn set_registers(&mut self, regs: &Registers) {
+        self.write_combined_register(regs.af & 0xfff0, 6);
+        self.write_combined_register(regs.bc, 0);
+        self.write_combined_register(regs.de, 2);
+        self.write_combined_register(regs.hl, 4);
+        self.stack_pointer = regs.sp;
+        self.program_counter = regs.pc;
+    }
+
+    fn interconnect(&self) -> &Interconnect {
+        &self.memory_map
+    }
+
+    fn interconnect_mut(&mut self) -> &mut Interconnect {
+        &mut self.memory_map
+    }
+}
+
+impl Snapshot for Cpu {
+    fn snapshot(&self, w: &mut StateWriter) {
+        w.write_u16(self.read_combined_register(6));
+        w.write_u16(self.read_combined_register(0));
+        w.write_u16(self.read_combined_register(2));
+        w.write_u16(self.read_combined_register(4));
+        w.write_u16(self.stack_pointer);
+        w.write_u16(self.program_counter);
+        w.write_bool(self.is_interrupts_enabled);
+        w.write_bool(self.has_to_enable_interrupts_next);
+        w.write_bool(self.halted);
+        self.memory_map.snapshot(w);
+    }
+
+    fn restore(&mut self, r: &mut StateReader) -> IoResult<()> {
+        // The low nibble of F always reads as 0
+        let af = try!(r.read_u16()) & 0xfff0;
+        self.write_combined_register(af, 6);
+
+        for &first_register in [0, 2, 4].iter() {
+            let v = try!(r.read_u16());
+            self.write_combined_register(v, first_register);
+        }
+
+        self.stack_pointer = try!(r.read_u16());
+        self.program_counter = try!(r.read_u16());
+        self.is_interrupts_enabled = try!(r.read_bool());
+        self.has_to_enable_interrupts_next = try!(r.read_bool());
+        self.halted = try!(r.read_bool());
+        self.memory_map.restore(r)
+    }
 }
@@ -0,0 +1,593 @@
+//! GDB remote serial protocol stub
+//!
+//! Lets GDB (or any other tool speaking the protocol) control the
+//! emulation over TCP. The registers are exposed as six 16 bit
+//! little endian values in this order: AF, BC, DE, HL, SP and PC.
+//!
+//! Software and hardware breakpoints are both implemented as PC
+//! breakpoints since we can't patch the ROM anyway. Watchpoints use
+//! the interconnect's memory watchpoints.
+
+use std::io::{Read, Result as IoResult, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+
+use cpu::Registers;
+use gameboy::GameBoy;
+use io::watch::{Access, Watchpoint};
+
+use super::{Debugger, StopReason};
+
+/// Connection with a GDB client
+pub struct GdbStub {
+    /// Debugger doing the actual work
+    debugger: Debugger,
+    /// Stream used to send packets to the client
+    stream: TcpStream,
+    /// Data received from the client by the reader thread
+    incoming: Receiver<Vec<u8>>,
+    /// Data received but not parsed yet
+    buf: Vec<u8>,
+    /// Last stop reply sent to the client
+    last_stop: String,
+    /// `false` once the client detached or disconnected
+    connected: bool,
+    /// `true` if the client asked us to kill the emulator
+    killed: bool,
+}
+
+impl GdbStub {
+    /// Wait for a GDB client to connect on `addr`
+    pub fn listen<A: ToSocketAddrs>(addr: A) -> IoResult<GdbStub> {
+        let listener = try!(TcpListener::bind(addr));
+
+        info!("Waiting for GDB on {}", try!(listener.local_addr()));
+
+        let (stream, peer) = try!(listener.accept());
+
+        info!("GDB connected from {}", peer);
+
+        GdbStub::from_stream(stream)
+    }
+
+    fn from_stream(stream: TcpStream) -> IoResult<GdbStub> {
+        try!(stream.set_nodelay(true));
+
+        let mut reader = try!(stream.try_clone());
+        let (tx, rx) = channel();
+
+        // Reading in a separate thread lets `run` stay non blocking
+        ::std::thread::spawn(move || loop {
+            let mut data = [0; 1024];
+
+            let n = match reader.read(&mut data) {
+                Ok(0) => return,
+                Ok(n) => n,
+                Err(e) => {
+                    info!("GDB disconnected: {}", e);
+                    return;
+                }
+            };
+
+            if tx.send(data[..n].to_vec()).is_err() {
+                return;
+            }
+        });
+
+        Ok(GdbStub {
+            // GDB expects the target to be stopped when it connects
+            debugger: Debugger::new(true),
+            stream: stream,
+            incoming: rx,
+            buf: Vec::new(),
+            last_stop: "S05".into(),
+            connected: true,
+            killed: false,
+        })
+    }
+
+    /// Return `true` if the emulation is stopped
+    pub fn paused(&self) -> bool {
+        self.debugger.paused()
+    }
+
+    /// Return `true` while the client is attached
+    pub fn connected(&self) -> bool {
+        self.connected
+    }
+
+    /// Return `true` if the client asked us to kill the emulator
+    pub fn killed(&self) -> bool {
+        self.killed
+    }
+
+    /// Handle the pending packets then run `gb` for at least `cycles`
+    /// system clock ticks, unless a breakpoint is hit earlier.
+    /// Returns the number of ticks elapsed.
+    pub fn run(&mut self, gb: &mut GameBoy, cycles: u64) -> u64 {
+        while self.connected {
+            match self.incoming.try_recv() {
+                Ok(data) => self.buf.extend_from_slice(&data),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => self.detach(gb),
+            }
+        }
+
+        while let Some(packet) = self.next_packet() {
+            match packet {
+                Packet::Interrupt => {
+                    if !self.debugger.paused() {
+                        self.debugger.pause();
+                        self.stop("S02".into());
+                    }
+                }
+                Packet::Command(cmd) => {
+                    let _ = self.stream.write_all(b"+");
+
+                    if let Some(reply) = self.command(gb, &cmd) {
+                        self.send(&reply);
+                    }
+                }
+            }
+        }
+
+        let (elapsed, stop) = self.debugger.run(gb, cycles);
+
+        if let Some(reason) = stop {
+            let reply = match reason {
+                StopReason::Watch(hit) => {
+                    let kind = match hit.access {
+                        Access::Read => "rwatch",
+                        Access::Write => "watch",
+                    };
+
+                    format!("T05{}:{:04x};", kind, hit.addr)
+                }
+                _ => "S05".into(),
+            };
+
+            self.stop(reply);
+        }
+
+        elapsed
+    }
+
+    /// Extract the next packet from the receive buffer
+    fn next_packet(&mut self) -> Option<Packet> {
+        loop {
+            match self.buf.first().cloned() {
+                None => return None,
+                Some(0x03) => {
+                    self.buf.remove(0);
+                    return Some(Packet::Interrupt);
+                }
+                Some(b'$') => break,
+                // Acks and garbage
+                Some(_) => {
+                    self.buf.remove(0);
+                }
+            }
+        }
+
+        let end = match self.buf.iter().position(|&b| b == b'#') {
+            // Wait for the checksum
+            Some(end) if end + 3 <= self.buf.len() => end,
+            _ => return None,
+        };
+
+        let packet: Vec<u8> = self.buf.drain(..end + 3).collect();
+        let data = &packet[1..end];
+        let expected = ::std::str::from_utf8(&packet[end + 1..])
+            .ok()
+            .and_then(|c| u8::from_str_radix(c, 16).ok());
+
+        if expected != Some(checksum(data)) {
+            warn!("Bad GDB packet checksum");
+            let _ = self.stream.write_all(b"-");
+            return self.next_packet();
+        }
+
+        Some(Packet::Command(String::from_utf8_lossy(data).into_owned()))
+    }
+
+    /// Handle a command packet and return the reply, if any
+    fn command(&mut self, gb: &mut GameBoy, cmd: &str) -> Option<String> {
+        // Empty and non-ASCII commands are unsupported, like unknown
+        // ones
+        let (c, args) = match cmd.chars().next() {
+            Some(c) if c.is_ascii() => (c, &cmd[1..]),
+            _ => return Some(String::new()),
+        };
+
+        let reply = match c {
+            '?' => self.last_stop.clone(),
+            'g' => {
+                let r = gb.registers();
+
+                [r.af, r.bc, r.de, r.hl, r.sp, r.pc]
+                    .iter()
+                    .map(|&v| le16(v))
+                    .collect()
+            }
+            'G' => {
+                let mut vals = [0; 6];
+
+                for (i, v) in vals.iter_mut().enumerate() {
+                    match args.get(i * 4..i * 4 + 4).and_then(parse_le16) {
+                        Some(r) => *v = r,
+                        None => return Some("E01".into()),
+                    }
+                }
+
+                gb.set_registers(&Registers {
+                    af: vals[0],
+                    bc: vals[1],
+                    de: vals[2],
+                    hl: vals[3],
+                    sp: vals[4],
+                    pc: vals[5],
+                });
+
+                "OK".into()
+            }
+            'p' => {
+                let r = gb.registers();
+
+                match parse_hex(args) {
+                    Some(0) => le16(r.af),
+                    Some(1) => le16(r.bc),
+                    Some(2) => le16(r.de),
+                    Some(3) => le16(r.hl),
+                    Some(4) => le16(r.sp),
+                    Some(5) => le16(r.pc),
+                    _ => "E01".into(),
+                }
+            }
+            'P' => {
+                let mut r = gb.registers();
+                let mut split = args.splitn(2, '=');
+                let n = split.next().and_then(parse_hex);
+                let v = split.next().and_then(parse_le16);
+
+                {
+                    let reg = match (n, v) {
+                        (Some(0), Some(_)) => &mut r.af,
+                        (Some(1), Some(_)) => &mut r.bc,
+                        (Some(2), Some(_)) => &mut r.de,
+                        (Some(3), Some(_)) => &mut r.hl,
+                        (Some(4), Some(_)) => &mut r.sp,
+                        (Some(5), Some(_)) => &mut r.pc,
+                        _ => return Some("E01".into()),
+                    };
+
+                    *reg = v.unwrap();
+                }
+
+                gb.set_registers(&r);
+
+                "OK".into()
+            }
+            'm' => {
+                let mut split = args.splitn(2, ',');
+                let addr = split.next().and_then(parse_hex);
+                let len = split.next().and_then(parse_hex);
+
+                match (addr, len) {
+                    // The reply must fit in the packet size we
+                    // advertised, two hex digits per byte
+                    (Some(addr), Some(len)) if len as usize <= PACKET_SIZE / 2 => (0..len)
+                        .map(|i| {
+                            let b = gb.interconnect().peek_byte((addr as u16).wrapping_add(i as u16));
+
+                            format!("{:02x}", b)
+                        })
+                        .collect(),
+                    _ => "E01".into(),
+                }
+            }
+            'M' => {
+                let mut split = args.splitn(2, ':');
+                let mut range = split.next().unwrap_or("").splitn(2, ',');
+                let addr = range.next().and_then(parse_hex);
+                let len = range.next().and_then(parse_hex);
+                let data = split.next().and_then(parse_bytes);
+
+                match (addr, len, data) {
+                    (Some(addr), Some(len), Some(ref data)) if data.len() == len as usize => {
+                        for (i, &b) in data.iter().enumerate() {
+                            gb.interconnect_mut().poke_byte((addr as u16).wrapping_add(i as u16), b);
+                        }
+
+                        "OK".into()
+                    }
+                    _ => "E01".into(),
+                }
+            }
+            'c' | 's' => {
+                if let Some(addr) = parse_hex(args) {
+                    let mut r = gb.registers();
+
+                    r.pc = addr as u16;
+                    gb.set_registers(&r);
+                }
+
+                match c {
+                    'c' => self.debugger.resume(),
+                    _ => self.debugger.step(),
+                }
+
+                // The reply is sent once we stop
+                return None;
+            }
+            'Z' | 'z' => {
+                let mut split = args.split(',');
+                let kind = split.next().and_then(parse_hex);
+                let addr = split.next().and_then(parse_hex).map(|a| a as u16);
+
+                match (kind, addr) {
+                    (Some(kind), Some(addr)) if kind <= 4 => {
+                        self.breakpoint(gb, kind, addr, c == 'Z');
+                        "OK".into()
+                    }
+                    // Unsupported breakpoint type
+                    (Some(_), Some(_)) => String::new(),
+                    _ => "E01".into(),
+                }
+            }
+            'k' => {
+                self.connected = false;
+                self.killed = true;
+                return None;
+            }
+            'D' => {
+                self.send("OK");
+                self.detach(gb);
+                return None;
+            }
+            'H' => "OK".into(),
+            'q' => {
+                if cmd.starts_with("qSupported") {
+                    format!("PacketSize={:x}", PACKET_SIZE)
+                } else if cmd.starts_with("qAttached") {
+                    "1".into()
+                } else {
+                    String::new()
+                }
+            }
+            // Unsupported packet
+            _ => String::new(),
+        };
+
+        Some(reply)
+    }
+
+    /// Insert or remove a breakpoint or watchpoint
+    fn breakpoint(&mut self, gb: &mut GameBoy, kind: u32, addr: u16, insert: bool) {
+        let (read, write) = match kind {
+            0 | 1 => {
+                if insert {
+                    self.debugger.add_breakpoint(addr);
+                } else {
+                    self.debugger.remove_breakpoint(addr);
+                }
+                return;
+            }
+            2 => (false, true),
+            3 => (true, false),
+            _ => (true, true),
+        };
+
+        let watchpoints = gb.interconnect_mut().watchpoints_mut();
+
+        // GDB may set a read and a write watchpoint on the same
+        // address separately
+        let mut w = watchpoints
+            .list()
+            .iter()
+            .find(|w| w.addr == addr)
+            .cloned()
+            .unwrap_or(Watchpoint {
+                addr: addr,
+                read: false,
+                write: false,
+            });
+
+        if insert {
+            w.read |= read;
+            w.write |= write;
+        } else {
+            w.read &= !read;
+            w.write &= !write;
+        }
+
+        match w.read || w.write {
+            true => watchpoints.add(w),
+            false => {
+                watchpoints.remove(addr);
+            }
+        }
+    }
+
+    /// Client went away: drop all the breakpoints and let the
+    /// emulation run freely
+    fn detach(&mut self, gb: &mut GameBoy) {
+        self.connected = false;
+        self.debugger = Debugger::new(false);
+        gb.interconnect_mut().watchpoints_mut().clear();
+    }
+
+    fn stop(&mut self, reply: String) {
+        self.send(&reply);
+        self.last_stop = reply;
+    }
+
+    fn send(&mut self, data: &str) {
+        let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+
+        if let Err(e) = self.stream.write_all(packet.as_bytes()) {
+            debug!("GDB write failed: {}", e);
+        }
+    }
+}
+
+/// Maximum size of the packets we handle, advertised to the client
+const PACKET_SIZE: usize = 0x1000;
+
+/// Data received from the client
+enum Packet {
+    /// Ctrl-C: stop the emulation
+    Interrupt,
+    /// Command packet, without the framing
+    Command(String),
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |s, &b| s.wrapping_add(b))
+}
+
+fn parse_hex(s: &str) -> Option<u32> {
+    u32::from_str_radix(s, 16).ok()
+}
+
+fn parse_bytes(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+
+    (0..s.len() / 2)
+        .map(|i| s.get(i * 2..i * 2 + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+        .collect()
+}
+
+/// Registers are sent in target byte order
+fn le16(v: u16) -> String {
+    format!("{:02x}{:02x}", v & 0xff, v >> 8)
+}
+
+fn parse_le16(s: &str) -> Option<u16> {
+    match parse_bytes(s) {
+        Some(ref b) if b.len() == 2 => Some(b[0] as u16 | (b[1] as u16) << 8),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    use super::{checksum, GdbStub};
+    use debugger::tests::gameboy;
+
+    /// Minimal GDB client
+    struct Client(TcpStream);
+
+    impl Client {
+        fn request(&mut self, cmd: &str) -> String {
+            self.send(cmd);
+            self.reply()
+        }
+
+        fn send(&mut self, cmd: &str) {
+            self.send_bytes(cmd.as_bytes());
+        }
+
+        fn send_bytes(&mut self, cmd: &[u8]) {
+            let mut packet = vec![b'$'];
+
+            packet.extend_from_slice(cmd);
+            packet.extend_from_slice(format!("#{:02x}", checksum(cmd)).as_bytes());
+
+            self.0.write_all(&packet).unwrap();
+
+            let mut ack = [0];
+            self.0.read_exact(&mut ack).unwrap();
+            assert!(ack[0] == b'+');
+        }
+
+        fn reply(&mut self) -> String {
+            let mut packet = Vec::new();
+            let mut b = [0];
+
+            loop {
+                self.0.read_exact(&mut b).unwrap();
+                packet.push(b[0]);
+
+                if packet.len() > 3 && packet[packet.len() - 3] == b'#' {
+                    break;
+                }
+            }
+
+            assert!(packet[0] == b'$');
+
+            String::from_utf8(packet[1..packet.len() - 3].to_vec()).unwrap()
+        }
+    }
+
+    #[test]
+    fn mock_client() {
+        let mut gb = gameboy(&[
+            // 0100: LD A, 0x42
+            0x3e, 0x42,
+            // 0102: LD (0xc000), A
+            0xea, 0x00, 0xc0,
+            // 0105: JR -2
+            0x18, 0xfe,
+        ]);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = ::std::thread::spawn(move || {
+            let mut c = Client(TcpStream::connect(addr).unwrap());
+
+            assert!(c.request("qSupported:swbreak+") == "PacketSize=1000");
+            assert!(c.request("?") == "S05");
+            // SP and PC are the last two registers
+            assert!(c.request("g").ends_with("feff0001"));
+            assert!(c.request("p5") == "0001");
+
+            assert!(c.request("Mc001,2:abcd") == "OK");
+            assert!(c.request("mc001,2") == "abcd");
+            assert!(c.request("mc000,800").len() == 0x1000);
+            assert!(c.request("mc000,801") == "E01");
+            assert!(c.request("m0,ffffffff") == "E01");
+
+            // Not UTF-8
+            c.send_bytes(b"\xffc000,1");
+            assert!(c.reply() == "");
+
+            // Write watchpoint on 0xc000
+            assert!(c.request("Z2,c000,1") == "OK");
+            c.send("c");
+            assert!(c.reply() == "T05watch:c000;");
+            assert!(c.request("mc000,1") == "42");
+            assert!(c.request("z2,c000,1") == "OK");
+
+            // Hardware breakpoint on the loop
+            assert!(c.request("Z1,105,1") == "OK");
+            c.send("c");
+            assert!(c.reply() == "S05");
+            assert!(c.request("p5") == "0501");
+
+            c.send("s");
+            assert!(c.reply() == "S05");
+
+            assert!(c.request("P5=0001") == "OK");
+            assert!(c.request("p5") == "0001");
+
+            c.send("k");
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        let mut stub = GdbStub::from_stream(stream).unwrap();
+
+        while stub.connected() {
+            stub.run(&mut gb, 1000);
+        }
+
+        client.join().unwrap();
+
+        assert!(stub.killed());
+    }
+}
@@ -0,0 +1,670 @@
+//! Interactive debugger
+//!
+//! The debugger sits between the frontend and the `GameBoy` and runs
+//! the emulation one instruction at a time in order to stop on
+//! breakpoints. It's driven by text commands, typically read from
+//! the terminal by `stdin_commands` while the frontend keeps
+//! refreshing the display.
+//!
+//! Addresses and values are always given in hexadecimal (with an
+//! optional `0x` or `$` prefix), counts are in decimal.
+
+use std::fmt;
+use std::io::BufRead;
+use std::sync::mpsc::{channel, Receiver};
+
+use cpu::Registers;
+use disasm;
+use gameboy::GameBoy;
+use io::watch::{Access, WatchHit, Watchpoint};
+use io::Interrupt;
+
+pub mod gdb;
+
+/// Debugger state
+pub struct Debugger {
+    /// PC breakpoints
+    breakpoints: Vec<u16>,
+    /// Interrupts we stop on when they're serviced
+    catch: Vec<Interrupt>,
+    /// `true` if the emulation is stopped
+    paused: bool,
+    /// What we're currently doing when not paused
+    mode: Mode,
+}
+
+/// Execution mode
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Mode {
+    /// Run until something stops us
+    Run,
+    /// Run `n` instructions
+    Step(u32),
+    /// Run until we reach `target` with the stack at or above `sp`
+    StepOver { target: u16, sp: u16 },
+    /// Run until a return instruction pops the stack above `sp`
+    Finish { sp: u16 },
+}
+
+/// Reason why the debugger stopped the emulation
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    /// Reached a PC breakpoint
+    Breakpoint(u16),
+    /// Hit a watchpoint
+    Watch(WatchHit),
+    /// A caught interrupt has been serviced
+    Interrupt(Interrupt),
+    /// Done stepping
+    Step,
+    /// Returned from the current function
+    Finished,
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StopReason::Breakpoint(addr) => write!(f, "Breakpoint at {:04x}", addr),
+            StopReason::Watch(hit) => {
+                let access = match hit.access {
+                    Access::Read => "Read",
+                    Access::Write => "Write",
+                };
+
+                write!(f, "{} of {:02x} at {:04x}", access, hit.value, hit.addr)
+            }
+            StopReason::Interrupt(it) => write!(f, "{:?} interrupt", it),
+            StopReason::Step => write!(f, "Step"),
+            StopReason::Finished => write!(f, "Returned"),
+        }
+    }
+}
+
+impl Debugger {
+    /// Create a new debugger. If `paused` is true the emulation won't
+    /// start until told to.
+    pub fn new(paused: bool) -> Debugger {
+        Debugger {
+            breakpoints: Vec::new(),
+            catch: Vec::new(),
+            paused: paused,
+            mode: Mode::Run,
+        }
+    }
+
+    /// Return `true` if the emulation is stopped
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Run `gb` for at least `cycles` system clock ticks, unless
+    /// something makes us stop earlier. Returns the number of ticks
+    /// elapsed and the reason we stopped, if any.
+    pub fn run(&mut self, gb: &mut GameBoy, cycles: u64) -> (u64, Option<StopReason>) {
+        let mut elapsed = 0;
+
+        if self.paused {
+            return (0, None);
+        }
+
+        // Discard anything left over from a previous run
+        gb.interconnect().watchpoints().take_hit();
+        gb.interconnect_mut().take_interrupt();
+
+        while elapsed < cycles {
+            let before = gb.registers();
+            let op = gb.interconnect().peek_byte(before.pc);
+
+            elapsed += gb.step_instruction();
+
+            if let Some(reason) = self.check(gb, &before, op) {
+                self.pause();
+                return (elapsed, Some(reason));
+            }
+        }
+
+        (elapsed, None)
+    }
+
+    /// Figure out whether we should stop after running the
+    /// instruction `op` with registers `before`
+    fn check(&mut self, gb: &mut GameBoy, before: &Registers, op: u8) -> Option<StopReason> {
+        let regs = gb.registers();
+
+        if let Some(hit) = gb.interconnect().watchpoints().take_hit() {
+            return Some(StopReason::Watch(hit));
+        }
+
+        if let Some(it) = gb.interconnect_mut().take_interrupt() {
+            if self.catch.contains(&it) {
+                return Some(StopReason::Interrupt(it));
+            }
+        }
+
+        match self.mode {
+            Mode::Run => (),
+            Mode::Step(n) => {
+                if n <= 1 {
+                    return Some(StopReason::Step);
+                }
+                self.mode = Mode::Step(n - 1);
+            }
+            Mode::StepOver { target, sp } => {
+                if regs.pc == target && regs.sp >= sp {
+                    return Some(StopReason::Step);
+                }
+            }
+            Mode::Finish { sp } => {
+                let ret = match op {
+                    0xc0 | 0xc8 | 0xc9 | 0xd0 | 0xd8 | 0xd9 => true,
+                    _ => false,
+                };
+
+                if ret && regs.pc != before.pc && regs.sp > sp {
+                    return Some(StopReason::Finished);
+                }
+            }
+        }
+
+        if self.breakpoints.contains(&regs.pc) {
+            return Some(StopReason::Breakpoint(regs.pc));
+        }
+
+        None
+    }
+
+    /// Execute the debugger command `line` and return its output
+    pub fn execute(&mut self, gb: &mut GameBoy, line: &str) -> String {
+        let args: Vec<&str> = line.split_whitespace().collect();
+
+        if args.is_empty() {
+            return String::new();
+        }
+
+        match self.command(gb, args[0], &args[1..]) {
+            Ok(s) => s,
+            Err(e) => format!("{}\n", e),
+        }
+    }
+
+    fn command(&mut self, gb: &mut GameBoy, cmd: &str, args: &[&str]) -> Result<String, String> {
+        match cmd {
+            "h" | "help" => Ok(HELP.into()),
+            "c" | "continue" => self.start(Mode::Run),
+            "s" | "step" => {
+                let n = match args.first() {
+                    Some(n) => try!(n.parse().map_err(|_| format!("Invalid count {}", n))),
+                    None => 1,
+                };
+
+                self.start(Mode::Step(n))
+            }
+            "n" | "next" => {
+                let regs = gb.registers();
+                let op = gb.interconnect().peek_byte(regs.pc);
+
+                let len = match op {
+                    // CALL
+                    0xc4 | 0xcc | 0xcd | 0xd4 | 0xdc => 3,
+                    // RST
+                    0xc7 | 0xcf | 0xd7 | 0xdf | 0xe7 | 0xef | 0xf7 | 0xff => 1,
+                    _ => return self.start(Mode::Step(1)),
+                };
+
+                self.start(Mode::StepOver {
+                    target: regs.pc.wrapping_add(len),
+                    sp: regs.sp,
+                })
+            }
+            "finish" => {
+                let sp = gb.registers().sp;
+
+                self.start(Mode::Finish { sp: sp })
+            }
+            "pause" => {
+                self.pause();
+                Ok(self.status(gb))
+            }
+            "b" | "break" => {
+                let addr = try!(parse_hex(try!(arg(args, 0))));
+
+                self.add_breakpoint(addr);
+
+                Ok(format!("Breakpoint at {:04x}\n", addr))
+            }
+            "delete" => {
+                let addr = try!(parse_hex(try!(arg(args, 0))));
+
+                match self.remove_breakpoint(addr) {
+                    true => Ok(String::new()),
+                    false => Err(format!("No breakpoint at {:04x}", addr)),
+                }
+            }
+            "watch" => {
+                let addr = try!(parse_hex(try!(arg(args, 0))));
+                let (read, write) = match args.get(1).cloned().unwrap_or("w") {
+                    "r" => (true, false),
+                    "w" => (false, true),
+                    "rw" => (true, true),
+                    a => return Err(format!("Invalid access {}", a)),
+                };
+
+                gb.interconnect_mut().watchpoints_mut().add(Watchpoint {
+                    addr: addr,
+                    read: read,
+                    write: write,
+                });
+
+                Ok(format!("Watchpoint at {:04x}\n", addr))
+            }
+            "unwatch" => {
+                let addr = try!(parse_hex(try!(arg(args, 0))));
+
+                match gb.interconnect_mut().watchpoints_mut().remove(addr) {
+                    true => Ok(String::new()),
+                    false => Err(format!("No watchpoint at {:04x}", addr)),
+                }
+            }
+            "catch" => {
+                let it = try!(parse_interrupt(try!(arg(args, 0))));
+
+                if !self.catch.contains(&it) {
+                    self.catch.push(it);
+                }
+
+                Ok(String::new())
+            }
+            "uncatch" => {
+                let it = try!(parse_interrupt(try!(arg(args, 0))));
+
+                self.catch.retain(|&i| i != it);
+
+                Ok(String::new())
+            }
+            "list" => {
+                let mut s = String::new();
+
+                for b in &self.breakpoints {
+                    s += &format!("break {:04x}\n", b);
+                }
+
+                for w in gb.interconnect().watchpoints().list() {
+                    let access = match (w.read, w.write) {
+                        (true, true) => "rw",
+                        (true, false) => "r",
+                        _ => "w",
+                    };
+
+                    s += &format!("watch {:04x} {}\n", w.addr, access);
+                }
+
+                for it in &self.catch {
+                    s += &format!("catch {:?}\n", it);
+                }
+
+                Ok(s)
+            }
+            "r" | "regs" => Ok(self.status(gb)),
+            "set" => {
+                let reg = try!(arg(args, 0));
+                let val = try!(parse_hex(try!(arg(args, 1))));
+                let mut regs = gb.registers();
+
+                try!(set_register(&mut regs, reg, val));
+
+                gb.set_registers(&regs);
+
+                Ok(self.status(gb))
+            }
+            "x" => {
+                let addr = try!(parse_hex(try!(arg(args, 0))));
+                let len = match args.get(1) {
+                    Some(n) => try!(n.parse().map_err(|_| format!("Invalid length {}", n))),
+                    None => 16,
+                };
+
+                Ok(dump(gb, addr, len))
+            }
+            "dis" => {
+                let addr = match args.first() {
+                    Some(a) => try!(parse_hex(a)),
+                    None => gb.registers().pc,
+                };
+                let count = match args.get(1) {
+                    Some(n) => try!(n.parse().map_err(|_| format!("Invalid count {}", n))),
+                    None => 10,
+                };
+
+                Ok(disassemble(gb, addr, count))
+            }
+            "w" => {
+                let mut addr = try!(parse_hex(try!(arg(args, 0))));
+
+                if args.len() < 2 {
+                    return Err("Missing value".into());
+                }
+
+                for v in &args[1..] {
+                    let v = try!(parse_hex(v));
+
+                    if v > 0xff {
+                        return Err(format!("Invalid byte {:x}", v));
+                    }
+
+                    gb.interconnect_mut().poke_byte(addr, v as u8);
+                    addr = addr.wrapping_add(1);
+                }
+
+                Ok(String::new())
+            }
+            "bank" => {
+                let cart = gb.cartridge();
+
+                Ok(format!("ROM bank {}/{}, RAM bank {}\n",
+                           cart.rom_bank(),
+                           cart.rom_banks(),
+                           cart.ram_bank()))
+            }
+            "rombank" => {
+                let bank = try!(parse_hex(try!(arg(args, 0))));
+                let cart = gb.interconnect_mut().cartridge_mut();
+
+                if bank >= cart.rom_banks() {
+                    return Err(format!("Invalid ROM bank {:x}", bank));
+                }
+
+                cart.force_rom_bank(bank);
+
+                Ok(String::new())
+            }
+            "rambank" => {
+                let bank = try!(parse_hex(try!(arg(args, 0))));
+                let cart = gb.interconnect_mut().cartridge_mut();
+                let banks = cart.parse_ram_banks().map(|(n, _)| n).unwrap_or(0);
+
+                if bank as usize >= banks {
+                    return Err(format!("Invalid RAM bank {:x}", bank));
+                }
+
+                cart.set_ram_bank(bank as u8);
+
+                Ok(String::new())
+            }
+            _ => Err(format!("Unknown command {}, try 'help'", cmd)),
+        }
+    }
+
+    fn start(&mut self, mode: Mode) -> Result<String, String> {
+        self.mode = mode;
+        self.paused = false;
+
+        Ok(String::new())
+    }
+
+    /// Resume the emulation until something stops it
+    pub fn resume(&mut self) {
+        let _ = self.start(Mode::Run);
+    }
+
+    /// Resume the emulation for a single instruction
+    pub fn step(&mut self) {
+        let _ = self.start(Mode::Step(1));
+    }
+
+    /// Stop the emulation
+    pub fn pause(&mut self) {
+        self.paused = true;
+        self.mode = Mode::Run;
+    }
+
+    /// Stop when PC reaches `addr`
+    pub fn add_breakpoint(&mut self, addr: u16) {
+        if !self.breakpoints.contains(&addr) {
+            self.breakpoints.push(addr);
+        }
+    }
+
+    /// Remove the breakpoint on `addr`. Returns `false` if there was
+    /// none.
+    pub fn remove_breakpoint(&mut self, addr: u16) -> bool {
+        let len = self.breakpoints.len();
+
+        self.breakpoints.retain(|&b| b != addr);
+
+        self.breakpoints.len() != len
+    }
+
+    /// Return a description of the stop `reason` followed by the
+    /// current CPU state
+    pub fn report(&self, gb: &GameBoy, reason: StopReason) -> String {
+        format!("{}\n{}", reason, self.status(gb))
+    }
+
+    fn status(&self, gb: &GameBoy) -> String {
+        let r = gb.registers();
+        let f = r.af as u8;
+
+        let flag = |bit: u8, c: char| match f & (1 << bit) != 0 {
+            true => c,
+            false => '-',
+        };
+
+        format!("AF: {:04x} BC: {:04x} DE: {:04x} HL: {:04x} SP: {:04x} PC: {:04x} [{}{}{}{}]\n{}",
+                r.af, r.bc, r.de, r.hl, r.sp, r.pc,
+                flag(7, 'Z'), flag(6, 'N'), flag(5, 'H'), flag(4, 'C'),
+                disassemble(gb, r.pc, 1))
+    }
+}
+
+/// Spawn a thread reading debugger commands from the standard input
+/// and return the channel they're sent to
+pub fn stdin_commands() -> Receiver<String> {
+    let (tx, rx) = channel();
+
+    ::std::thread::spawn(move || {
+        let stdin = ::std::io::stdin();
+
+        for line in stdin.lock().lines() {
+            let line = match line {
+                Ok(l) => l,
+                Err(_) => return,
+            };
+
+            if tx.send(line).is_err() {
+                return;
+            }
+        }
+    });
+
+    rx
+}
+
+const HELP: &'static str = "\
+c, continue            resume execution
+s, step [n]            run n instructions (default 1)
+n, next                step over calls
+finish                 run until the current function returns
+pause                  stop execution
+b, break <addr>        break when PC reaches addr
+delete <addr>          remove the breakpoint at addr
+watch <addr> [r|w|rw]  break on memory access (default w)
+unwatch <addr>         remove the watchpoint at addr
+catch <int>            break when interrupt is serviced
+                       (vblank, lcdc, timer, serial, button)
+uncatch <int>          stop catching interrupt
+list                   list breakpoints, watchpoints and catches
+r, regs                show the CPU registers
+set <reg> <val>        set a register (a, f, b, ..., af, bc, sp, pc)
+x <addr> [len]         dump len bytes of memory (default 16)
+dis [addr] [n]         disassemble n instructions (default 10 at PC)
+w <addr> <byte>...     write bytes to memory
+bank                   show the cartridge ROM and RAM banks
+rombank <n>            map ROM bank n in [4000, 7fff]
+rambank <n>            map RAM bank n in [a000, bfff]
+";
+
+fn arg<'a>(args: &[&'a str], n: usize) -> Result<&'a str, String> {
+    args.get(n).cloned().ok_or("Missing argument".into())
+}
+
+fn parse_hex(s: &str) -> Result<u16, String> {
+    let digits = if s.starts_with("0x") {
+        &s[2..]
+    } else if s.starts_with('$') {
+        &s[1..]
+    } else {
+        s
+    };
+
+    u16::from_str_radix(digits, 16).map_err(|_| format!("Invalid number {}", s))
+}
+
+fn parse_interrupt(s: &str) -> Result<Interrupt, String> {
+    match s {
+        "vblank" => Ok(Interrupt::VBlank),
+        "lcdc" => Ok(Interrupt::Lcdc),
+        "timer" => Ok(Interrupt::Timer),
+        "serial" => Ok(Interrupt::Serial),
+        "button" => Ok(Interrupt::Button),
+        _ => Err(format!("Unknown interrupt {}", s)),
+    }
+}
+
+fn set_register(regs: &mut Registers, reg: &str, val: u16) -> Result<(), String> {
+    let set_high = |r: u16| (r & 0xff) | (val << 8);
+    let set_low = |r: u16| (r & 0xff00) | (val & 0xff);
+
+    if val > 0xff && reg.len() == 1 {
+        return Err(format!("Invalid value {:x} for {}", val, reg));
+    }
+
+    match reg {
+        "a" => regs.af = set_high(regs.af),
+        "f" => regs.af = set_low(regs.af),
+        "b" => regs.bc = set_high(regs.bc),
+        "c" => regs.bc = set_low(regs.bc),
+        "d" => regs.de = set_high(regs.de),
+        "e" => regs.de = set_low(regs.de),
+        "h" => regs.hl = set_high(regs.hl),
+        "l" => regs.hl = set_low(regs.hl),
+        "af" => regs.af = val,
+        "bc" => regs.bc = val,
+        "de" => regs.de = val,
+        "hl" => regs.hl = val,
+        "sp" => regs.sp = val,
+        "pc" => regs.pc = val,
+        _ => return Err(format!("Unknown register {}", reg)),
+    }
+
+    Ok(())
+}
+
+/// Disassemble `count` instructions starting at `addr`
+fn disassemble(gb: &GameBoy, addr: u16, count: u32) -> String {
+    let bank = gb.cartridge().rom_bank();
+    let fetch = |a| gb.interconnect().peek_byte(a);
+
+    let mut end = addr as u32;
+
+    for _ in 0..count {
+        end += disasm::decode(bank, end as u16, &fetch).len as u32;
+    }
+
+    disasm::listing(bank, addr, ::std::cmp::min(end, 0x10000), fetch)
+}
+
+/// Hexdump `len` bytes starting at `addr`, 16 per line
+fn dump(gb: &GameBoy, addr: u16, len: u32) -> String {
+    let mut s = String::new();
+
+    for i in 0..len {
+        let a = addr.wrapping_add(i as u16);
+
+        if i % 16 == 0 {
+            if i != 0 {
+                s.push('\n');
+            }
+            s += &format!("{:04x}:", a);
+        }
+
+        s += &format!(" {:02x}", gb.interconnect().peek_byte(a));
+    }
+
+    s.push('\n');
+
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Debugger, StopReason};
+    use gameboy::{self, CpuCore, GameBoy};
+
+    /// Build a GameBoy running `code` at 0x0100
+    pub fn gameboy(code: &[u8]) -> GameBoy {
+        gameboy::test_gameboy(code, CpuCore::Gamedorp)
+    }
+
+    #[test]
+    fn step_over_and_finish() {
+        let mut gb = gameboy(&[
+            // 0100: CALL 0x0110
+            0xcd, 0x10, 0x01,
+            // 0103: JR -2
+            0x18, 0xfe,
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+            // 0110: NOP, NOP, RET
+            0x00, 0x00, 0xc9,
+        ]);
+        let mut dbg = Debugger::new(true);
+
+        assert!(dbg.run(&mut gb, 1000) == (0, None));
+
+        dbg.execute(&mut gb, "next");
+        assert!(dbg.run(&mut gb, 1000).1 == Some(StopReason::Step));
+        assert!(gb.registers().pc == 0x103);
+        assert!(gb.registers().sp == 0xfffe);
+
+        dbg.execute(&mut gb, "set pc 100");
+        dbg.execute(&mut gb, "step 2");
+        assert!(dbg.run(&mut gb, 1000).1 == Some(StopReason::Step));
+        assert!(gb.registers().pc == 0x111);
+
+        dbg.execute(&mut gb, "finish");
+        assert!(dbg.run(&mut gb, 1000).1 == Some(StopReason::Finished));
+        assert!(gb.registers().pc == 0x103);
+    }
+
+    #[test]
+    fn breakpoints_and_watchpoints() {
+        let mut gb = gameboy(&[
+            // 0100: LD A, 0x42
+            0x3e, 0x42,
+            // 0102: LD (0xc000), A
+            0xea, 0x00, 0xc0,
+            // 0105: JR -2
+            0x18, 0xfe,
+        ]);
+        let mut dbg = Debugger::new(false);
+
+        dbg.execute(&mut gb, "watch c000");
+        dbg.execute(&mut gb, "b $0105");
+
+        match dbg.run(&mut gb, 1000).1 {
+            Some(StopReason::Watch(hit)) => {
+                assert!(hit.addr == 0xc000);
+                assert!(hit.value == 0x42);
+            }
+            r => panic!("Unexpected stop {:?}", r),
+        }
+        assert!(dbg.paused());
+
+        dbg.execute(&mut gb, "c");
+        assert!(dbg.run(&mut gb, 1000).1 == Some(StopReason::Breakpoint(0x105)));
+
+        dbg.execute(&mut gb, "set a 12");
+        dbg.execute(&mut gb, "w c001 ab cd");
+        assert!(gb.registers().af >> 8 == 0x12);
+        assert!(dbg.execute(&mut gb, "x c000 3") == "c000: 42 ab cd\n");
+    }
+}
@@ -0,0 +1,682 @@
+//! SM83 disassembler
+//!
+//! Standalone decoder for the base and 0xCB-prefixed opcode tables,
+//! independent of either CPU implementation. Memory is accessed
+//! through a `fetch` closure so that it works on a ROM image as well
+//! as on the live memory map.
+//!
+//! Addresses are displayed as `bank:address`, where the bank is the
+//! ROM bank the code lives in (always 0 outside of the bankable ROM
+//! range).
+
+use std::collections::HashSet;
+use std::fmt;
+use std::io::{Result as IoResult, Write};
+
+/// Size of a ROM bank
+const ROM_BANK_SIZE: usize = 0x4000;
+
+/// A memory location
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Location {
+    /// ROM bank
+    pub bank: u16,
+    /// CPU address
+    pub addr: u16,
+}
+
+impl Location {
+    /// Location of `addr` assuming ROM bank `bank` is mapped in the
+    /// bankable range
+    pub fn new(bank: u16, addr: u16) -> Location {
+        let bank = match addr {
+            0x4000...0x7fff => bank,
+            _ => 0,
+        };
+
+        Location {
+            bank: bank,
+            addr: addr,
+        }
+    }
+
+    /// Name of the label placed at this location
+    pub fn label(&self) -> String {
+        format!("L{:02x}_{:04x}", self.bank, self.addr)
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:04x}", self.bank, self.addr)
+    }
+}
+
+/// A decoded instruction
+#[derive(Clone, Debug)]
+pub struct Instruction {
+    /// Where the instruction is located
+    pub location: Location,
+    /// Raw bytes, only the first `len` are valid
+    pub bytes: [u8; 3],
+    /// Instruction length in bytes
+    pub len: u16,
+    /// Mnemonic, with a lowercase placeholder for the operand (if any)
+    mnemonic: String,
+    /// Absolute address of the jump or call target, if any
+    pub target: Option<u16>,
+}
+
+impl Instruction {
+    /// Return the assembly for this instruction. `label` returns the
+    /// name to use for jump targets, if any.
+    pub fn text<F>(&self, label: F) -> String
+    where
+        F: Fn(Location) -> Option<String>,
+    {
+        let b1 = self.bytes[1];
+        let nn = b1 as u16 | (self.bytes[2] as u16) << 8;
+
+        let address = |addr: u16| {
+            label(Location::new(self.location.bank, addr)).unwrap_or(format!("${:04x}", addr))
+        };
+
+        let (placeholder, operand) = if self.mnemonic.contains("d16") {
+            ("d16", format!("${:04x}", nn))
+        } else if self.mnemonic.contains("a16") {
+            ("a16", address(nn))
+        } else if self.mnemonic.contains("d8") {
+            ("d8", format!("${:02x}", b1))
+        } else if self.mnemonic.contains("a8") {
+            ("a8", format!("$ff{:02x}", b1))
+        } else if self.mnemonic.contains("r8") {
+            ("r8", address(self.target.unwrap_or(0)))
+        } else if self.mnemonic.contains("s8") {
+            let s = b1 as i8;
+            // The sign replaces the `+` in `SP+s8`
+            let placeholder = match self.mnemonic.contains("+s8") {
+                true => "+s8",
+                false => "s8",
+            };
+
+            match s < 0 {
+                true => (placeholder, format!("-${:02x}", -(s as i16))),
+                false => (placeholder, format!("+${:02x}", s)),
+            }
+        } else {
+            return self.mnemonic.clone();
+        };
+
+        self.mnemonic.replace(placeholder, &operand)
+    }
+
+    /// Raw bytes formatted in hexadecimal
+    pub fn hex(&self) -> String {
+        self.bytes[..self.len as usize]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}  {:<8}  {}", self.location, self.hex(), self.text(|_| None))
+    }
+}
+
+/// Decode the instruction at `addr`. `bank` is the ROM bank mapped in
+/// the bankable range and `fetch` returns the byte at the given
+/// address.
+pub fn decode<F>(bank: u16, addr: u16, fetch: F) -> Instruction
+where
+    F: Fn(u16) -> u8,
+{
+    let op = fetch(addr);
+    let b1 = fetch(addr.wrapping_add(1));
+    let b2 = fetch(addr.wrapping_add(2));
+
+    let (mnemonic, len) = match op {
+        0xcb => (cb_mnemonic(b1), 2),
+        // STOP is followed by a padding byte
+        0x10 => ("STOP".into(), 2),
+        _ => match OPCODES[op as usize] {
+            Some(m) => (m.into(), operand_len(m) + 1),
+            None => (format!("DB ${:02x}", op), 1),
+        },
+    };
+
+    let target = match op {
+        // JR
+        0x18 | 0x20 | 0x28 | 0x30 | 0x38 => Some(addr.wrapping_add(2).wrapping_add(b1 as i8 as u16)),
+        // JP, CALL
+        0xc2 | 0xc3 | 0xc4 | 0xca | 0xcc | 0xcd | 0xd2 | 0xd4 | 0xda | 0xdc => {
+            Some(b1 as u16 | (b2 as u16) << 8)
+        }
+        // RST
+        0xc7 | 0xcf | 0xd7 | 0xdf | 0xe7 | 0xef | 0xf7 | 0xff => Some((op & 0x38) as u16),
+        _ => None,
+    };
+
+    Instruction {
+        location: Location::new(bank, addr),
+        bytes: [op, b1, b2],
+        len: len,
+        mnemonic: mnemonic,
+        target: target,
+    }
+}
+
+/// Return the size of the operand of mnemonic `m`
+fn operand_len(m: &str) -> u16 {
+    if m.contains("d16") || m.contains("a16") {
+        2
+    } else if ["d8", "a8", "r8", "s8"].iter().any(|p| m.contains(p)) {
+        1
+    } else {
+        0
+    }
+}
+
+/// Build the mnemonic of a 0xCB-prefixed instruction
+fn cb_mnemonic(op: u8) -> String {
+    const REGS: [&'static str; 8] = ["B", "C", "D", "E", "H", "L", "[HL]", "A"];
+    const SHIFTS: [&'static str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SWAP", "SRL"];
+
+    let reg = REGS[(op & 7) as usize];
+    let n = (op >> 3) & 7;
+
+    match op >> 6 {
+        0 => format!("{} {}", SHIFTS[n as usize], reg),
+        1 => format!("BIT {}, {}", n, reg),
+        2 => format!("RES {}, {}", n, reg),
+        _ => format!("SET {}, {}", n, reg),
+    }
+}
+
+/// Cartridge header fields in bank 0: `(start, end, description)`
+static HEADER: [(u16, u16, &'static str); 13] = [
+    (0x104, 0x134, "Nintendo logo"),
+    (0x134, 0x143, "Title"),
+    (0x143, 0x144, "CGB flag"),
+    (0x144, 0x146, "New licensee code"),
+    (0x146, 0x147, "SGB flag"),
+    (0x147, 0x148, "Cartridge type"),
+    (0x148, 0x149, "ROM size"),
+    (0x149, 0x14a, "RAM size"),
+    (0x14a, 0x14b, "Destination code"),
+    (0x14b, 0x14c, "Old licensee code"),
+    (0x14c, 0x14d, "Mask ROM version"),
+    (0x14d, 0x14e, "Header checksum"),
+    (0x14e, 0x150, "Global checksum"),
+];
+
+/// One line of a listing
+enum Line {
+    /// Decoded instruction
+    Code(Instruction),
+    /// Header data
+    Data {
+        location: Location,
+        bytes: Vec<u8>,
+        comment: &'static str,
+    },
+}
+
+/// Linear sweep disassembly of `[start, end[`. The cartridge header
+/// is decoded as data.
+fn sweep<F>(bank: u16, start: u16, end: u32, fetch: &F) -> Vec<Line>
+where
+    F: Fn(u16) -> u8,
+{
+    let mut lines = Vec::new();
+    let mut addr = start as u32;
+
+    while addr < end {
+        let a = addr as u16;
+
+        let header = match Location::new(bank, a).bank {
+            0 => HEADER.iter().find(|&&(s, e, _)| a >= s && a < e),
+            _ => None,
+        };
+
+        if let Some(&(_, e, comment)) = header {
+            let e = ::std::cmp::min(e as u32, end) as u16;
+
+            // Split long fields in chunks of 8 bytes
+            for (i, chunk) in (a..e).collect::<Vec<_>>().chunks(8).enumerate() {
+                lines.push(Line::Data {
+                    location: Location::new(bank, chunk[0]),
+                    bytes: chunk.iter().map(|&a| fetch(a)).collect(),
+                    comment: if i == 0 { comment } else { "" },
+                });
+            }
+
+            addr = e as u32;
+            continue;
+        }
+
+        let i = decode(bank, a, fetch);
+
+        addr += i.len as u32;
+        lines.push(Line::Code(i));
+    }
+
+    lines
+}
+
+/// Write the listing of `sections`, where each section is the
+/// disassembly of a region. Jump targets that fall on the start of an
+/// instruction get a label.
+fn write_listing(w: &mut Write, sections: &[Vec<Line>]) -> IoResult<()> {
+    let mut starts = HashSet::new();
+    let mut targets = HashSet::new();
+
+    for line in sections.iter().flat_map(|s| s.iter()) {
+        if let Line::Code(ref i) = *line {
+            starts.insert(i.location);
+
+            if let Some(t) = i.target {
+                targets.insert(Location::new(i.location.bank, t));
+            }
+        }
+    }
+
+    let label = |l: Location| match starts.contains(&l) && targets.contains(&l) {
+        true => Some(l.label()),
+        false => None,
+    };
+
+    for section in sections {
+        for line in section {
+            match *line {
+                Line::Code(ref i) => {
+                    if let Some(l) = label(i.location) {
+                        try!(writeln!(w, "{}:", l));
+                    }
+
+                    try!(writeln!(w, "{}  {:<8}  {}", i.location, i.hex(), i.text(&label)));
+                }
+                Line::Data {
+                    location,
+                    ref bytes,
+                    comment,
+                } => {
+                    let data: Vec<_> = bytes.iter().map(|b| format!("${:02x}", b)).collect();
+                    let text = format!("DB {}", data.join(", "));
+
+                    match comment.is_empty() {
+                        true => try!(writeln!(w, "{}  {:<8}  {}", location, "", text)),
+                        false => try!(writeln!(w, "{}  {:<8}  {:<40} ; {}", location, "", text, comment)),
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Return the listing of `[start, end[`, `bank` being the ROM bank
+/// mapped in the bankable range
+pub fn listing<F>(bank: u16, start: u16, end: u32, fetch: F) -> String
+where
+    F: Fn(u16) -> u8,
+{
+    let mut out = Vec::new();
+
+    // Writing to a Vec can't fail
+    write_listing(&mut out, &[sweep(bank, start, end, &fetch)]).unwrap();
+
+    String::from_utf8(out).unwrap()
+}
+
+/// Disassemble a whole ROM image, bank by bank
+pub fn disassemble_rom(rom: &[u8], w: &mut Write) -> IoResult<()> {
+    let mut sections = Vec::new();
+
+    for (bank, data) in rom.chunks(ROM_BANK_SIZE).enumerate() {
+        let base = match bank {
+            0 => 0,
+            _ => ROM_BANK_SIZE,
+        };
+
+        let fetch = |addr: u16| data.get(addr as usize - base).cloned().unwrap_or(0xff);
+
+        sections.push(sweep(bank as u16, base as u16, (base + data.len()) as u32, &fetch));
+    }
+
+    write_listing(w, &sections)
+}
+
+/// Base opcode table. Operands are represented by a lowercase
+/// placeholder: `d8`/`d16` for immediate values, `a8` for the low
+/// byte of an address in the 0xff00 page, `a16` for an absolute
+/// address, `r8` for a relative jump and `s8` for a signed offset.
+/// `None` for undefined opcodes.
+static OPCODES: [Option<&'static str>; 0x100] = [
+    // Opcodes 0X
+    Some("NOP"),
+    Some("LD BC, d16"),
+    Some("LD [BC], A"),
+    Some("INC BC"),
+    Some("INC B"),
+    Some("DEC B"),
+    Some("LD B, d8"),
+    Some("RLCA"),
+    Some("LD [a16], SP"),
+    Some("ADD HL, BC"),
+    Some("LD A, [BC]"),
+    Some("DEC BC"),
+    Some("INC C"),
+    Some("DEC C"),
+    Some("LD C, d8"),
+    Some("RRCA"),
+    // Opcodes 1X
+    Some("STOP"),
+    Some("LD DE, d16"),
+    Some("LD [DE], A"),
+    Some("INC DE"),
+    Some("INC D"),
+    Some("DEC D"),
+    Some("LD D, d8"),
+    Some("RLA"),
+    Some("JR r8"),
+    Some("ADD HL, DE"),
+    Some("LD A, [DE]"),
+    Some("DEC DE"),
+    Some("INC E"),
+    Some("DEC E"),
+    Some("LD E, d8"),
+    Some("RRA"),
+    // Opcodes 2X
+    Some("JR NZ, r8"),
+    Some("LD HL, d16"),
+    Some("LD [HL+], A"),
+    Some("INC HL"),
+    Some("INC H"),
+    Some("DEC H"),
+    Some("LD H, d8"),
+    Some("DAA"),
+    Some("JR Z, r8"),
+    Some("ADD HL, HL"),
+    Some("LD A, [HL+]"),
+    Some("DEC HL"),
+    Some("INC L"),
+    Some("DEC L"),
+    Some("LD L, d8"),
+    Some("CPL"),
+    // Opcodes 3X
+    Some("JR NC, r8"),
+    Some("LD SP, d16"),
+    Some("LD [HL-], A"),
+    Some("INC SP"),
+    Some("INC [HL]"),
+    Some("DEC [HL]"),
+    Some("LD [HL], d8"),
+    Some("SCF"),
+    Some("JR C, r8"),
+    Some("ADD HL, SP"),
+    Some("LD A, [HL-]"),
+    Some("DEC SP"),
+    Some("INC A"),
+    Some("DEC A"),
+    Some("LD A, d8"),
+    Some("CCF"),
+    // Opcodes 4X
+    Some("LD B, B"),
+    Some("LD B, C"),
+    Some("LD B, D"),
+    Some("LD B, E"),
+    Some("LD B, H"),
+    Some("LD B, L"),
+    Some("LD B, [HL]"),
+    Some("LD B, A"),
+    Some("LD C, B"),
+    Some("LD C, C"),
+    Some("LD C, D"),
+    Some("LD C, E"),
+    Some("LD C, H"),
+    Some("LD C, L"),
+    Some("LD C, [HL]"),
+    Some("LD C, A"),
+    // Opcodes 5X
+    Some("LD D, B"),
+    Some("LD D, C"),
+    Some("LD D, D"),
+    Some("LD D, E"),
+    Some("LD D, H"),
+    Some("LD D, L"),
+    Some("LD D, [HL]"),
+    Some("LD D, A"),
+    Some("LD E, B"),
+    Some("LD E, C"),
+    Some("LD E, D"),
+    Some("LD E, E"),
+    Some("LD E, H"),
+    Some("LD E, L"),
+    Some("LD E, [HL]"),
+    Some("LD E, A"),
+    // Opcodes 6X
+    Some("LD H, B"),
+    Some("LD H, C"),
+    Some("LD H, D"),
+    Some("LD H, E"),
+    Some("LD H, H"),
+    Some("LD H, L"),
+    Some("LD H, [HL]"),
+    Some("LD H, A"),
+    Some("LD L, B"),
+    Some("LD L, C"),
+    Some("LD L, D"),
+    Some("LD L, E"),
+    Some("LD L, H"),
+    Some("LD L, L"),
+    Some("LD L, [HL]"),
+    Some("LD L, A"),
+    // Opcodes 7X
+    Some("LD [HL], B"),
+    Some("LD [HL], C"),
+    Some("LD [HL], D"),
+    Some("LD [HL], E"),
+    Some("LD [HL], H"),
+    Some("LD [HL], L"),
+    Some("HALT"),
+    Some("LD [HL], A"),
+    Some("LD A, B"),
+    Some("LD A, C"),
+    Some("LD A, D"),
+    Some("LD A, E"),
+    Some("LD A, H"),
+    Some("LD A, L"),
+    Some("LD A, [HL]"),
+    Some("LD A, A"),
+    // Opcodes 8X
+    Some("ADD A, B"),
+    Some("ADD A, C"),
+    Some("ADD A, D"),
+    Some("ADD A, E"),
+    Some("ADD A, H"),
+    Some("ADD A, L"),
+    Some("ADD A, [HL]"),
+    Some("ADD A, A"),
+    Some("ADC A, B"),
+    Some("ADC A, C"),
+    Some("ADC A, D"),
+    Some("ADC A, E"),
+    Some("ADC A, H"),
+    Some("ADC A, L"),
+    Some("ADC A, [HL]"),
+    Some("ADC A, A"),
+    // Opcodes 9X
+    Some("SUB B"),
+    Some("SUB C"),
+    Some("SUB D"),
+    Some("SUB E"),
+    Some("SUB H"),
+    Some("SUB L"),
+    Some("SUB [HL]"),
+    Some("SUB A"),
+    Some("SBC A, B"),
+    Some("SBC A, C"),
+    Some("SBC A, D"),
+    Some("SBC A, E"),
+    Some("SBC A, H"),
+    Some("SBC A, L"),
+    Some("SBC A, [HL]"),
+    Some("SBC A, A"),
+    // Opcodes AX
+    Some("AND B"),
+    Some("AND C"),
+    Some("AND D"),
+    Some("AND E"),
+    Some("AND H"),
+    Some("AND L"),
+    Some("AND [HL]"),
+    Some("AND A"),
+    Some("XOR B"),
+    Some("XOR C"),
+    Some("XOR D"),
+    Some("XOR E"),
+    Some("XOR H"),
+    Some("XOR L"),
+    Some("XOR [HL]"),
+    Some("XOR A"),
+    // Opcodes BX
+    Some("OR B"),
+    Some("OR C"),
+    Some("OR D"),
+    Some("OR E"),
+    Some("OR H"),
+    Some("OR L"),
+    Some("OR [HL]"),
+    Some("OR A"),
+    Some("CP B"),
+    Some("CP C"),
+    Some("CP D"),
+    Some("CP E"),
+    Some("CP H"),
+    Some("CP L"),
+    Some("CP [HL]"),
+    Some("CP A"),
+    // Opcodes CX
+    Some("RET NZ"),
+    Some("POP BC"),
+    Some("JP NZ, a16"),
+    Some("JP a16"),
+    Some("CALL NZ, a16"),
+    Some("PUSH BC"),
+    Some("ADD A, d8"),
+    Some("RST $00"),
+    Some("RET Z"),
+    Some("RET"),
+    Some("JP Z, a16"),
+    Some("PREFIX CB"),
+    Some("CALL Z, a16"),
+    Some("CALL a16"),
+    Some("ADC A, d8"),
+    Some("RST $08"),
+    // Opcodes DX
+    Some("RET NC"),
+    Some("POP DE"),
+    Some("JP NC, a16"),
+    None,
+    Some("CALL NC, a16"),
+    Some("PUSH DE"),
+    Some("SUB d8"),
+    Some("RST $10"),
+    Some("RET C"),
+    Some("RETI"),
+    Some("JP C, a16"),
+    None,
+    Some("CALL C, a16"),
+    None,
+    Some("SBC A, d8"),
+    Some("RST $18"),
+    // Opcodes EX
+    Some("LDH [a8], A"),
+    Some("POP HL"),
+    Some("LD [C], A"),
+    None,
+    None,
+    Some("PUSH HL"),
+    Some("AND d8"),
+    Some("RST $20"),
+    Some("ADD SP, s8"),
+    Some("JP HL"),
+    Some("LD [a16], A"),
+    None,
+    None,
+    None,
+    Some("XOR d8"),
+    Some("RST $28"),
+    // Opcodes FX
+    Some("LDH A, [a8]"),
+    Some("POP AF"),
+    Some("LD A, [C]"),
+    Some("DI"),
+    None,
+    Some("PUSH AF"),
+    Some("OR d8"),
+    Some("RST $30"),
+    Some("LD HL, SP+s8"),
+    Some("LD SP, HL"),
+    Some("LD A, [a16]"),
+    Some("EI"),
+    None,
+    None,
+    Some("CP d8"),
+    Some("RST $38"),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, listing};
+
+    fn text(code: &[u8]) -> String {
+        let i = decode(0, 0x200, |a| code.get((a - 0x200) as usize).cloned().unwrap_or(0));
+
+        assert!(i.len as usize == code.len());
+
+        i.text(|_| None)
+    }
+
+    #[test]
+    fn opcodes() {
+        assert!(text(&[0x00]) == "NOP");
+        assert!(text(&[0x01, 0x34, 0x12]) == "LD BC, $1234");
+        assert!(text(&[0x3e, 0x42]) == "LD A, $42");
+        assert!(text(&[0xe0, 0x40]) == "LDH [$ff40], A");
+        assert!(text(&[0xf8, 0xfe]) == "LD HL, SP-$02");
+        assert!(text(&[0xe8, 0x05]) == "ADD SP, +$05");
+        assert!(text(&[0x18, 0xfe]) == "JR $0200");
+        assert!(text(&[0xc4, 0x50, 0x01]) == "CALL NZ, $0150");
+        assert!(text(&[0xff]) == "RST $38");
+        assert!(text(&[0xd3]) == "DB $d3");
+        assert!(text(&[0xcb, 0x37]) == "SWAP A");
+        assert!(text(&[0xcb, 0x7e]) == "BIT 7, [HL]");
+        assert!(text(&[0xcb, 0xc1]) == "SET 0, C");
+    }
+
+    #[test]
+    fn labels_and_header() {
+        let mut rom = vec![0; 0x160];
+
+        // 0100: NOP; JP 0x0150
+        rom[0x100..0x104].copy_from_slice(&[0x00, 0xc3, 0x50, 0x01]);
+        rom[0x134..0x138].copy_from_slice(b"TEST");
+        // 0150: JR -2
+        rom[0x150..0x152].copy_from_slice(&[0x18, 0xfe]);
+
+        let l = listing(0, 0x100, 0x152, |a| rom[a as usize]);
+        let lines: Vec<&str> = l.lines().collect();
+
+        assert!(lines[1] == "00:0101  c3 50 01  JP L00_0150");
+        assert!(lines[2].starts_with("00:0104            DB $00"));
+        assert!(lines[2].ends_with("; Nintendo logo"));
+        assert!(l.contains("DB $54, $45, $53, $54"));
+        assert!(lines[lines.len() - 2] == "L00_0150:");
+        assert!(lines[lines.len() - 1] == "00:0150  18 fe     JR L00_0150");
+    }
+}
@@ -0,0 +1,827 @@
+//! Headless facade owning the whole emulated machine. Frontends (or
+//! any other tool embedding the emulator) only need to feed it a
+//! cartridge and drive it with `run_frame` or `run_cycles`.
+
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Result as IoResult, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Receiver;
+
+use log::LogLevel;
+
+use cartridge::Cartridge;
+use cpu::{CanRunInstruction, Registers};
+use disasm;
+use gpu::{Color, Gpu, Renderer};
+use io::serial::SerialLink;
+use io::Interconnect;
+use movie::{self, Movie, Session};
+use palette::DmgPalette;
+use savestate::{self, StateReader, StateWriter};
+use spu::{Sample, SampleBuffer, Spu};
+use ui::Display;
+
+use self::video::VideoRecorder;
+
+mod video;
+
+/// The two CPU implementations available
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CpuCore {
+    /// Default CPU emulation (`cpu` module)
+    Gamedorp,
+    /// CPU emulation inherited from gb-rs (`gb_rs_cpu` module)
+    GbRs,
+}
+
+/// A complete Game Boy: CPU, interconnect, GPU, SPU and cartridge
+pub struct GameBoy {
+    /// CPU, owning the rest of the machine through its interconnect
+    cpu: Box<CanRunInstruction>,
+    /// Receiving end of the SPU sample channel. `None` once it's been
+    /// handed over to an audio backend.
+    audio: Option<Receiver<SampleBuffer>>,
+    /// Number of system clock ticks elapsed since power on
+    cycles: u64,
+    /// `true` if half a system clock tick is left over from the last
+    /// instruction run in CGB double speed mode
+    half_tick: bool,
+    /// Input movie being recorded or played back
+    movie: Option<Session>,
+    /// Video recorder, if a recording is in progress
+    video: Option<VideoRecorder>,
+}
+
+impl GameBoy {
+    /// Build a new GameBoy rendering to `display`
+    pub fn new(cart: Cartridge, display: Box<Display>, core: CpuCore) -> GameBoy {
+        let gpu = Gpu::new(display);
+        let (spu, audio) = Spu::new();
+        let inter = Interconnect::new(cart, gpu, spu);
+
+        let cpu: Box<CanRunInstruction> = match core {
+            CpuCore::Gamedorp => Box::new(::cpu::Cpu::new(inter)),
+            CpuCore::GbRs => Box::new(::gb_rs_cpu::Cpu::new(inter)),
+        };
+
+        GameBoy {
+            cpu: cpu,
+            audio: Some(audio),
+            cycles: 0,
+            half_tick: false,
+            movie: None,
+            video: None,
+        }
+    }
+
+    /// Build a new GameBoy without any display attached. The
+    /// rendered frames can be retrieved with `frame`.
+    pub fn headless(cart: Cartridge, core: CpuCore) -> GameBoy {
+        GameBoy::new(cart, Box::new(::ui::dummy::DummyDisplay), core)
+    }
+
+    /// Run instructions until at least `cycles` system clock ticks
+    /// have elapsed. Returns the actual number of ticks elapsed,
+    /// which can be slightly more since instructions can't be
+    /// interrupted halfway.
+    pub fn run_cycles(&mut self, cycles: u64) -> u64 {
+        let mut elapsed = 0;
+
+        while elapsed < cycles {
+            elapsed += self.run_instruction();
+        }
+
+        self.cycles += elapsed;
+
+        elapsed
+    }
+
+    /// Run until the GPU completes the current frame. If the LCD is
+    /// disabled this returns after the duration of one frame.
+    /// Returns the number of system clock ticks elapsed.
+    pub fn run_frame(&mut self) -> u64 {
+        let frame = self.gpu().frame_count();
+        let mut elapsed = 0;
+
+        while elapsed < CYCLES_PER_FRAME && self.gpu().frame_count() == frame {
+            elapsed += self.run_instruction();
+        }
+
+        self.cycles += elapsed;
+
+        elapsed
+    }
+
+    /// Return the pixels of the last frame rendered by the GPU
+    pub fn frame(&self) -> &[[Color; 160]; 144] {
+        self.gpu().frame()
+    }
+
+    /// Replace the pixels of the last frame rendered by the GPU, see
+    /// `Gpu::set_frame`
+    pub fn set_frame(&mut self, frame: &[[Color; 160]; 144]) {
+        self.cpu.interconnect_mut().gpu_mut().set_frame(frame);
+    }
+
+    /// Return the number of frames completed by the GPU since power
+    /// on
+    pub fn frame_count(&self) -> u64 {
+        self.gpu().frame_count()
+    }
+
+    /// Update the state of the GB buttons. While a movie is active
+    /// the buttons are only sampled at the next frame boundary when
+    /// recording and ignored when playing back.
+    pub fn set_buttons(&mut self, buttons: ::ui::Buttons) {
+        if let Some(ref mut movie) = self.movie {
+            if movie.set_buttons(buttons) {
+                return;
+            }
+        }
+
+        self.cpu.interconnect_mut().set_buttons(buttons);
+    }
+
+    /// Start recording an input movie. Must be called at power on,
+    /// before running anything. A movie already active is stopped.
+    pub fn start_movie_recording(&mut self) -> IoResult<()> {
+        try!(self.check_power_on());
+
+        let cart = self.cartridge();
+        let movie = Movie::new(cart.checksum(), cart.ram().to_vec(), cart.rtc_registers());
+
+        self.movie = Some(Session::record(movie));
+
+        Ok(())
+    }
+
+    /// Play `movie` back: restore the power on state it was recorded
+    /// with and feed its inputs instead of the ones set with
+    /// `set_buttons` until it's over. Must be called at power on,
+    /// before running anything. The cartridge RAM is overwritten with
+    /// the one of the movie so it's not saved anymore.
+    pub fn play_movie(&mut self, movie: Movie) -> IoResult<()> {
+        try!(self.check_power_on());
+
+        {
+            let cart = self.cartridge_mut();
+
+            try!(movie.check(cart.checksum(), cart.ram().len(), cart.rtc_registers().is_some()));
+
+            cart.detach_save_file();
+            cart.ram_mut().copy_from_slice(movie.ram());
+
+            if let Some(regs) = movie.rtc() {
+                cart.set_rtc_registers(regs);
+            }
+        }
+
+        self.movie = Some(Session::play(movie));
+
+        Ok(())
+    }
+
+    /// Stop the active movie and return it, with the frames recorded
+    /// so far when recording
+    pub fn stop_movie(&mut self) -> Option<Movie> {
+        self.movie.take().map(|m| m.into_movie())
+    }
+
+    /// Return `true` if a movie is being recorded or played back
+    pub fn movie_active(&self) -> bool {
+        self.movie.as_ref().map_or(false, |m| m.active())
+    }
+
+    /// Return `true` if a movie is being recorded
+    pub fn movie_recording(&self) -> bool {
+        self.movie.as_ref().map_or(false, |m| m.recording())
+    }
+
+    /// Return the last noteworthy movie event (desync, end of
+    /// playback) since the last call, if any
+    pub fn take_movie_event(&mut self) -> Option<movie::Event> {
+        self.movie.as_mut().and_then(|m| m.take_event())
+    }
+
+    /// Return an error if the machine has already started running
+    fn check_power_on(&self) -> IoResult<()> {
+        match self.cycles {
+            0 => Ok(()),
+            _ => Err(Error::new(ErrorKind::InvalidInput, "movies must start at power on")),
+        }
+    }
+
+    /// Append all the audio samples generated since the last call to
+    /// `out`, as interleaved left and right samples. Nothing is
+    /// returned once the channel has been taken by `take_audio_channel`.
+    pub fn drain_audio(&mut self, out: &mut Vec<Sample>) {
+        if let Some(ref audio) = self.audio {
+            while let Ok(buf) = audio.try_recv() {
+                out.extend_from_slice(&buf);
+            }
+        }
+    }
+
+    /// Stop (`true`) or resume sending the audio samples to the
+    /// channel, for instance while running faster than the backend
+    /// can play them. Audio recordings are not affected.
+    pub fn set_audio_muted(&mut self, muted: bool) {
+        self.cpu.interconnect_mut().spu_mut().set_muted(muted);
+    }
+
+    /// Take the SPU sample channel to feed an asynchronous audio
+    /// backend. Returns `None` if it's already been taken.
+    pub fn take_audio_channel(&mut self) -> Option<Receiver<SampleBuffer>> {
+        self.audio.take()
+    }
+
+    /// Return the number of system clock ticks elapsed since power on
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Return the cartridge
+    pub fn cartridge(&self) -> &Cartridge {
+        self.cpu.interconnect().cartridge()
+    }
+
+    /// Return the cartridge
+    pub fn cartridge_mut(&mut self) -> &mut Cartridge {
+        self.cpu.interconnect_mut().cartridge_mut()
+    }
+
+    /// Return `true` if the cartridge's rumble motor is on or has
+    /// been turned on since the last call. Should be polled regularly
+    /// by the frontend to drive a `ui::Rumble`.
+    pub fn take_rumble(&mut self) -> bool {
+        self.cpu.interconnect_mut().cartridge_mut().take_rumble()
+    }
+
+    /// Plug `link` into the serial port. The port starts out
+    /// `Disconnected`.
+    pub fn set_serial_link(&mut self, link: Box<SerialLink>) {
+        self.cpu.interconnect_mut().set_serial_link(link);
+    }
+
+    /// Serialize the state of the whole machine. The cartridge ROM
+    /// itself is not included, only its checksum.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+
+        w.write_bytes(savestate::MAGIC);
+        w.write_u32(savestate::VERSION);
+        w.write_u16(self.cartridge().checksum());
+        w.write_u64(self.cycles);
+        self.cpu.snapshot(&mut w);
+
+        w.into_bytes()
+    }
+
+    /// Restore a state generated by `snapshot`. If the state is
+    /// invalid an error is returned and the machine is left
+    /// untouched.
+    pub fn restore(&mut self, state: &[u8]) -> IoResult<()> {
+        let mut r = StateReader::new(state);
+
+        let mut magic = [0; 8];
+        try!(r.read_bytes(&mut magic));
+        if &magic != savestate::MAGIC {
+            return savestate::invalid("not a save state");
+        }
+
+        let version = try!(r.read_u32());
+        if version != savestate::VERSION {
+            return savestate::invalid(&format!(
+                "unsupported version {} (expected {})",
+                version,
+                savestate::VERSION
+            ));
+        }
+
+        if try!(r.read_u16()) != self.cartridge().checksum() {
+            return savestate::invalid("state was saved with a different ROM");
+        }
+
+        // Past this point a failure would leave the machine half
+        // restored, keep a copy of the current state to roll back.
+        let backup = self.snapshot();
+
+        if let Err(e) = self.restore_body(&mut r) {
+            let mut r = StateReader::new(&backup[HEADER_LEN..]);
+
+            self.restore_body(&mut r)
+                .expect("Couldn't roll back to the previous state");
+
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    /// Restore everything following the header
+    fn restore_body(&mut self, r: &mut StateReader) -> IoResult<()> {
+        self.cycles = try!(r.read_u64());
+        try!(self.cpu.restore(r));
+
+        if !r.is_empty() {
+            return savestate::invalid("trailing data");
+        }
+
+        Ok(())
+    }
+
+    /// Write the state of the machine to the file at `path`
+    pub fn save_state(&self, path: &Path) -> IoResult<()> {
+        let state = self.snapshot();
+
+        let mut f = try!(File::create(path));
+
+        f.write_all(&state)
+    }
+
+    /// Load the state of the machine from the file at `path`
+    pub fn load_state(&mut self, path: &Path) -> IoResult<()> {
+        let mut state = Vec::new();
+
+        try!(try!(File::open(path)).read_to_end(&mut state));
+
+        self.restore(&state)
+    }
+
+    /// Return the path of the save state file for numbered `slot`
+    pub fn state_slot_path(&self, slot: u8) -> PathBuf {
+        savestate::slot_path(self.cartridge().path(), slot)
+    }
+
+    /// Run a single instruction (or wait for one tick if the CPU is
+    /// halted) and return the number of system clock ticks elapsed.
+    pub fn step_instruction(&mut self) -> u64 {
+        let elapsed = self.run_instruction();
+
+        self.cycles += elapsed;
+
+        elapsed
+    }
+
+    /// Run a single instruction and return the number of system
+    /// clock ticks elapsed
+    fn run_instruction(&mut self) -> u64 {
+        let ticks = self.execute_instruction();
+
+        if let Some(ref mut video) = self.video {
+            video.step(ticks, self.cpu.interconnect().gpu());
+        }
+
+        let boundary = match self.movie {
+            Some(ref mut movie) => movie.step(ticks),
+            None => false,
+        };
+
+        if boundary {
+            let checksum = self.state_checksum();
+
+            if let Some(ref mut movie) = self.movie {
+                let buttons = movie.frame(checksum);
+
+                self.cpu.interconnect_mut().set_buttons(buttons);
+            }
+        }
+
+        ticks
+    }
+
+    /// Return a checksum of the state of the machine, used to check
+    /// that movies don't desync
+    fn state_checksum(&self) -> u32 {
+        let mut w = StateWriter::new();
+
+        // The cycle counter is left out, it's only up to date between
+        // two runs
+        self.cpu.snapshot(&mut w);
+
+        movie::checksum(&w.into_bytes())
+    }
+
+    /// Run a single instruction, without the movie bookkeeping
+    fn execute_instruction(&mut self) -> u64 {
+        if log_enabled!(LogLevel::Trace) {
+            self.trace();
+        }
+
+        let double_speed = self.cpu.interconnect().double_speed();
+        let stopped = self.cpu.interconnect().stopped();
+        let mut ticks = self.cpu.run_next_instruction() as u64;
+
+        if double_speed {
+            // In double speed mode the CPU clock runs twice as fast as
+            // the system clock
+            ticks += self.half_tick as u64;
+
+            self.half_tick = ticks & 1 != 0;
+
+            ticks /= 2;
+        }
+
+        if stopped {
+            // The CPU only let the time pass, the sound recording keeps
+            // up with the video
+            for _ in 0..ticks {
+                self.cpu.interconnect_mut().step_stopped();
+            }
+        }
+
+        ticks
+    }
+
+    /// Return the current value of the CPU registers
+    pub fn registers(&self) -> Registers {
+        self.cpu.registers()
+    }
+
+    /// Overwrite the CPU registers
+    pub fn set_registers(&mut self, regs: &Registers) {
+        self.cpu.set_registers(regs)
+    }
+
+    /// Direct access to the memory map, for debugging tools
+    pub fn interconnect(&self) -> &Interconnect {
+        self.cpu.interconnect()
+    }
+
+    /// Direct access to the memory map, for debugging tools
+    pub fn interconnect_mut(&mut self) -> &mut Interconnect {
+        self.cpu.interconnect_mut()
+    }
+
+    /// Set the colors used to display the shades of the DMG. Ignored
+    /// in Game Boy Color mode.
+    pub fn set_dmg_palette(&mut self, palette: DmgPalette) {
+        self.cpu.interconnect_mut().gpu_mut().set_dmg_palette(palette);
+    }
+
+    /// Select the GPU rendering engine
+    pub fn set_renderer(&mut self, renderer: Renderer) {
+        self.cpu.interconnect_mut().gpu_mut().set_renderer(renderer);
+    }
+
+    /// Start recording the audio output to the WAV file at `path`, at
+    /// `rate` Hz or at the native `SAMPLE_RATE` if `None`. If `stems`
+    /// is true each of the four sounds is also recorded on its own,
+    /// before mixing, next to `path`: "song.wav" gives
+    /// "song-sound1.wav" to "song-sound4.wav". A recording already in
+    /// progress is stopped first.
+    pub fn start_audio_recording(&mut self, path: &Path, stems: bool, rate: Option<u32>) -> IoResult<()> {
+        self.cpu.interconnect_mut().spu_mut().start_recording(path, stems, rate)
+    }
+
+    /// Stop the audio recording in progress, if any, and finalize the
+    /// files. Returns the first error encountered during the
+    /// recording.
+    pub fn stop_audio_recording(&mut self) -> IoResult<()> {
+        self.cpu.interconnect_mut().spu_mut().stop_recording()
+    }
+
+    /// Return `true` if an audio recording is in progress
+    pub fn audio_recording(&self) -> bool {
+        self.cpu.interconnect().spu().recording()
+    }
+
+    /// Start recording the video to the Y4M file at `path` and the
+    /// sound to a WAV file next to it: "clip.y4m" gives "clip.wav".
+    /// The sound uses the audio recorder so any audio recording in
+    /// progress is stopped, a video recording in progress is stopped
+    /// first.
+    pub fn start_video_recording(&mut self, path: &Path) -> IoResult<()> {
+        try!(self.stop_video_recording());
+
+        let audio_path = path.with_extension("wav");
+
+        try!(self.start_audio_recording(&audio_path, false, None));
+
+        let video = match VideoRecorder::new(path, self.gpu()) {
+            Ok(v) => v,
+            Err(e) => {
+                // Don't leave an empty sound track behind
+                let _ = self.stop_audio_recording();
+                let _ = ::std::fs::remove_file(&audio_path);
+
+                return Err(e);
+            }
+        };
+
+        self.video = Some(video);
+
+        Ok(())
+    }
+
+    /// Stop the video recording in progress, if any, and finalize the
+    /// files. Returns the first error encountered during the
+    /// recording.
+    pub fn stop_video_recording(&mut self) -> IoResult<()> {
+        let video = match self.video.take() {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+
+        let audio = self.stop_audio_recording();
+
+        try!(video.finish());
+
+        audio
+    }
+
+    /// Return `true` if a video recording is in progress
+    pub fn video_recording(&self) -> bool {
+        self.video.is_some()
+    }
+
+    /// Start logging the writes to the sound registers to the VGM
+    /// file at `path`. A log already in progress is stopped first.
+    pub fn start_vgm_log(&mut self, path: &Path) -> IoResult<()> {
+        self.cpu.interconnect_mut().vgm_mut().start(path)
+    }
+
+    /// Stop the VGM log in progress, if any, and finalize the
+    /// file. Returns the first error encountered while logging.
+    pub fn stop_vgm_log(&mut self) -> IoResult<()> {
+        self.cpu.interconnect_mut().vgm_mut().stop()
+    }
+
+    /// Return `true` if a VGM log is in progress
+    pub fn vgm_logging(&self) -> bool {
+        self.cpu.interconnect().vgm().logging()
+    }
+
+    /// Send the last frame to the display again
+    pub fn redraw(&mut self) {
+        self.cpu.interconnect_mut().gpu_mut().redraw();
+    }
+
+    /// Log the instruction about to be executed along with the CPU
+    /// state
+    fn trace(&self) {
+        let r = self.registers();
+        let ic = self.interconnect();
+        let i = disasm::decode(ic.cartridge().rom_bank(), r.pc, |a| ic.peek_byte(a));
+
+        trace!("{:<40} AF={:04x} BC={:04x} DE={:04x} HL={:04x} SP={:04x}",
+               i.to_string(), r.af, r.bc, r.de, r.hl, r.sp);
+    }
+
+    fn gpu(&self) -> &Gpu {
+        self.cpu.interconnect().gpu()
+    }
+}
+
+/// Build a GameBoy running `code` at 0x0100 with the bootrom
+/// skipped. Shared by the tests of the various modules.
+#[cfg(test)]
+pub fn test_gameboy(code: &[u8], core: CpuCore) -> GameBoy {
+    let mut rom = vec![0; 0x8000];
+
+    rom[0x100..0x100 + code.len()].copy_from_slice(code);
+
+    let mut gb = GameBoy::headless(Cartridge::from_vec(rom), core);
+    let mut regs = gb.registers();
+
+    gb.interconnect_mut().poke_byte(0xff50, 1);
+    regs.pc = 0x100;
+    regs.sp = 0xfffe;
+    gb.set_registers(&regs);
+
+    gb
+}
+
+/// Number of system clock ticks in one frame: 154 lines of 456 ticks
+pub const CYCLES_PER_FRAME: u64 = 154 * 456;
+
+/// Size of the save state header: magic, version and ROM checksum
+const HEADER_LEN: usize = 8 + 4 + 2;
+
+#[cfg(test)]
+mod tests {
+    use super::{CpuCore, GameBoy, CYCLES_PER_FRAME};
+    use cartridge::Cartridge;
+
+    fn gameboy(core: CpuCore) -> GameBoy {
+        // Empty ROM: the bootrom will lock up when it fails to
+        // validate the logo, which is plenty to exercise the whole
+        // machine.
+        GameBoy::headless(Cartridge::from_vec(vec![0; 0x8000]), core)
+    }
+
+    /// Running from a restored state must give the same result as
+    /// running from the point where it was taken
+    fn snapshot_is_deterministic(core: CpuCore) {
+        let mut gb = gameboy(core);
+
+        for _ in 0..10 {
+            gb.run_frame();
+        }
+
+        let saved = gb.snapshot();
+
+        for _ in 0..5 {
+            gb.run_frame();
+        }
+
+        let expected = gb.snapshot();
+
+        gb.restore(&saved).unwrap();
+        assert!(gb.snapshot() == saved);
+
+        for _ in 0..5 {
+            gb.run_frame();
+        }
+
+        assert!(gb.snapshot() == expected);
+    }
+
+    #[test]
+    fn snapshot_gamedorp() {
+        snapshot_is_deterministic(CpuCore::Gamedorp);
+    }
+
+    #[test]
+    fn snapshot_gb_rs() {
+        snapshot_is_deterministic(CpuCore::GbRs);
+    }
+
+    #[test]
+    fn snapshot_shared_between_cores() {
+        let mut gb = gameboy(CpuCore::Gamedorp);
+
+        for _ in 0..3 {
+            gb.run_frame();
+        }
+
+        let saved = gb.snapshot();
+
+        let mut other = gameboy(CpuCore::GbRs);
+        other.restore(&saved).unwrap();
+
+        assert!(other.snapshot() == saved);
+    }
+
+    #[test]
+    fn invalid_state_is_rejected() {
+        let mut gb = gameboy(CpuCore::Gamedorp);
+
+        gb.run_frame();
+
+        let saved = gb.snapshot();
+
+        // Bad magic
+        let mut bad = saved.clone();
+        bad[0] = b'X';
+        assert!(gb.restore(&bad).is_err());
+
+        // Truncated state, must roll back
+        gb.run_frame();
+        let current = gb.snapshot();
+        assert!(gb.restore(&saved[..saved.len() - 1]).is_err());
+        assert!(gb.snapshot() == current);
+    }
+
+    #[test]
+    fn cgb_registers() {
+        let mut rom = vec![0; 0x8000];
+        // CGB compatible
+        rom[0x143] = 0x80;
+
+        let mut gb = GameBoy::headless(Cartridge::from_vec(rom), CpuCore::Gamedorp);
+        let inter = gb.cpu.interconnect_mut();
+
+        // Internal RAM banking
+        inter.store_byte(0xff70, 2);
+        inter.store_byte(0xd000, 0x42);
+        inter.store_byte(0xff70, 3);
+        assert!(inter.fetch_byte(0xd000) != 0x42);
+        inter.store_byte(0xff70, 2);
+        assert!(inter.fetch_byte(0xd000) == 0x42);
+        assert!(inter.fetch_byte(0xf000) == 0x42);
+
+        // General purpose DMA of one block into VRAM bank 1
+        for i in 0..0x10 {
+            inter.store_byte(0xc100 + i, i as u8);
+        }
+        inter.store_byte(0xff4f, 1);
+        inter.store_byte(0xff51, 0xc1);
+        inter.store_byte(0xff52, 0x00);
+        inter.store_byte(0xff53, 0x88);
+        inter.store_byte(0xff54, 0x00);
+        inter.store_byte(0xff55, 0x00);
+        assert!(inter.fetch_byte(0xff55) == 0xff);
+        for i in 0..0x10 {
+            assert!(inter.fetch_byte(0x8800 + i) == i as u8);
+        }
+        inter.store_byte(0xff4f, 0);
+        assert!(inter.fetch_byte(0x8801) != 0x01);
+
+        // Speed switch
+        inter.store_byte(0xff4d, 1);
+        assert!(inter.fetch_byte(0xff4d) == 0x7f);
+        assert!(inter.speed_switch());
+        assert!(inter.double_speed());
+        assert!(inter.fetch_byte(0xff4d) == 0xfe);
+        assert!(!inter.speed_switch());
+    }
+
+    fn stop_until_button_press(core: CpuCore) {
+        // LD A, 0x10; LDH [P1], A; STOP; LD A, 0x42; LD [0xc000], A;
+        // JR -2
+        let mut gb = super::test_gameboy(&[0x3e, 0x10, 0xe0, 0x00, 0x10, 0x00, 0x3e, 0x42,
+                                           0xea, 0x00, 0xc0, 0x18, 0xfe, 0x00], core);
+
+        gb.run_frame();
+        assert!(gb.interconnect().stopped());
+        assert!(gb.interconnect().peek_byte(0xc000) != 0x42);
+
+        // The directions aren't selected
+        let mut buttons = ::ui::Buttons::new(::ui::ButtonState::Up);
+        buttons.up = ::ui::ButtonState::Down;
+        gb.set_buttons(buttons);
+        gb.run_frame();
+        assert!(gb.interconnect().stopped());
+
+        buttons.start = ::ui::ButtonState::Down;
+        gb.set_buttons(buttons);
+        gb.run_frame();
+        assert!(!gb.interconnect().stopped());
+        assert!(gb.interconnect().peek_byte(0xc000) == 0x42);
+    }
+
+    #[test]
+    fn stop_gamedorp() {
+        stop_until_button_press(CpuCore::Gamedorp);
+    }
+
+    #[test]
+    fn stop_gb_rs() {
+        stop_until_button_press(CpuCore::GbRs);
+    }
+
+    #[test]
+    fn dmg_has_no_cgb_registers() {
+        let mut gb = gameboy(CpuCore::Gamedorp);
+        let inter = gb.cpu.interconnect_mut();
+
+        inter.store_byte(0xff4d, 1);
+        assert!(inter.fetch_byte(0xff4d) == 0xff);
+        assert!(!inter.speed_switch());
+    }
+
+    #[test]
+    fn video_recording_failure() {
+        let dir = ::std::env::temp_dir().join(format!("gb-rs-video-{}", ::std::process::id()));
+        let video = dir.join("clip.y4m");
+        let audio = dir.join("clip.wav");
+        let mut gb = gameboy(CpuCore::Gamedorp);
+
+        // Either file can't be created, the other one mustn't be
+        // left behind
+        ::std::fs::create_dir_all(&audio).unwrap();
+
+        let audio_failed = gb.start_video_recording(&video).is_err();
+        let video_left = video.exists();
+
+        ::std::fs::remove_dir(&audio).unwrap();
+        ::std::fs::create_dir(&video).unwrap();
+
+        let video_failed = gb.start_video_recording(&video).is_err();
+        let audio_left = audio.exists();
+
+        let _ = ::std::fs::remove_dir_all(&dir);
+
+        assert!(audio_failed && !video_left);
+        assert!(video_failed && !audio_left);
+        assert!(!gb.video_recording());
+        assert!(!gb.audio_recording());
+    }
+
+    #[test]
+    fn video_recording_while_stopped() {
+        let dir = ::std::env::temp_dir().join(format!("gb-rs-stopped-{}", ::std::process::id()));
+        let video = dir.join("clip.y4m");
+        // LD A, 0x30; LDH [P1], A; STOP
+        let mut gb = super::test_gameboy(&[0x3e, 0x30, 0xe0, 0x00, 0x10, 0x00], CpuCore::Gamedorp);
+
+        gb.cpu.interconnect_mut().store_byte(0xff26, 0x80);
+        gb.run_cycles(100);
+        assert!(gb.interconnect().stopped());
+
+        ::std::fs::create_dir_all(&dir).unwrap();
+        gb.start_video_recording(&video).unwrap();
+        let ticks = gb.run_cycles(CYCLES_PER_FRAME * 10);
+        gb.stop_video_recording().unwrap();
+
+        let wav = ::std::fs::read(dir.join("clip.wav")).unwrap();
+        let _ = ::std::fs::remove_dir_all(&dir);
+
+        // The sound keeps being recorded, one stereo frame every 95
+        // ticks
+        let frames = (wav[40] as u64 | (wav[41] as u64) << 8 | (wav[42] as u64) << 16) / 4;
+
+        assert!(frames == ticks / 95 || frames == ticks / 95 + 1);
+    }
+}
@@ -0,0 +1,98 @@
+//! Video recording
+//!
+//! The frames are written to a Y4M file at the exact frame rate of
+//! the GB (4194304 / 70224, about 59.73Hz) while the SPU records the
+//! sound to a WAV file at its native rate. Both are driven by the
+//! system clock: a frame is written every `CYCLES_PER_FRAME` ticks,
+//! showing the last frame completed by the GPU, and a sound frame
+//! every 95 ticks, including while the LCD or the SPU are off and
+//! while the clock is stopped by the STOP instruction. The only drift
+//! comes from the rounding of the sample rate in the WAV header, see
+//! `spu::SAMPLE_RATE`.
+
+use std::fs::File;
+use std::io::{BufWriter, Error, Result as IoResult};
+use std::path::Path;
+
+use gameboy::CYCLES_PER_FRAME;
+use gpu::{Color, Gpu};
+use ui::framebuffer::{self, Frame};
+use y4m::Y4mWriter;
+use GAMEBOY_SYSTEM_CLOCK_FREQUENCY;
+
+/// Video recorder state
+pub struct VideoRecorder {
+    out: Y4mWriter<BufWriter<File>>,
+    /// Last frame completed by the GPU
+    last: Frame,
+    /// Value of the GPU frame counter when `last` was copied
+    frame_count: u64,
+    /// System clock ticks elapsed since the last frame was written
+    ticks: u64,
+    /// First error encountered while writing, reported by `finish`
+    error: Option<Error>,
+}
+
+impl VideoRecorder {
+    /// Create a recorder writing to the Y4M file at `path`. `gpu`
+    /// gives the frame to start with.
+    pub fn new(path: &Path, gpu: &Gpu) -> IoResult<VideoRecorder> {
+        let file = try!(File::create(path));
+
+        // Frame rate as a fraction: system clock ticks per second
+        // over ticks per frame
+        let out = try!(Y4mWriter::new(
+            BufWriter::new(file),
+            160,
+            144,
+            GAMEBOY_SYSTEM_CLOCK_FREQUENCY as u32,
+            CYCLES_PER_FRAME as u32
+        ));
+
+        // The frame being drawn is incomplete, start with white until
+        // the GPU is done with it
+        let white = Color::from_rgb888(0xff, 0xff, 0xff);
+
+        Ok(VideoRecorder {
+            out: out,
+            last: [[white; 160]; 144],
+            frame_count: gpu.frame_count(),
+            ticks: 0,
+            error: None,
+        })
+    }
+
+    /// Count `ticks` system clock ticks, `gpu` being the state of the
+    /// GPU after they've elapsed
+    pub fn step(&mut self, ticks: u64, gpu: &Gpu) {
+        // The GPU only starts drawing the next frame a few lines
+        // later, right after the frame counter changes the frame is
+        // complete
+        if gpu.frame_count() != self.frame_count {
+            self.frame_count = gpu.frame_count();
+            self.last = *gpu.frame();
+        }
+
+        self.ticks += ticks;
+
+        if self.ticks >= CYCLES_PER_FRAME {
+            self.ticks -= CYCLES_PER_FRAME;
+
+            if self.error.is_none() {
+                if let Err(e) = self.out.write_frame(&framebuffer::rgb(&self.last, 1)) {
+                    self.error = Some(e);
+                }
+            }
+        }
+    }
+
+    /// Flush the file. Returns the first error encountered during the
+    /// recording.
+    pub fn finish(self) -> IoResult<()> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+
+        self.out.finish().map(|_| ())
+    }
+}
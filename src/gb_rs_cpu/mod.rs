@@ -1,14 +1,16 @@
 //! Game Boy CPU emulation
 
 use io::{Interconnect, Interrupt};
+use savestate::{Snapshot, StateReader, StateWriter};
 use std::fmt::{Debug, Error, Formatter};
+use std::io::Result as IoResult;
 
 use gb_rs_cpu::instructions::next_instruction;
 
 mod instructions;
 
 /// CPU state.
-pub struct Cpu<'a> {
+pub struct Cpu {
     /// CPU registers (except for `F` register)
     pub regs: Registers,
     /// CPU flags (`F` register)
@@ -20,7 +22,7 @@ pub struct Cpu<'a> {
     /// CPU halted flag
     halted: bool,
     /// Interconnect to access external ressources (RAM, ROM, peripherals...)
-    pub inter: Interconnect<'a>,
+    pub inter: Interconnect,
     /// Number of cycles elapsed running the current instruction
     instruction_cycles: u8,
 }
@@ -65,9 +67,9 @@ pub struct Flags {
     pub c: bool,
 }
 
-impl<'a> Cpu<'a> {
+impl Cpu {
     /// Create a new Cpu instance and reset it
-    pub fn new<'n>(inter: Interconnect<'n>) -> Cpu<'n> {
+    pub fn new(inter: Interconnect) -> Cpu {
         // Default register values at startup. Taken from the
         // unofficial Game Boy CPU manual.
         let regs = Registers {
@@ -108,6 +110,7 @@ impl<'a> Cpu<'a> {
             Interrupt::VBlank => 0x40,
             Interrupt::Lcdc => 0x48,
             Interrupt::Timer => 0x50,
+            Interrupt::Serial => 0x58,
             Interrupt::Button => 0x60
         };
 
@@ -438,8 +441,8 @@ impl<'a> Cpu<'a> {
 
     /// Stop, blank the screen and wait for button press
     fn stop(&mut self) {
-        println!("{:?}", *self);
-        panic!("STOP is not implemented");
+        // The interconnect also takes care of the CGB speed switch
+        self.inter.stop();
     }
 
     // Partial reset procedure used for benchmarks
@@ -449,7 +452,7 @@ impl<'a> Cpu<'a> {
     }
 }
 
-impl<'a> Debug for Cpu<'a> {
+impl Debug for Cpu {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
         try!(writeln!(f, "Registers:"));
 
@@ -519,7 +522,43 @@ impl<'a> Debug for Cpu<'a> {
     }
 }
 
-impl<'n> ::cpu::CanRunInstruction for self::Cpu<'n> {
+/// Same layout as `cpu::Cpu` so that save states can be shared
+/// between both cores
+impl Snapshot for Cpu {
+    fn snapshot(&self, w: &mut StateWriter) {
+        w.write_u16(self.af());
+        w.write_u16(self.bc());
+        w.write_u16(self.de());
+        w.write_u16(self.hl());
+        w.write_u16(self.sp());
+        w.write_u16(self.pc());
+        w.write_bool(self.iten);
+        w.write_bool(self.iten_enable_next);
+        w.write_bool(self.halted);
+        self.inter.snapshot(w);
+    }
+
+    fn restore(&mut self, r: &mut StateReader) -> IoResult<()> {
+        let af = try!(r.read_u16());
+        self.set_af(af);
+        let bc = try!(r.read_u16());
+        self.set_bc(bc);
+        let de = try!(r.read_u16());
+        self.set_de(de);
+        let hl = try!(r.read_u16());
+        self.set_hl(hl);
+        let sp = try!(r.read_u16());
+        self.set_sp(sp);
+        let pc = try!(r.read_u16());
+        self.set_pc(pc);
+        self.iten = try!(r.read_bool());
+        self.iten_enable_next = try!(r.read_bool());
+        self.halted = try!(r.read_bool());
+        self.inter.restore(r)
+    }
+}
+
+impl ::cpu::CanRunInstruction for self::Cpu {
     /// Execute an instruction or wait for an interrupt if the system
     /// is halted. The rest of the emulator state will be advanced
     /// indirectly by the `advance` method below. The function returns
@@ -528,6 +567,13 @@ impl<'n> ::cpu::CanRunInstruction for self::Cpu<'n> {
     fn run_next_instruction(&mut self) -> u8 {
         self.instruction_cycles = 0;
 
+        if self.inter.stopped() {
+            // Nothing runs until a button is pressed, just let the
+            // time pass
+            self.instruction_cycles = 4;
+            return self.instruction_cycles;
+        }
+
         if self.iten {
             if let Some(it) = self.inter.next_interrupt_ack() {
                 // We have a pending interrupt!
@@ -567,4 +613,32 @@ impl<'n> ::cpu::CanRunInstruction for self::Cpu<'n> {
 
         self.instruction_cycles
     }
+
+    fn registers(&self) -> ::cpu::Registers {
+        ::cpu::Registers {
+            af: self.af(),
+            bc: self.bc(),
+            de: self.de(),
+            hl: self.hl(),
+            sp: self.sp(),
+            pc: self.pc(),
+        }
+    }
+
+    fn set_registers(&mut self, regs: &::cpu::Registers) {
+        self.set_af(regs.af);
+        self.set_bc(regs.bc);
+        self.set_de(regs.de);
+        self.set_hl(regs.hl);
+        self.set_sp(regs.sp);
+        self.set_pc(regs.pc);
+    }
+
+    fn interconnect(&self) -> &Interconnect {
+        &self.inter
+    }
+
+    fn interconnect_mut(&mut self) -> &mut Interconnect {
+        &mut self.inter
+    }
 }
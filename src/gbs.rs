@@ -0,0 +1,426 @@
+//! GBS music player
+//!
+//! GBS files contain the sound engine and music data ripped from a
+//! game, along with the address of two routines: `init`, called once
+//! with the song number in A, and `play`, called at a regular rate
+//! (either on vertical blank or from the timer interrupt). There's
+//! no ROM to go with them so we build one around the data: the
+//! interrupt vectors call `play` and a small driver sets up the
+//! hardware, calls `init` and then waits for interrupts forever.
+//!
+//! Switching songs raises the serial interrupt, whose vector jumps
+//! back to the driver to start over with the new song number.
+
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Result as IoResult};
+use std::path::Path;
+
+use cartridge::Cartridge;
+use gameboy::GameBoy;
+
+/// Size of the GBS header, the data follows
+const HEADER_SIZE: usize = 0x70;
+
+/// Each ROM bank is always 16KB
+const ROM_BANK_SIZE: usize = 16 * 1024;
+
+/// Address of the driver in the generated ROM, right after the
+/// cartridge header
+const DRIVER: u16 = 0x150;
+
+/// Address of the song number (starting at 0) passed to `init`.
+/// Stored in the logo area of the cartridge header since there's no
+/// bootrom to check it.
+const SONG: u16 = 0x104;
+
+/// Contents of a GBS file
+pub struct Gbs {
+    /// Number of songs
+    songs: u8,
+    /// Song to play first, starting at 1
+    first_song: u8,
+    /// Address where the data is loaded, at least 0x400
+    load: u16,
+    /// Address of the init routine
+    init: u16,
+    /// Address of the play routine
+    play: u16,
+    /// Initial stack pointer
+    sp: u16,
+    /// Timer modulo
+    tma: u8,
+    /// Timer control. If bit 2 is set `play` is called from the
+    /// timer interrupt instead of vertical blank. Bit 7 selects the
+    /// CGB double speed mode.
+    tac: u8,
+    title: String,
+    author: String,
+    copyright: String,
+    /// Code and music data
+    data: Vec<u8>,
+}
+
+impl Gbs {
+    /// Load the GBS file at `path`
+    pub fn from_path(path: &Path) -> IoResult<Gbs> {
+        let mut gbs = Vec::new();
+
+        try!(try!(File::open(path)).read_to_end(&mut gbs));
+
+        Gbs::parse(&gbs)
+    }
+
+    /// Parse the contents of a GBS file
+    pub fn parse(gbs: &[u8]) -> IoResult<Gbs> {
+        if gbs.len() < HEADER_SIZE || &gbs[0..3] != b"GBS" {
+            return invalid("not a GBS file");
+        }
+
+        if gbs[3] != 1 {
+            return invalid("unsupported GBS version");
+        }
+
+        let u16_at = |off: usize| gbs[off] as u16 | (gbs[off + 1] as u16) << 8;
+
+        let load = u16_at(0x06);
+
+        // The generated ROM needs the space below for the interrupt
+        // vectors and the driver
+        if load < 0x400 || load >= 0x8000 {
+            return invalid("invalid load address");
+        }
+
+        let data = &gbs[HEADER_SIZE..];
+
+        // The ROM can't be bigger than what the MBC5 can map
+        if load as usize + data.len() > 0x100 * ROM_BANK_SIZE {
+            return invalid("GBS data too big");
+        }
+
+        let songs = gbs[0x04];
+
+        if songs == 0 {
+            return invalid("no songs");
+        }
+
+        Ok(Gbs {
+            songs: songs,
+            first_song: match gbs[0x05] {
+                n @ 1...0xff if n <= songs => n,
+                _ => 1,
+            },
+            load: load,
+            init: u16_at(0x08),
+            play: u16_at(0x0a),
+            sp: u16_at(0x0c),
+            tma: gbs[0x0e],
+            tac: gbs[0x0f],
+            title: string(&gbs[0x10..0x30]),
+            author: string(&gbs[0x30..0x50]),
+            copyright: string(&gbs[0x50..0x70]),
+            data: data.to_vec(),
+        })
+    }
+
+    /// Return the number of songs
+    pub fn songs(&self) -> u8 {
+        self.songs
+    }
+
+    /// Return the song to play first, starting at 1
+    pub fn first_song(&self) -> u8 {
+        self.first_song
+    }
+
+    /// Return the title of the game
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    /// Return the composer
+    pub fn author(&self) -> &str {
+        &self.author
+    }
+
+    /// Return the copyright notice
+    pub fn copyright(&self) -> &str {
+        &self.copyright
+    }
+
+    /// Build a cartridge running the player. `path` is only used to
+    /// name related files, like save states.
+    pub fn cartridge(&self, path: &Path) -> Cartridge {
+        let size = self.load as usize + self.data.len();
+        let banks = ::std::cmp::max(2, (size + ROM_BANK_SIZE - 1) / ROM_BANK_SIZE);
+        let banks = banks.next_power_of_two();
+
+        let mut rom = vec![0xff; banks * ROM_BANK_SIZE];
+
+        rom[self.load as usize..size].copy_from_slice(&self.data);
+
+        // RST vectors are relocated to the start of the data
+        for rst in 0..8 {
+            let vector = rst * 8;
+
+            put(&mut rom, vector, &[0xc3]);
+            put_u16(&mut rom, vector + 1, self.load + vector as u16);
+        }
+
+        let call_play = [0xcd, self.play as u8, (self.play >> 8) as u8, 0xd9];
+
+        // VBlank: CALL play; RETI
+        put(&mut rom, 0x40, &call_play);
+        // LCD stat: RETI
+        put(&mut rom, 0x48, &[0xd9]);
+        // Timer: CALL play; RETI
+        put(&mut rom, 0x50, &call_play);
+        // Serial: JP DRIVER
+        put(&mut rom, 0x58, &[0xc3, DRIVER as u8, (DRIVER >> 8) as u8]);
+        // Joypad: RETI
+        put(&mut rom, 0x60, &[0xd9]);
+
+        // Entry point, unused since we don't run the bootrom
+        put(&mut rom, 0x100, &[0x00, 0xc3, DRIVER as u8, (DRIVER >> 8) as u8]);
+        rom[SONG as usize] = self.first_song - 1;
+
+        // Header: title, MBC5 with RAM, ROM size and 8KB of RAM
+        for (i, &b) in self.title.as_bytes().iter().take(15).enumerate() {
+            rom[0x134 + i] = b.to_ascii_uppercase();
+        }
+        rom[0x143] = match self.tac & 0x80 != 0 {
+            true => 0x80,
+            false => 0x00,
+        };
+        rom[0x147] = 0x1a;
+        rom[0x148] = banks.trailing_zeros() as u8 - 1;
+        rom[0x149] = 0x02;
+
+        let driver = self.driver();
+
+        put(&mut rom, DRIVER as usize, &driver);
+
+        update_checksum(&mut rom);
+
+        Cartridge::from_rom(rom, path)
+    }
+
+    /// Generate the code setting up the hardware, calling `init` and
+    /// then waiting for the interrupts calling `play`
+    fn driver(&self) -> Vec<u8> {
+        let mut d = Vec::new();
+
+        // DI; LD SP, sp
+        d.extend_from_slice(&[0xf3, 0x31, self.sp as u8, (self.sp >> 8) as u8]);
+
+        if self.tac & 0x80 != 0 {
+            // Switch to double speed unless KEY1 says we already are:
+            // LDH A, [KEY1]; BIT 7, A; JR NZ, +6; LD A, 1;
+            // LDH [KEY1], A; STOP
+            d.extend_from_slice(&[0xf0, 0x4d, 0xcb, 0x7f, 0x20, 0x06,
+                                  0x3e, 0x01, 0xe0, 0x4d, 0x10, 0x00]);
+        }
+
+        // Mask all interrupts and reset the sound unit with both
+        // outputs at full volume:
+        // XOR A; LDH [IE], A; LDH [NR52], A; LD A, 0x80;
+        // LDH [NR52], A; LD A, 0x77; LDH [NR50], A; LD A, 0xff;
+        // LDH [NR51], A
+        d.extend_from_slice(&[0xaf, 0xe0, 0xff, 0xe0, 0x26, 0x3e, 0x80,
+                              0xe0, 0x26, 0x3e, 0x77, 0xe0, 0x24, 0x3e,
+                              0xff, 0xe0, 0x25]);
+
+        // Enable the cartridge RAM and clear it along with the
+        // internal RAM:
+        // LD A, 0x0a; LD [0x0000], A; LD HL, 0xa000;
+        // loop: XOR A; LD [HL+], A; LD A, H; CP 0xe0; JR NZ, loop
+        d.extend_from_slice(&[0x3e, 0x0a, 0xea, 0x00, 0x00, 0x21, 0x00,
+                              0xa0, 0xaf, 0x22, 0x7c, 0xfe, 0xe0, 0x20,
+                              0xf9]);
+
+        // Map bank 1 and configure the timer, then turn the LCD on
+        // for the vertical blank interrupt:
+        // LD A, 1; LD [0x2000], A; LD A, tma; LDH [TMA], A;
+        // LD A, tac; LDH [TAC], A; LD A, 0x80; LDH [LCDC], A
+        d.extend_from_slice(&[0x3e, 0x01, 0xea, 0x00, 0x20,
+                              0x3e, self.tma, 0xe0, 0x06,
+                              0x3e, self.tac & 7, 0xe0, 0x07,
+                              0x3e, 0x80, 0xe0, 0x40]);
+
+        // LD A, [SONG]; CALL init
+        d.extend_from_slice(&[0xfa, SONG as u8, (SONG >> 8) as u8,
+                              0xcd, self.init as u8, (self.init >> 8) as u8]);
+
+        // Call play from the timer or vertical blank interrupt, the
+        // serial interrupt is used to switch songs
+        let it = match self.tac & 4 != 0 {
+            true => 0x04,
+            false => 0x01,
+        } | 0x08;
+
+        // LD A, it; LDH [IE], A; LDH A, [IF]; AND 0x08; LDH [IF], A;
+        // EI
+        d.extend_from_slice(&[0x3e, it, 0xe0, 0xff, 0xf0, 0x0f, 0xe6,
+                              0x08, 0xe0, 0x0f, 0xfb]);
+
+        // loop: HALT; NOP; JR loop
+        d.extend_from_slice(&[0x76, 0x00, 0x18, 0xfc]);
+
+        d
+    }
+
+    /// Start playing `song` (starting at 1) on `gameboy`, which must
+    /// be running the cartridge built by `cartridge` and must not
+    /// have run any instruction yet.
+    pub fn start(&self, gameboy: &mut GameBoy, song: u8) {
+        {
+            let rom = gameboy.cartridge_mut().rom_mut();
+
+            rom[SONG as usize] = song.wrapping_sub(1);
+            update_checksum(rom);
+        }
+
+        // There's no bootrom to run, jump straight to the driver
+        gameboy.interconnect_mut().poke_byte(0xff50, 1);
+
+        let mut regs = gameboy.registers();
+
+        regs.pc = DRIVER;
+        regs.sp = self.sp;
+
+        gameboy.set_registers(&regs);
+    }
+
+    /// Switch to `song` (starting at 1) while the player is running
+    pub fn select_song(&self, gameboy: &mut GameBoy, song: u8) {
+        {
+            let rom = gameboy.cartridge_mut().rom_mut();
+
+            rom[SONG as usize] = song.wrapping_sub(1);
+            update_checksum(rom);
+        }
+
+        // Raise the serial interrupt to restart the driver
+        let inter = gameboy.interconnect_mut();
+
+        let ie = inter.peek_byte(0xffff);
+        inter.poke_byte(0xffff, ie | 0x08);
+
+        let it = inter.peek_byte(0xff0f);
+        inter.poke_byte(0xff0f, it | 0x08);
+    }
+}
+
+/// Recompute the global checksum of the generated ROM. Save states
+/// use it to tell ROMs apart.
+fn update_checksum(rom: &mut [u8]) {
+    let checksum = rom.iter().fold(0u16, |sum, &b| sum.wrapping_add(b as u16))
+        .wrapping_sub(rom[0x14e] as u16 + rom[0x14f] as u16);
+
+    put(rom, 0x14e, &[(checksum >> 8) as u8, checksum as u8]);
+}
+
+fn put(rom: &mut [u8], addr: usize, bytes: &[u8]) {
+    rom[addr..addr + bytes.len()].copy_from_slice(bytes);
+}
+
+fn put_u16(rom: &mut [u8], addr: usize, v: u16) {
+    put(rom, addr, &[v as u8, (v >> 8) as u8]);
+}
+
+/// Convert a 0-padded header string
+fn string(b: &[u8]) -> String {
+    let len = b.iter().position(|&c| c == 0).unwrap_or(b.len());
+
+    String::from_utf8_lossy(&b[..len]).into_owned()
+}
+
+fn invalid<T>(msg: &str) -> IoResult<T> {
+    Err(Error::new(ErrorKind::InvalidData, msg))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Gbs;
+    use gameboy::{CpuCore, GameBoy};
+    use std::path::Path;
+
+    fn gbs_file() -> Vec<u8> {
+        let mut gbs = vec![0; 0x70];
+
+        gbs[0..4].copy_from_slice(b"GBS\x01");
+        // 3 songs, starting with the second one
+        gbs[0x04] = 3;
+        gbs[0x05] = 2;
+        // Load and init at 0x400, play at 0x404, stack at 0xfffe
+        gbs[0x06..0x0e].copy_from_slice(&[0x00, 0x04, 0x00, 0x04, 0x04, 0x04, 0xfe, 0xff]);
+        gbs[0x10..0x14].copy_from_slice(b"Test");
+
+        // init: LD [0xc000], A; RET
+        gbs.extend_from_slice(&[0xea, 0x00, 0xc0, 0xc9]);
+        // play: LD HL, 0xc001; INC [HL]; RET
+        gbs.extend_from_slice(&[0x21, 0x01, 0xc0, 0x34, 0xc9]);
+
+        gbs
+    }
+
+    #[test]
+    fn parse() {
+        let gbs = Gbs::parse(&gbs_file()).unwrap();
+
+        assert!(gbs.songs() == 3);
+        assert!(gbs.first_song() == 2);
+        assert!(gbs.title() == "Test");
+        assert!(gbs.author() == "");
+
+        let mut bad = gbs_file();
+        bad[0x07] = 0x03;
+        assert!(Gbs::parse(&bad).is_err());
+    }
+
+    #[test]
+    fn play_songs() {
+        let gbs = Gbs::parse(&gbs_file()).unwrap();
+        let mut gb = GameBoy::headless(gbs.cartridge(Path::new("test.gbs")), CpuCore::Gamedorp);
+
+        gbs.start(&mut gb, gbs.first_song());
+
+        // Clearing the RAM takes a few frames
+        for _ in 0..10 {
+            gb.run_frame();
+        }
+
+        // init got the song number, play runs once per frame
+        assert!(gb.interconnect().peek_byte(0xc000) == 1);
+
+        let count = gb.interconnect().peek_byte(0xc001);
+
+        for _ in 0..10 {
+            gb.run_frame();
+        }
+
+        assert!(gb.interconnect().peek_byte(0xc001) == count + 10);
+
+        let checksum = gb.cartridge().checksum();
+
+        gbs.select_song(&mut gb, 3);
+
+        // The global checksum covers the song number
+        let sum = {
+            let rom = gb.cartridge_mut().rom_mut();
+
+            rom.iter().fold(0u16, |sum, &b| sum.wrapping_add(b as u16))
+                .wrapping_sub(rom[0x14e] as u16 + rom[0x14f] as u16)
+        };
+
+        assert!(gb.cartridge().checksum() == sum);
+        assert!(checksum != sum);
+
+        for _ in 0..10 {
+            gb.run_frame();
+        }
+
+        // The RAM was cleared before calling init again
+        assert!(gb.interconnect().peek_byte(0xc000) == 2);
+        assert!(gb.interconnect().peek_byte(0xc001) < 10);
+    }
+}
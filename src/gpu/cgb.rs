@@ -0,0 +1,129 @@
+//! Game Boy Color specific GPU state
+
+use gpu::{Color, Shade};
+
+/// Color palette RAM. There are two of them: one for the background
+/// and window, the other for sprites. Each contains 8 palettes of 4
+/// colors, each color being a little endian 15bit RGB value.
+pub struct ColorPalettes {
+    /// Raw palette memory
+    ram: [u8; 64],
+    /// Index of the byte accessed through the data register
+    index: u8,
+    /// If `true` `index` is incremented after each write to the data
+    /// register
+    auto_increment: bool,
+}
+
+impl ColorPalettes {
+    /// Create a new palette RAM with all colors set to white
+    pub fn new() -> ColorPalettes {
+        ColorPalettes {
+            ram: [0xff; 64],
+            index: 0,
+            auto_increment: false,
+        }
+    }
+
+    /// Return the value of the specification register (BCPS/OCPS)
+    pub fn spec(&self) -> u8 {
+        // Bit 6 is unused and reads as 1
+        0x40 | ((self.auto_increment as u8) << 7) | self.index
+    }
+
+    /// Handle reconfiguration of the specification register
+    /// (BCPS/OCPS)
+    pub fn set_spec(&mut self, spec: u8) {
+        self.index = spec & 0x3f;
+        self.auto_increment = spec & 0x80 != 0;
+    }
+
+    /// Return the palette byte currently selected (BCPD/OCPD)
+    pub fn data(&self) -> u8 {
+        self.ram[self.index as usize]
+    }
+
+    /// Write the palette byte currently selected (BCPD/OCPD)
+    pub fn set_data(&mut self, val: u8) {
+        self.ram[self.index as usize] = val;
+
+        if self.auto_increment {
+            self.index = (self.index + 1) & 0x3f;
+        }
+    }
+
+    /// Return the color used for `shade` in `palette`
+    pub fn color(&self, palette: u8, shade: Shade) -> Color {
+        let i = (palette as usize) * 8 + (shade as usize) * 2;
+
+        let lo = self.ram[i] as u16;
+        let hi = self.ram[i + 1] as u16;
+
+        Color::from_rgb555((hi << 8) | lo)
+    }
+
+    /// Return the raw palette memory
+    pub fn ram(&self) -> &[u8; 64] {
+        &self.ram
+    }
+
+    /// Return the raw palette memory
+    pub fn ram_mut(&mut self) -> &mut [u8; 64] {
+        &mut self.ram
+    }
+}
+
+/// Attributes of a background or window tile, stored in VRAM bank 1
+/// at the same address as the tile index in bank 0
+#[derive(Clone, Copy)]
+pub struct TileAttributes {
+    /// Color palette number
+    pub palette: u8,
+    /// VRAM bank containing the tile data
+    pub bank: usize,
+    /// If `true` the tile is flipped horizontally
+    pub x_flip: bool,
+    /// If `true` the tile is flipped vertically
+    pub y_flip: bool,
+    /// If `true` the tile is displayed on top of the sprites
+    pub priority: bool,
+}
+
+impl TileAttributes {
+    /// Decode the attribute byte `attrs`
+    pub fn from_reg(attrs: u8) -> TileAttributes {
+        TileAttributes {
+            palette: attrs & 7,
+            bank: ((attrs >> 3) & 1) as usize,
+            x_flip: attrs & 0x20 != 0,
+            y_flip: attrs & 0x40 != 0,
+            priority: attrs & 0x80 != 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ColorPalettes;
+    use gpu::Shade;
+
+    #[test]
+    fn palette_auto_increment() {
+        let mut p = ColorPalettes::new();
+
+        // Palette 1, color 1 with auto increment
+        p.set_spec(0x80 | 0x0a);
+        p.set_data(0x1f);
+        p.set_data(0x7c);
+        assert!(p.spec() == 0xc0 | 0x0c);
+
+        let c = p.color(1, Shade::LightGrey);
+        assert!(c.rgb555() == 0x7c1f);
+        assert!(c.rgb888() == (0xff, 0x00, 0xff));
+
+        // Index wraps around
+        p.set_spec(0xbf);
+        p.set_data(0);
+        assert!(p.spec() == 0xc0);
+    }
+}
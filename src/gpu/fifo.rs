@@ -0,0 +1,799 @@
+//! Pixel FIFO renderer
+//!
+//! Alternative to the scanline renderer in the parent module which
+//! emulates the way the real PPU generates the pixels during mode 3:
+//! a fetcher reads the background and window tiles 8 pixels at a time
+//! and pushes them to a FIFO which shifts one pixel out to the LCD at
+//! each dot. Sprites are fetched when the current pixel reaches their
+//! X coordinate and mixed in a second FIFO.
+//!
+//! Since VRAM and the registers are read while the line is being
+//! drawn, mid-scanline changes of SCX, SCY, the palettes or LCDC show
+//! up on screen and the length of mode 3 depends on the fine scroll,
+//! the window and the number of sprites on the line.
+
+use std::io::Result as IoResult;
+
+use gpu::cgb::TileAttributes;
+use gpu::{sprite, Color, Gpu, Shade, SpriteSize, TileSet};
+use savestate::{self, Snapshot, StateReader, StateWriter};
+
+/// Pixel FIFO state
+pub struct Fifo {
+    /// Background/window pixels. The fetcher only pushes new pixels
+    /// when the FIFO is empty so it never holds more than 8.
+    bg: [BgPixel; 8],
+    /// Number of pixels left in `bg`, the next one is at `8 - bg_len`
+    bg_len: u8,
+    /// Sprite pixels, aligned with the next 8 background pixels
+    obj: [ObjPixel; 8],
+    /// Background/window tile fetcher
+    fetcher: Fetcher,
+    /// Number of pixels output on the current line
+    lx: u8,
+    /// Pixels left to discard at the beginning of the line for the
+    /// SCX fine scroll
+    discard: u8,
+    /// Internal window line counter, only incremented on lines where
+    /// the window is displayed
+    window_line: u8,
+    /// `true` once LY matched WY during the current frame
+    wy_triggered: bool,
+    /// `true` if the fetcher switched to the window on this line
+    window_active: bool,
+    /// OAM indexes of the sprites on the current line, in OAM order
+    sprites: [u8; 10],
+    /// Number of valid entries in `sprites`
+    sprite_count: u8,
+    /// Bitmap of the entries of `sprites` already fetched
+    sprites_fetched: u16,
+    /// Bitmap of the tile columns already delayed by a sprite fetch
+    penalized_tiles: u32,
+    /// Dots left before the pipeline resumes after a sprite fetch
+    stall: u8,
+}
+
+/// Background or window pixel
+#[derive(Clone, Copy, Default)]
+struct BgPixel {
+    /// Color index before palette, 0 to 3
+    color: u8,
+    /// CGB palette number
+    palette: u8,
+    /// CGB BG-to-OAM priority
+    priority: bool,
+}
+
+/// Sprite pixel
+#[derive(Clone, Copy, Default)]
+struct ObjPixel {
+    /// Color index before palette, 0 is transparent
+    color: u8,
+    /// OBP0/OBP1 on the DMG, color palette on the CGB
+    palette: u8,
+    /// Sprite is displayed behind background colors 1-3
+    background: bool,
+    /// OAM index of the sprite, used for priority on the CGB
+    index: u8,
+}
+
+/// Steps of the tile fetcher, each one takes two dots except `Push`
+/// which is attempted at every dot until the FIFO is empty
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum FetchStep {
+    Tile = 0,
+    DataLow = 1,
+    DataHigh = 2,
+    Push = 3,
+}
+
+/// Background/window tile fetcher state
+#[derive(Clone, Copy)]
+struct Fetcher {
+    /// Current step
+    step: FetchStep,
+    /// `true` on the second dot of a step
+    second_dot: bool,
+    /// Tile column being fetched, relative to the start of the
+    /// background (SCX / 8) or of the window
+    x: u8,
+    /// `true` if fetching window tiles
+    window: bool,
+    /// `true` until the first (discarded) fetch of the line is done
+    first: bool,
+    /// Tile index read from the tile map
+    tile: u8,
+    /// CGB tile attributes
+    attrs: u8,
+    /// Low bits of the tile row
+    low: u8,
+    /// High bits of the tile row
+    high: u8,
+}
+
+impl Fetcher {
+    fn new(window: bool) -> Fetcher {
+        Fetcher {
+            step: FetchStep::Tile,
+            second_dot: false,
+            x: 0,
+            window: window,
+            first: !window,
+            tile: 0,
+            attrs: 0,
+            low: 0,
+            high: 0,
+        }
+    }
+}
+
+impl Fifo {
+    /// Create an idle FIFO
+    pub fn new() -> Fifo {
+        Fifo {
+            bg: [BgPixel::default(); 8],
+            bg_len: 0,
+            obj: [ObjPixel::default(); 8],
+            fetcher: Fetcher::new(false),
+            lx: 0,
+            discard: 0,
+            window_line: 0,
+            wy_triggered: false,
+            window_active: false,
+            sprites: [0; 10],
+            sprite_count: 0,
+            sprites_fetched: 0,
+            penalized_tiles: 0,
+            stall: 0,
+        }
+    }
+}
+
+impl Gpu {
+    /// Called when entering mode 3: select the sprites displayed on
+    /// the line and reset the pipeline
+    pub fn fifo_start_line(&mut self) {
+        let line = self.line;
+
+        if line == 0 {
+            self.fifo.window_line = 0;
+            self.fifo.wy_triggered = false;
+        }
+
+        // The WY condition is checked at the start of each line even
+        // if the window is disabled
+        if line == self.wy {
+            self.fifo.wy_triggered = true;
+        }
+
+        // OAM scan: the first 10 sprites in OAM order covering the
+        // line are displayed
+        let height = self.sprite_size.height() as i32;
+        let mut count = 0;
+
+        for (i, sprite) in self.oam.iter().enumerate() {
+            if count == self.fifo.sprites.len() {
+                break;
+            }
+
+            let top = sprite.top_line();
+            let y = line as i32;
+
+            if y >= top && y < top + height {
+                self.fifo.sprites[count] = i as u8;
+                count += 1;
+            }
+        }
+
+        self.fifo.sprite_count = count as u8;
+        self.fifo.sprites_fetched = 0;
+        self.fifo.penalized_tiles = 0;
+        self.fifo.stall = 0;
+        self.fifo.bg_len = 0;
+        self.fifo.obj = [ObjPixel::default(); 8];
+        self.fifo.fetcher = Fetcher::new(false);
+        self.fifo.lx = 0;
+        self.fifo.discard = self.scx & 7;
+        self.fifo.window_active = false;
+    }
+
+    /// Run the pipeline for one dot of mode 3. Returns `true` on the
+    /// dot following the output of the last pixel of the line, when
+    /// mode 3 ends.
+    pub fn fifo_dot(&mut self) -> bool {
+        if self.fifo.lx >= 160 {
+            return true;
+        }
+
+        if self.fifo.stall > 0 {
+            self.fifo.stall -= 1;
+            return false;
+        }
+
+        self.fetcher_dot();
+
+        if self.fifo.bg_len == 0 {
+            // Nothing to output yet
+            return false;
+        }
+
+        if self.fifo.discard > 0 {
+            self.fifo.discard -= 1;
+            self.pop_bg();
+            return false;
+        }
+
+        if self.start_window() {
+            return false;
+        }
+
+        if self.start_sprite_fetch() {
+            return false;
+        }
+
+        let bg = self.pop_bg();
+        let obj = self.pop_obj();
+        let color = self.mix(bg, obj);
+
+        let x = self.fifo.lx;
+        let y = self.line;
+
+        self.frame[y as usize][x as usize] = color;
+        self.display.set_pixel(x as u32, y as u32, color);
+
+        self.fifo.lx += 1;
+
+        if self.fifo.lx == 160 && self.fifo.window_active {
+            self.fifo.window_line = self.fifo.window_line.wrapping_add(1);
+        }
+
+        false
+    }
+
+    /// Advance the tile fetcher by one dot
+    fn fetcher_dot(&mut self) {
+        let mut f = self.fifo.fetcher;
+
+        if f.step == FetchStep::Push {
+            if self.fifo.bg_len == 0 {
+                self.push_tile(&f);
+                f.x = f.x.wrapping_add(1);
+                f.step = FetchStep::Tile;
+            }
+
+            self.fifo.fetcher = f;
+            return;
+        }
+
+        // Each step does its work on its second dot
+        if !f.second_dot {
+            f.second_dot = true;
+            self.fifo.fetcher = f;
+            return;
+        }
+
+        f.second_dot = false;
+
+        let (row, map) = match f.window {
+            true => (self.fifo.window_line, self.window_tile_map),
+            false => (self.line.wrapping_add(self.scy), self.bg_tile_map),
+        };
+
+        f.step = match f.step {
+            FetchStep::Tile => {
+                let tx = match f.window {
+                    true => f.x & 31,
+                    false => ((self.scx >> 3).wrapping_add(f.x)) & 31,
+                };
+                let addr = Gpu::tile_map_addr(tx, row / 8, map);
+
+                f.tile = self.vram[addr];
+                f.attrs = match self.cgb {
+                    true => self.vram[0x2000 + addr],
+                    false => 0,
+                };
+
+                FetchStep::DataLow
+            }
+            FetchStep::DataLow => {
+                f.low = self.tile_row(&f, row)[0];
+                FetchStep::DataHigh
+            }
+            FetchStep::DataHigh => {
+                f.high = self.tile_row(&f, row)[1];
+
+                match f.first {
+                    // The first fetch of the line is thrown away
+                    true => {
+                        f.first = false;
+                        FetchStep::Tile
+                    }
+                    false => FetchStep::Push,
+                }
+            }
+            FetchStep::Push => unreachable!(),
+        };
+
+        self.fifo.fetcher = f;
+    }
+
+    /// Read the two bytes of the row of the fetched tile
+    fn tile_row(&self, f: &Fetcher, row: u8) -> [u8; 2] {
+        let attrs = TileAttributes::from_reg(f.attrs);
+
+        let y = match attrs.y_flip {
+            true => 7 - row % 8,
+            false => row % 8,
+        };
+
+        let addr = attrs.bank * 0x2000 + (self.bg_win_tile_set.tile_addr(f.tile) + 2 * y as u16) as usize;
+
+        [self.vram[addr], self.vram[addr + 1]]
+    }
+
+    /// Push the 8 pixels fetched by `f` to the background FIFO
+    fn push_tile(&mut self, f: &Fetcher) {
+        let attrs = TileAttributes::from_reg(f.attrs);
+
+        for i in 0..8 {
+            let bit = match attrs.x_flip {
+                true => i,
+                false => 7 - i,
+            };
+
+            self.fifo.bg[i] = BgPixel {
+                color: ((f.high >> bit) & 1) << 1 | ((f.low >> bit) & 1),
+                palette: attrs.palette,
+                priority: attrs.priority,
+            };
+        }
+
+        self.fifo.bg_len = 8;
+    }
+
+    fn pop_bg(&mut self) -> BgPixel {
+        let p = self.fifo.bg[8 - self.fifo.bg_len as usize];
+
+        self.fifo.bg_len -= 1;
+
+        p
+    }
+
+    fn pop_obj(&mut self) -> ObjPixel {
+        let p = self.fifo.obj[0];
+
+        for i in 0..7 {
+            self.fifo.obj[i] = self.fifo.obj[i + 1];
+        }
+        self.fifo.obj[7] = ObjPixel::default();
+
+        p
+    }
+
+    /// Switch the fetcher to the window if we reached it. Returns
+    /// `true` if the pipeline has been restarted.
+    fn start_window(&mut self) -> bool {
+        if !self.window_enabled || !self.fifo.wy_triggered || self.fifo.window_active {
+            return false;
+        }
+
+        if (self.fifo.lx as u16) + 7 < self.wx as u16 {
+            return false;
+        }
+
+        self.fifo.window_active = true;
+        self.fifo.bg_len = 0;
+        self.fifo.fetcher = Fetcher::new(true);
+
+        // With WX below 7 the beginning of the window is off screen
+        if self.fifo.lx == 0 && self.wx < 7 {
+            self.fifo.discard = 7 - self.wx;
+        }
+
+        true
+    }
+
+    /// Fetch the next sprite starting at the current pixel, if any.
+    /// Returns `true` if the pipeline is stalled by the fetch.
+    fn start_sprite_fetch(&mut self) -> bool {
+        // On the DMG sprites aren't fetched at all when they're
+        // disabled
+        if !self.sprites_enabled && !self.cgb {
+            return false;
+        }
+
+        let lx = self.fifo.lx as i32;
+
+        for i in 0..self.fifo.sprite_count as usize {
+            if self.fifo.sprites_fetched & (1 << i) != 0 {
+                continue;
+            }
+
+            let index = self.fifo.sprites[i];
+            let sprite = self.oam[index as usize];
+
+            if sprite.left_column() > lx {
+                continue;
+            }
+
+            self.fifo.sprites_fetched |= 1 << i;
+
+            if self.sprites_enabled {
+                self.merge_sprite(index);
+            }
+
+            // This dot is the first one of the penalty
+            self.fifo.stall = self.sprite_penalty(sprite.x_pos()) - 1;
+
+            return true;
+        }
+
+        false
+    }
+
+    /// Number of dots the fetch of a sprite at OAM X position `x`
+    /// stalls the pipeline
+    fn sprite_penalty(&mut self, x: u8) -> u8 {
+        if x == 0 {
+            return 11;
+        }
+
+        // The fetcher has to finish the tile under the sprite's
+        // leftmost pixel, only the first sprite on a tile pays for
+        // it
+        let align = match self.fifo.window_active {
+            true => 7u8.wrapping_sub(self.wx) & 7,
+            false => self.scx & 7,
+        };
+        let pos = x as u32 + align as u32;
+        let tile = pos / 8;
+
+        let wait = match self.fifo.penalized_tiles & (1 << tile) != 0 {
+            true => 0,
+            false => (7 - pos % 8).saturating_sub(2) as u8,
+        };
+
+        self.fifo.penalized_tiles |= 1 << tile;
+
+        6 + wait
+    }
+
+    /// Mix the pixels of the sprite at OAM `index` into the sprite
+    /// FIFO
+    fn merge_sprite(&mut self, index: u8) {
+        let sprite = self.oam[index as usize];
+
+        let (height, tile) = match self.sprite_size {
+            SpriteSize::Sz8x8 => (7, sprite.tile()),
+            // For 16pix tiles the LSB is ignored
+            SpriteSize::Sz8x16 => (15, sprite.tile() & 0xfe),
+        };
+
+        let row = (self.line as i32 - sprite.top_line()) & 15;
+        let row = match sprite.y_flip() {
+            true => height - row,
+            false => row,
+        };
+
+        let bank = match self.cgb {
+            true => sprite.vram_bank(),
+            false => 0,
+        };
+
+        let addr = bank * 0x2000 + (TileSet::Set1.tile_addr(tile) + 2 * row as u16) as usize;
+        let low = self.vram[addr];
+        let high = self.vram[addr + 1];
+
+        let palette = match self.cgb {
+            true => sprite.color_palette(),
+            false => match sprite.palette() {
+                sprite::Palette::Obp0 => 0,
+                sprite::Palette::Obp1 => 1,
+            },
+        };
+
+        for i in 0..8 {
+            // Position relative to the current pixel
+            let pos = sprite.left_column() + i - self.fifo.lx as i32;
+
+            if pos < 0 {
+                // Off screen on the left
+                continue;
+            }
+
+            let bit = match sprite.x_flip() {
+                true => i,
+                false => 7 - i,
+            };
+
+            let pixel = ObjPixel {
+                color: ((high >> bit) & 1) << 1 | ((low >> bit) & 1),
+                palette: palette,
+                background: sprite.background(),
+                index: index,
+            };
+
+            let slot = &mut self.fifo.obj[pos as usize];
+
+            // Sprites fetched earlier have priority on the DMG, on the
+            // CGB the OAM index decides
+            let replace = slot.color == 0 || (self.cgb && pixel.color != 0 && index < slot.index);
+
+            if replace {
+                *slot = pixel;
+            }
+        }
+    }
+
+    /// Compute the final color of a pixel
+    fn mix(&self, bg: BgPixel, obj: ObjPixel) -> Color {
+        // On the DMG LCDC bit 0 disables the background and window, on
+        // the CGB it puts all sprites on top of them instead
+        let bg_color = match self.bg_enabled || self.cgb {
+            true => bg.color,
+            false => 0,
+        };
+
+        let obj_visible = self.sprites_enabled
+            && obj.color != 0
+            && (bg_color == 0 || (self.cgb && !self.bg_enabled) || !(obj.background || (self.cgb && bg.priority)));
+
+        if obj_visible {
+            let shade = Shade::from_u8(obj.color);
+
+            return match self.cgb {
+                true => self.obj_palettes.color(obj.palette, shade),
+                false => {
+                    let (palette, colors) = match obj.palette {
+                        0 => (self.obp0, &self.dmg_palette.obj0),
+                        _ => (self.obp1, &self.dmg_palette.obj1),
+                    };
+
+                    colors[palette.transform(shade) as usize]
+                }
+            };
+        }
+
+        let shade = Shade::from_u8(bg_color);
+
+        match (self.cgb, self.bg_enabled) {
+            (true, _) => self.bg_palettes.color(bg.palette, shade),
+            (false, true) => self.dmg_palette.bg[self.bgp.transform(shade) as usize],
+            (false, false) => self.dmg_palette.bg[0],
+        }
+    }
+}
+
+impl Snapshot for Fifo {
+    fn snapshot(&self, w: &mut StateWriter) {
+        for p in self.bg.iter() {
+            w.write_u8(p.color);
+            w.write_u8(p.palette);
+            w.write_bool(p.priority);
+        }
+        w.write_u8(self.bg_len);
+
+        for p in self.obj.iter() {
+            w.write_u8(p.color);
+            w.write_u8(p.palette);
+            w.write_bool(p.background);
+            w.write_u8(p.index);
+        }
+
+        let f = &self.fetcher;
+        w.write_u8(f.step as u8);
+        w.write_bool(f.second_dot);
+        w.write_u8(f.x);
+        w.write_bool(f.window);
+        w.write_bool(f.first);
+        w.write_u8(f.tile);
+        w.write_u8(f.attrs);
+        w.write_u8(f.low);
+        w.write_u8(f.high);
+
+        w.write_u8(self.lx);
+        w.write_u8(self.discard);
+        w.write_u8(self.window_line);
+        w.write_bool(self.wy_triggered);
+        w.write_bool(self.window_active);
+        w.write_bytes(&self.sprites);
+        w.write_u8(self.sprite_count);
+        w.write_u16(self.sprites_fetched);
+        w.write_u32(self.penalized_tiles);
+        w.write_u8(self.stall);
+    }
+
+    fn restore(&mut self, r: &mut StateReader) -> IoResult<()> {
+        for p in self.bg.iter_mut() {
+            p.color = try!(r.read_u8()) & 3;
+            p.palette = try!(r.read_u8()) & 7;
+            p.priority = try!(r.read_bool());
+        }
+        self.bg_len = try!(r.read_u8());
+
+        for p in self.obj.iter_mut() {
+            p.color = try!(r.read_u8()) & 3;
+            p.palette = try!(r.read_u8()) & 7;
+            p.background = try!(r.read_bool());
+            p.index = try!(r.read_u8());
+        }
+
+        let f = &mut self.fetcher;
+        f.step = match try!(r.read_u8()) {
+            0 => FetchStep::Tile,
+            1 => FetchStep::DataLow,
+            2 => FetchStep::DataHigh,
+            3 => FetchStep::Push,
+            _ => return savestate::invalid("pixel fetcher step"),
+        };
+        f.second_dot = try!(r.read_bool());
+        f.x = try!(r.read_u8());
+        f.window = try!(r.read_bool());
+        f.first = try!(r.read_bool());
+        f.tile = try!(r.read_u8());
+        f.attrs = try!(r.read_u8());
+        f.low = try!(r.read_u8());
+        f.high = try!(r.read_u8());
+
+        self.lx = try!(r.read_u8());
+        self.discard = try!(r.read_u8());
+        self.window_line = try!(r.read_u8());
+        self.wy_triggered = try!(r.read_bool());
+        self.window_active = try!(r.read_bool());
+        try!(r.read_bytes(&mut self.sprites));
+        self.sprite_count = try!(r.read_u8());
+        self.sprites_fetched = try!(r.read_u16());
+        self.penalized_tiles = try!(r.read_u32());
+        self.stall = try!(r.read_u8());
+
+        if self.bg_len > 8 || self.lx > 160 || self.discard > 7 {
+            return savestate::invalid("pixel FIFO position");
+        }
+
+        if self.sprite_count > 10 || self.sprites.iter().any(|&s| s >= 40) {
+            return savestate::invalid("pixel FIFO sprites");
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use gpu::{timings, Gpu, Mode, Renderer};
+
+    /// Return the number of dots spent in mode 3 on line 1
+    fn mode3_length(gpu: &mut Gpu) -> u16 {
+        while gpu.line() != 1 || gpu.mode() != Mode::Active {
+            gpu.step();
+        }
+
+        while gpu.mode() == Mode::Active {
+            gpu.step();
+        }
+
+        gpu.htick - timings::HACTIVE_ON
+    }
+
+    fn gpu() -> Gpu {
+        let mut gpu = Gpu::new(Box::new(::ui::dummy::DummyDisplay));
+
+        gpu.set_renderer(Renderer::Fifo);
+        // LCD and background on, sprites off
+        gpu.set_lcdc(0x91);
+
+        gpu
+    }
+
+    #[test]
+    fn mode3_timing() {
+        assert!(mode3_length(&mut gpu()) == 172);
+
+        // The fine scroll pixels are discarded
+        let mut g = gpu();
+        g.set_scx(5);
+        assert!(mode3_length(&mut g) == 177);
+
+        // Sprite aligned on the first tile
+        let mut g = gpu();
+        g.set_lcdc(0x93);
+        g.set_oam(0, 16);
+        g.set_oam(1, 8);
+        assert!(mode3_length(&mut g) == 183);
+
+        // Window starting on the left edge of the screen, the fetcher
+        // restarts with the window tiles
+        let mut g = gpu();
+        g.set_lcdc(0xb1);
+        g.set_wy(0);
+        g.set_wx(7);
+        assert!(mode3_length(&mut g) == 179);
+    }
+
+    /// SCX changes between lines are picked up at the next line with
+    /// both renderers
+    #[test]
+    fn renders_like_scanline() {
+        let mut scanline = Gpu::new(Box::new(::ui::dummy::DummyDisplay));
+        let mut fifo = gpu();
+
+        scanline.set_lcdc(0x91);
+
+        for g in [&mut scanline, &mut fifo].iter_mut() {
+            // Checkerboard tile in tile 0, alternating tiles in the map
+            for i in 0..16 {
+                g.set_vram(i, if i & 2 == 0 { 0x55 } else { 0xaa });
+                g.set_vram(0x10 + i, 0xff);
+            }
+            for i in 0..32 {
+                g.set_vram(0x1800 + i, (i & 1) as u8);
+            }
+            g.set_bgp(0xe4);
+            g.set_scx(3);
+
+            while g.line() != 1 {
+                g.step();
+            }
+
+            g.set_scx(6);
+
+            while g.line() != 3 {
+                g.step();
+            }
+        }
+
+        for l in 0..3 {
+            assert!(scanline.frame()[l][..] == fifo.frame()[l][..]);
+        }
+    }
+
+    /// BGP changes during mode 3 only show up with the FIFO renderer,
+    /// from the pixel popped on the dot of the write
+    #[test]
+    fn mid_line_palette_change() {
+        let mut scanline = Gpu::new(Box::new(::ui::dummy::DummyDisplay));
+        let mut fifo = gpu();
+
+        scanline.set_lcdc(0x91);
+
+        for g in [&mut scanline, &mut fifo].iter_mut() {
+            // Black tile 0 all over the map
+            for i in 0..16 {
+                g.set_vram(i, 0xff);
+            }
+            for i in 0..0x400 {
+                g.set_vram(0x1800 + i, 0);
+            }
+            g.set_bgp(0xe4);
+
+            while g.line() != 1 || g.mode() != Mode::Active {
+                g.step();
+            }
+
+            // Past the point where the scanline renderer draws the line
+            for _ in 0..60 {
+                g.step();
+            }
+
+            // Color 3 becomes white
+            g.set_bgp(0x24);
+
+            while g.line() != 2 {
+                g.step();
+            }
+        }
+
+        let black = scanline.frame()[1][0];
+        let white = fifo.frame()[1][159];
+
+        assert!(black != white);
+        assert!(scanline.frame()[1].iter().all(|&c| c == black));
+
+        // The first pixel only comes out on the 12th dot of mode 3
+        // once the first tile has been fetched, so 49 pixels were
+        // output before the write
+        for (x, &c) in fifo.frame()[1].iter().enumerate() {
+            assert!(c == if x < 49 { black } else { white });
+        }
+    }
+}
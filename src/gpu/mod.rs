@@ -1,14 +1,26 @@
 //! Game Boy GPU emulation
 
+use std::io::Result as IoResult;
+
+use gpu::cgb::{ColorPalettes, TileAttributes};
+use gpu::fifo::Fifo;
 use gpu::sprite::Sprite;
+use palette::DmgPalette;
+use savestate::{self, Snapshot, StateReader, StateWriter};
 use ui::Display;
 
+mod cgb;
+mod fifo;
 mod sprite;
 
 /// GPU state.
-pub struct Gpu<'a> {
+pub struct Gpu {
     /// Emulator Display
-    display: &'a mut (Display + 'a),
+    display: Box<Display>,
+    /// Copy of the pixels sent to the display for the current frame
+    frame: [[Color; 160]; 144],
+    /// Number of frames completed since power on
+    frame_count: u64,
     /// Current line. [0,143] is active video, [144,153] is blanking.
     line: u8,
     /// Counter for the horizontal period
@@ -17,8 +29,13 @@ pub struct Gpu<'a> {
     mode: Mode,
     /// Object attritube memory, 40 sprites long
     oam: [Sprite; 40],
-    /// Video Ram
-    vram: [u8; 0x2000],
+    /// Video Ram. The CGB has two 8KB banks, the DMG only uses the
+    /// first one.
+    vram: [u8; 0x4000],
+    /// VRAM bank currently mapped for the CPU
+    vram_bank: usize,
+    /// `true` if we're emulating a Game Boy Color
+    cgb: bool,
     /// `true` if the LCD is enabled.
     enabled: bool,
     /// Which tile map the window uses
@@ -41,6 +58,12 @@ pub struct Gpu<'a> {
     obp0: Palette,
     /// Object palette 1
     obp1: Palette,
+    /// CGB background and window color palettes
+    bg_palettes: ColorPalettes,
+    /// CGB sprite color palettes
+    obj_palettes: ColorPalettes,
+    /// Colors used to display the DMG shades
+    dmg_palette: DmgPalette,
     /// Line compare
     lyc: u8,
     /// VBlank interrupt status
@@ -69,6 +92,22 @@ pub struct Gpu<'a> {
     /// None. There can't be more than 10 sprites displayed on each
     /// line.
     line_cache: [[Option<u8>; 10]; 144],
+    /// Renderer used to generate the pixels
+    renderer: Renderer,
+    /// State of the pixel FIFO renderer
+    fifo: Fifo,
+}
+
+/// The two rendering engines available
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Renderer {
+    /// Render each line in one go at a fixed point of mode 3. Fast
+    /// but blind to mid-scanline register changes.
+    Scanline,
+    /// Generate pixels one dot at a time through the pixel FIFO (see
+    /// the `fifo` module). Slower but mode 3 has the right length and
+    /// mid-scanline effects are displayed.
+    Fifo,
 }
 
 /// Current GPU mode
@@ -114,16 +153,20 @@ enum LcdItStatus {
     Acked,
 }
 
-impl<'a> Gpu<'a> {
+impl Gpu {
     /// Create a new Gpu instance.
-    pub fn new<'n>(display: &'n mut Display) -> Gpu<'n> {
+    pub fn new(display: Box<Display>) -> Gpu {
         Gpu {
             line: 0,
             htick: 0,
             mode: Mode::Prelude,
             oam: [Sprite::new(); 40],
-            vram: [0xca; 0x2000],
+            vram: [0xca; 0x4000],
+            vram_bank: 0,
+            cgb: false,
             display: display,
+            frame: [[Shade::White.color(); 160]; 144],
+            frame_count: 0,
             enabled: false,
             window_tile_map: TileMap::Low,
             window_enabled: false,
@@ -135,6 +178,9 @@ impl<'a> Gpu<'a> {
             bgp: Palette::from_reg(0xff),
             obp0: Palette::from_reg(0xff),
             obp1: Palette::from_reg(0xff),
+            bg_palettes: ColorPalettes::new(),
+            obj_palettes: ColorPalettes::new(),
+            dmg_palette: DmgPalette::grey(),
             lyc: 0x00,
             it_vblank: false,
             iten_lyc: false,
@@ -147,9 +193,33 @@ impl<'a> Gpu<'a> {
             wx: 0,
             wy: 0,
             line_cache: [[None; 10]; 144],
+            renderer: Renderer::Scanline,
+            fifo: Fifo::new(),
         }
     }
 
+    /// Set the colors used to display the shades in DMG mode. Takes
+    /// effect at the next pixel drawn.
+    pub fn set_dmg_palette(&mut self, palette: DmgPalette) {
+        self.dmg_palette = palette;
+    }
+
+    /// Select the rendering engine. Takes effect at the next line.
+    pub fn set_renderer(&mut self, renderer: Renderer) {
+        self.renderer = renderer;
+    }
+
+    /// Return the rendering engine in use
+    pub fn renderer(&self) -> Renderer {
+        self.renderer
+    }
+
+    /// Switch to Game Boy Color mode
+    pub fn set_cgb(&mut self, cgb: bool) {
+        self.cgb = cgb;
+        self.rebuild_line_cache();
+    }
+
     /// Called at each tick of the system clock. Move the emulated
     /// state one step forward.
     pub fn step(&mut self) {
@@ -183,19 +253,36 @@ impl<'a> Gpu<'a> {
                         // done drawing the current frame
                         self.it_vblank = true;
                         self.display.flip();
+                        self.frame_count = self.frame_count.wrapping_add(1);
                         Mode::VBlank
                     } else {
                         Mode::Prelude
                     }
                 }
-                timings::HACTIVE_ON => Mode::Active,
-                timings::HSYNC_ON => Mode::HBlank,
+                timings::HACTIVE_ON => {
+                    if self.renderer == Renderer::Fifo {
+                        self.fifo_start_line();
+                    }
+                    Mode::Active
+                }
+                // With the pixel FIFO mode 3 ends when the last pixel
+                // is out
+                timings::HSYNC_ON if self.renderer == Renderer::Scanline => Mode::HBlank,
                 _ => cur_mode,
             }
         };
 
         self.mode = new_mode;
 
+        if self.renderer == Renderer::Fifo {
+            if self.mode == Mode::Active && self.fifo_dot() {
+                self.mode = Mode::HBlank;
+            }
+
+            self.update_ldc_interrupt();
+            return;
+        }
+
         // Compute at which cycle the first pixel will actually be
         // output on the screen. I don't know where this comes from
         // but it's what GearBoy seems to use. Using 48 for the first
@@ -223,6 +310,36 @@ impl<'a> Gpu<'a> {
         self.mode
     }
 
+    /// Return the pixels of the current frame. Once `frame_count`
+    /// has changed the frame is complete until the GPU starts
+    /// drawing the next one.
+    pub fn frame(&self) -> &[[Color; 160]; 144] {
+        &self.frame
+    }
+
+    /// Replace the pixels of the current frame, they're not part of
+    /// the save states. Call `redraw` to display them.
+    pub fn set_frame(&mut self, frame: &[[Color; 160]; 144]) {
+        self.frame = *frame;
+    }
+
+    /// Send the last frame to the display again. Used to refresh the
+    /// screen while the emulation is paused.
+    pub fn redraw(&mut self) {
+        for (y, line) in self.frame.iter().enumerate() {
+            for (x, &color) in line.iter().enumerate() {
+                self.display.set_pixel(x as u32, y as u32, color);
+            }
+        }
+
+        self.display.flip();
+    }
+
+    /// Return the number of frames completed since power on
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
     /// Handle reconfig through LCDC register
     pub fn set_lcdc(&mut self, lcdc: u8) {
         self.enabled = lcdc & 0x80 != 0;
@@ -405,14 +522,65 @@ impl<'a> Gpu<'a> {
         self.wx = wx
     }
 
-    /// Get byte from VRAM
+    /// Get byte from VRAM in the currently selected bank
     pub fn vram(&self, addr: u16) -> u8 {
-        self.vram[addr as usize]
+        self.vram[self.vram_bank * 0x2000 + addr as usize]
     }
 
-    /// Set byte in VRAM
+    /// Set byte in VRAM in the currently selected bank
     pub fn set_vram(&mut self, addr: u16, val: u8) {
-        self.vram[addr as usize] = val;
+        self.vram[self.vram_bank * 0x2000 + addr as usize] = val;
+    }
+
+    /// Return value of the VBK register
+    pub fn vbk(&self) -> u8 {
+        // Only bit 0 is used
+        0xfe | self.vram_bank as u8
+    }
+
+    /// Select the VRAM bank accessed by the CPU
+    pub fn set_vbk(&mut self, vbk: u8) {
+        self.vram_bank = (vbk & 1) as usize;
+    }
+
+    /// Return value of the BCPS register
+    pub fn bcps(&self) -> u8 {
+        self.bg_palettes.spec()
+    }
+
+    /// Handle reconfiguration of the BCPS register
+    pub fn set_bcps(&mut self, bcps: u8) {
+        self.bg_palettes.set_spec(bcps);
+    }
+
+    /// Return value of the BCPD register
+    pub fn bcpd(&self) -> u8 {
+        self.bg_palettes.data()
+    }
+
+    /// Handle write to the BCPD register
+    pub fn set_bcpd(&mut self, bcpd: u8) {
+        self.bg_palettes.set_data(bcpd);
+    }
+
+    /// Return value of the OCPS register
+    pub fn ocps(&self) -> u8 {
+        self.obj_palettes.spec()
+    }
+
+    /// Handle reconfiguration of the OCPS register
+    pub fn set_ocps(&mut self, ocps: u8) {
+        self.obj_palettes.set_spec(ocps);
+    }
+
+    /// Return value of the OCPD register
+    pub fn ocpd(&self) -> u8 {
+        self.obj_palettes.data()
+    }
+
+    /// Handle write to the OCPD register
+    pub fn set_ocpd(&mut self, ocpd: u8) {
+        self.obj_palettes.set_data(ocpd);
     }
 
     /// Get byte from OAM
@@ -605,32 +773,55 @@ impl<'a> Gpu<'a> {
         // screen resolution is 256x256). The tile map contains one u8
         // per tile which is the index of the tile to use in the tile
         // set.
-        let tile_index = self.tile_index(tile_map_x, tile_map_y, map);
+        let map_addr = Gpu::tile_map_addr(tile_map_x, tile_map_y, map);
+
+        let tile_index = self.vram[map_addr];
+
+        // On the CGB the attributes of each tile are stored in bank 1
+        // at the same address
+        let attrs = match self.cgb {
+            true => TileAttributes::from_reg(self.vram[0x2000 + map_addr]),
+            false => TileAttributes::from_reg(0),
+        };
+
+        let tile_x = match attrs.x_flip {
+            true => 7 - tile_x,
+            false => tile_x,
+        };
 
-        let tile_color = self.pix_color(tile_index, tile_x, tile_y, set);
+        let tile_y = match attrs.y_flip {
+            true => 7 - tile_y,
+            false => tile_y,
+        };
+
+        let shade = self.pix_color(tile_index, tile_x, tile_y, set, attrs.bank);
+
+        let color = match self.cgb {
+            true => self.bg_palettes.color(attrs.palette, shade),
+            false => self.dmg_palette.bg[self.bgp.transform(shade) as usize],
+        };
 
         AlphaColor {
-            // Transform tile_color through the palette
-            color: self.bgp.transform(tile_color),
+            color: color,
             // The pixel is transparent if the value pre-palette is white
-            opaque: tile_color != Color::White,
+            opaque: shade != Shade::White,
+            priority: attrs.priority,
         }
     }
 
-    /// Return the tile index for the tile at (`tx`, `ty`) in `map`
-    fn tile_index(&self, tx: u8, ty: u8, map: TileMap) -> u8 {
+    /// Return the VRAM offset of the tile at (`tx`, `ty`) in `map`
+    fn tile_map_addr(tx: u8, ty: u8, map: TileMap) -> usize {
         let base = map.base();
 
         let tx = tx as u16;
         let ty = ty as u16;
 
-        let map_addr = base + (ty * 32) + tx;
-
-        self.vram[map_addr as usize]
+        (base + (ty * 32) + tx) as usize
     }
 
-    /// Get the color of pixel (`x`, `y`) in `tile`.
-    fn pix_color(&self, tile: u8, x: u8, y: u8, set: TileSet) -> Color {
+    /// Get the shade of pixel (`x`, `y`) in `tile`, reading the tile
+    /// data from VRAM `bank`.
+    fn pix_color(&self, tile: u8, x: u8, y: u8, set: TileSet, bank: usize) -> Shade {
         if x >= 8 || y >= 16 {
             panic!("tile pos out of range ({}, {})", x, y);
         }
@@ -639,7 +830,7 @@ impl<'a> Gpu<'a> {
 
         let addr = base + 2 * (y as u16);
 
-        let addr = addr as usize;
+        let addr = bank * 0x2000 + addr as usize;
         let x = (7 - x) as usize;
 
         // Each row of 8 pixels is split across two contiguous bytes:
@@ -647,7 +838,7 @@ impl<'a> Gpu<'a> {
         let lsb = (self.vram[addr] >> x) & 1;
         let msb = (self.vram[addr + 1] >> x) & 1;
 
-        Color::from_u8(msb << 1 | lsb)
+        Shade::from_u8(msb << 1 | lsb)
     }
 
     /// Rebuild the entire Sprite cache for each line. This is pretty
@@ -701,10 +892,18 @@ impl<'a> Gpu<'a> {
 
                         // When sprites overlap the one with the
                         // smallest x pos is on top. If the x values
-                        // are equal then the offset in OAM is used.
-                        if sprite.x_pos() < other_sprite.x_pos()
-                            || (sprite.x_pos() == other_sprite.x_pos() && index < other)
-                        {
+                        // are equal then the offset in OAM is
+                        // used. On the CGB only the offset in OAM
+                        // matters.
+                        let higher_priority = match self.cgb {
+                            true => index < other,
+                            false => {
+                                sprite.x_pos() < other_sprite.x_pos()
+                                    || (sprite.x_pos() == other_sprite.x_pos() && index < other)
+                            }
+                        };
+
+                        if higher_priority {
                             // Our sprite is higher priority, move the
                             // rest of the cacheline one place. We
                             // know that the last item is None since
@@ -724,23 +923,30 @@ impl<'a> Gpu<'a> {
 
     /// Render a single pixel from the display
     fn render_pixel(&mut self, x: u8, y: u8) {
-        let bg_col =
+        let mut bg_col =
             // Window is always on top of background
             if self.window_enabled && self.in_window(x, y) {
                 self.window_color(x, y)
-            } else if self.bg_enabled {
+            } else if self.bg_enabled || self.cgb {
                 self.background_color(x, y)
             } else {
                 // No background or window
-                AlphaColor { color: Color::White, opaque: false }
+                AlphaColor { color: self.dmg_palette.bg[0], opaque: false, priority: false }
             };
 
+        if self.cgb && !self.bg_enabled {
+            // On the CGB LCDC bit 0 doesn't disable the background,
+            // instead it puts all sprites on top of it
+            bg_col.opaque = false;
+        }
+
         let col = if self.sprites_enabled {
             self.render_sprite(x, y, bg_col)
         } else {
             bg_col.color
         };
 
+        self.frame[y as usize][x as usize] = col;
         self.display.set_pixel(x as u32, y as u32, col);
     }
 
@@ -759,13 +965,17 @@ impl<'a> Gpu<'a> {
                     }
 
                     if sprite_x < 0 {
-                        // It's too early to draw that sprite. Since
-                        // sprites are in order on the line we know
-                        // there's no sprite remaining to be drawn
-                        break;
+                        // It's too early to draw that sprite. On the
+                        // DMG sprites are in order on the line so we
+                        // know there's no sprite remaining to be
+                        // drawn
+                        match self.cgb {
+                            true => continue,
+                            false => break,
+                        }
                     }
 
-                    if sprite.background() && bg_col.opaque {
+                    if (sprite.background() || bg_col.priority) && bg_col.opaque {
                         // Sprite is covered by the background
                         continue;
                     }
@@ -788,21 +998,35 @@ impl<'a> Gpu<'a> {
                         false => sprite_x,
                     };
 
+                    let bank = match self.cgb {
+                        true => sprite.vram_bank(),
+                        false => 0,
+                    };
+
                     // Sprites always use TileSet 1
-                    let pix = self.pix_color(tile, sprite_x as u8, sprite_y as u8, TileSet::Set1);
+                    let pix = self.pix_color(
+                        tile,
+                        sprite_x as u8,
+                        sprite_y as u8,
+                        TileSet::Set1,
+                        bank,
+                    );
 
                     // White color (0) pre-palette denotes a
                     // transparent pixel
-                    if pix != Color::White {
+                    if pix != Shade::White {
                         // Pixel is not transparent, compute the color
                         // and return that
+                        if self.cgb {
+                            return self.obj_palettes.color(sprite.color_palette(), pix);
+                        }
 
-                        let palette = match sprite.palette() {
-                            sprite::Palette::Obp0 => self.obp0,
-                            sprite::Palette::Obp1 => self.obp1,
+                        let (palette, colors) = match sprite.palette() {
+                            sprite::Palette::Obp0 => (self.obp0, &self.dmg_palette.obj0),
+                            sprite::Palette::Obp1 => (self.obp1, &self.dmg_palette.obj1),
                         };
 
-                        return palette.transform(pix);
+                        return colors[palette.transform(pix) as usize];
                     }
                 }
             }
@@ -812,34 +1036,214 @@ impl<'a> Gpu<'a> {
     }
 }
 
-/// All possible color values on the original game boy
-#[derive(Clone, Copy, PartialEq, Eq)]
-pub enum Color {
+impl Snapshot for Gpu {
+    fn snapshot(&self, w: &mut StateWriter) {
+        w.write_u64(self.frame_count);
+        w.write_u8(self.lcdc());
+        w.write_u8(self.stat());
+        w.write_u8(self.line);
+        w.write_u16(self.htick);
+        w.write_u8(self.mode as u8);
+
+        for i in 0..(self.oam.len() * 4) {
+            w.write_u8(self.oam(i as u16));
+        }
+
+        w.write_bytes(&self.vram);
+        w.write_u8(self.vram_bank as u8);
+        w.write_u8(self.bgp());
+        w.write_u8(self.obp0());
+        w.write_u8(self.obp1());
+        w.write_u8(self.bcps());
+        w.write_bytes(self.bg_palettes.ram());
+        w.write_u8(self.ocps());
+        w.write_bytes(self.obj_palettes.ram());
+        w.write_u8(self.lyc);
+        w.write_bool(self.it_vblank);
+        w.write_u8(match self.lcd_it_status {
+            LcdItStatus::Inactive => 0,
+            LcdItStatus::Triggered => 1,
+            LcdItStatus::Acked => 2,
+        });
+        w.write_u8(self.scy);
+        w.write_u8(self.scx);
+        w.write_u8(self.wx);
+        w.write_u8(self.wy);
+
+        for line in self.line_cache.iter() {
+            for entry in line.iter() {
+                // OAM indexes are always below 40, use 0xff for
+                // empty entries
+                w.write_u8(entry.unwrap_or(0xff));
+            }
+        }
+
+        self.fifo.snapshot(w);
+    }
+
+    fn restore(&mut self, r: &mut StateReader) -> IoResult<()> {
+        self.frame_count = try!(r.read_u64());
+        // The timing state is restored below, after `set_lcdc` and
+        // `set_stat` had a chance to mess with it.
+        let lcdc = try!(r.read_u8());
+        self.set_lcdc(lcdc);
+        let stat = try!(r.read_u8());
+        self.set_stat(stat);
+
+        self.line = try!(r.read_u8());
+        if self.line >= timings::VTOTAL {
+            return savestate::invalid("GPU line out of range");
+        }
+
+        self.htick = try!(r.read_u16());
+        if self.htick >= timings::HTOTAL {
+            return savestate::invalid("GPU htick out of range");
+        }
+
+        self.mode = match try!(r.read_u8()) {
+            0 => Mode::HBlank,
+            1 => Mode::VBlank,
+            2 => Mode::Prelude,
+            3 => Mode::Active,
+            _ => return savestate::invalid("GPU mode"),
+        };
+
+        for i in 0..self.oam.len() {
+            let sprite = self.sprite_mut(i);
+
+            sprite.set_y_pos(try!(r.read_u8()));
+            sprite.set_x_pos(try!(r.read_u8()));
+            sprite.set_tile(try!(r.read_u8()));
+            sprite.set_flags(try!(r.read_u8()));
+        }
+
+        try!(r.read_bytes(&mut self.vram));
+        self.vram_bank = match try!(r.read_u8()) {
+            b @ 0...1 => b as usize,
+            _ => return savestate::invalid("VRAM bank"),
+        };
+        self.set_bgp(try!(r.read_u8()));
+        self.set_obp0(try!(r.read_u8()));
+        self.set_obp1(try!(r.read_u8()));
+        self.set_bcps(try!(r.read_u8()));
+        try!(r.read_bytes(self.bg_palettes.ram_mut()));
+        self.set_ocps(try!(r.read_u8()));
+        try!(r.read_bytes(self.obj_palettes.ram_mut()));
+        self.lyc = try!(r.read_u8());
+        self.it_vblank = try!(r.read_bool());
+        self.lcd_it_status = match try!(r.read_u8()) {
+            0 => LcdItStatus::Inactive,
+            1 => LcdItStatus::Triggered,
+            2 => LcdItStatus::Acked,
+            _ => return savestate::invalid("LCD interrupt status"),
+        };
+        self.scy = try!(r.read_u8());
+        self.scx = try!(r.read_u8());
+        self.wx = try!(r.read_u8());
+        self.wy = try!(r.read_u8());
+
+        for y in 0..self.line_cache.len() {
+            for i in 0..self.line_cache[y].len() {
+                self.line_cache[y][i] = match try!(r.read_u8()) {
+                    0xff => None,
+                    n if (n as usize) < self.oam.len() => Some(n),
+                    _ => return savestate::invalid("sprite line cache"),
+                };
+            }
+        }
+
+        self.fifo.restore(r)
+    }
+}
+
+/// Color of a pixel sent to the display. The Game Boy Color uses 15
+/// bit RGB values, 5 bits per component. Red is in the low bits, like
+/// in the CGB palette RAM.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Color(u16);
+
+impl Color {
+    /// Build a color from a 15bit RGB value
+    pub fn from_rgb555(rgb: u16) -> Color {
+        Color(rgb & 0x7fff)
+    }
+
+    /// Build a color from its 5bit components
+    pub fn from_rgb(r: u8, g: u8, b: u8) -> Color {
+        let r = (r & 0x1f) as u16;
+        let g = (g & 0x1f) as u16;
+        let b = (b & 0x1f) as u16;
+
+        Color(r | (g << 5) | (b << 10))
+    }
+
+    /// Return the 15bit RGB value of the color
+    pub fn rgb555(self) -> u16 {
+        self.0
+    }
+
+    /// Build a color from 8bit components, dropping the low bits
+    pub fn from_rgb888(r: u8, g: u8, b: u8) -> Color {
+        Color::from_rgb(r >> 3, g >> 3, b >> 3)
+    }
+
+    /// Return the color as 8bit (red, green, blue) components
+    pub fn rgb888(self) -> (u8, u8, u8) {
+        // Replicate the high bits into the low ones so that full
+        // intensity maps to 0xff
+        let expand = |c: u16| {
+            let c = (c & 0x1f) as u8;
+
+            (c << 3) | (c >> 2)
+        };
+
+        (expand(self.0), expand(self.0 >> 5), expand(self.0 >> 10))
+    }
+}
+
+/// The four shades of the original game boy. On the CGB they're used
+/// as an index into the color palettes.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Shade {
+    /// Lightest shade
     White = 0,
+    /// Light grey shade
     LightGrey = 1,
+    /// Dark grey shade
     DarkGrey = 2,
+    /// Darkest shade
     Black = 3,
 }
 
-impl Color {
-    /// Create a color from a u8 in the range 0...3
-    fn from_u8(c: u8) -> Color {
+impl Shade {
+    /// Create a shade from a u8 in the range 0...3
+    fn from_u8(c: u8) -> Shade {
         match c {
-            0 => Color::White,
-            1 => Color::LightGrey,
-            2 => Color::DarkGrey,
-            3 => Color::Black,
+            0 => Shade::White,
+            1 => Shade::LightGrey,
+            2 => Shade::DarkGrey,
+            3 => Shade::Black,
             _ => panic!("Invalid color: 0x{:02x}", c),
         }
     }
+
+    /// Return the color displayed for this shade on the DMG
+    pub fn color(self) -> Color {
+        match self {
+            Shade::White => Color::from_rgb(31, 31, 31),
+            Shade::LightGrey => Color::from_rgb(21, 21, 21),
+            Shade::DarkGrey => Color::from_rgb(10, 10, 10),
+            Shade::Black => Color::from_rgb(0, 0, 0),
+        }
+    }
 }
 
 /// Palette description
 #[derive(Clone, Copy)]
 struct Palette {
-    /// Each color can be mapped to an other one independently of the
+    /// Each shade can be mapped to an other one independently of the
     /// others
-    map: [Color; 4],
+    map: [Shade; 4],
 }
 
 impl Palette {
@@ -850,11 +1254,11 @@ impl Palette {
     /// 0b00_01_10_11 is a palette that reverses the colors.
     fn from_reg(r: u8) -> Palette {
         let mut p = Palette {
-            map: [Color::White, Color::White, Color::White, Color::White],
+            map: [Shade::White, Shade::White, Shade::White, Shade::White],
         };
 
         for i in 0..p.map.len() {
-            p.map[i] = Color::from_u8((r >> (i * 2)) & 0x3)
+            p.map[i] = Shade::from_u8((r >> (i * 2)) & 0x3)
         }
 
         p
@@ -871,8 +1275,8 @@ impl Palette {
         p
     }
 
-    /// Transform color `c` through the palette
-    fn transform(&self, c: Color) -> Color {
+    /// Transform shade `c` through the palette
+    fn transform(&self, c: Shade) -> Shade {
         self.map[c as usize]
     }
 }
@@ -884,6 +1288,9 @@ struct AlphaColor {
     /// If `true` the color is fully opaque, otherwise fully
     /// transparent.
     opaque: bool,
+    /// If `true` the pixel is displayed on top of sprites when it's
+    /// opaque (CGB tile attribute)
+    priority: bool,
 }
 
 /// There are two tile maps available on the GameBoy. Each map is
@@ -982,22 +1389,30 @@ mod tests {
         }
     }
 
-    /// Make sure color conversion to and from symbolic values works
+    /// Make sure shade conversion to and from symbolic values works
     #[test]
-    fn color_conversion() {
+    fn shade_conversion() {
         for v in 0..4 {
-            let c = super::Color::from_u8(v);
+            let c = super::Shade::from_u8(v);
 
             assert!(c as u8 == v);
         }
     }
 
+    #[test]
+    fn color_conversion() {
+        let c = super::Color::from_rgb(0x1f, 0x10, 0);
+
+        assert!(c.rgb555() == 0x021f);
+        assert!(c.rgb888() == (0xff, 0x84, 0x00));
+        assert!(super::Shade::White.color().rgb888() == (0xff, 0xff, 0xff));
+    }
+
     /// Test that the GPU state remains the same after a fixed number
     /// of steps. The point is to help spot regressions.
     #[test]
     fn gpu_step() {
-        let mut d = ::ui::dummy::DummyDisplay;
-        let mut gpu = super::Gpu::new(&mut d);
+        let mut gpu = super::Gpu::new(Box::new(::ui::dummy::DummyDisplay));
 
         for _ in 0..1000 {
             gpu.step();
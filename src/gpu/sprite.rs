@@ -18,6 +18,10 @@ pub struct Sprite {
     y_flip: bool,
     /// Which palette the sprite uses
     palette: Palette,
+    /// VRAM bank containing the tile (CGB only)
+    vram_bank: usize,
+    /// Which color palette the sprite uses (CGB only)
+    color_palette: u8,
 }
 
 impl Sprite {
@@ -30,6 +34,8 @@ impl Sprite {
             x_flip: false,
             y_flip: false,
             palette: Palette::Obp0,
+            vram_bank: 0,
+            color_palette: 0,
         }
     }
 
@@ -83,6 +89,14 @@ impl Sprite {
         self.palette
     }
 
+    pub fn vram_bank(&self) -> usize {
+        self.vram_bank
+    }
+
+    pub fn color_palette(&self) -> u8 {
+        self.color_palette
+    }
+
     pub fn x_flip(&self) -> bool {
         self.x_flip
     }
@@ -100,7 +114,9 @@ impl Sprite {
         self.palette = match flags & 0x10 != 0 {
             false => Palette::Obp0,
             true => Palette::Obp1,
-        }
+        };
+        self.vram_bank = ((flags >> 3) & 1) as usize;
+        self.color_palette = flags & 7;
     }
 
     /// Reconstruct flags register value from Sprite state
@@ -116,6 +132,9 @@ impl Sprite {
             Palette::Obp1 => 1,
         } << 4;
 
+        r |= (self.vram_bank as u8) << 3;
+        r |= self.color_palette;
+
         r
     }
 }
@@ -1,6 +1,11 @@
 //! The GameBoy ROM that gets mapped at address 0 at startup. It's not
 //! accessible during normal game execution.
 
+/// Offset of the operand of the `LD A 1` executed right before the
+/// bootrom unmaps itself and jumps into the cartridge
+#[cfg(not(feature = "sgb_bootrom"))]
+pub const HANDOFF_A: u16 = 0xfd;
+
 /// Original GameBoy bootrom. It scrolls the NINTENDO logo down the
 /// screen and emits the signature two notes when it reaches the
 /// middle. It also checks that the cartridge ROM header is correct
@@ -167,6 +172,11 @@ pub static BOOTROM: [u8; 0x100] = [
     0xE0, 0x50, // LD [0xff00 + 0x50] A
 ];
 
+/// Offset of the operand of the `LD A 1` executed right before the
+/// bootrom unmaps itself and jumps into the cartridge
+#[cfg(feature = "sgb_bootrom")]
+pub const HANDOFF_A: u16 = 0xfd;
+
 /// Super GameBoy bootrom, lifted from
 /// http://www.its.caltech.edu/~costis/sgb_hack/. It doesn't scroll
 /// the logo down the screen so it's faster than the original GB.
@@ -348,3 +358,66 @@ pub static BOOTROM: [u8; 0x100] = [
     0x3E, 0x01, // LD     A 1
     0xE0, 0x50, // LD     [0xff00 + 0x50] A
 ];
+
+#[cfg(test)]
+mod tests {
+    use super::{BOOTROM, HANDOFF_A};
+    use cartridge::Cartridge;
+    use gameboy::{CpuCore, GameBoy};
+
+    /// Nintendo logo the bootroms check in the cartridge header
+    const LOGO: [u8; 0x30] = [
+        0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+        0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+        0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+    ];
+
+    /// Run the bootrom with a valid cartridge header, in CGB mode if
+    /// `cgb` is true, and return the value of A when it jumps into
+    /// the cartridge
+    fn handoff_a(cgb: bool) -> u8 {
+        let mut rom = vec![0; 0x8000];
+
+        rom[0x104..0x134].copy_from_slice(&LOGO);
+
+        if cgb {
+            rom[0x143] = 0x80;
+        }
+
+        rom[0x14d] = rom[0x134..0x14d].iter().fold(0u8, |sum, &b| sum.wrapping_sub(b).wrapping_sub(1));
+
+        let mut gb = GameBoy::headless(Cartridge::from_vec(rom), CpuCore::Gamedorp);
+
+        // The DMG bootrom takes a bit more than 2 seconds
+        for _ in 0..0x1000000 {
+            if gb.registers().pc == 0x100 {
+                return (gb.registers().af >> 8) as u8;
+            }
+
+            gb.step_instruction();
+        }
+
+        panic!("The bootrom never reached the cartridge");
+    }
+
+    fn check_handoff() {
+        // LD A, 1; LDH [0xff50], A
+        let at = HANDOFF_A as usize;
+        assert!(BOOTROM[at - 1..at + 3] == [0x3e, 0x01, 0xe0, 0x50]);
+
+        assert!(handoff_a(false) == 0x01);
+        assert!(handoff_a(true) == 0x11);
+    }
+
+    #[test]
+    #[cfg(not(feature = "sgb_bootrom"))]
+    fn dmg_handoff() {
+        check_handoff();
+    }
+
+    #[test]
+    #[cfg(feature = "sgb_bootrom")]
+    fn sgb_handoff() {
+        check_handoff();
+    }
+}
@@ -6,38 +6,44 @@
 //! one of those two lines with a pin of the gameboy and sets one bit
 //! in the INPUT register (if the line is selected).
 
-use std::cell::Cell;
+use std::io::Result as IoResult;
 
-pub struct Buttons<'a> {
+use savestate::{Snapshot, StateReader, StateWriter};
+
+pub struct Buttons {
     /// `true` if the "directions" line is active
     directions_selected: bool,
     /// `true` if the "buttons" line is active
     /// Controller interface
     buttons_selected: bool,
-    /// Abstract interface to the actual UI
-    buttons: &'a Cell<::ui::Buttons>,
-    last_input: u8,
-    interrupt_sent:bool
+    /// Last button state reported by the UI
+    buttons: ::ui::Buttons,
+    /// State of the P10-P13 input lines (low nibble of the INPUT
+    /// register) at the last update, used to detect transitions
+    lines: u8,
+    /// Interrupt flag, set when one of the input lines goes low
+    interrupt: bool,
 }
 
-impl<'a> Buttons<'a> {
-    pub fn new<'n>(buttons: &'n Cell<::ui::Buttons>) -> Buttons<'n> {
+impl Buttons {
+    pub fn new() -> Buttons {
         Buttons {
             directions_selected: false,
             buttons_selected: false,
-            buttons: buttons,
-            last_input: 0,
-            interrupt_sent: true
+            buttons: ::ui::Buttons::new(::ui::ButtonState::Up),
+            lines: 0xf,
+            interrupt: false,
         }
     }
 
     /// Return the value of the INPUT register. Lines are to 1 when
     /// inactive.
     pub fn input(&self) -> u8 {
-        let buttons = self.buttons.get();
+        let buttons = self.buttons;
 
         // For simplicity we'll mark the active lines with 1 and
-        // invert the value at the end
+        // invert the value at the end. When both lines are selected
+        // the buttons of both groups pull the input lines down.
         let mut active = 0;
 
         if self.directions_selected {
@@ -61,16 +67,117 @@ impl<'a> Buttons<'a> {
         !active
     }
 
+    /// Return `true` if one of the input lines is low, i.e. a button
+    /// of a selected group is pressed
+    pub fn pressed(&self) -> bool {
+        self.input() & 0xf != 0xf
+    }
+
     pub fn interrupt(&self) -> bool {
-        self.interrupt_sent || self.input() != self.last_input
+        self.interrupt
     }
 
     pub fn ack_interrupt(&mut self) {
-        self.interrupt_sent = false;
+        self.interrupt = false;
+    }
+
+    /// Force the interrupt flag, used when IF is written
+    pub fn force_interrupt(&mut self, set: bool) {
+        self.interrupt = set;
+    }
+
+    /// Update the button state reported by the UI
+    pub fn set_state(&mut self, buttons: ::ui::Buttons) {
+        self.buttons = buttons;
+        self.update_lines();
     }
+
     pub fn set_input(&mut self, val: u8) {
         // We select the lines by setting the bit to 0
         self.directions_selected = val & 0x10 == 0;
         self.buttons_selected = val & 0x20 == 0;
+        self.update_lines();
+    }
+
+    /// Raise the interrupt if any of the input lines went from high
+    /// to low
+    fn update_lines(&mut self) {
+        let lines = self.input() & 0xf;
+
+        if self.lines & !lines != 0 {
+            self.interrupt = true;
+        }
+
+        self.lines = lines;
+    }
+}
+
+impl Snapshot for Buttons {
+    /// The button state itself isn't saved: it comes from the UI and
+    /// will be refreshed at the next update.
+    fn snapshot(&self, w: &mut StateWriter) {
+        w.write_bool(self.directions_selected);
+        w.write_bool(self.buttons_selected);
+        w.write_u8(self.lines);
+        w.write_bool(self.interrupt);
+    }
+
+    fn restore(&mut self, r: &mut StateReader) -> IoResult<()> {
+        self.directions_selected = try!(r.read_bool());
+        self.buttons_selected = try!(r.read_bool());
+        self.lines = try!(r.read_u8()) & 0xf;
+        self.interrupt = try!(r.read_bool());
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Buttons;
+    use ui::{ButtonState, Buttons as UiButtons};
+
+    #[test]
+    fn matrix_and_interrupt() {
+        let mut b = Buttons::new();
+        let mut state = UiButtons::new(ButtonState::Up);
+
+        state.right = ButtonState::Down;
+        state.start = ButtonState::Down;
+        b.set_state(state);
+
+        // No line selected, nothing can be pressed
+        assert!(b.input() == 0xff);
+        assert!(!b.interrupt());
+
+        // Selecting a line with a button held pulls the input low
+        b.set_input(0x20);
+        assert!(b.input() == 0xee);
+        assert!(b.interrupt());
+        b.ack_interrupt();
+
+        // Both lines selected: the two groups are combined
+        b.set_input(0x00);
+        assert!(b.input() == 0xc6);
+        assert!(b.interrupt());
+        b.ack_interrupt();
+
+        // Releasing a button doesn't raise the interrupt
+        state.start = ButtonState::Up;
+        b.set_state(state);
+        assert!(b.input() == 0xce);
+        assert!(!b.interrupt());
+
+        // Pressing a button of an unselected group doesn't either
+        b.set_input(0x10);
+        b.ack_interrupt();
+        state.left = ButtonState::Down;
+        b.set_state(state);
+        assert!(!b.interrupt());
+
+        state.a = ButtonState::Down;
+        b.set_state(state);
+        assert!(b.input() == 0xde);
+        assert!(b.interrupt());
     }
 }
@@ -0,0 +1,269 @@
+//! OAM DMA.
+//!
+//! Writing to the DMA register starts copying 160 bytes from
+//! `source << 8` into OAM. The transfer starts one machine cycle
+//! after the write and copies one byte per machine cycle. While it
+//! runs the DMA controller owns the bus it reads from: the CPU reads
+//! the byte being transferred instead of what's actually at the
+//! address and its writes are lost. OAM can't be accessed at all. The
+//! I/O registers and HRAM are always reachable, which is why games
+//! run their DMA routine from HRAM. The actual copy is done by the
+//! interconnect since it needs access to the whole memory map.
+
+use std::io::Result as IoResult;
+
+use savestate::{self, Snapshot, StateReader, StateWriter};
+
+/// Number of bytes copied by a transfer
+const LENGTH: u8 = 160;
+
+/// Number of clock ticks between the write to the DMA register and
+/// the first byte copied: the rest of the machine cycle of the write
+/// plus one machine cycle of setup
+const START_DELAY: u8 = 8;
+
+/// Number of clock ticks per byte copied
+const TICKS_PER_BYTE: u8 = 4;
+
+/// OAM DMA controller state
+pub struct OamDma {
+    /// Last value written to the DMA register
+    source: u8,
+    /// Clock ticks left before the requested transfer starts, 0 if
+    /// none is pending. A pending transfer doesn't interrupt the
+    /// running one until it actually starts.
+    starting: u8,
+    /// `true` while a transfer is in progress
+    running: bool,
+    /// Source page of the running transfer
+    page: u8,
+    /// Index of the last byte copied
+    index: u8,
+    /// Clock ticks left before the next byte is copied
+    ticks: u8,
+    /// Last byte copied. The CPU reads it when it accesses the bus
+    /// used by the transfer.
+    last: u8,
+}
+
+/// The memory buses the DMA can read from
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Bus {
+    /// Cartridge ROM and RAM, plus the internal RAM on the DMG
+    External,
+    /// Internal RAM (CGB only, the DMG puts it on the external bus)
+    Wram,
+    /// VRAM
+    Video,
+}
+
+impl Bus {
+    /// Return the bus `addr` is on. Must not be called for addresses
+    /// in [0xfe00, 0xffff].
+    fn from_addr(addr: u16, cgb: bool) -> Bus {
+        match addr {
+            0x8000...0x9fff => Bus::Video,
+            0xc000...0xfdff if cgb => Bus::Wram,
+            _ => Bus::External,
+        }
+    }
+}
+
+impl OamDma {
+    /// Create a new idle controller
+    pub fn new() -> OamDma {
+        OamDma {
+            source: 0,
+            starting: 0,
+            running: false,
+            page: 0,
+            index: 0,
+            ticks: 0,
+            last: 0xff,
+        }
+    }
+
+    /// Return the value of the DMA register
+    pub fn source(&self) -> u8 {
+        self.source
+    }
+
+    /// Handle write to the DMA register: request a new transfer
+    pub fn start(&mut self, source: u8) {
+        self.source = source;
+        self.starting = START_DELAY;
+    }
+
+    /// Called at each tick of the CPU clock. Returns the source
+    /// address and OAM offset of the byte to copy now, if any.
+    pub fn step(&mut self) -> Option<(u16, u16)> {
+        if self.starting > 0 {
+            self.starting -= 1;
+
+            if self.starting == 0 {
+                // The new transfer replaces the current one, if any
+                self.running = true;
+                self.page = self.source;
+                self.index = 0;
+                self.ticks = TICKS_PER_BYTE;
+
+                return Some((self.source_addr(), 0));
+            }
+        }
+
+        if !self.running {
+            return None;
+        }
+
+        self.ticks -= 1;
+
+        if self.ticks > 0 {
+            return None;
+        }
+
+        if self.index == LENGTH - 1 {
+            self.running = false;
+            return None;
+        }
+
+        self.index += 1;
+        self.ticks = TICKS_PER_BYTE;
+
+        Some((self.source_addr(), self.index as u16))
+    }
+
+    /// Record the byte just copied, it's what the CPU sees on the
+    /// source bus
+    pub fn set_last(&mut self, b: u8) {
+        self.last = b;
+    }
+
+    /// Return the address of the current source byte. Sources above
+    /// 0xdfff read from the internal RAM echo.
+    fn source_addr(&self) -> u16 {
+        let addr = ((self.page as u16) << 8) | self.index as u16;
+
+        match addr {
+            0xe000...0xffff => addr - 0x2000,
+            _ => addr,
+        }
+    }
+
+    /// Check if a CPU access to `addr` conflicts with the running
+    /// transfer. If it does return the value read by the CPU, the
+    /// write is dropped.
+    pub fn conflict(&self, addr: u16, cgb: bool) -> Option<u8> {
+        if !self.running {
+            return None;
+        }
+
+        match addr {
+            // OAM and the unusable area after it
+            0xfe00...0xfeff => Some(0xff),
+            // I/O and HRAM
+            0xff00...0xffff => None,
+            _ => {
+                let dma_bus = Bus::from_addr(self.source_addr(), cgb);
+
+                match Bus::from_addr(addr, cgb) == dma_bus {
+                    true => Some(self.last),
+                    false => None,
+                }
+            }
+        }
+    }
+}
+
+impl Snapshot for OamDma {
+    fn snapshot(&self, w: &mut StateWriter) {
+        w.write_u8(self.source);
+        w.write_u8(self.starting);
+        w.write_bool(self.running);
+        w.write_u8(self.page);
+        w.write_u8(self.index);
+        w.write_u8(self.ticks);
+        w.write_u8(self.last);
+    }
+
+    fn restore(&mut self, r: &mut StateReader) -> IoResult<()> {
+        self.source = try!(r.read_u8());
+        self.starting = try!(r.read_u8());
+        self.running = try!(r.read_bool());
+        self.page = try!(r.read_u8());
+        self.index = try!(r.read_u8());
+        self.ticks = try!(r.read_u8());
+        self.last = try!(r.read_u8());
+
+        if self.starting > START_DELAY {
+            return savestate::invalid("OAM DMA start delay");
+        }
+
+        if self.running && (self.index >= LENGTH || self.ticks == 0 || self.ticks > TICKS_PER_BYTE) {
+            return savestate::invalid("OAM DMA position");
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::OamDma;
+
+    #[test]
+    fn transfer_timing() {
+        let mut dma = OamDma::new();
+
+        dma.start(0xc1);
+        assert!(dma.source() == 0xc1);
+
+        // Start delay
+        for _ in 0..7 {
+            assert!(dma.step().is_none());
+            assert!(!dma.running);
+        }
+
+        for i in 0..160 {
+            assert!(dma.step() == Some((0xc100 + i, i)));
+            assert!(dma.running);
+
+            for _ in 0..3 {
+                assert!(dma.step().is_none());
+            }
+        }
+
+        assert!(dma.step().is_none());
+        assert!(!dma.running);
+    }
+
+    #[test]
+    fn bus_conflicts() {
+        let mut dma = OamDma::new();
+
+        dma.start(0x40);
+
+        while !dma.running {
+            dma.step();
+        }
+
+        dma.set_last(0x12);
+
+        // ROM, cartridge RAM and internal RAM share the external bus
+        assert!(dma.conflict(0x0150, false) == Some(0x12));
+        assert!(dma.conflict(0xa000, false) == Some(0x12));
+        assert!(dma.conflict(0xc000, false) == Some(0x12));
+        // The CGB has a separate bus for the internal RAM
+        assert!(dma.conflict(0xc000, true) == None);
+        assert!(dma.conflict(0x8000, false) == None);
+        assert!(dma.conflict(0xfe00, false) == Some(0xff));
+        assert!(dma.conflict(0xff80, false) == None);
+        assert!(dma.conflict(0xff46, false) == None);
+
+        // High sources read from the internal RAM echo
+        let mut dma = OamDma::new();
+
+        dma.start(0xfe);
+
+        assert!((0..8).filter_map(|_| dma.step()).next() == Some((0xde00, 0)));
+    }
+}
@@ -0,0 +1,210 @@
+//! Game Boy Color VRAM DMA.
+//!
+//! Copies blocks of 16 bytes into VRAM, either all at once (general
+//! purpose DMA) or one block at the beginning of each horizontal
+//! blanking period (HBlank DMA). The actual copy is done by the
+//! interconnect since it needs access to the whole memory map.
+
+use std::io::Result as IoResult;
+
+use savestate::{self, Snapshot, StateReader, StateWriter};
+
+/// VRAM DMA controller state
+pub struct Hdma {
+    /// Source address (HDMA1/HDMA2). The low 4 bits are ignored.
+    source: u16,
+    /// Destination offset in VRAM (HDMA3/HDMA4). The low 4 bits are
+    /// ignored.
+    destination: u16,
+    /// Number of 16 byte blocks left to copy
+    blocks: u8,
+    /// Current transfer mode
+    mode: Mode,
+    /// `true` if the GPU was in HBlank the last time we checked
+    in_hblank: bool,
+}
+
+/// Transfer mode
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// No transfer in progress
+    Idle,
+    /// General purpose DMA, copies everything at once
+    General,
+    /// Copies one block per HBlank
+    HBlank,
+}
+
+impl Hdma {
+    /// Create a new idle controller
+    pub fn new() -> Hdma {
+        Hdma {
+            source: 0,
+            destination: 0,
+            blocks: 0,
+            mode: Mode::Idle,
+            in_hblank: false,
+        }
+    }
+
+    /// Handle write to HDMA1
+    pub fn set_source_high(&mut self, val: u8) {
+        self.source = (self.source & 0x00ff) | ((val as u16) << 8);
+    }
+
+    /// Handle write to HDMA2
+    pub fn set_source_low(&mut self, val: u8) {
+        self.source = (self.source & 0xff00) | ((val & 0xf0) as u16);
+    }
+
+    /// Handle write to HDMA3. The destination is always in VRAM so
+    /// the top 3 bits are ignored.
+    pub fn set_destination_high(&mut self, val: u8) {
+        self.destination = (self.destination & 0x00ff) | (((val & 0x1f) as u16) << 8);
+    }
+
+    /// Handle write to HDMA4
+    pub fn set_destination_low(&mut self, val: u8) {
+        self.destination = (self.destination & 0xff00) | ((val & 0xf0) as u16);
+    }
+
+    /// Return the value of HDMA5: bit 7 is cleared while an HBlank
+    /// transfer is in progress and the low bits contain the number
+    /// of blocks left minus one. Reads 0xff once the transfer is
+    /// complete.
+    pub fn control(&self) -> u8 {
+        let remaining = self.blocks.wrapping_sub(1) & 0x7f;
+
+        match self.mode {
+            Mode::HBlank => remaining,
+            _ => 0x80 | remaining,
+        }
+    }
+
+    /// Handle write to HDMA5: start a new transfer, or cancel the
+    /// current HBlank transfer
+    pub fn set_control(&mut self, val: u8) {
+        let hblank = val & 0x80 != 0;
+
+        if self.mode == Mode::HBlank && !hblank {
+            self.mode = Mode::Idle;
+            return;
+        }
+
+        self.blocks = (val & 0x7f) + 1;
+        self.mode = match hblank {
+            true => Mode::HBlank,
+            false => Mode::General,
+        };
+    }
+
+    /// Return `true` if a general purpose transfer has blocks left to
+    /// copy
+    pub fn general_pending(&self) -> bool {
+        self.mode == Mode::General
+    }
+
+    /// Called at each GPU step with `true` if the GPU is in
+    /// HBlank. Returns `true` if a block should be copied now.
+    pub fn hblank_edge(&mut self, hblank: bool) -> bool {
+        let edge = hblank && !self.in_hblank;
+
+        self.in_hblank = hblank;
+
+        edge && self.mode == Mode::HBlank
+    }
+
+    /// Return the source address and VRAM destination offset of the
+    /// next block to copy and move on to the following one
+    pub fn next_block(&mut self) -> (u16, u16) {
+        let block = (self.source, self.destination);
+
+        self.source = self.source.wrapping_add(0x10);
+        self.destination = (self.destination + 0x10) & 0x1ff0;
+        self.blocks -= 1;
+
+        if self.blocks == 0 {
+            self.mode = Mode::Idle;
+        }
+
+        block
+    }
+}
+
+impl Snapshot for Hdma {
+    fn snapshot(&self, w: &mut StateWriter) {
+        w.write_u16(self.source);
+        w.write_u16(self.destination);
+        w.write_u8(self.blocks);
+        w.write_u8(match self.mode {
+            Mode::Idle => 0,
+            Mode::General => 1,
+            Mode::HBlank => 2,
+        });
+        w.write_bool(self.in_hblank);
+    }
+
+    fn restore(&mut self, r: &mut StateReader) -> IoResult<()> {
+        self.source = try!(r.read_u16()) & 0xfff0;
+        self.destination = try!(r.read_u16()) & 0x1ff0;
+        self.blocks = try!(r.read_u8());
+        self.mode = match try!(r.read_u8()) {
+            0 => Mode::Idle,
+            1 => Mode::General,
+            2 => Mode::HBlank,
+            _ => return savestate::invalid("HDMA mode"),
+        };
+        self.in_hblank = try!(r.read_bool());
+
+        if self.mode != Mode::Idle && (self.blocks == 0 || self.blocks > 0x80) {
+            return savestate::invalid("HDMA length");
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Hdma;
+
+    #[test]
+    fn hblank_transfer() {
+        let mut hdma = Hdma::new();
+
+        hdma.set_source_high(0xc1);
+        hdma.set_source_low(0x2f);
+        hdma.set_destination_high(0xff);
+        hdma.set_destination_low(0xf0);
+
+        // Two blocks in HBlank mode
+        hdma.set_control(0x81);
+        assert!(!hdma.general_pending());
+        assert!(hdma.control() == 0x01);
+
+        assert!(!hdma.hblank_edge(false));
+        assert!(hdma.hblank_edge(true));
+        assert!(!hdma.hblank_edge(true));
+        assert!(hdma.next_block() == (0xc120, 0x1ff0));
+        assert!(hdma.control() == 0x00);
+
+        assert!(!hdma.hblank_edge(false));
+        assert!(hdma.hblank_edge(true));
+        // Destination wraps around within VRAM
+        assert!(hdma.next_block() == (0xc130, 0x0000));
+        assert!(hdma.control() == 0xff);
+        assert!(!hdma.hblank_edge(false));
+        assert!(!hdma.hblank_edge(true));
+    }
+
+    #[test]
+    fn cancel() {
+        let mut hdma = Hdma::new();
+
+        hdma.set_control(0x83);
+        hdma.set_control(0x00);
+
+        assert!(!hdma.general_pending());
+        assert!(hdma.control() == 0x83);
+    }
+}
@@ -1,104 +1,347 @@
 //! Input/Output abstraction for memory, ROM and I/O mapped registers
 
-use gpu::Gpu;
+use std::io::Result as IoResult;
+
+use gpu::{self, Gpu};
+use savestate::{self, Snapshot, StateReader, StateWriter};
 use spu::Spu;
 
 use cartridge::Cartridge;
-use std::cell::Cell;
 
 use self::io_map::{NR3_RAM_END, NR3_RAM_START};
 
 pub mod buttons;
+pub mod dma;
+pub mod hdma;
 pub mod ram;
+pub mod serial;
 pub mod timer;
+pub mod vgm;
+pub mod watch;
 
 mod bootrom;
 
 /// Interconnect struct used by the CPU and GPU to access the ROM, RAM
 /// and registers
-pub struct Interconnect<'a> {
+pub struct Interconnect {
     /// Cartridge interface
     cartridge: Cartridge,
-    /// internal RAM
+    /// internal RAM. The CGB has 8 4KB banks, the DMG only uses the
+    /// first two.
     iram: ram::Ram,
+    /// Internal RAM bank mapped at [0xd000, 0xdfff] (CGB only)
+    iram_bank: u8,
     /// 0-page RAM
     zpage: ram::Ram,
     /// Timer instance
     pub timer: timer::Timer,
+    /// Serial link controller
+    serial: serial::Serial,
     /// GPU instance
-    gpu: Gpu<'a>,
+    gpu: Gpu,
     /// SPU instance
     spu: Spu,
     /// Enabled interrupts
     it_enabled: Interrupts,
-    /// Current DMA source address
-    dma_src: u16,
-    /// Current DMA index in OAM
-    dma_idx: u16,
+    /// OAM DMA
+    dma: dma::OamDma,
     /// Controller interface
-    buttons: buttons::Buttons<'a>,
+    buttons: buttons::Buttons,
+    /// `true` if we're emulating a Game Boy Color
+    cgb: bool,
+    /// `true` if the CGB is running in double speed mode
+    double_speed: bool,
+    /// `true` if a speed switch will occur on the next STOP
+    speed_switch_armed: bool,
+    /// `true` if the CPU executed STOP, which stops the system clock
+    /// until a button is pressed
+    stopped: bool,
+    /// In double speed mode the rest of the hardware only runs every
+    /// other tick. `true` if the next tick is skipped.
+    odd_tick: bool,
+    /// CGB VRAM DMA
+    hdma: hdma::Hdma,
+    /// Memory watchpoints set by the debugger
+    watchpoints: watch::Watchpoints,
+    /// VGM logger tapping the writes to the sound registers
+    vgm: vgm::VgmLogger,
+    /// Last interrupt acknowledged by the CPU, for the debugger
+    last_interrupt: Option<Interrupt>,
     /// The game boy starts up mapping the bootrom at address [0,
     /// 0xff]. The last thing the bootrom does is writing 0x01 to
     /// UNMAP_BOOTROM to remove itself from the memory map.
     pub bootrom: bool,
 }
 
-impl<'a> Interconnect<'a> {
+impl Interconnect {
     /// Create a new Interconnect
-    pub fn new<'n>(
-        cartridge: Cartridge,
-        gpu: Gpu<'n>,
-        spu: Spu,
-        buttons: &'n Cell<::ui::Buttons>,
-    ) -> Interconnect<'n> {
-        let iram = ram::Ram::new(0x2000);
+    pub fn new(cartridge: Cartridge, mut gpu: Gpu, spu: Spu) -> Interconnect {
+        // The header tells us if the game wants a Game Boy Color
+        let cgb = cartridge.cgb();
+
+        gpu.set_cgb(cgb);
+
+        let iram = ram::Ram::new(0x8000);
         let zpage = ram::Ram::new(0x7f);
 
         let timer = timer::Timer::new();
 
+        let serial = serial::Serial::new();
+
         let it_enabled = Interrupts::from_register(0);
 
-        let buttons = buttons::Buttons::new(buttons);
+        let buttons = buttons::Buttons::new();
 
         Interconnect {
             cartridge: cartridge,
             iram: iram,
+            iram_bank: 1,
             zpage: zpage,
             timer: timer,
+            serial: serial,
             gpu: gpu,
             spu: spu,
             it_enabled: it_enabled,
-            dma_src: 0,
-            dma_idx: map::range_size(map::OAM),
+            dma: dma::OamDma::new(),
             buttons: buttons,
+            cgb: cgb,
+            double_speed: false,
+            speed_switch_armed: false,
+            stopped: false,
+            odd_tick: false,
+            hdma: hdma::Hdma::new(),
+            watchpoints: watch::Watchpoints::new(),
+            vgm: vgm::VgmLogger::new(),
+            last_interrupt: None,
             bootrom: true,
         }
     }
 
+    /// Called at each tick of the CPU clock
     pub fn step(&mut self) {
-        self.gpu.step();
-        self.spu.step();
+        // In double speed mode the timer, serial port and OAM DMA run
+        // at the CPU speed, everything else keeps running at the
+        // normal system clock speed.
+        let normal_tick = match self.double_speed {
+            true => {
+                self.odd_tick = !self.odd_tick;
+                !self.odd_tick
+            }
+            false => true,
+        };
+
+        if normal_tick {
+            self.gpu.step();
+            self.spu.step();
+            self.vgm.step();
+            self.hdma_step();
+        }
+
         self.dma_step();
         self.timer.step();
+        self.serial.step();
+
+        if normal_tick {
+            self.cartridge.step();
+        }
+    }
+
+    /// Called at each tick of the system clock while it's stopped,
+    /// instead of `step`. See `stopped`.
+    pub fn step_stopped(&mut self) {
+        self.spu.step_stopped();
     }
 
-    pub fn dma_step(&mut self) {
-        let end = map::range_size(map::OAM);
+    /// Return `true` if the CGB is running in double speed mode
+    pub fn double_speed(&self) -> bool {
+        self.double_speed
+    }
+
+    /// Called when the CPU executes STOP. On the CGB this performs
+    /// the speed switch requested through KEY1, in which case `true`
+    /// is returned.
+    pub fn speed_switch(&mut self) -> bool {
+        if !self.cgb || !self.speed_switch_armed {
+            return false;
+        }
+
+        self.double_speed = !self.double_speed;
+        self.speed_switch_armed = false;
+        self.odd_tick = false;
+        // The divider is reset by the switch
+        self.timer.reset_div();
+
+        true
+    }
 
-        if self.dma_idx >= end {
-            // No dma transfer in progress
+    /// Called when the CPU executes STOP. Unless it's used for a CGB
+    /// speed switch this stops the system clock until a button is
+    /// pressed, see `stopped`.
+    pub fn stop(&mut self) {
+        if self.speed_switch() {
             return;
         }
 
-        let b = self.fetch_byte(self.dma_src);
-        self.gpu.set_oam(self.dma_idx, b);
+        self.stopped = true;
+        // The divider is reset when entering STOP mode
+        self.timer.reset_div();
+        self.wake_up();
+    }
+
+    /// Return `true` while the system clock is stopped. The CPU
+    /// shouldn't run nor step the rest of the hardware until a button
+    /// is pressed, only `step_stopped`.
+    pub fn stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// Leave STOP mode if a button of a selected group is pressed
+    fn wake_up(&mut self) {
+        if self.stopped && self.buttons.pressed() {
+            self.stopped = false;
+        }
+    }
+
+    /// Return the cartridge
+    pub fn cartridge(&self) -> &Cartridge {
+        &self.cartridge
+    }
+
+    /// Return the cartridge
+    pub fn cartridge_mut(&mut self) -> &mut Cartridge {
+        &mut self.cartridge
+    }
+
+    /// Return the GPU
+    pub fn gpu(&self) -> &Gpu {
+        &self.gpu
+    }
+
+    /// Return the GPU
+    pub fn gpu_mut(&mut self) -> &mut Gpu {
+        &mut self.gpu
+    }
+
+    /// Return the SPU
+    pub fn spu(&self) -> &Spu {
+        &self.spu
+    }
+
+    /// Return the SPU
+    pub fn spu_mut(&mut self) -> &mut Spu {
+        &mut self.spu
+    }
+
+    /// Return the VGM logger
+    pub fn vgm(&self) -> &vgm::VgmLogger {
+        &self.vgm
+    }
+
+    /// Return the VGM logger
+    pub fn vgm_mut(&mut self) -> &mut vgm::VgmLogger {
+        &mut self.vgm
+    }
+
+    /// Update the state of the buttons as reported by the UI
+    pub fn set_buttons(&mut self, buttons: ::ui::Buttons) {
+        self.buttons.set_state(buttons);
+        self.wake_up();
+    }
+
+    /// Plug `link` into the serial port
+    pub fn set_serial_link(&mut self, link: Box<serial::SerialLink>) {
+        self.serial.set_link(link);
+    }
+
+    /// Copy the next OAM DMA byte if it's time
+    fn dma_step(&mut self) {
+        if let Some((src, dst)) = self.dma.step() {
+            let b = self.peek_byte(src);
+
+            self.dma.set_last(b);
+            self.gpu.set_oam(dst, b);
+        }
+    }
+
+    /// Copy the next HBlank DMA block if the GPU just entered HBlank
+    fn hdma_step(&mut self) {
+        let hblank = self.gpu.mode() == gpu::Mode::HBlank;
+
+        if self.hdma.hblank_edge(hblank) {
+            self.hdma_copy_block();
+        }
+    }
+
+    /// Copy the next 16 byte block of the current VRAM DMA
+    /// transfer. The CPU isn't stalled during the copy.
+    fn hdma_copy_block(&mut self) {
+        let (src, dst) = self.hdma.next_block();
+
+        for i in 0..0x10 {
+            let b = self.peek_byte(src.wrapping_add(i));
+
+            self.gpu.set_vram(dst + i, b);
+        }
+    }
+
+    /// Return the offset in `iram` of `off` within the internal RAM
+    /// range, taking the CGB bank switching into account
+    fn iram_offset(&self, off: u16) -> u16 {
+        match off {
+            0...0xfff => off,
+            _ => (self.iram_bank as u16) * 0x1000 + (off - 0x1000),
+        }
+    }
+
+    /// Return the memory watchpoints
+    pub fn watchpoints(&self) -> &watch::Watchpoints {
+        &self.watchpoints
+    }
+
+    /// Return the memory watchpoints
+    pub fn watchpoints_mut(&mut self) -> &mut watch::Watchpoints {
+        &mut self.watchpoints
+    }
 
-        self.dma_src += 1;
-        self.dma_idx += 1;
+    /// Return the last interrupt acknowledged by the CPU since the
+    /// last call, if any
+    pub fn take_interrupt(&mut self) -> Option<Interrupt> {
+        self.last_interrupt.take()
     }
 
-    /// Get byte from peripheral mapped at `addr`
+    /// Get byte from peripheral mapped at `addr` on behalf of the
+    /// CPU
     pub fn fetch_byte(&self, addr: u16) -> u8 {
+        let b = match self.dma.conflict(addr, self.cgb) {
+            Some(b) => b,
+            None => self.peek_byte(addr),
+        };
+
+        if !self.watchpoints.is_empty() {
+            self.watchpoints.check(addr, watch::Access::Read, b);
+        }
+
+        b
+    }
+
+    /// Store `val` into peripheral mapped at `addr` on behalf of the
+    /// CPU
+    pub fn store_byte(&mut self, addr: u16, val: u8) {
+        if !self.watchpoints.is_empty() {
+            self.watchpoints.check(addr, watch::Access::Write, val);
+        }
+
+        if self.dma.conflict(addr, self.cgb).is_some() {
+            // The bus is busy with the OAM DMA
+            return;
+        }
+
+        self.poke_byte(addr, val);
+    }
+
+    /// Get byte from peripheral mapped at `addr` without triggering
+    /// the watchpoints
+    pub fn peek_byte(&self, addr: u16) -> u8 {
         if let Some(off) = map::in_range(addr, map::ROM) {
             if self.bootrom && off < 0x100 {
                 // Bootrom is still mapped, read from it
@@ -109,6 +352,13 @@ impl<'a> Interconnect<'a> {
                         self.bootrom, should_read_bootrom
                     )
                 }
+                // We don't have a CGB bootrom, the DMG one is used in
+                // both modes. The CGB bootrom hands over to the game
+                // with A = 0x11 which is how games detect that
+                // they're running on a Color.
+                if self.cgb && off == bootrom::HANDOFF_A {
+                    return 0x11;
+                }
                 return bootrom::BOOTROM[off as usize];
             }
 
@@ -124,11 +374,11 @@ impl<'a> Interconnect<'a> {
         }
 
         if let Some(off) = map::in_range(addr, map::IRAM) {
-            return self.iram.byte(off);
+            return self.iram.byte(self.iram_offset(off));
         }
 
         if let Some(off) = map::in_range(addr, map::IRAM_ECHO) {
-            return self.iram.byte(off);
+            return self.iram.byte(self.iram_offset(off));
         }
 
         if let Some(off) = map::in_range(addr, map::OAM) {
@@ -151,8 +401,9 @@ impl<'a> Interconnect<'a> {
         0xff
     }
 
-    /// Store `val` into peripheral mapped at `addr`
-    pub fn store_byte(&mut self, addr: u16, val: u8) {
+    /// Store `val` into peripheral mapped at `addr` without
+    /// triggering the watchpoints
+    pub fn poke_byte(&mut self, addr: u16, val: u8) {
         trace!("storing {:x} in {:x}\n", val, addr);
         if let Some(off) = map::in_range(addr, map::ROM) {
             return self.cartridge.set_rom_byte(off, val);
@@ -167,10 +418,12 @@ impl<'a> Interconnect<'a> {
         }
 
         if let Some(off) = map::in_range(addr, map::IRAM) {
+            let off = self.iram_offset(off);
             return self.iram.set_byte(off, val);
         }
 
         if let Some(off) = map::in_range(addr, map::IRAM_ECHO) {
+            let off = self.iram_offset(off);
             return self.iram.set_byte(off, val);
         }
 
@@ -190,21 +443,22 @@ impl<'a> Interconnect<'a> {
             return self.it_enabled = Interrupts::from_register(val);
         }
 
-        if self.bootrom && addr == map::UNMAP_BOOTROM {
-            if val == 1 {
-                // Unmap bootrom
-                info!("disabling bootrom!\n");
-                self.bootrom = false;
-            }
-            return;
-        }
-
         debug!("Write to unmapped memory {:04x}: {:02x}", addr, val);
     }
 
     /// Return the highest priority active interrupt after
     /// acknowledging it. If no interrupt is pending return `None`.
     pub fn next_interrupt_ack(&mut self) -> Option<Interrupt> {
+        let it = self.ack_interrupt();
+
+        if it.is_some() {
+            self.last_interrupt = it;
+        }
+
+        it
+    }
+
+    fn ack_interrupt(&mut self) -> Option<Interrupt> {
         if self.it_enabled.vblank && self.gpu.it_vblank() {
             self.gpu.ack_it_vblank();
             Some(Interrupt::VBlank)
@@ -214,8 +468,10 @@ impl<'a> Interconnect<'a> {
         } else if self.it_enabled.timer && self.timer.interrupt() {
             self.timer.ack_interrupt();
             Some(Interrupt::Timer)
+        } else if self.it_enabled.serial && self.serial.interrupt() {
+            self.serial.ack_interrupt();
+            Some(Interrupt::Serial)
         } else if self.it_enabled.button && self.buttons.interrupt() {
-            // TODO: test
             self.buttons.ack_interrupt();
             Some(Interrupt::Button)
         } else {
@@ -232,6 +488,10 @@ impl<'a> Interconnect<'a> {
             Some(Interrupt::Lcdc)
         } else if self.it_enabled.timer && self.timer.interrupt() {
             Some(Interrupt::Timer)
+        } else if self.it_enabled.serial && self.serial.interrupt() {
+            Some(Interrupt::Serial)
+        } else if self.it_enabled.button && self.buttons.interrupt() {
+            Some(Interrupt::Button)
         } else {
             None
         }
@@ -243,28 +503,22 @@ impl<'a> Interconnect<'a> {
             // Controller input
             io_map::INPUT => self.buttons.input(),
             // Serial link
-            io_map::SB => {
-                debug!("unhandled read from serial data");
-                0
-            }
-            io_map::SC => {
-                debug!("unhandled read from serial control");
-                0
-            }
+            io_map::SB => self.serial.data(),
+            io_map::SC => self.serial.control(),
             // Timers
             io_map::DIV => self.timer.div(),
             io_map::TIMA => self.timer.counter(),
             io_map::TMA => self.timer.modulo(),
             io_map::TAC => self.timer.config(),
             // DMA
-            io_map::DMA => self.dma_addr(),
+            io_map::DMA => self.dma.source(),
             // Interrupt Flags
             io_map::IF => Interrupts {
                 vblank: self.gpu.it_vblank(),
                 lcdc: self.gpu.it_lcd(),
                 timer: self.timer.interrupt(),
-                serial: false,
-                button: false,
+                serial: self.serial.interrupt(),
+                button: self.buttons.interrupt(),
             }
             .as_register(),
             // SPU registers
@@ -307,6 +561,17 @@ impl<'a> Interconnect<'a> {
             io_map::LCD_OBP1 => self.gpu.obp1(),
             io_map::LCD_WY => self.gpu.wy(),
             io_map::LCD_WX => self.gpu.wx(),
+            // CGB registers
+            io_map::KEY1 if self.cgb => {
+                0x7e | ((self.double_speed as u8) << 7) | (self.speed_switch_armed as u8)
+            }
+            io_map::VBK if self.cgb => self.gpu.vbk(),
+            io_map::HDMA5 if self.cgb => self.hdma.control(),
+            io_map::BCPS if self.cgb => self.gpu.bcps(),
+            io_map::BCPD if self.cgb => self.gpu.bcpd(),
+            io_map::OCPS if self.cgb => self.gpu.ocps(),
+            io_map::OCPD if self.cgb => self.gpu.ocpd(),
+            io_map::SVBK if self.cgb => 0xf8 | self.iram_bank,
             _ => {
                 warn!("Unhandled IO read from 0x{:04x}", 0xff00 | addr);
                 // Unmapped addresses read as full 1s
@@ -317,19 +582,23 @@ impl<'a> Interconnect<'a> {
 
     /// Set value of IO port
     fn set_io(&mut self, addr: u16, val: u8) {
+        if let io_map::NR10...NR3_RAM_END = addr {
+            self.vgm.write((addr - io_map::NR10) as u8, val);
+        }
+
         match addr {
             // Controller input
             io_map::INPUT => self.buttons.set_input(val),
             // Seral link
-            io_map::SB => debug!("unhandled write to serial data: 0x{:02x}", val),
-            io_map::SC => debug!("unhandled write to serial control: 0x{:02x}", val),
+            io_map::SB => self.serial.set_data(val),
+            io_map::SC => self.serial.set_control(val),
             // Timers
             io_map::DIV => self.timer.reset_div(),
             io_map::TIMA => self.timer.set_counter(val),
             io_map::TMA => self.timer.set_modulo(val),
             io_map::TAC => self.timer.set_config(val),
             // DMA
-            io_map::DMA => self.start_dma(val),
+            io_map::DMA => self.dma.start(val),
             // Interrupt Flags
             io_map::IF => {
                 let f = Interrupts::from_register(val);
@@ -339,6 +608,8 @@ impl<'a> Interconnect<'a> {
                 self.gpu.force_it_vblank(f.vblank);
                 self.gpu.force_it_lcd(f.lcdc);
                 self.timer.force_interrupt(f.timer);
+                self.serial.force_interrupt(f.serial);
+                self.buttons.force_interrupt(f.button);
             }
             // SPU registers
             io_map::NR10 => self.spu.set_nr10(val),
@@ -380,27 +651,94 @@ impl<'a> Interconnect<'a> {
             io_map::LCD_OBP1 => self.gpu.set_obp1(val),
             io_map::LCD_WY => self.gpu.set_wy(val),
             io_map::LCD_WX => self.gpu.set_wx(val),
+            io_map::UNMAP_BOOTROM => {
+                if self.bootrom && val & 1 != 0 {
+                    // Unmap bootrom
+                    info!("disabling bootrom!\n");
+                    self.bootrom = false;
+                }
+            }
+            // CGB registers
+            io_map::KEY1 if self.cgb => self.speed_switch_armed = val & 1 != 0,
+            io_map::VBK if self.cgb => self.gpu.set_vbk(val),
+            io_map::HDMA1 if self.cgb => self.hdma.set_source_high(val),
+            io_map::HDMA2 if self.cgb => self.hdma.set_source_low(val),
+            io_map::HDMA3 if self.cgb => self.hdma.set_destination_high(val),
+            io_map::HDMA4 if self.cgb => self.hdma.set_destination_low(val),
+            io_map::HDMA5 if self.cgb => {
+                self.hdma.set_control(val);
+
+                // General purpose DMA copies everything right away
+                while self.hdma.general_pending() {
+                    self.hdma_copy_block();
+                }
+            }
+            io_map::BCPS if self.cgb => self.gpu.set_bcps(val),
+            io_map::BCPD if self.cgb => self.gpu.set_bcpd(val),
+            io_map::OCPS if self.cgb => self.gpu.set_ocps(val),
+            io_map::OCPD if self.cgb => self.gpu.set_ocpd(val),
+            io_map::SVBK if self.cgb => {
+                // Bank 0 can't be mapped here, selecting it maps bank 1
+                self.iram_bank = match val & 7 {
+                    0 => 1,
+                    n => n,
+                };
+            }
             _ => warn!("Unhandled IO write to IO 0x{:02x}: 0x{:02x}", addr, val),
         }
     }
 
-    /// Return the base of the last DMA transfer (only the high byte,
-    /// the low byte is always 0)
-    fn dma_addr(&self) -> u8 {
-        (self.dma_src >> 8) as u8
-    }
+}
 
-    /// Start a new transfer from (`src` << 8) into OAM
-    fn start_dma(&mut self, src: u8) {
-        self.dma_idx = 0;
-        self.dma_src = (src as u16) << 8;
+impl Snapshot for Interconnect {
+    fn snapshot(&self, w: &mut StateWriter) {
+        self.cartridge.snapshot(w);
+        self.iram.snapshot(w);
+        self.zpage.snapshot(w);
+        self.timer.snapshot(w);
+        self.serial.snapshot(w);
+        self.gpu.snapshot(w);
+        self.spu.snapshot(w);
+        w.write_u8(self.it_enabled.as_register());
+        self.dma.snapshot(w);
+        self.buttons.snapshot(w);
+        w.write_bool(self.bootrom);
+        w.write_u8(self.iram_bank);
+        w.write_bool(self.double_speed);
+        w.write_bool(self.speed_switch_armed);
+        w.write_bool(self.stopped);
+        w.write_bool(self.odd_tick);
+        self.hdma.snapshot(w);
+    }
 
-        self.dma_step();
+    fn restore(&mut self, r: &mut StateReader) -> IoResult<()> {
+        try!(self.cartridge.restore(r));
+        try!(self.iram.restore(r));
+        try!(self.zpage.restore(r));
+        try!(self.timer.restore(r));
+        try!(self.serial.restore(r));
+        try!(self.gpu.restore(r));
+        try!(self.spu.restore(r));
+        self.it_enabled = Interrupts::from_register(try!(r.read_u8()));
+        try!(self.dma.restore(r));
+        try!(self.buttons.restore(r));
+        self.bootrom = try!(r.read_bool());
+        self.iram_bank = match try!(r.read_u8()) {
+            b @ 1...7 => b,
+            _ => return savestate::invalid("internal RAM bank"),
+        };
+        self.double_speed = try!(r.read_bool());
+        self.speed_switch_armed = try!(r.read_bool());
+        self.stopped = try!(r.read_bool());
+        self.odd_tick = try!(r.read_bool());
+        try!(self.hdma.restore(r));
+
+        Ok(())
     }
 }
 
 /// The various sources of interrupt, from highest to lowest priority
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Interrupt {
     /// GPU entered vertical blanking
     VBlank,
@@ -408,9 +746,10 @@ pub enum Interrupt {
     Lcdc,
     /// Timer overflow
     Timer,
+    /// Serial transfer complete
+    Serial,
     /// Button pressed
-    Button
-    // TODO: implement serial
+    Button,
 }
 
 /// GB Interrupts, from highest to lowest priority
@@ -470,10 +809,7 @@ mod map {
     /// Object Attribute Memory
     pub const OAM: (u16, u16) = (0xfe00, 0xfe9f);
     /// IO ports
-    pub const IO: (u16, u16) = (0xff00, 0xff4b);
-    /// Register used to unmap the bootrom. Should not be used by
-    /// regular games.
-    pub const UNMAP_BOOTROM: u16 = 0xff50;
+    pub const IO: (u16, u16) = (0xff00, 0xff7f);
     /// Zero page memory
     pub const ZERO_PAGE: (u16, u16) = (0xff80, 0xfffe);
     /// Interrupt Enable register
@@ -491,13 +827,6 @@ mod map {
             None
         }
     }
-
-    /// Return the size of `range` in bytes
-    pub fn range_size(range: (u16, u16)) -> u16 {
-        let (first, last) = range;
-
-        return last - first + 1;
-    }
 }
 
 mod io_map {
@@ -590,4 +919,31 @@ mod io_map {
     pub const LCD_WY: u16 = 0x4a;
     /// Window X position + 7
     pub const LCD_WX: u16 = 0x4b;
+    /// CGB speed switch
+    pub const KEY1: u16 = 0x4d;
+    /// CGB VRAM bank
+    pub const VBK: u16 = 0x4f;
+    /// Register used to unmap the bootrom. Should not be used by
+    /// regular games.
+    pub const UNMAP_BOOTROM: u16 = 0x50;
+    /// CGB VRAM DMA source, high byte
+    pub const HDMA1: u16 = 0x51;
+    /// CGB VRAM DMA source, low byte
+    pub const HDMA2: u16 = 0x52;
+    /// CGB VRAM DMA destination, high byte
+    pub const HDMA3: u16 = 0x53;
+    /// CGB VRAM DMA destination, low byte
+    pub const HDMA4: u16 = 0x54;
+    /// CGB VRAM DMA length, mode and start
+    pub const HDMA5: u16 = 0x55;
+    /// CGB background palette index
+    pub const BCPS: u16 = 0x68;
+    /// CGB background palette data
+    pub const BCPD: u16 = 0x69;
+    /// CGB sprite palette index
+    pub const OCPS: u16 = 0x6a;
+    /// CGB sprite palette data
+    pub const OCPD: u16 = 0x6b;
+    /// CGB internal RAM bank
+    pub const SVBK: u16 = 0x70;
 }
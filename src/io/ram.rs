@@ -1,7 +1,10 @@
 //! RAM emulation
 
+use std::io::Result as IoResult;
 use std::iter;
 
+use savestate::{Snapshot, StateReader, StateWriter};
+
 /// RAM image
 pub struct Ram {
     data: Vec<u8>,
@@ -24,3 +27,13 @@ impl Ram {
         self.data[offset as usize] = val;
     }
 }
+
+impl Snapshot for Ram {
+    fn snapshot(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.data);
+    }
+
+    fn restore(&mut self, r: &mut StateReader) -> IoResult<()> {
+        r.read_bytes(&mut self.data)
+    }
+}
@@ -0,0 +1,513 @@
+//! Serial link port emulation.
+//!
+//! A transfer exchanges the content of the SB register with the one
+//! of the peer, one bit at a time. The Game Boy driving the clock
+//! ("internal clock") shifts the bits at 8192Hz, the other one
+//! ("external clock") waits for the peer to start the transfer. In
+//! both cases the serial interrupt fires once the 8 bits have been
+//! exchanged.
+//!
+//! The other end of the cable is abstracted by the `SerialLink`
+//! trait. We only exchange whole bytes with it: the byte is sent when
+//! a transfer we clock starts and the reply is picked up when it
+//! ends. The link must never block the emulation, if the reply is
+//! late the transfer simply lasts longer and if it doesn't come at
+//! all we behave as if the cable was unplugged.
+
+use std::io::{Read, Result as IoResult, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{channel, Receiver};
+
+use savestate::{Snapshot, StateReader, StateWriter};
+
+/// Other end of the link cable
+pub trait SerialLink {
+    /// Called when a transfer clocked by this Game Boy starts: send
+    /// `byte` to the peer. The byte it sends back is retrieved with
+    /// `reply`.
+    fn start_transfer(&mut self, byte: u8);
+
+    /// Return the byte sent back by the peer for the last transfer
+    /// started with `start_transfer`, or `None` if it hasn't arrived
+    /// yet. Must not block.
+    fn reply(&mut self) -> Option<u8>;
+
+    /// Called regularly to check if the peer clocked a transfer. If
+    /// this Game Boy is waiting for one `reply` contains the byte to
+    /// send back and the byte received from the peer is
+    /// returned. Otherwise `reply` is `None`: the peer can't see our
+    /// data and `None` is returned.
+    fn poll(&mut self, reply: Option<u8>) -> Option<u8>;
+}
+
+/// Nothing plugged in: the data line is pulled up so we always read
+/// 0xff and nobody ever clocks a transfer for us.
+pub struct Disconnected;
+
+impl SerialLink for Disconnected {
+    fn start_transfer(&mut self, _: u8) {}
+
+    fn reply(&mut self) -> Option<u8> {
+        Some(0xff)
+    }
+
+    fn poll(&mut self, _: Option<u8>) -> Option<u8> {
+        None
+    }
+}
+
+/// Cable plugged back into the same Game Boy: every byte sent is
+/// received back.
+#[derive(Default)]
+pub struct Loopback {
+    /// Byte sent by the transfer in progress
+    byte: Option<u8>,
+}
+
+impl Loopback {
+    /// Create a new loopback cable
+    pub fn new() -> Loopback {
+        Loopback { byte: None }
+    }
+}
+
+impl SerialLink for Loopback {
+    fn start_transfer(&mut self, byte: u8) {
+        self.byte = Some(byte);
+    }
+
+    fn reply(&mut self) -> Option<u8> {
+        self.byte.take()
+    }
+
+    fn poll(&mut self, _: Option<u8>) -> Option<u8> {
+        None
+    }
+}
+
+/// Link with an other emulator instance over TCP. Each message is
+/// two bytes long: a message type (`MSG_TRANSFER` or `MSG_REPLY`)
+/// followed by the data byte.
+pub struct TcpLink {
+    /// Stream used to send messages to the peer
+    stream: TcpStream,
+    /// Messages received from the peer by the reader thread
+    incoming: Receiver<[u8; 2]>,
+}
+
+impl TcpLink {
+    /// Wait for a peer to connect on `addr`
+    pub fn listen<A: ToSocketAddrs>(addr: A) -> IoResult<TcpLink> {
+        let listener = try!(TcpListener::bind(addr));
+
+        info!(
+            "Waiting for link cable peer on {}",
+            try!(listener.local_addr())
+        );
+
+        let (stream, peer) = try!(listener.accept());
+
+        info!("Link cable peer connected from {}", peer);
+
+        TcpLink::from_stream(stream)
+    }
+
+    /// Connect to a peer listening on `addr`
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> IoResult<TcpLink> {
+        let stream = try!(TcpStream::connect(addr));
+
+        info!("Link cable connected to {}", try!(stream.peer_addr()));
+
+        TcpLink::from_stream(stream)
+    }
+
+    fn from_stream(stream: TcpStream) -> IoResult<TcpLink> {
+        // Transfers are latency bound and tiny
+        try!(stream.set_nodelay(true));
+
+        let mut reader = try!(stream.try_clone());
+        let (tx, rx) = channel();
+
+        // Reading in a separate thread lets `poll` stay non blocking
+        ::std::thread::spawn(move || loop {
+            let mut msg = [0; 2];
+
+            if let Err(e) = reader.read_exact(&mut msg) {
+                info!("Link cable disconnected: {}", e);
+                return;
+            }
+
+            if tx.send(msg).is_err() {
+                // Link dropped
+                return;
+            }
+        });
+
+        Ok(TcpLink {
+            stream: stream,
+            incoming: rx,
+        })
+    }
+
+    fn send(&mut self, msg_type: u8, byte: u8) {
+        if let Err(e) = self.stream.write_all(&[msg_type, byte]) {
+            debug!("Link cable write failed: {}", e);
+        }
+    }
+}
+
+impl SerialLink for TcpLink {
+    fn start_transfer(&mut self, byte: u8) {
+        // Drop the replies to the transfers that timed out so that
+        // they're not mistaken for the reply to this one
+        while let Some(b) = self.reply() {
+            debug!("Dropping late link cable reply {:02x}", b);
+        }
+
+        self.send(MSG_TRANSFER, byte);
+    }
+
+    fn reply(&mut self) -> Option<u8> {
+        loop {
+            match self.incoming.try_recv() {
+                Ok([MSG_REPLY, b]) => return Some(b),
+                Ok([MSG_TRANSFER, _]) => {
+                    // The peer is also driving the clock, neither of
+                    // us is listening.
+                    self.send(MSG_REPLY, 0xff);
+                }
+                Ok(msg) => warn!("Unexpected link cable message {:?}", msg),
+                Err(_) => return None,
+            }
+        }
+    }
+
+    fn poll(&mut self, reply: Option<u8>) -> Option<u8> {
+        match self.incoming.try_recv() {
+            Ok([MSG_TRANSFER, b]) => match reply {
+                Some(r) => {
+                    self.send(MSG_REPLY, r);
+                    Some(b)
+                }
+                None => {
+                    self.send(MSG_REPLY, 0xff);
+                    None
+                }
+            },
+            Ok(msg) => {
+                // Probably a reply that arrived after the timeout
+                debug!("Dropping link cable message {:?}", msg);
+                None
+            }
+            Err(_) => None,
+        }
+    }
+}
+
+/// Serial controller state
+pub struct Serial {
+    /// Content of the SB register: byte to send, replaced by the
+    /// byte received once the transfer completes
+    data: u8,
+    /// `true` while a transfer is in progress (SC bit 7)
+    transferring: bool,
+    /// `true` if we drive the clock (SC bit 0)
+    internal_clock: bool,
+    /// Number of system clock ticks before the end of an internal
+    /// clock transfer, or before polling the link otherwise
+    counter: u16,
+    /// Number of system clock ticks spent waiting for the reply to
+    /// an internal clock transfer past its normal duration
+    waited: u32,
+    /// True if interrupt is pending
+    interrupt: bool,
+    /// Other end of the cable
+    link: Box<SerialLink>,
+}
+
+impl Serial {
+    /// Create a new serial controller with nothing plugged in
+    pub fn new() -> Serial {
+        Serial {
+            data: 0,
+            transferring: false,
+            internal_clock: false,
+            counter: POLL_PERIOD,
+            waited: 0,
+            interrupt: false,
+            link: Box::new(Disconnected),
+        }
+    }
+
+    /// Plug `link` into the serial port
+    pub fn set_link(&mut self, link: Box<SerialLink>) {
+        self.link = link;
+    }
+
+    /// Called at each tick of the system clock
+    pub fn step(&mut self) {
+        self.counter -= 1;
+
+        if self.counter != 0 {
+            return;
+        }
+
+        if self.transferring && self.internal_clock {
+            match self.link.reply() {
+                Some(b) => {
+                    self.data = b;
+                    self.complete();
+                }
+                None if self.waited >= LINK_TIMEOUT => {
+                    // The peer is gone or unresponsive, behave as if
+                    // the cable was unplugged
+                    debug!("Link cable transfer timed out");
+                    self.data = 0xff;
+                    self.complete();
+                }
+                None => self.waited += POLL_PERIOD as u32,
+            }
+        } else {
+            // Check if the peer started a transfer
+            let reply = match self.transferring {
+                true => Some(self.data),
+                false => None,
+            };
+
+            if let Some(b) = self.link.poll(reply) {
+                self.data = b;
+                self.complete();
+            }
+        }
+
+        self.counter = POLL_PERIOD;
+    }
+
+    fn complete(&mut self) {
+        self.transferring = false;
+        self.interrupt = true;
+    }
+
+    /// Return the value of the SB register
+    pub fn data(&self) -> u8 {
+        self.data
+    }
+
+    /// Set the value of the SB register
+    pub fn set_data(&mut self, val: u8) {
+        self.data = val;
+    }
+
+    /// Return the value of the SC register. Unused bits read as 1.
+    pub fn control(&self) -> u8 {
+        let mut r = 0x7e;
+
+        r |= (self.transferring as u8) << 7;
+        r |= self.internal_clock as u8;
+
+        r
+    }
+
+    /// Configure the serial controller and possibly start a new
+    /// transfer
+    pub fn set_control(&mut self, val: u8) {
+        self.transferring = val & 0x80 != 0;
+        self.internal_clock = val & 1 != 0;
+
+        self.waited = 0;
+        self.counter = match self.transferring && self.internal_clock {
+            true => {
+                self.link.start_transfer(self.data);
+                TRANSFER_CYCLES
+            }
+            false => POLL_PERIOD,
+        };
+    }
+
+    /// Return interrupt status
+    pub fn interrupt(&self) -> bool {
+        self.interrupt
+    }
+
+    /// Acknowledge interrupt
+    pub fn ack_interrupt(&mut self) {
+        self.interrupt = false;
+    }
+
+    /// Force interrupt state
+    pub fn force_interrupt(&mut self, set: bool) {
+        self.interrupt = set;
+    }
+}
+
+impl Snapshot for Serial {
+    /// The link itself is not part of the machine state
+    fn snapshot(&self, w: &mut StateWriter) {
+        w.write_u8(self.data);
+        w.write_bool(self.transferring);
+        w.write_bool(self.internal_clock);
+        w.write_u16(self.counter);
+        w.write_bool(self.interrupt);
+    }
+
+    fn restore(&mut self, r: &mut StateReader) -> IoResult<()> {
+        self.data = try!(r.read_u8());
+        self.transferring = try!(r.read_bool());
+        self.internal_clock = try!(r.read_bool());
+        self.counter = try!(r.read_u16());
+        self.interrupt = try!(r.read_bool());
+        self.waited = 0;
+
+        if self.counter == 0 || self.counter > TRANSFER_CYCLES {
+            self.counter = POLL_PERIOD;
+        }
+
+        Ok(())
+    }
+}
+
+/// With the internal clock bits are shifted at 8192Hz, that's 512
+/// system clock ticks per bit
+const TRANSFER_CYCLES: u16 = 8 * 512;
+
+/// How often we check if the peer started a transfer, in system
+/// clock ticks. That's the duration of a single bit with the internal
+/// clock, the external clock can be faster but we don't need to be
+/// that precise.
+const POLL_PERIOD: u16 = 512;
+
+/// How long we wait for the peer to reply to a transfer past its
+/// normal duration before giving up, in system clock ticks (about a
+/// quarter of a second)
+const LINK_TIMEOUT: u32 = 0x100000;
+
+/// TCP link message: transfer started, contains the byte sent by the
+/// Game Boy driving the clock
+const MSG_TRANSFER: u8 = 0x01;
+
+/// TCP link message: reply to `MSG_TRANSFER`, contains the byte sent
+/// back by the other Game Boy
+const MSG_REPLY: u8 = 0x02;
+
+#[cfg(test)]
+mod tests {
+    use super::{Loopback, Serial, TcpLink, TRANSFER_CYCLES};
+    use std::net::TcpListener;
+
+    fn run(serial: &mut Serial, cycles: u16) {
+        for _ in 0..cycles {
+            serial.step();
+        }
+    }
+
+    #[test]
+    fn disconnected() {
+        let mut serial = Serial::new();
+
+        serial.set_data(0x42);
+        serial.set_control(0x81);
+
+        run(&mut serial, TRANSFER_CYCLES - 1);
+        assert!(serial.control() == 0xff);
+        assert!(!serial.interrupt());
+
+        serial.step();
+        assert!(serial.control() == 0x7f);
+        assert!(serial.data() == 0xff);
+        assert!(serial.interrupt());
+    }
+
+    #[test]
+    fn loopback() {
+        let mut serial = Serial::new();
+
+        serial.set_link(Box::new(Loopback::new()));
+        serial.set_data(0x42);
+        serial.set_control(0x81);
+
+        run(&mut serial, TRANSFER_CYCLES);
+        assert!(serial.data() == 0x42);
+        assert!(serial.interrupt());
+    }
+
+    #[test]
+    fn external_clock_never_completes_alone() {
+        let mut serial = Serial::new();
+
+        serial.set_data(0x42);
+        serial.set_control(0x80);
+
+        run(&mut serial, 4 * TRANSFER_CYCLES);
+        assert!(serial.control() == 0xfe);
+        assert!(!serial.interrupt());
+    }
+
+    #[test]
+    fn tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let connect = ::std::thread::spawn(move || TcpLink::connect(addr).unwrap());
+
+        let (stream, _) = listener.accept().unwrap();
+        let slave_link = TcpLink::from_stream(stream).unwrap();
+        let master_link = connect.join().unwrap();
+
+        let mut slave = Serial::new();
+        slave.set_link(Box::new(slave_link));
+        slave.set_data(0x55);
+        slave.set_control(0x80);
+
+        let mut master = Serial::new();
+        master.set_link(Box::new(master_link));
+        master.set_data(0xaa);
+        master.set_control(0x81);
+
+        // Neither side blocks, the master keeps waiting for the reply
+        // while the slave hasn't seen the transfer
+        while !slave.interrupt() {
+            slave.step();
+        }
+
+        // Slow the master down so that the reply has plenty of time
+        // to come back before it gives up
+        let mut cycles = 0u32;
+
+        while !master.interrupt() {
+            master.step();
+
+            cycles += 1;
+            if cycles % 0x1000 == 0 {
+                ::std::thread::sleep(::std::time::Duration::from_millis(1));
+            }
+        }
+
+        assert!(slave.data() == 0xaa);
+        assert!(master.data() == 0x55);
+    }
+
+    #[test]
+    fn tcp_peer_gone() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let connect = ::std::thread::spawn(move || TcpLink::connect(addr).unwrap());
+
+        // The peer never answers
+        let (_stream, _) = listener.accept().unwrap();
+
+        let mut serial = Serial::new();
+        serial.set_link(Box::new(connect.join().unwrap()));
+        serial.set_data(0x42);
+        serial.set_control(0x81);
+
+        run(&mut serial, TRANSFER_CYCLES);
+        assert!(!serial.interrupt());
+
+        while !serial.interrupt() {
+            serial.step();
+        }
+
+        assert!(serial.data() == 0xff);
+    }
+}
@@ -1,4 +1,25 @@
 //! Timer emulation
+//!
+//! DIV and TIMA are both driven by a free-running 16 bit counter
+//! incremented at each tick of the system clock. DIV is its upper
+//! byte. TIMA is incremented on the falling edge of one of the counter
+//! bits, selected by TAC and ANDed with the timer enable bit. Since
+//! it's really the edge that matters, resetting DIV or changing TAC
+//! while the selected bit is high increments TIMA as well.
+//!
+//! When TIMA overflows it reads 0 for one machine cycle, then it's
+//! reloaded with TMA and the interrupt is raised. Writing TIMA during
+//! the first cycle cancels the reload, writing it during the reload
+//! cycle is ignored and writing TMA during that cycle goes through to
+//! TIMA.
+
+use std::io::Result as IoResult;
+
+use savestate::{self, Snapshot, StateReader, StateWriter};
+
+/// Number of clock ticks between the overflow of TIMA and its reload
+/// from TMA, and duration of the reload itself
+const RELOAD_TICKS: u8 = 4;
 
 /// Timer state
 pub struct Timer {
@@ -9,11 +30,17 @@ pub struct Timer {
     pub modulo: u8,
     /// If true timer is counting and generating interrupts
     pub enabled: bool,
-    /// System clock divider. When `enabled`, at each tick of the
-    /// divider clock `counter` is incremented.
+    /// Selects which bit of `system_counter` clocks `counter` when
+    /// `enabled`
     pub divider: Divider,
-    /// Free-running counter used as a divider for the sysclk
-    pub counter_16k: u32,
+    /// Free-running counter incremented at each tick of the system
+    /// clock. DIV is the upper byte.
+    pub system_counter: u16,
+    /// Clock ticks left before `counter` is reloaded after an
+    /// overflow, 0 if no reload is pending
+    overflow_delay: u8,
+    /// Clock ticks left in the cycle `counter` is being reloaded
+    reloading: u8,
     /// True if interrupt is pending
     pub interrupt: bool,
 }
@@ -25,28 +52,58 @@ impl Timer {
             modulo: 0,
             enabled: false,
             divider: Divider::Div1024,
-            counter_16k: 0,
+            system_counter: 0,
+            overflow_delay: 0,
+            reloading: 0,
             interrupt: false,
         }
     }
 
     pub fn step(&mut self) {
-        self.counter_16k += 1;
+        if self.reloading > 0 {
+            self.reloading -= 1;
+        }
 
-        if !self.enabled {
-            return;
+        if self.overflow_delay > 0 {
+            self.overflow_delay -= 1;
+
+            if self.overflow_delay == 0 {
+                self.counter = self.modulo;
+                self.interrupt = true;
+                self.reloading = RELOAD_TICKS;
+            }
         }
 
-        let mask = (1 << (self.divider as usize)) - 1;
+        let counter = self.system_counter.wrapping_add(1);
+
+        self.set_system_counter(counter);
+    }
+
+    /// Return the level of the signal clocking `counter`
+    fn signal(&self) -> bool {
+        self.enabled && self.system_counter & self.divider.mask() != 0
+    }
+
+    /// Update the system counter, incrementing `counter` on the falling
+    /// edge of the selected bit
+    fn set_system_counter(&mut self, counter: u16) {
+        let prev = self.signal();
+
+        self.system_counter = counter;
+
+        self.check_edge(prev);
+    }
 
-        if self.counter_16k & mask == 0 {
-            // Divided clock ticked, increment counter
+    /// Increment `counter` if the clock signal was high before the
+    /// last change and is now low
+    fn check_edge(&mut self, prev: bool) {
+        if prev && !self.signal() {
             self.counter = self.counter.wrapping_add(1);
 
             if self.counter == 0 {
-                // Timer overflowed.
-                self.interrupt = true;
-                self.counter = self.modulo;
+                // Timer overflowed, the reload from `modulo` is
+                // delayed
+                self.overflow_delay = RELOAD_TICKS;
             }
         }
     }
@@ -58,18 +115,25 @@ impl Timer {
 
     /// Reset counter value
     pub fn set_counter(&mut self, counter: u8) {
+        if self.reloading > 0 {
+            // The reload from TMA wins
+            return;
+        }
+
         self.counter = counter;
+        // Cancel the pending reload, if any
+        self.overflow_delay = 0;
     }
 
     /// Get value of "DIV" register. It contains a free running
     /// counter of SysClk / 256 -> 16.384kHz
     pub fn div(&self) -> u8 {
-        (self.counter_16k >> 8) as u8
+        (self.system_counter >> 8) as u8
     }
 
-    /// Reset 16.384kHz counter to 0
+    /// Reset the system counter to 0
     pub fn reset_div(&mut self) {
-        self.counter_16k = 0;
+        self.set_system_counter(0);
     }
 
     /// Return the current value of the `modulo`
@@ -80,10 +144,16 @@ impl Timer {
     /// Set the value loaded into the counter when it overflows.
     pub fn set_modulo(&mut self, modulo: u8) {
         self.modulo = modulo;
+
+        if self.reloading > 0 {
+            self.counter = modulo;
+        }
     }
 
     /// Configure the timer using values specified in the config register
     pub fn set_config(&mut self, cfg: u8) {
+        let prev = self.signal();
+
         self.enabled = cfg & 4 != 0;
 
         self.divider = match cfg & 3 {
@@ -93,6 +163,8 @@ impl Timer {
             3 => Divider::Div256,
             _ => unreachable!(),
         };
+
+        self.check_edge(prev);
     }
 
     /// Return configuration register value
@@ -100,9 +172,15 @@ impl Timer {
         let mut r = 0;
 
         r |= (self.enabled as u8) << 2;
-        r |= self.divider as u8;
+        r |= match self.divider {
+            Divider::Div1024 => 0,
+            Divider::Div16 => 1,
+            Divider::Div64 => 2,
+            Divider::Div256 => 3,
+        };
 
-        r
+        // Unused bits read as 1
+        0xf8 | r
     }
 
     /// Return interrupt status
@@ -121,6 +199,42 @@ impl Timer {
     }
 }
 
+impl Snapshot for Timer {
+    fn snapshot(&self, w: &mut StateWriter) {
+        w.write_u8(self.counter);
+        w.write_u8(self.modulo);
+        w.write_bool(self.enabled);
+        w.write_u8(self.divider as u8);
+        w.write_u16(self.system_counter);
+        w.write_u8(self.overflow_delay);
+        w.write_u8(self.reloading);
+        w.write_bool(self.interrupt);
+    }
+
+    fn restore(&mut self, r: &mut StateReader) -> IoResult<()> {
+        self.counter = try!(r.read_u8());
+        self.modulo = try!(r.read_u8());
+        self.enabled = try!(r.read_bool());
+        self.divider = match try!(r.read_u8()) {
+            4 => Divider::Div16,
+            6 => Divider::Div64,
+            8 => Divider::Div256,
+            10 => Divider::Div1024,
+            _ => return savestate::invalid("timer divider"),
+        };
+        self.system_counter = try!(r.read_u16());
+        self.overflow_delay = try!(r.read_u8());
+        self.reloading = try!(r.read_u8());
+        self.interrupt = try!(r.read_bool());
+
+        if self.overflow_delay > RELOAD_TICKS || self.reloading > RELOAD_TICKS {
+            return savestate::invalid("timer reload delay");
+        }
+
+        Ok(())
+    }
+}
+
 /// Possible divider values usable as timer clock source.
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Divider {
@@ -133,3 +247,103 @@ pub enum Divider {
     /// Divide sysclk by 1024. Timer clock is 4.096kHz
     Div1024 = 10,
 }
+
+impl Divider {
+    /// Return the mask of the system counter bit whose falling edge
+    /// clocks the timer
+    fn mask(self) -> u16 {
+        1 << (self as u16 - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Timer;
+
+    #[test]
+    fn falling_edge() {
+        let mut timer = Timer::new();
+
+        // Enabled, 16 ticks per increment
+        timer.set_config(0x05);
+
+        for _ in 0..32 {
+            timer.step();
+        }
+        assert!(timer.counter() == 2);
+
+        // Bit 3 is high: resetting DIV is a falling edge
+        for _ in 0..8 {
+            timer.step();
+        }
+        timer.reset_div();
+        assert!(timer.counter() == 3);
+
+        // So is disabling the timer
+        for _ in 0..8 {
+            timer.step();
+        }
+        timer.set_config(0x01);
+        assert!(timer.counter() == 4);
+
+        // Switching to a divider whose bit is low too
+        timer.set_config(0x05);
+        timer.set_config(0x06);
+        assert!(timer.counter() == 5);
+        assert!(timer.div() == 0);
+        assert!(timer.config() == 0xfe);
+    }
+
+    #[test]
+    fn overflow_reload() {
+        let mut timer = Timer::new();
+
+        timer.set_modulo(0x42);
+        timer.set_counter(0xff);
+        timer.set_config(0x05);
+
+        for _ in 0..16 {
+            timer.step();
+        }
+
+        // TIMA reads 0 for one machine cycle before the reload
+        assert!(timer.counter() == 0);
+        assert!(!timer.interrupt());
+
+        for _ in 0..4 {
+            timer.step();
+        }
+        assert!(timer.counter() == 0x42);
+        assert!(timer.interrupt());
+
+        // Writes to TIMA are ignored during the reload, TMA goes
+        // through
+        timer.set_counter(0x10);
+        assert!(timer.counter() == 0x42);
+        timer.set_modulo(0x20);
+        assert!(timer.counter() == 0x20);
+
+        for _ in 0..4 {
+            timer.step();
+        }
+        timer.set_counter(0x10);
+        assert!(timer.counter() == 0x10);
+
+        // Writing TIMA before the reload cancels it
+        let mut timer = Timer::new();
+
+        timer.set_counter(0xff);
+        timer.set_config(0x05);
+
+        for _ in 0..16 {
+            timer.step();
+        }
+        timer.set_counter(0x80);
+
+        for _ in 0..8 {
+            timer.step();
+        }
+        assert!(timer.counter() == 0x80);
+        assert!(!timer.interrupt());
+    }
+}
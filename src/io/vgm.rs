@@ -0,0 +1,275 @@
+//! VGM logging of the sound registers
+//!
+//! VGM files record the register writes of sound chips along with
+//! their timing, which makes them a compact way to rip music that
+//! many tools can play back or convert. Version 1.61 of the format
+//! added the Game Boy sound unit: each write to the registers
+//! between NR10 and the end of the wave RAM is stored as a `0xb3
+//! <register - NR10> <value>` command. Delays are expressed in
+//! samples at 44.1kHz, the writes are timestamped in system clock
+//! ticks and converted when the delays are written out.
+
+use std::fs::File;
+use std::io::{BufWriter, Error, Result as IoResult, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use GAMEBOY_SYSTEM_CLOCK_FREQUENCY;
+
+/// Number of sound registers, from NR10 (0xff10) to the end of the
+/// wave RAM (0xff3f)
+const REGISTER_COUNT: usize = 0x30;
+
+/// Sample rate used for the delays
+const VGM_SAMPLE_RATE: u64 = 44100;
+
+/// Size of the header, the commands start right after it
+const HEADER_SIZE: u32 = 0x100;
+
+/// Write a VGM file
+pub struct VgmWriter<W: Write + Seek> {
+    out: W,
+    /// Number of samples waited so far
+    samples: u64,
+    /// Number of bytes of commands written so far
+    data_size: u32,
+}
+
+impl<W: Write + Seek> VgmWriter<W> {
+    /// Write the header and return the writer
+    pub fn new(mut out: W) -> IoResult<VgmWriter<W>> {
+        let mut header = [0; HEADER_SIZE as usize];
+
+        header[0..4].copy_from_slice(b"Vgm ");
+        // Version 1.61
+        set_u32(&mut header, 0x08, 0x161);
+        // Offset of the commands, relative to this field
+        set_u32(&mut header, 0x34, HEADER_SIZE - 0x34);
+        // Clock of the Game Boy sound unit
+        set_u32(&mut header, 0x80, GAMEBOY_SYSTEM_CLOCK_FREQUENCY as u32);
+
+        try!(out.write_all(&header));
+
+        Ok(VgmWriter {
+            out: out,
+            samples: 0,
+            data_size: 0,
+        })
+    }
+
+    /// Wait until `clock` system clock ticks after the beginning of
+    /// the log
+    pub fn wait_until(&mut self, clock: u64) -> IoResult<()> {
+        let target = clock * VGM_SAMPLE_RATE / GAMEBOY_SYSTEM_CLOCK_FREQUENCY;
+
+        while self.samples < target {
+            let wait = ::std::cmp::min(target - self.samples, 0xffff);
+
+            match wait {
+                // Short waits have their own commands
+                1...16 => try!(self.command(&[0x70 | (wait as u8 - 1)])),
+                735 => try!(self.command(&[0x62])),
+                882 => try!(self.command(&[0x63])),
+                _ => try!(self.command(&[0x61, wait as u8, (wait >> 8) as u8])),
+            }
+
+            self.samples += wait;
+        }
+
+        Ok(())
+    }
+
+    /// Log a write of `val` to the sound register `reg` (offset from
+    /// NR10)
+    pub fn write(&mut self, reg: u8, val: u8) -> IoResult<()> {
+        self.command(&[0xb3, reg, val])
+    }
+
+    fn command(&mut self, c: &[u8]) -> IoResult<()> {
+        self.data_size += c.len() as u32;
+
+        self.out.write_all(c)
+    }
+
+    /// End the log `clock` system clock ticks after its beginning,
+    /// fill in the header and return the underlying writer
+    pub fn finish(mut self, clock: u64) -> IoResult<W> {
+        try!(self.wait_until(clock));
+        try!(self.command(&[0x66]));
+
+        // End of file offset, relative to this field
+        try!(self.out.seek(SeekFrom::Start(0x04)));
+        try!(write_u32(&mut self.out, HEADER_SIZE + self.data_size - 0x04));
+
+        // Total number of samples
+        try!(self.out.seek(SeekFrom::Start(0x18)));
+        try!(write_u32(&mut self.out, self.samples as u32));
+
+        try!(self.out.seek(SeekFrom::End(0)));
+        try!(self.out.flush());
+
+        Ok(self.out)
+    }
+}
+
+/// Logger tapping the writes to the sound registers
+pub struct VgmLogger {
+    /// Last value written to each sound register, used to dump the
+    /// current state of the sound unit when a log starts
+    regs: [u8; REGISTER_COUNT],
+    /// Log in progress, if any
+    log: Option<Log>,
+}
+
+/// State of a log in progress
+struct Log {
+    writer: VgmWriter<BufWriter<File>>,
+    /// System clock ticks since the beginning of the log
+    clock: u64,
+    /// First error encountered while writing, reported when the log
+    /// is stopped
+    error: Option<Error>,
+}
+
+impl Log {
+    fn check(&mut self, r: IoResult<()>) {
+        if let Err(e) = r {
+            if self.error.is_none() {
+                error!("VGM logging failed: {}", e);
+                self.error = Some(e);
+            }
+        }
+    }
+}
+
+impl VgmLogger {
+    /// Create an idle logger
+    pub fn new() -> VgmLogger {
+        VgmLogger {
+            regs: [0; REGISTER_COUNT],
+            log: None,
+        }
+    }
+
+    /// Called at each tick of the system clock
+    pub fn step(&mut self) {
+        if let Some(ref mut log) = self.log {
+            log.clock += 1;
+        }
+    }
+
+    /// Handle a write to the sound register `reg` (offset from NR10)
+    pub fn write(&mut self, reg: u8, val: u8) {
+        self.regs[reg as usize] = val;
+
+        if let Some(ref mut log) = self.log {
+            let clock = log.clock;
+            let r = log.writer.wait_until(clock).and_then(|_| log.writer.write(reg, val));
+
+            log.check(r);
+        }
+    }
+
+    /// Start logging to the VGM file at `path`. A log already in
+    /// progress is stopped first.
+    pub fn start(&mut self, path: &Path) -> IoResult<()> {
+        try!(self.stop());
+
+        let file = try!(File::create(path));
+        let mut writer = try!(VgmWriter::new(BufWriter::new(file)));
+
+        // Bring the player to the current state: enable the sound
+        // unit, fill the wave RAM then restore the other registers
+        // without retriggering the channels
+        try!(writer.write(0x16, self.regs[0x16]));
+
+        for reg in 0x20..0x30 {
+            try!(writer.write(reg, self.regs[reg as usize]));
+        }
+
+        for reg in 0..0x16 {
+            let val = match reg {
+                // Unused registers
+                0x05 | 0x0f => continue,
+                // NRx4
+                0x04 | 0x09 | 0x0e | 0x13 => self.regs[reg as usize] & 0x7f,
+                _ => self.regs[reg as usize],
+            };
+
+            try!(writer.write(reg, val));
+        }
+
+        self.log = Some(Log {
+            writer: writer,
+            clock: 0,
+            error: None,
+        });
+
+        Ok(())
+    }
+
+    /// Stop the log in progress, if any. Returns the first error
+    /// encountered while logging.
+    pub fn stop(&mut self) -> IoResult<()> {
+        match self.log.take() {
+            Some(log) => {
+                if let Some(e) = log.error {
+                    return Err(e);
+                }
+
+                log.writer.finish(log.clock).map(|_| ())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Return `true` if a log is in progress
+    pub fn logging(&self) -> bool {
+        self.log.is_some()
+    }
+}
+
+fn set_u32(buf: &mut [u8], offset: usize, v: u32) {
+    for i in 0..4 {
+        buf[offset + i] = (v >> (i * 8)) as u8;
+    }
+}
+
+fn write_u32<W: Write>(w: &mut W, v: u32) -> IoResult<()> {
+    let mut buf = [0; 4];
+
+    set_u32(&mut buf, 0, v);
+
+    w.write_all(&buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::VgmWriter;
+    use std::io::Cursor;
+
+    #[test]
+    fn commands() {
+        let mut vgm = VgmWriter::new(Cursor::new(Vec::new())).unwrap();
+
+        vgm.write(0x16, 0x80).unwrap();
+        // One sample is about 95 clock ticks
+        vgm.wait_until(100).unwrap();
+        vgm.write(0x14, 0x77).unwrap();
+        // One frame at 60Hz
+        vgm.wait_until(100 + 69905).unwrap();
+
+        let data = vgm.finish(0x400000).unwrap().into_inner();
+
+        assert!(&data[0..4] == b"Vgm ");
+        assert!(data[0x80..0x84] == [0, 0, 0x40, 0]);
+        // 44100 samples in total
+        assert!(data[0x18..0x1c] == [0x44, 0xac, 0, 0]);
+
+        let commands = &data[0x100..];
+
+        assert!(commands[0..6] == [0xb3, 0x16, 0x80, 0x70, 0xb3, 0x14]);
+        assert!(commands[6..8] == [0x77, 0x62]);
+        assert!(commands[8..] == [0x61, 0x64, 0xa9, 0x66]);
+        assert!(data[0x04..0x08] == [0x08, 0x01, 0, 0]);
+    }
+}
@@ -0,0 +1,120 @@
+//! Memory watchpoints used by the debugger.
+//!
+//! The interconnect checks every memory access made by the CPU
+//! against the watchpoints and remembers the first one that matched
+//! until the debugger comes to collect it.
+
+use std::cell::Cell;
+
+/// Kind of memory access
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    /// Memory read
+    Read,
+    /// Memory write
+    Write,
+}
+
+/// Watchpoint on a single address
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    /// Watched address
+    pub addr: u16,
+    /// Trigger on reads
+    pub read: bool,
+    /// Trigger on writes
+    pub write: bool,
+}
+
+impl Watchpoint {
+    fn matches(&self, addr: u16, access: Access) -> bool {
+        self.addr == addr
+            && match access {
+                Access::Read => self.read,
+                Access::Write => self.write,
+            }
+    }
+}
+
+/// Description of a watched memory access
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WatchHit {
+    /// Address accessed
+    pub addr: u16,
+    /// Kind of access
+    pub access: Access,
+    /// Value read or written
+    pub value: u8,
+}
+
+/// Set of watchpoints
+pub struct Watchpoints {
+    points: Vec<Watchpoint>,
+    /// First watchpoint hit since the last call to `take_hit`. Reads
+    /// go through `&self` so we need interior mutability here.
+    hit: Cell<Option<WatchHit>>,
+}
+
+impl Watchpoints {
+    /// Create an empty set
+    pub fn new() -> Watchpoints {
+        Watchpoints {
+            points: Vec::new(),
+            hit: Cell::new(None),
+        }
+    }
+
+    /// Return `true` if no watchpoint is set
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    /// Add a new watchpoint, replacing any existing one on the same
+    /// address
+    pub fn add(&mut self, w: Watchpoint) {
+        self.remove(w.addr);
+        self.points.push(w);
+    }
+
+    /// Remove the watchpoint on `addr`. Returns `false` if there was
+    /// none.
+    pub fn remove(&mut self, addr: u16) -> bool {
+        let len = self.points.len();
+
+        self.points.retain(|w| w.addr != addr);
+
+        self.points.len() != len
+    }
+
+    /// Remove all the watchpoints
+    pub fn clear(&mut self) {
+        self.points.clear();
+        self.hit.set(None);
+    }
+
+    /// Return all the watchpoints
+    pub fn list(&self) -> &[Watchpoint] {
+        &self.points
+    }
+
+    /// Called on each memory access
+    pub fn check(&self, addr: u16, access: Access, value: u8) {
+        if self.hit.get().is_some() {
+            // Keep the first one
+            return;
+        }
+
+        if self.points.iter().any(|w| w.matches(addr, access)) {
+            self.hit.set(Some(WatchHit {
+                addr: addr,
+                access: access,
+                value: value,
+            }));
+        }
+    }
+
+    /// Return the first watchpoint hit since the last call, if any
+    pub fn take_hit(&self) -> Option<WatchHit> {
+        self.hit.replace(None)
+    }
+}
@@ -0,0 +1,54 @@
+//! gb-rs: Game Boy emulator core
+//! Ressources:
+//!
+//! Opcode map: http://www.pastraiser.com/cpu/gameboy/gameboy_opcodes.html
+//! JS emulator: http://imrannazar.com/GameBoy-Emulation-in-JavaScript:-The-CPU
+//! Lots of info about GC quircks: http://www.devrs.com/gb/files/faqs.html
+//! Accuracy tests: http://tasvideos.org/EmulatorResources/GBAccuracyTests.html
+//!
+//! The whole machine is wrapped in `GameBoy`, which doesn't depend on
+//! any particular frontend. The SDL2 frontend lives in `ui::sdl2` and
+//! is only built with the `sdl` feature.
+#![warn(missing_docs)]
+
+#[macro_use]
+extern crate lazy_static;
+
+#[macro_use]
+extern crate log;
+
+extern crate ascii;
+#[cfg(feature = "sdl")]
+extern crate sdl2;
+
+pub use cartridge::Cartridge;
+pub use cpu::Registers;
+pub use gameboy::{CpuCore, GameBoy, CYCLES_PER_FRAME};
+pub use gpu::{Color, Renderer};
+pub use io::serial::{Disconnected, Loopback, SerialLink, TcpLink};
+pub use spu::{samples_per_steps, Sample, SampleBuffer, AUDIO_CHANNELS, SAMPLE_RATE};
+
+mod cartridge;
+mod cpu;
+pub mod debugger;
+pub mod disasm;
+mod gameboy;
+pub mod gbs;
+mod gb_rs_cpu;
+mod gpu;
+mod io;
+pub mod movie;
+pub mod palette;
+pub mod png;
+#[cfg(feature = "sdl")]
+mod resampler;
+pub mod rewind;
+mod savestate;
+mod spu;
+pub mod testrom;
+pub mod ui;
+pub mod wav;
+pub mod y4m;
+
+/// Frequency of the Game Boy system clock in Hz
+pub const GAMEBOY_SYSTEM_CLOCK_FREQUENCY: u64 = 0x400000;
@@ -7,39 +7,48 @@
 //! Accuracy tests: http://tasvideos.org/EmulatorResources/GBAccuracyTests.html
 #![warn(missing_docs)]
 
-#[macro_use]
-extern crate lazy_static;
-
-#[macro_use]
-extern crate log;
-
-extern crate ascii;
-extern crate num;
-extern crate sdl2;
+extern crate gb_rs;
 extern crate time;
 #[cfg(windows)] extern crate winapi;
 
-use std::path::Path;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::{channel, Receiver};
+use std::sync::Arc;
 use std::time::Duration;
 #[cfg(windows)] use winapi::um::processthreadsapi::{GetCurrentProcess, SetThreadPriority};
 
-use cpu::CanRunInstruction;
-use ui::Audio;
-
-mod cartridge;
-mod cpu;
-mod gb_rs_cpu;
-mod gpu;
-mod io;
-mod resampler;
-mod spu;
-mod ui;
+use gb_rs::debugger::gdb::GdbStub;
+use gb_rs::debugger::{self, Debugger};
+use gb_rs::gbs::Gbs;
+use gb_rs::movie::{self, Movie};
+use gb_rs::palette;
+use gb_rs::rewind::Rewind;
+use gb_rs::testrom;
+use gb_rs::ui::framebuffer::{FrameBuffer, Screen};
+use gb_rs::ui::input::Bindings;
+use gb_rs::ui::{self, Audio, Rumble};
+use gb_rs::{Cartridge, CpuCore, GameBoy, Renderer, SerialLink, TcpLink, GAMEBOY_SYSTEM_CLOCK_FREQUENCY};
 
 
 const INSTRUCTIONS_BETWEEN_TICKS: u64 = 0x2000;
-const GAMEBOY_SYSTEM_CLOCK_FREQUENCY: u64 = 0x400000;
 const AUDIO_RESAMPLING_ADJUST_DELAY_SECONDS: u64 = 1;
+/// Number of sync ticks between two redraws of the screen while the
+/// emulation is paused (roughly 60 times per second)
+const PAUSED_REDRAW_TICKS: u32 = 8;
+/// Speeds cycled through by the slow motion hotkey
+const SLOW_MOTION_SPEEDS: [f32; 3] = [1., 0.5, 0.25];
+/// Default number of frames between two rewind snapshots
+const REWIND_INTERVAL_FRAMES: u32 = 4;
+/// Default memory used by the rewind history, in MiB
+const REWIND_BUFFER_MIB: usize = 32;
+/// Rewinding plays the history backwards this many times faster than
+/// it was recorded
+const REWIND_SPEED: u64 = 2;
+/// Size of the window relative to the GB screen, also used for the
+/// upscaled screenshots
+const UPSCALE: u8 = 5;
 
 
 #[allow(dead_code)]
@@ -47,62 +56,719 @@ fn main() {
     let argv: Vec<_> = std::env::args().collect();
 
     if argv.len() < 2 {
-        print!("Usage: {} <rom-file>\n", argv[0]);
+        print_usage(&argv[0]);
         return;
     }
 
+    if argv[1] == "--test-roms" {
+        let core = match argv.get(3).map(|s| s.as_str()) {
+            Some("gb-rs") => CpuCore::GbRs,
+            _ => CpuCore::Gamedorp,
+        };
+
+        match argv.get(2) {
+            Some(dir) => run_test_roms(Path::new(dir), core),
+            None => print_usage(&argv[0]),
+        }
+        return;
+    }
+
+    let mut core = CpuCore::Gamedorp;
+    let mut link: Option<Box<SerialLink>> = None;
+    let mut debug = false;
+    let mut gdb_addr = None;
+    let mut disasm = false;
+    let mut renderer = Renderer::Scanline;
+    let mut palettes = palette::presets();
+    let mut palette_name = None;
+    let mut record_path = None;
+    let mut record_stems = false;
+    let mut record_rate = None;
+    let mut vgm_path = None;
+    let mut input_path = None;
+    let mut song = None;
+    // Uncapped by default
+    let mut fast_forward = None;
+    let mut rewind_interval = REWIND_INTERVAL_FRAMES;
+    let mut rewind_buffer = REWIND_BUFFER_MIB;
+    let mut movie_record = None;
+    let mut movie_play = None;
+    let mut video_path = None;
+    let mut screenshots = Vec::new();
+    let mut screenshot_scale = 1;
+
+    let mut args = argv[2..].iter();
+
+    while let Some(arg) = args.next() {
+        let r = match arg.as_str() {
+            "gb-rs" => {
+                core = CpuCore::GbRs;
+                continue;
+            }
+            "--debug" => {
+                debug = true;
+                continue;
+            }
+            "--disasm" => {
+                disasm = true;
+                continue;
+            }
+            "--fifo" => {
+                renderer = Renderer::Fifo;
+                continue;
+            }
+            "--palette" => match args.next() {
+                Some(name) => {
+                    palette_name = Some(name);
+                    continue;
+                }
+                None => None,
+            },
+            "--palettes" => match args.next() {
+                Some(path) => match palette::load(Path::new(path)) {
+                    Ok(p) => {
+                        palettes.extend(p);
+                        continue;
+                    }
+                    Err(e) => panic!("Failed to load palettes from {}: {}", path, e),
+                },
+                None => None,
+            },
+            "--input" => match args.next() {
+                Some(path) => {
+                    input_path = Some(path);
+                    continue;
+                }
+                None => None,
+            },
+            "--record" => match args.next() {
+                Some(path) => {
+                    record_path = Some(PathBuf::from(path));
+                    continue;
+                }
+                None => None,
+            },
+            "--record-video" => match args.next() {
+                Some(path) => {
+                    video_path = Some(PathBuf::from(path));
+                    continue;
+                }
+                None => None,
+            },
+            "--record-stems" => {
+                record_stems = true;
+                continue;
+            }
+            "--record-rate" => match args.next().and_then(|r| r.parse().ok()) {
+                Some(rate) => {
+                    record_rate = Some(rate);
+                    continue;
+                }
+                None => None,
+            },
+            "--vgm" => match args.next() {
+                Some(path) => {
+                    vgm_path = Some(path);
+                    continue;
+                }
+                None => None,
+            },
+            "--track" => match args.next().and_then(|t| t.parse().ok()) {
+                Some(t) => {
+                    song = Some(t);
+                    continue;
+                }
+                None => None,
+            },
+            "--fast-forward" => match args.next().and_then(|f| f.parse::<f32>().ok()) {
+                Some(f) if f >= 0. => {
+                    fast_forward = if f > 0. { Some(f) } else { None };
+                    continue;
+                }
+                _ => None,
+            },
+            "--rewind-interval" => match args.next().and_then(|f| f.parse().ok()) {
+                Some(f) if f > 0 => {
+                    rewind_interval = f;
+                    continue;
+                }
+                _ => None,
+            },
+            "--rewind-buffer" => match args.next().and_then(|m| m.parse().ok()) {
+                Some(m) => {
+                    rewind_buffer = m;
+                    continue;
+                }
+                None => None,
+            },
+            "--movie-record" => match args.next() {
+                Some(path) => {
+                    movie_record = Some(path);
+                    continue;
+                }
+                None => None,
+            },
+            "--movie-play" => match args.next() {
+                Some(path) => {
+                    movie_play = Some(path);
+                    continue;
+                }
+                None => None,
+            },
+            "--screenshot-at" => match (args.next().and_then(|f| f.parse::<u64>().ok()), args.next()) {
+                (Some(frame), Some(path)) => {
+                    screenshots.push((frame, PathBuf::from(path)));
+                    continue;
+                }
+                _ => None,
+            },
+            "--screenshot-scaled" => {
+                screenshot_scale = UPSCALE as u32;
+                continue;
+            }
+            "--gdb" => match args.next() {
+                Some(addr) => {
+                    gdb_addr = Some(addr);
+                    continue;
+                }
+                None => None,
+            },
+            "--link-listen" => args.next().map(|addr| {
+                println!("Waiting for link cable peer on {}", addr);
+                TcpLink::listen(addr.as_str())
+            }),
+            "--link-connect" => args.next().map(|addr| TcpLink::connect(addr.as_str())),
+            _ => None,
+        };
+
+        match r {
+            Some(Ok(l)) => link = Some(Box::new(l)),
+            Some(Err(e)) => panic!("Failed to setup link cable: {}", e),
+            None => {
+                print_usage(&argv[0]);
+                return;
+            }
+        }
+    }
+
     let rompath = Path::new(&argv[1]);
 
-    let cart = match cartridge::Cartridge::from_path(&rompath) {
-        Ok(r) => r,
-        Err(e) => panic!("Failed to load ROM: {}", e),
+    // GBS files are played by a ROM generated around the music data
+    let gbs = match rompath.extension().map_or(false, |e| e.eq_ignore_ascii_case("gbs")) {
+        true => match Gbs::from_path(&rompath) {
+            Ok(gbs) => Some(gbs),
+            Err(e) => panic!("Failed to load GBS: {}", e),
+        },
+        false => None,
+    };
+
+    let cart = match gbs {
+        Some(ref gbs) => gbs.cartridge(&rompath),
+        None => match Cartridge::from_path(&rompath) {
+            Ok(r) => r,
+            Err(e) => panic!("Failed to load ROM: {}", e),
+        },
     };
 
+    if disasm {
+        let stdout = io::stdout();
+
+        if let Err(e) = gb_rs::disasm::disassemble_rom(cart.rom(), &mut stdout.lock()) {
+            panic!("Failed to write disassembly: {}", e);
+        }
+        return;
+    }
+
     print!("Loaded ROM {:?}\n", cart);
 
-    let sdl2 = ui::sdl2::Context::new();
-    let mut display = sdl2.new_display(5, true);
-    let gpu = gpu::Gpu::new(&mut display);
-    let (spu, audio_channel) = spu::Spu::new();
+    let mut sdl2 = ui::sdl2::Context::new();
+
+    if let Some(path) = input_path {
+        let title = cart.name();
+
+        let r = Bindings::load(Path::new(path), title.as_ref().map(|t| t.as_str()))
+            .map_err(|e| e.to_string())
+            .and_then(|b| sdl2.set_bindings(&b));
+
+        if let Err(e) = r {
+            panic!("Failed to load input bindings from {}: {}", path, e);
+        }
+    }
+    let display = FrameBuffer::new(Box::new(sdl2.new_display(UPSCALE, true)));
+    let screen = display.screen();
+
+    let mut gameboy = GameBoy::new(cart, Box::new(display), core);
+
+    gameboy.set_renderer(renderer);
+
+    let mut palette_index = match palette_name {
+        Some(name) => match palettes.iter().position(|p| p.name == *name) {
+            Some(i) => i,
+            None => {
+                let names: Vec<_> = palettes.iter().map(|p| p.name.as_str()).collect();
+
+                panic!("Unknown palette {}, available: {}", name, names.join(", "));
+            }
+        },
+        None => 0,
+    };
+
+    gameboy.set_dmg_palette(palettes[palette_index].clone());
+
+    if let Some(link) = link {
+        gameboy.set_serial_link(link);
+    }
+
+    let mut song = song.unwrap_or(gbs.as_ref().map_or(1, |gbs| gbs.first_song()));
+
+    if let Some(ref gbs) = gbs {
+        println!("{} - {} ({})", gbs.title(), gbs.author(), gbs.copyright());
+        println!("Use left and right to change songs");
+
+        song = song.max(1).min(gbs.songs());
+        gbs.start(&mut gameboy, song);
+        print_song(gbs, song);
+    }
+
+    if let Some(path) = vgm_path {
+        match gameboy.start_vgm_log(Path::new(path)) {
+            Ok(()) => println!("Logging sound registers to {}", path),
+            Err(e) => println!("Couldn't log sound registers to {}: {}", path, e),
+        }
+    }
+
+    if let Some(path) = movie_play {
+        let r = Movie::load(Path::new(path)).and_then(|m| gameboy.play_movie(m));
+
+        match r {
+            Ok(()) => println!("Playing movie {}", path),
+            Err(e) => panic!("Failed to play movie {}: {}", path, e),
+        }
+    } else if movie_record.is_some() {
+        if let Err(e) = gameboy.start_movie_recording() {
+            panic!("Failed to record movie: {}", e);
+        }
+    }
+
+    // Recordings started from the hotkey go next to the ROM unless
+    // a path was given
+    let record_base = record_path.clone().unwrap_or(rompath.with_extension("wav"));
+
+    // The video recording takes over the audio recorder
+    if let Some(ref path) = video_path {
+        start_video_recording(&mut gameboy, path);
+    } else if let Some(ref path) = record_path {
+        start_audio_recording(&mut gameboy, path, record_stems, record_rate);
+    }
+
+    let video_base = rompath.with_extension("y4m");
+    let screenshot_base = rompath.with_extension("png");
+
+    let audio_channel = gameboy.take_audio_channel().unwrap();
     let mut audio = ui::sdl2::Audio::new(audio_channel, &sdl2.sdl2);
     audio.start();
-    let inter = io::Interconnect::new(cart, gpu, spu, sdl2.buttons());
 
-    let mut cpu: Box<CanRunInstruction> = if argv.len() > 2 && &argv[2] == "gb-rs" {
-        Box::new(::gb_rs_cpu::Cpu::new(inter))
-    } else {
-        Box::new(cpu::Cpu::new(inter))
+    let mut gdb = gdb_addr.map(|addr| {
+        println!("Waiting for GDB on {}", addr);
+
+        match GdbStub::listen(addr.as_str()) {
+            Ok(stub) => stub,
+            Err(e) => panic!("Failed to start GDB stub: {}", e),
+        }
+    });
+
+    let mut speed = Speed {
+        paused: false,
+        fast_forward: false,
+        fast_forward_factor: fast_forward,
+        slow_motion: 0,
+        rewind: false,
     };
 
-    let tick_rx = start_sending_sync_ticks();
+    // A 0 buffer disables rewinding
+    let mut rewind = match rewind_buffer {
+        0 => None,
+        mib => Some(Rewind::new(rewind_interval, mib << 20)),
+    };
+    let rewind_step_ticks = (rewind_interval as u64 * gb_rs::CYCLES_PER_FRAME / (INSTRUCTIONS_BETWEEN_TICKS * REWIND_SPEED)).max(1);
+    let mut rewind_ticks = 0;
+
+    let tick_speed = Arc::new(AtomicUsize::new(100));
+    let tick_rx = start_sending_sync_ticks(tick_speed.clone());
+
+    // The debugger starts paused so that breakpoints can be set
+    // before the first instruction runs
+    let mut debugger = match debug {
+        true => {
+            print!("Debugger started, type 'help' for the list of commands\n> ");
+            Some((Debugger::new(true), debugger::stdin_commands()))
+        }
+        false => None,
+    };
 
     let mut audio_adjust_count = 0;
     let mut cycles: u64 = 0;
+    let mut paused_ticks = 0;
+    let mut last_buttons = sdl2.buttons().get();
 
     loop {
-        while cycles < INSTRUCTIONS_BETWEEN_TICKS {
-            // The actual emulator takes place here!
-            cycles += cpu.run_next_instruction() as u64;
+        // The actual emulator takes place here!
+        let paused = if let Some((ref mut debugger, ref commands)) = debugger {
+            while let Ok(line) = commands.try_recv() {
+                print!("{}> ", debugger.execute(&mut gameboy, &line));
+            }
+
+            let (elapsed, stop) = debugger.run(&mut gameboy, INSTRUCTIONS_BETWEEN_TICKS - cycles);
+
+            if let Some(reason) = stop {
+                print!("{}> ", debugger.report(&gameboy, reason));
+            }
+
+            // We may have stopped early
+            cycles = (cycles + elapsed).saturating_sub(INSTRUCTIONS_BETWEEN_TICKS);
+
+            debugger.paused()
+        } else if let Some(ref mut gdb) = gdb {
+            let elapsed = gdb.run(&mut gameboy, INSTRUCTIONS_BETWEEN_TICKS - cycles);
+
+            if gdb.killed() {
+                break;
+            }
+
+            cycles = (cycles + elapsed).saturating_sub(INSTRUCTIONS_BETWEEN_TICKS);
+
+            gdb.paused()
+        } else if speed.paused {
+            true
+        } else if speed.rewind {
+            if rewind_ticks % rewind_step_ticks == 0 {
+                if let Some(ref mut rewind) = rewind {
+                    match rewind.step_back(&mut gameboy) {
+                        Ok(_) => gameboy.redraw(),
+                        Err(e) => {
+                            println!("Rewind failed: {}", e);
+                            speed.rewind = false;
+                        }
+                    }
+                }
+            }
+            rewind_ticks += 1;
+            true
+        } else {
+            cycles += gameboy.run_cycles(INSTRUCTIONS_BETWEEN_TICKS - cycles);
+            cycles -= INSTRUCTIONS_BETWEEN_TICKS;
+
+            if let Some(ref mut rewind) = rewind {
+                rewind.record(&gameboy);
+            }
+            false
+        };
+        let _ = io::stdout().flush();
+        // Keep the window alive while the emulation is stopped
+        if paused && !speed.rewind {
+            if paused_ticks % PAUSED_REDRAW_TICKS == 0 {
+                gameboy.redraw();
+            }
+            paused_ticks += 1;
+        } else {
+            paused_ticks = 0;
         }
-        cycles -= INSTRUCTIONS_BETWEEN_TICKS;
+        // Pausing and frame advance are left to the debugger when
+        // there's one
+        let standalone = debugger.is_none() && gdb.is_none();
+        let current_speed = speed;
+
         // Update controller status
         match sdl2.update_buttons() {
             ui::Event::PowerOff => break,
+            ui::Event::SaveState(slot) => {
+                let path = gameboy.state_slot_path(slot);
+
+                match gameboy.save_state(&path) {
+                    Ok(()) => println!("Saved state to {}", path.display()),
+                    Err(e) => println!("Couldn't save state to {}: {}", path.display(), e),
+                }
+            }
+            ui::Event::LoadState(_) if gameboy.movie_active() => {
+                println!("Can't load a state while a movie is active");
+            }
+            ui::Event::LoadState(slot) => {
+                let path = gameboy.state_slot_path(slot);
+
+                match gameboy.load_state(&path) {
+                    Ok(()) => println!("Loaded state from {}", path.display()),
+                    Err(e) => println!("Couldn't load state from {}: {}", path.display(), e),
+                }
+            }
+            ui::Event::NextPalette => {
+                palette_index = (palette_index + 1) % palettes.len();
+
+                let palette = &palettes[palette_index];
+
+                println!("Palette: {}", palette.name);
+                gameboy.set_dmg_palette(palette.clone());
+            }
+            ui::Event::ToggleAudioRecording if gameboy.video_recording() => {
+                println!("The audio is already being recorded along with the video");
+            }
+            ui::Event::ToggleAudioRecording => {
+                if gameboy.audio_recording() {
+                    stop_audio_recording(&mut gameboy);
+                } else {
+                    // Don't overwrite the previous recordings
+                    let path = (1..).map(|take| take_path(&record_base, take)).find(|p| !p.exists()).unwrap();
+
+                    start_audio_recording(&mut gameboy, &path, record_stems, record_rate);
+                }
+            }
+            ui::Event::ToggleVideoRecording => {
+                if gameboy.video_recording() {
+                    stop_video_recording(&mut gameboy);
+                } else {
+                    // The sound goes to a WAV file next to the video,
+                    // neither should be overwritten
+                    let path = (1..)
+                        .map(|take| take_path(&video_base, take))
+                        .find(|p| !p.exists() && !p.with_extension("wav").exists())
+                        .unwrap();
+
+                    start_video_recording(&mut gameboy, &path);
+                }
+            }
+            ui::Event::TogglePause => {
+                if standalone {
+                    speed.paused = !speed.paused;
+                    println!("{}", if speed.paused { "Paused" } else { "Resumed" });
+                }
+            }
+            ui::Event::FrameAdvance => {
+                if standalone {
+                    speed.paused = true;
+                    gameboy.run_frame();
+                }
+            }
+            ui::Event::FastForward(on) => speed.fast_forward = on,
+            ui::Event::Rewind(on) => {
+                speed.rewind = on && standalone && rewind.is_some() && !gameboy.movie_active();
+                rewind_ticks = 0;
+            }
+            ui::Event::SlowMotion => {
+                speed.slow_motion = (speed.slow_motion + 1) % SLOW_MOTION_SPEEDS.len();
+                println!("Speed: {}%", SLOW_MOTION_SPEEDS[speed.slow_motion] * 100.);
+            }
+            ui::Event::Screenshot(scaled) => {
+                // Don't overwrite the screenshots taken in previous
+                // sessions
+                let path = (1..).map(|take| take_path(&screenshot_base, take)).find(|p| !p.exists()).unwrap();
+                let scale = if scaled { UPSCALE as u32 } else { 1 };
+
+                save_screenshot(&screen, &path, scale);
+            }
             ui::Event::None => (),
         }
-        // Sleep until next batch cycle
+
+        // Frames of emulated time like movies, the GPU doesn't count
+        // them while the LCD is off. Only the frames completed so far
+        // can be captured, the one being drawn is still incomplete.
+        let frame_count = gameboy.cycles() / gb_rs::CYCLES_PER_FRAME;
+
+        while let Some(i) = screenshots.iter().position(|&(frame, _)| frame_count >= frame) {
+            let (_, path) = screenshots.remove(i);
+
+            save_screenshot(&screen, &path, screenshot_scale);
+        }
+
+        if speed != current_speed {
+            // Start measuring the audio sample rate over again
+            audio_adjust_count = 0;
+
+            let factor = speed.factor();
+
+            // Audio can't keep up when uncapped and there's nothing
+            // sensible to play while rewinding
+            gameboy.set_audio_muted(factor.is_none() || speed.rewind);
+            audio.set_speed(factor.unwrap_or(1.));
+            tick_speed.store(factor.map_or(0, |f| (f * 100.) as usize), Ordering::Relaxed);
+        }
+        match gameboy.take_movie_event() {
+            Some(movie::Event::Desync(frame)) => println!("Movie desynchronized at frame {}", frame),
+            Some(movie::Event::Finished) => println!("Movie finished"),
+            None => (),
+        }
+        let buttons = sdl2.buttons().get();
+
+        if let Some(ref gbs) = gbs {
+            let previous = song;
+
+            if buttons.left.is_down() && !last_buttons.left.is_down() && song > 1 {
+                song -= 1;
+            }
+            if buttons.right.is_down() && !last_buttons.right.is_down() && song < gbs.songs() {
+                song += 1;
+            }
+
+            if song != previous {
+                gbs.select_song(&mut gameboy, song);
+                print_song(gbs, song);
+            }
+        }
+
+        last_buttons = buttons;
+        gameboy.set_buttons(buttons);
+        sdl2.set_rumble(gameboy.take_rumble());
+        // Sleep until next batch cycle, unless we're running as fast
+        // as possible
+        if speed.factor().is_none() && !paused {
+            while let Ok(()) = tick_rx.try_recv() {}
+            continue;
+        }
         if let Err(e) = tick_rx.recv() {
             panic!("Timer died: {:?}", e);
         }
+        if paused {
+            continue;
+        }
         audio_adjust_count += INSTRUCTIONS_BETWEEN_TICKS;
         if audio_adjust_count >= GAMEBOY_SYSTEM_CLOCK_FREQUENCY * AUDIO_RESAMPLING_ADJUST_DELAY_SECONDS {
             // Retrieve the number of samples generated since the last
             // adjustment
-            let s = spu::samples_per_steps(audio_adjust_count as u32);
+            let s = gb_rs::samples_per_steps(audio_adjust_count as u32);
             audio.adjust_resampling(s);
             audio_adjust_count = 0;
         }
     }
+
+    stop_video_recording(&mut gameboy);
+    stop_audio_recording(&mut gameboy);
+
+    if let (Some(path), true) = (movie_record, gameboy.movie_recording()) {
+        if let Some(m) = gameboy.stop_movie() {
+            match m.save(Path::new(path)) {
+                Ok(()) => println!("Saved movie ({} frames) to {}", m.frames(), path),
+                Err(e) => println!("Couldn't save movie to {}: {}", path, e),
+            }
+        }
+    }
+
+    if gameboy.vgm_logging() {
+        if let Err(e) = gameboy.stop_vgm_log() {
+            println!("VGM logging failed: {}", e);
+        }
+    }
+}
+
+/// Emulation speed controlled by the hotkeys
+#[derive(Clone, Copy, PartialEq)]
+struct Speed {
+    /// `true` while the emulation is paused
+    paused: bool,
+    /// `true` while fast forward is held
+    fast_forward: bool,
+    /// Speed factor while fast forwarding, `None` to run as fast as
+    /// possible
+    fast_forward_factor: Option<f32>,
+    /// Index of the current speed in `SLOW_MOTION_SPEEDS`
+    slow_motion: usize,
+    /// `true` while rewind is held
+    rewind: bool,
+}
+
+impl Speed {
+    /// Return the speed factor relative to the real hardware or
+    /// `None` if it's uncapped
+    fn factor(&self) -> Option<f32> {
+        match self.fast_forward {
+            true => self.fast_forward_factor,
+            false => Some(SLOW_MOTION_SPEEDS[self.slow_motion]),
+        }
+    }
+}
+
+fn print_song(gbs: &Gbs, song: u8) {
+    println!("Song {}/{}", song, gbs.songs());
+}
+
+fn start_audio_recording(gameboy: &mut GameBoy, path: &Path, stems: bool, rate: Option<u32>) {
+    match gameboy.start_audio_recording(path, stems, rate) {
+        Ok(()) => println!("Recording audio to {}", path.display()),
+        Err(e) => println!("Couldn't record audio to {}: {}", path.display(), e),
+    }
+}
+
+fn stop_audio_recording(gameboy: &mut GameBoy) {
+    if !gameboy.audio_recording() {
+        return;
+    }
+
+    match gameboy.stop_audio_recording() {
+        Ok(()) => println!("Audio recording stopped"),
+        Err(e) => println!("Audio recording failed: {}", e),
+    }
+}
+
+fn start_video_recording(gameboy: &mut GameBoy, path: &Path) {
+    match gameboy.start_video_recording(path) {
+        Ok(()) => println!("Recording video to {} and {}", path.display(), path.with_extension("wav").display()),
+        Err(e) => println!("Couldn't record video to {}: {}", path.display(), e),
+    }
+}
+
+fn stop_video_recording(gameboy: &mut GameBoy) {
+    if !gameboy.video_recording() {
+        return;
+    }
+
+    match gameboy.stop_video_recording() {
+        Ok(()) => println!("Video recording stopped"),
+        Err(e) => println!("Video recording failed: {}", e),
+    }
+}
+
+/// Return the path of the recording number `take`: "song.wav" for
+/// the first one, then "song-2.wav", "song-3.wav"...
+fn take_path(base: &Path, take: u32) -> PathBuf {
+    if take <= 1 {
+        return base.to_path_buf();
+    }
+
+    let stem = base.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+    let mut path = base.with_file_name(format!("{}-{}", stem, take));
+
+    if let Some(ext) = base.extension() {
+        path.set_extension(ext);
+    }
+
+    path
+}
+
+fn save_screenshot(screen: &Screen, path: &Path, scale: u32) {
+    match screen.save_png(path, scale) {
+        Ok(()) => println!("Saved screenshot to {}", path.display()),
+        Err(e) => println!("Couldn't save screenshot to {}: {}", path.display(), e),
+    }
+}
+
+fn print_usage(prog: &str) {
+    print!(
+        "Usage: {} <rom-file | gbs-file> [gb-rs] [--fifo] [--palette <name>] [--palettes <file>] [--input <file>] [--record <file.wav>] [--record-stems] [--record-rate <hz>] [--record-video <file.y4m>] [--vgm <file.vgm>] [--track <n>] [--fast-forward <factor>] [--rewind-interval <frames>] [--rewind-buffer <MiB>] [--movie-record <file> | --movie-play <file>] [--screenshot-at <frame> <file.png>]... [--screenshot-scaled] [--disasm | --debug | --gdb <addr>] [--link-listen <addr> | --link-connect <addr>]\n",
+        prog
+    );
+    print!("       {} --test-roms <dir> [gb-rs]\n", prog);
+}
+
+/// Run all the test ROMs found in `dir` and print a report. Exits
+/// with an error status if any of them didn't pass.
+fn run_test_roms(dir: &Path, core: CpuCore) {
+    let results = match testrom::run_dir(dir, core) {
+        Ok(r) => r,
+        Err(e) => panic!("Failed to run test ROMs: {}", e),
+    };
+
+    print!("{}", testrom::report(&results));
+
+    if results.iter().any(|&(_, ref o)| *o != testrom::Outcome::Passed) {
+        std::process::exit(1);
+    }
 }
 
 // In order to synchronize the emulation speed with the wall clock
@@ -113,8 +779,11 @@ fn main() {
 // for a while. If the GRANULARITY value is too low we'll go to
 // sleep very often which will have poor performance. If it's too
 // high it might look like the emulation is stuttering.
-fn start_sending_sync_ticks() -> Receiver<()> {
-    let batch_duration = (INSTRUCTIONS_BETWEEN_TICKS as f64 * (1_000_000_000 as f64 / GAMEBOY_SYSTEM_CLOCK_FREQUENCY as f64)) as u64;
+//
+// `speed` is the emulation speed in percent of the real hardware,
+// the ticks are sent at the normal rate when it's 0 (uncapped).
+fn start_sending_sync_ticks(speed: Arc<AtomicUsize>) -> Receiver<()> {
+    let normal_duration = (INSTRUCTIONS_BETWEEN_TICKS as f64 * (1_000_000_000 as f64 / GAMEBOY_SYSTEM_CLOCK_FREQUENCY as f64)) as u64;
     let (tick_tx, tick_rx) = channel();
 
     ::std::thread::spawn(move || {
@@ -125,6 +794,10 @@ fn start_sending_sync_ticks() -> Receiver<()> {
         }
         let mut last_time = time::precise_time_ns();
         loop {
+            let batch_duration = match speed.load(Ordering::Relaxed) {
+                0 => normal_duration,
+                percent => normal_duration * 100 / percent as u64,
+            };
             let current_time = time::precise_time_ns();
             let duration_since_last_tick = current_time - last_time;
             if duration_since_last_tick > batch_duration {
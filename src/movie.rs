@@ -0,0 +1,508 @@
+//! Input movies: exact replays of a play session
+//!
+//! A movie starts at power on. It stores what's needed to bring the
+//! machine back to the same power on state (the ROM checksum, the
+//! bootrom the emulator was built with, the cartridge RAM loaded from
+//! the save file and the registers of the real time clock) followed by
+//! one entry per frame.
+//!
+//! Frames are counted on the system clock (`CYCLES_PER_FRAME` ticks
+//! each) so that they keep going while the LCD is off. While a movie
+//! is active the inputs set by the frontend are only applied at the
+//! frame boundaries: that's when they're sampled while recording and
+//! fed back while playing, which makes the replay independent of the
+//! way the frontend schedules the emulation. Each entry also stores a
+//! checksum of the machine state at the boundary, a mismatch during
+//! playback means the replay went off track.
+//!
+//! File layout, little endian:
+//!
+//! ```text
+//! "GBRSMOVI" | version: u32 | ROM checksum: u16 | SGB bootrom: u8
+//! RAM size: u32 | RAM | has RTC: u8 | RTC registers: 10 bytes if any
+//! frame count: u32 | frames: (buttons: u8, checksum: u32)...
+//! ```
+
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Result as IoResult, Write};
+use std::path::Path;
+
+use gameboy::CYCLES_PER_FRAME;
+use savestate::{StateReader, StateWriter};
+use ui::{ButtonState, Buttons};
+
+/// Magic string found at the beginning of every movie file
+const MAGIC: &'static [u8; 8] = b"GBRSMOVI";
+
+/// Version of the movie format
+const VERSION: u32 = 1;
+
+/// `true` if the emulator is built with the Super Game Boy bootrom
+const SGB_BOOTROM: bool = cfg!(feature = "sgb_bootrom");
+
+/// Input movie
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Movie {
+    /// Checksum of the ROM the movie was recorded with
+    rom_checksum: u16,
+    /// `true` if the movie was recorded with the SGB bootrom
+    sgb_bootrom: bool,
+    /// Cartridge RAM at power on
+    ram: Vec<u8>,
+    /// Registers of the real time clock at power on, if the
+    /// cartridge has one
+    rtc: Option<[u8; 10]>,
+    /// Inputs and state checksums
+    frames: Vec<Frame>,
+}
+
+/// One frame of a movie
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Frame {
+    /// Buttons held during the frame, see `pack_buttons`
+    buttons: u8,
+    /// Checksum of the machine state at the beginning of the frame
+    checksum: u32,
+}
+
+impl Movie {
+    /// Create an empty movie starting from the given power on state
+    pub fn new(rom_checksum: u16, ram: Vec<u8>, rtc: Option<[u8; 10]>) -> Movie {
+        Movie {
+            rom_checksum: rom_checksum,
+            sgb_bootrom: SGB_BOOTROM,
+            ram: ram,
+            rtc: rtc,
+            frames: Vec::new(),
+        }
+    }
+
+    /// Load a movie from the file at `path`
+    pub fn load(path: &Path) -> IoResult<Movie> {
+        let mut data = Vec::new();
+
+        try!(try!(File::open(path)).read_to_end(&mut data));
+
+        Movie::parse(&data)
+    }
+
+    /// Write the movie to the file at `path`
+    pub fn save(&self, path: &Path) -> IoResult<()> {
+        let mut f = try!(File::create(path));
+
+        f.write_all(&self.to_bytes())
+    }
+
+    fn parse(data: &[u8]) -> IoResult<Movie> {
+        let mut r = StateReader::new(data);
+
+        let mut magic = [0; 8];
+        try!(r.read_bytes(&mut magic));
+        if &magic != MAGIC {
+            return invalid("not a movie");
+        }
+
+        let version = try!(r.read_u32());
+        if version != VERSION {
+            return invalid(&format!("unsupported movie version {} (expected {})", version, VERSION));
+        }
+
+        let rom_checksum = try!(r.read_u16());
+        let sgb_bootrom = try!(r.read_bool());
+
+        // Don't trust the length with the allocation
+        let ram_len = try!(r.read_u32()) as usize;
+        if ram_len > r.remaining() {
+            return invalid("truncated movie");
+        }
+
+        let mut ram = vec![0; ram_len];
+        try!(r.read_bytes(&mut ram));
+
+        let rtc = match try!(r.read_bool()) {
+            true => {
+                let mut regs = [0; 10];
+                try!(r.read_bytes(&mut regs));
+                Some(regs)
+            }
+            false => None,
+        };
+
+        let count = try!(r.read_u32());
+        let mut frames = Vec::new();
+
+        for _ in 0..count {
+            let buttons = try!(r.read_u8());
+            let checksum = try!(r.read_u32());
+
+            frames.push(Frame {
+                buttons: buttons,
+                checksum: checksum,
+            });
+        }
+
+        if !r.is_empty() {
+            return invalid("trailing data");
+        }
+
+        Ok(Movie {
+            rom_checksum: rom_checksum,
+            sgb_bootrom: sgb_bootrom,
+            ram: ram,
+            rtc: rtc,
+            frames: frames,
+        })
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+
+        w.write_bytes(MAGIC);
+        w.write_u32(VERSION);
+        w.write_u16(self.rom_checksum);
+        w.write_bool(self.sgb_bootrom);
+        w.write_u32(self.ram.len() as u32);
+        w.write_bytes(&self.ram);
+        w.write_bool(self.rtc.is_some());
+        if let Some(ref regs) = self.rtc {
+            w.write_bytes(regs);
+        }
+        w.write_u32(self.frames.len() as u32);
+        for f in &self.frames {
+            w.write_u8(f.buttons);
+            w.write_u32(f.checksum);
+        }
+
+        w.into_bytes()
+    }
+
+    /// Return an error if the movie can't be played back on a
+    /// cartridge with the given checksum and power on state
+    pub fn check(&self, rom_checksum: u16, ram_size: usize, rtc: bool) -> IoResult<()> {
+        if self.rom_checksum != rom_checksum {
+            return invalid("movie was recorded with a different ROM");
+        }
+
+        if self.sgb_bootrom != SGB_BOOTROM {
+            return invalid(match self.sgb_bootrom {
+                true => "movie was recorded with the SGB bootrom",
+                false => "movie was recorded with the DMG bootrom",
+            });
+        }
+
+        if self.ram.len() != ram_size || self.rtc.is_some() != rtc {
+            return invalid("movie doesn't match the cartridge hardware");
+        }
+
+        Ok(())
+    }
+
+    /// Return the cartridge RAM at power on
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    /// Return the registers of the real time clock at power on
+    pub fn rtc(&self) -> Option<&[u8; 10]> {
+        self.rtc.as_ref()
+    }
+
+    /// Return the number of frames in the movie
+    pub fn frames(&self) -> usize {
+        self.frames.len()
+    }
+}
+
+/// Something worth telling the user about a movie
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// The state of the machine doesn't match the movie anymore,
+    /// starting at the given frame. Only reported once.
+    Desync(usize),
+    /// Playback reached the end of the movie, the inputs are handed
+    /// back to the frontend
+    Finished,
+}
+
+/// Movie being recorded or played back
+pub struct Session {
+    movie: Movie,
+    /// Index of the next frame to play back, `None` while recording
+    playback: Option<usize>,
+    /// System clock ticks since the last frame boundary
+    ticks: u64,
+    /// Last buttons set by the frontend, recorded at the next frame
+    /// boundary
+    pending: u8,
+    /// `true` once a desync has been reported
+    desynced: bool,
+    /// Event waiting to be picked up
+    event: Option<Event>,
+}
+
+impl Session {
+    /// Start recording `movie`, which must be empty
+    pub fn record(movie: Movie) -> Session {
+        Session::new(movie, None)
+    }
+
+    /// Start playing `movie` back
+    pub fn play(movie: Movie) -> Session {
+        Session::new(movie, Some(0))
+    }
+
+    fn new(movie: Movie, playback: Option<usize>) -> Session {
+        Session {
+            movie: movie,
+            playback: playback,
+            ticks: 0,
+            pending: 0,
+            desynced: false,
+            event: None,
+        }
+    }
+
+    /// Return `true` while the movie drives the inputs: always when
+    /// recording, until the end of the movie when playing back
+    pub fn active(&self) -> bool {
+        match self.playback {
+            Some(f) => f < self.movie.frames.len(),
+            None => true,
+        }
+    }
+
+    /// Handle the buttons set by the frontend. Returns `true` if the
+    /// movie takes care of the inputs, in which case they must not be
+    /// applied directly.
+    pub fn set_buttons(&mut self, buttons: Buttons) -> bool {
+        if self.playback.is_none() {
+            self.pending = pack_buttons(buttons);
+        }
+
+        self.active()
+    }
+
+    /// Count `ticks` system clock ticks. Returns `true` when a frame
+    /// boundary is crossed, `frame` must then be called.
+    pub fn step(&mut self, ticks: u64) -> bool {
+        if !self.active() {
+            return false;
+        }
+
+        self.ticks += ticks;
+
+        if self.ticks >= CYCLES_PER_FRAME {
+            self.ticks -= CYCLES_PER_FRAME;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Handle a frame boundary, `checksum` being the checksum of the
+    /// machine state. Returns the buttons to apply for the next frame.
+    pub fn frame(&mut self, checksum: u32) -> Buttons {
+        let index = match self.playback {
+            Some(ref mut i) => {
+                *i += 1;
+                *i - 1
+            }
+            None => {
+                self.movie.frames.push(Frame {
+                    buttons: self.pending,
+                    checksum: checksum,
+                });
+
+                return unpack_buttons(self.pending);
+            }
+        };
+
+        let frame = self.movie.frames[index];
+
+        if frame.checksum != checksum && !self.desynced {
+            self.desynced = true;
+            self.event = Some(Event::Desync(index));
+        }
+
+        if !self.active() {
+            self.event = Some(Event::Finished);
+        }
+
+        unpack_buttons(frame.buttons)
+    }
+
+    /// Return the event that occurred since the last call, if any.
+    /// A desync hidden by the end of the movie on the same frame is
+    /// lost, which is fine since the user can't do anything about it
+    /// anymore.
+    pub fn take_event(&mut self) -> Option<Event> {
+        self.event.take()
+    }
+
+    /// Return `true` if the movie is being recorded
+    pub fn recording(&self) -> bool {
+        self.playback.is_none()
+    }
+
+    /// Stop the session and return the movie
+    pub fn into_movie(self) -> Movie {
+        self.movie
+    }
+}
+
+/// Compute the checksum of a machine state (32bit FNV-1a)
+pub fn checksum(state: &[u8]) -> u32 {
+    state.iter().fold(0x811c9dc5, |h, &b| (h ^ b as u32).wrapping_mul(0x01000193))
+}
+
+/// Pack the state of the buttons in a byte, in the order of the P1
+/// register: right, left, up, down, A, B, select, start
+fn pack_buttons(buttons: Buttons) -> u8 {
+    let order = [
+        buttons.right,
+        buttons.left,
+        buttons.up,
+        buttons.down,
+        buttons.a,
+        buttons.b,
+        buttons.select,
+        buttons.start,
+    ];
+
+    order.iter().enumerate().fold(0, |p, (i, b)| p | ((b.is_down() as u8) << i))
+}
+
+/// Unpack buttons packed by `pack_buttons`
+fn unpack_buttons(packed: u8) -> Buttons {
+    let state = |bit: u8| match packed & (1 << bit) != 0 {
+        true => ButtonState::Down,
+        false => ButtonState::Up,
+    };
+
+    Buttons {
+        right: state(0),
+        left: state(1),
+        up: state(2),
+        down: state(3),
+        a: state(4),
+        b: state(5),
+        select: state(6),
+        start: state(7),
+        interrupt: false,
+    }
+}
+
+/// Return an `InvalidData` error with the given description
+fn invalid<T>(what: &str) -> IoResult<T> {
+    Err(Error::new(ErrorKind::InvalidData, what))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pack_buttons, unpack_buttons, Event, Movie};
+    use gameboy::{self, CpuCore, GameBoy};
+    use ui::{ButtonState, Buttons};
+
+    /// Build a GameBoy polling the buttons in a loop and storing the
+    /// result at 0xc000
+    fn gameboy() -> GameBoy {
+        gameboy::test_gameboy(
+            &[
+                0x3e, 0x10, // LD A, 0x10
+                0xe0, 0x00, // LDH [INPUT], A
+                0xf0, 0x00, // LDH A, [INPUT]
+                0xea, 0x00, 0xc0, // LD [0xc000], A
+                0x18, 0xf5, // JR 0x100
+            ],
+            CpuCore::Gamedorp,
+        )
+    }
+
+    #[test]
+    fn buttons() {
+        for p in 0..0x100 {
+            assert!(pack_buttons(unpack_buttons(p as u8)) == p as u8);
+        }
+
+        let mut b = Buttons::new(ButtonState::Up);
+        b.start = ButtonState::Down;
+        b.up = ButtonState::Down;
+
+        assert!(pack_buttons(b) == 0x84);
+    }
+
+    #[test]
+    fn bogus_ram_length() {
+        let mut data = Movie::new(0, vec![0; 16], None).to_bytes();
+
+        // RAM length, after the magic, version, ROM checksum and
+        // bootrom flag
+        data[15..19].copy_from_slice(&[0xff; 4]);
+
+        assert!(Movie::parse(&data).is_err());
+    }
+
+    #[test]
+    fn record_and_play() {
+        let mut gb = gameboy();
+
+        gb.start_movie_recording().unwrap();
+
+        let mut held = Buttons::new(ButtonState::Up);
+
+        // Inputs change in the middle of the frames, the movie must
+        // apply them at the same point on playback
+        for i in 0..30 {
+            held.a = match i % 7 {
+                0 => ButtonState::Down,
+                _ => ButtonState::Up,
+            };
+
+            gb.run_cycles(10_000);
+            gb.set_buttons(held);
+            gb.run_frame();
+        }
+
+        let movie = gb.stop_movie().unwrap();
+
+        assert!(movie.frames() >= 29);
+        assert!(Movie::parse(&movie.to_bytes()).unwrap() == movie);
+
+        // The inputs of the frontend are ignored during playback, any
+        // difference with the recording would cause a desync
+        let mut gb = gameboy();
+
+        gb.play_movie(movie.clone()).unwrap();
+        gb.set_buttons(Buttons::new(ButtonState::Down));
+
+        let mut event = None;
+
+        while event.is_none() {
+            gb.run_cycles(1000);
+            event = gb.take_movie_event();
+        }
+
+        assert!(event == Some(Event::Finished));
+
+        // A machine going off track is noticed
+        let mut gb = gameboy();
+
+        gb.play_movie(movie).unwrap();
+        gb.run_frame();
+
+        let v = gb.interconnect().peek_byte(0xc001);
+        gb.interconnect_mut().poke_byte(0xc001, !v);
+
+        let mut event = None;
+
+        while event.is_none() {
+            gb.run_cycles(1000);
+            event = gb.take_movie_event();
+        }
+
+        assert!(match event {
+            Some(Event::Desync(f)) => f <= 2,
+            _ => false,
+        });
+    }
+}
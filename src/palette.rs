@@ -0,0 +1,291 @@
+//! Colors used to display the four shades of the original Game Boy.
+//!
+//! The DMG LCD only knows four shades of (greenish) grey, the GPU
+//! maps them to actual colors through a `DmgPalette`. The background
+//! and window, the sprites using OBP0 and the sprites using OBP1 each
+//! get their own four colors, which is how the CGB boot ROM colorizes
+//! DMG games.
+//!
+//! Besides the built-in presets palettes can be loaded from a config
+//! file made of sections like:
+//!
+//! ```text
+//! # Comments start with '#'
+//! [sepia]
+//! colors = f8e8c8 d8a870 a06030 402010
+//!
+//! [blue sprites]
+//! bg   = ffffff aaaaaa 555555 000000
+//! obj0 = ffffff 7bb5ff 0000ff 000021
+//! obj1 = ffffff ff8484 943a3a 000000
+//! ```
+//!
+//! `colors` sets all three layers at once, each layer lists its
+//! colors from the lightest shade to the darkest as 24 bit
+//! hexadecimal RGB values.
+
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Result as IoResult};
+use std::path::Path;
+
+use gpu::{Color, Shade};
+
+/// Colors used to display each shade of the DMG, for each layer
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DmgPalette {
+    /// Name of the palette
+    pub name: String,
+    /// Background and window colors
+    pub bg: [Color; 4],
+    /// Colors of the sprites using OBP0
+    pub obj0: [Color; 4],
+    /// Colors of the sprites using OBP1
+    pub obj1: [Color; 4],
+}
+
+impl DmgPalette {
+    /// Build a palette using the same four `colors` for every layer
+    pub fn uniform(name: &str, colors: [Color; 4]) -> DmgPalette {
+        DmgPalette {
+            name: name.into(),
+            bg: colors,
+            obj0: colors,
+            obj1: colors,
+        }
+    }
+
+    /// Default grey palette
+    pub fn grey() -> DmgPalette {
+        DmgPalette::uniform(
+            "grey",
+            [
+                Shade::White.color(),
+                Shade::LightGrey.color(),
+                Shade::DarkGrey.color(),
+                Shade::Black.color(),
+            ],
+        )
+    }
+}
+
+/// Built-in palettes as (name, bg, obj0, obj1) with the colors given
+/// as 24 bit RGB values. The `cgb-*` ones are the palettes the CGB
+/// boot ROM lets the user pick with a button combination while the
+/// logo is displayed.
+static PRESETS: [(&'static str, [u32; 4], [u32; 4], [u32; 4]); 16] = [
+    ("dmg",
+     [0x9bbc0f, 0x8bac0f, 0x306230, 0x0f380f],
+     [0x9bbc0f, 0x8bac0f, 0x306230, 0x0f380f],
+     [0x9bbc0f, 0x8bac0f, 0x306230, 0x0f380f]),
+    ("pocket",
+     [0xc4cfa1, 0x8b956d, 0x4d533c, 0x1f1f1f],
+     [0xc4cfa1, 0x8b956d, 0x4d533c, 0x1f1f1f],
+     [0xc4cfa1, 0x8b956d, 0x4d533c, 0x1f1f1f]),
+    ("light",
+     [0x00b581, 0x009a71, 0x00694a, 0x004f3b],
+     [0x00b581, 0x009a71, 0x00694a, 0x004f3b],
+     [0x00b581, 0x009a71, 0x00694a, 0x004f3b]),
+    ("high-contrast",
+     [0xffffff, 0xb0b0b0, 0x404040, 0x000000],
+     [0xffffff, 0xb0b0b0, 0x404040, 0x000000],
+     [0xffffff, 0xb0b0b0, 0x404040, 0x000000]),
+    ("cgb-up",
+     [0xffffff, 0xffad63, 0x843100, 0x000000],
+     [0xffffff, 0xffad63, 0x843100, 0x000000],
+     [0xffffff, 0xffad63, 0x843100, 0x000000]),
+    ("cgb-up-a",
+     [0xffffff, 0xff8484, 0x943a3a, 0x000000],
+     [0xffffff, 0xff8484, 0x943a3a, 0x000000],
+     [0xffffff, 0xff8484, 0x943a3a, 0x000000]),
+    ("cgb-up-b",
+     [0xffe6c5, 0xce9c84, 0x846b29, 0x5a3108],
+     [0xffe6c5, 0xce9c84, 0x846b29, 0x5a3108],
+     [0xffe6c5, 0xce9c84, 0x846b29, 0x5a3108]),
+    ("cgb-left",
+     [0xffffff, 0x63a5ff, 0x0000ff, 0x000000],
+     [0xffffff, 0xff8484, 0x943a3a, 0x000000],
+     [0xffffff, 0xff8484, 0x943a3a, 0x000000]),
+    ("cgb-left-a",
+     [0xffffff, 0x8c8cde, 0x52528c, 0x000000],
+     [0xffffff, 0xff8484, 0x943a3a, 0x000000],
+     [0xffffff, 0xffad63, 0x843100, 0x000000]),
+    ("cgb-left-b",
+     [0xffffff, 0xa5a5a5, 0x525252, 0x000000],
+     [0xffffff, 0xa5a5a5, 0x525252, 0x000000],
+     [0xffffff, 0xa5a5a5, 0x525252, 0x000000]),
+    ("cgb-down",
+     [0xffffa5, 0xff9494, 0x9494ff, 0x000000],
+     [0xffffa5, 0xff9494, 0x9494ff, 0x000000],
+     [0xffffa5, 0xff9494, 0x9494ff, 0x000000]),
+    ("cgb-down-a",
+     [0xffffff, 0xffff00, 0xff0000, 0x000000],
+     [0xffffff, 0xffff00, 0xff0000, 0x000000],
+     [0xffffff, 0xffff00, 0xff0000, 0x000000]),
+    ("cgb-down-b",
+     [0xffffff, 0xffff00, 0x7b4a00, 0x000000],
+     [0xffffff, 0x63a5ff, 0x0000ff, 0x000000],
+     [0xffffff, 0x7bff31, 0x008400, 0x000000]),
+    ("cgb-right",
+     [0xffffff, 0x52ff00, 0xff4200, 0x000000],
+     [0xffffff, 0x52ff00, 0xff4200, 0x000000],
+     [0xffffff, 0x52ff00, 0xff4200, 0x000000]),
+    ("cgb-right-a",
+     [0xffffff, 0x7bff31, 0x0063c5, 0x000000],
+     [0xffffff, 0xff8484, 0x943a3a, 0x000000],
+     [0xffffff, 0xff8484, 0x943a3a, 0x000000]),
+    ("cgb-right-b",
+     [0x000000, 0x008484, 0xffde00, 0xffffff],
+     [0x000000, 0x008484, 0xffde00, 0xffffff],
+     [0x000000, 0x008484, 0xffde00, 0xffffff]),
+];
+
+/// Convert a 24 bit RGB value
+fn color(rgb: u32) -> Color {
+    Color::from_rgb888((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8)
+}
+
+fn colors(rgb: &[u32; 4]) -> [Color; 4] {
+    [color(rgb[0]), color(rgb[1]), color(rgb[2]), color(rgb[3])]
+}
+
+/// Return the built-in palettes, starting with the default grey one
+pub fn presets() -> Vec<DmgPalette> {
+    let mut palettes = vec![DmgPalette::grey()];
+
+    for &(name, ref bg, ref obj0, ref obj1) in PRESETS.iter() {
+        palettes.push(DmgPalette {
+            name: name.into(),
+            bg: colors(bg),
+            obj0: colors(obj0),
+            obj1: colors(obj1),
+        });
+    }
+
+    palettes
+}
+
+/// Load the palettes defined in the config file at `path`
+pub fn load(path: &Path) -> IoResult<Vec<DmgPalette>> {
+    let mut config = String::new();
+
+    try!(try!(File::open(path)).read_to_string(&mut config));
+
+    parse(&config).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+/// Parse the palettes defined in `config`
+pub fn parse(config: &str) -> Result<Vec<DmgPalette>, String> {
+    let mut palettes: Vec<DmgPalette> = Vec::new();
+
+    for (n, line) in config.lines().enumerate() {
+        let line = match line.find('#') {
+            Some(p) => &line[..p],
+            None => line,
+        };
+        let line = line.trim();
+
+        if line.is_empty() {
+            continue;
+        }
+
+        let error = |msg: &str| format!("line {}: {}", n + 1, msg);
+
+        if line.starts_with('[') {
+            if !line.ends_with(']') || line.len() < 3 {
+                return Err(error("invalid section header"));
+            }
+
+            let name = line[1..line.len() - 1].trim();
+
+            palettes.push(DmgPalette::uniform(name, DmgPalette::grey().bg));
+            continue;
+        }
+
+        let (key, value) = match line.find('=') {
+            Some(p) => (line[..p].trim(), line[p + 1..].trim()),
+            None => return Err(error("expected 'key = colors'")),
+        };
+
+        let palette = match palettes.last_mut() {
+            Some(p) => p,
+            None => return Err(error("colors outside of a palette section")),
+        };
+
+        let mut c = [Color::from_rgb555(0); 4];
+        let mut count = 0;
+
+        for word in value.split_whitespace() {
+            if count == 4 || word.len() != 6 {
+                return Err(error("expected four RRGGBB colors"));
+            }
+
+            match u32::from_str_radix(word, 16) {
+                Ok(rgb) => c[count] = color(rgb),
+                Err(_) => return Err(error("invalid color")),
+            }
+
+            count += 1;
+        }
+
+        if count != 4 {
+            return Err(error("expected four RRGGBB colors"));
+        }
+
+        match key {
+            "colors" => {
+                palette.bg = c;
+                palette.obj0 = c;
+                palette.obj1 = c;
+            }
+            "bg" => palette.bg = c,
+            "obj0" => palette.obj0 = c,
+            "obj1" => palette.obj1 = c,
+            _ => return Err(error("unknown key")),
+        }
+    }
+
+    Ok(palettes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, presets, DmgPalette};
+    use gpu::Color;
+
+    #[test]
+    fn config_file() {
+        let palettes = parse(
+            "# Test\n\
+             [sepia]\n\
+             colors = f8e8c8 d8a870 a06030 402010\n\
+             \n\
+             [mixed]  \n\
+             bg = ffffff aaaaaa 555555 000000 # grey\n\
+             obj1 = ff0000 00ff00 0000ff 000000\n",
+        ).unwrap();
+
+        assert!(palettes.len() == 2);
+        assert!(palettes[0].name == "sepia");
+        assert!(palettes[0].obj1[0] == Color::from_rgb888(0xf8, 0xe8, 0xc8));
+        assert!(palettes[1].name == "mixed");
+        assert!(palettes[1].bg[3] == Color::from_rgb(0, 0, 0));
+        assert!(palettes[1].obj0 == DmgPalette::grey().obj0);
+        assert!(palettes[1].obj1[2] == Color::from_rgb(0, 0, 0x1f));
+
+        assert!(parse("colors = ffffff 000000 000000 000000").is_err());
+        assert!(parse("[a]\ncolors = ffffff 000000 000000").is_err());
+        assert!(parse("[a]\nbg = ffffff 000000 000000 00000g").is_err());
+        assert!(parse("[a]\nfoo = ffffff 000000 000000 000000").is_err());
+    }
+
+    #[test]
+    fn preset_names() {
+        let p = presets();
+
+        assert!(p[0] == DmgPalette::grey());
+
+        for (i, a) in p.iter().enumerate() {
+            assert!(p[i + 1..].iter().all(|b| a.name != b.name));
+        }
+    }
+}
@@ -0,0 +1,447 @@
+//! Minimal PNG writer for 24 bit RGB pictures
+//!
+//! The image data is compressed with a simple LZ77 matcher (one
+//! candidate per hash bucket, no lazy matching) and the fixed Huffman
+//! codes of deflate. That's far from optimal but screenshots of the
+//! GB screen are made of large flat areas and repeated tiles, which
+//! compress well enough that way.
+
+use std::io::{Result as IoResult, Write};
+
+/// PNG file signature
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+/// Size of the deflate window
+const WINDOW: usize = 32768;
+
+/// Shortest and longest matches deflate can encode
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+
+/// Number of bits of the match finder hash
+const HASH_BITS: u32 = 15;
+
+/// Base match length of the length codes 257 to 285
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+
+/// Number of extra bits of the length codes 257 to 285
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+
+/// Base distance of the distance codes 0 to 29
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+
+/// Number of extra bits of the distance codes 0 to 29
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// Write a `width`x`height` picture to `out`. `rgb` contains the
+/// pixels line by line, 3 bytes per pixel.
+pub fn write_rgb<W: Write>(out: &mut W, width: u32, height: u32, rgb: &[u8]) -> IoResult<()> {
+    let stride = width as usize * 3;
+
+    assert!(rgb.len() == stride * height as usize);
+
+    try!(out.write_all(&SIGNATURE));
+
+    let mut header = Vec::with_capacity(13);
+    push_u32(&mut header, width);
+    push_u32(&mut header, height);
+    // 8 bits per component, RGB, deflate, adaptive filtering (the
+    // only method defined), no interlacing
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    try!(write_chunk(out, b"IHDR", &header));
+
+    // Each line starts with its filter type, we always use 0 (none)
+    let mut raw = Vec::with_capacity((stride + 1) * height as usize);
+
+    for line in rgb.chunks(stride) {
+        raw.push(0);
+        raw.extend_from_slice(line);
+    }
+
+    try!(write_chunk(out, b"IDAT", &zlib(&raw)));
+
+    write_chunk(out, b"IEND", &[])
+}
+
+fn write_chunk<W: Write>(out: &mut W, kind: &[u8; 4], data: &[u8]) -> IoResult<()> {
+    let mut chunk = Vec::with_capacity(data.len() + 12);
+
+    push_u32(&mut chunk, data.len() as u32);
+    chunk.extend_from_slice(kind);
+    chunk.extend_from_slice(data);
+
+    // The CRC covers the type and the data
+    let crc = crc32(&chunk[4..]);
+    push_u32(&mut chunk, crc);
+
+    out.write_all(&chunk)
+}
+
+fn push_u32(buf: &mut Vec<u8>, v: u32) {
+    for i in (0..4).rev() {
+        buf.push((v >> (i * 8)) as u8);
+    }
+}
+
+/// Wrap the deflated `data` in a zlib stream
+fn zlib(data: &[u8]) -> Vec<u8> {
+    // Deflate with a 32KB window, no preset dictionary, "fastest"
+    // compression level
+    let mut out = vec![0x78, 0x01];
+
+    out.extend_from_slice(&deflate(data));
+    push_u32(&mut out, adler32(data));
+
+    out
+}
+
+/// Compress `data` in a single deflate block using the fixed Huffman
+/// codes
+fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut w = BitWriter::new();
+
+    // Final block, fixed Huffman codes
+    w.write(1, 1);
+    w.write(1, 2);
+
+    let hash = |i: usize| {
+        let v = (data[i] as u32) | ((data[i + 1] as u32) << 8) | ((data[i + 2] as u32) << 16);
+
+        (v.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+    };
+
+    // Last position where each hash was seen, plus one (0 for never)
+    let mut head = vec![0usize; 1 << HASH_BITS];
+
+    let mut i = 0;
+
+    while i < data.len() {
+        let mut length = 0;
+        let mut distance = 0;
+
+        if i + MIN_MATCH <= data.len() {
+            let h = hash(i);
+            let candidate = head[h];
+
+            head[h] = i + 1;
+
+            if candidate > 0 && i - (candidate - 1) <= WINDOW {
+                let c = candidate - 1;
+                let max = ::std::cmp::min(MAX_MATCH, data.len() - i);
+
+                while length < max && data[c + length] == data[i + length] {
+                    length += 1;
+                }
+
+                distance = i - c;
+            }
+        }
+
+        if length >= MIN_MATCH {
+            write_match(&mut w, length, distance);
+
+            // Keep track of the positions we skip
+            for j in i + 1..i + length {
+                if j + MIN_MATCH <= data.len() {
+                    head[hash(j)] = j + 1;
+                }
+            }
+
+            i += length;
+        } else {
+            write_symbol(&mut w, data[i] as u16);
+            i += 1;
+        }
+    }
+
+    // End of block
+    write_symbol(&mut w, 256);
+
+    w.finish()
+}
+
+/// Write a literal/length symbol using the fixed Huffman code
+fn write_symbol(w: &mut BitWriter, symbol: u16) {
+    let (code, bits) = match symbol {
+        0...143 => (0x30 + symbol, 8),
+        144...255 => (0x190 + symbol - 144, 9),
+        256...279 => (symbol - 256, 7),
+        _ => (0xc0 + symbol - 280, 8),
+    };
+
+    w.write_huffman(code as u32, bits);
+}
+
+/// Write a back reference `distance` bytes behind, `length` bytes
+/// long
+fn write_match(w: &mut BitWriter, length: usize, distance: usize) {
+    let l = LENGTH_BASE.iter().rposition(|&b| b as usize <= length).unwrap();
+
+    write_symbol(w, 257 + l as u16);
+    w.write((length - LENGTH_BASE[l] as usize) as u32, LENGTH_EXTRA[l]);
+
+    let d = DISTANCE_BASE.iter().rposition(|&b| b as usize <= distance).unwrap();
+
+    // Distance codes are always 5 bits long
+    w.write_huffman(d as u32, 5);
+    w.write((distance - DISTANCE_BASE[d] as usize) as u32, DISTANCE_EXTRA[d]);
+}
+
+/// Deflate bit stream, filled from the least significant bit of each
+/// byte
+struct BitWriter {
+    out: Vec<u8>,
+    acc: u32,
+    bits: u8,
+}
+
+impl BitWriter {
+    fn new() -> BitWriter {
+        BitWriter {
+            out: Vec::new(),
+            acc: 0,
+            bits: 0,
+        }
+    }
+
+    /// Write the `bits` low bits of `v`, least significant first
+    fn write(&mut self, v: u32, bits: u8) {
+        self.acc |= v << self.bits;
+        self.bits += bits;
+
+        while self.bits >= 8 {
+            self.out.push(self.acc as u8);
+            self.acc >>= 8;
+            self.bits -= 8;
+        }
+    }
+
+    /// Write a Huffman code, they're stored most significant bit
+    /// first
+    fn write_huffman(&mut self, code: u32, bits: u8) {
+        let reversed = code.reverse_bits() >> (32 - bits);
+
+        self.write(reversed, bits);
+    }
+
+    /// Flush the last partial byte and return the stream
+    fn finish(mut self) -> Vec<u8> {
+        if self.bits > 0 {
+            self.out.push(self.acc as u8);
+        }
+
+        self.out
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+
+    for &b in data {
+        crc ^= b as u32;
+
+        for _ in 0..8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ 0xedb88320,
+                _ => crc >> 1,
+            };
+        }
+    }
+
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let mut a = 1u32;
+    let mut b = 0u32;
+
+    for &d in data {
+        a = (a + d as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{adler32, crc32, write_rgb, zlib, DISTANCE_BASE, DISTANCE_EXTRA, LENGTH_BASE, LENGTH_EXTRA};
+
+    /// Deflate bit stream reader
+    struct BitReader<'a> {
+        data: &'a [u8],
+        pos: usize,
+    }
+
+    impl<'a> BitReader<'a> {
+        fn bit(&mut self) -> u32 {
+            let b = (self.data[self.pos / 8] >> (self.pos % 8)) & 1;
+
+            self.pos += 1;
+
+            b as u32
+        }
+
+        fn bits(&mut self, n: u8) -> u32 {
+            (0..n).fold(0, |v, i| v | (self.bit() << i))
+        }
+
+        /// Read a Huffman code, most significant bit first
+        fn huffman(&mut self, bits: u8) -> u32 {
+            (0..bits).fold(0, |v, _| (v << 1) | self.bit())
+        }
+
+        /// Decode a literal/length symbol using the fixed Huffman code
+        fn symbol(&mut self) -> u16 {
+            let c = self.huffman(7);
+
+            if c < 0x18 {
+                return 256 + c as u16;
+            }
+
+            let c = (c << 1) | self.bit();
+
+            match c {
+                0x30...0xbf => (c - 0x30) as u16,
+                0xc0...0xc7 => (280 + c - 0xc0) as u16,
+                _ => (144 + ((c << 1) | self.bit()) - 0x190) as u16,
+            }
+        }
+    }
+
+    /// Decompress a zlib stream made of fixed Huffman blocks and check
+    /// its checksum. Independent of the compressor, it only follows
+    /// RFC 1950 and 1951.
+    fn inflate(z: &[u8]) -> Vec<u8> {
+        assert!(z[0] == 0x78 && ((z[0] as u16) << 8 | z[1] as u16) % 31 == 0);
+
+        let mut r = BitReader { data: &z[2..], pos: 0 };
+        let mut out: Vec<u8> = Vec::new();
+
+        loop {
+            let last = r.bit() == 1;
+
+            assert!(r.bits(2) == 1);
+
+            loop {
+                let symbol = r.symbol();
+
+                if symbol < 256 {
+                    out.push(symbol as u8);
+                    continue;
+                }
+
+                if symbol == 256 {
+                    break;
+                }
+
+                let l = (symbol - 257) as usize;
+                let length = LENGTH_BASE[l] as usize + r.bits(LENGTH_EXTRA[l]) as usize;
+                let d = r.huffman(5) as usize;
+                let distance = DISTANCE_BASE[d] as usize + r.bits(DISTANCE_EXTRA[d]) as usize;
+
+                for _ in 0..length {
+                    let b = out[out.len() - distance];
+                    out.push(b);
+                }
+            }
+
+            if last {
+                break;
+            }
+        }
+
+        let end = 2 + (r.pos + 7) / 8;
+        let adler = z[end..end + 4].iter().fold(0, |v, &b| (v << 8) | b as u32);
+
+        assert!(adler == adler32(&out));
+
+        out
+    }
+
+    #[test]
+    fn inflate_known_stream() {
+        // Python's zlib.compress(data, 9), a single block using the
+        // fixed codes with a few back references
+        let z = [
+            0x78, 0xda, 0x73, 0xca, 0x49, 0x2c, 0x4a, 0x4f, 0x57, 0x70, 0x82, 0x50, 0xbe, 0xf9, 0xf9,
+            0x79, 0xa9, 0x95, 0xa9, 0x68, 0x5c, 0x1d, 0x85, 0x80, 0xc4, 0xe2, 0xe2, 0xd4, 0x14, 0x54,
+            0x4a, 0x11, 0x00, 0x78, 0x41, 0x14, 0xf3,
+        ];
+
+        assert!(inflate(&z)[..] == b"Blargg Blargg Mooneye Blargg Mooneye, Passed Passed Passed!"[..]);
+    }
+
+    #[test]
+    fn deflate_round_trip() {
+        // Pseudo-random tiles repeated at various distances, with
+        // runs longer than the longest match and bytes of both
+        // literal code lengths
+        let mut seed = 1u32;
+        let mut tiles = Vec::new();
+
+        for _ in 0..16 {
+            let tile: Vec<u8> = (0..48)
+                .map(|_| {
+                    seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                    (seed >> 16) as u8
+                })
+                .collect();
+
+            tiles.push(tile);
+        }
+
+        let mut data = Vec::new();
+
+        for i in 0..2000 {
+            data.extend_from_slice(&tiles[(i * 7 + i / 13) % 16]);
+
+            if i % 100 == 0 {
+                data.extend(::std::iter::repeat(0xff).take(1000));
+            }
+        }
+
+        let z = zlib(&data);
+
+        assert!(inflate(&z) == data);
+        // The repeated tiles are found
+        assert!(z.len() < data.len() / 10);
+
+        assert!(inflate(&zlib(&[])).is_empty());
+        assert!(inflate(&zlib(b"ab")) == b"ab");
+    }
+
+    #[test]
+    fn checksums() {
+        assert!(crc32(b"IEND") == 0xae426082);
+        assert!(adler32(b"Wikipedia") == 0x11e60398);
+    }
+
+    #[test]
+    fn flat_picture() {
+        let rgb: Vec<u8> = (0..160 * 144).flat_map(|_| vec![0x9b, 0xbc, 0x0f]).collect();
+        let mut png = Vec::new();
+
+        write_rgb(&mut png, 160, 144, &rgb).unwrap();
+
+        assert!(png[..8] == [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n']);
+        assert!(png[12..16] == *b"IHDR");
+        // 160x144
+        assert!(png[16..24] == [0, 0, 0, 160, 0, 0, 0, 144]);
+        assert!(png[png.len() - 12..] == [0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xae, 0x42, 0x60, 0x82]);
+        // Runs of identical pixels compress to almost nothing
+        assert!(png.len() < 1000);
+    }
+}
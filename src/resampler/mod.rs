@@ -1,12 +1,15 @@
 //! Resampler implementation, used to adapt the sound samples to the
 //! output sample rate.
+//!
+//! Samples are handled as stereo frames: the buffers received from
+//! the SPU and the ones filled for the backend contain interleaved
+//! left and right samples.
 
-use num::{Bounded, FromPrimitive, Integer};
 use std::default::Default;
 use std::sync::mpsc::Receiver;
 use std::sync::{Arc, Condvar, Mutex};
 
-use spu::SampleBuffer;
+use spu::{SampleBuffer, AUDIO_CHANNELS};
 
 use self::fifo::Fifo;
 use self::worker::AsyncResampler;
@@ -16,13 +19,33 @@ mod worker;
 
 pub struct Resampler<T: Send> {
     async: Arc<Async<T>>,
-    last_sample: T,
+    last_frame: Frame<T>,
 }
 
-impl<T> Resampler<T>
-where
-    T: Copy + Send + Default + Integer + Bounded + FromPrimitive + 'static,
-{
+/// One sample for each output channel
+type Frame<T> = [T; AUDIO_CHANNELS];
+
+/// Sample formats the resampler can output
+pub trait OutputSample: Copy + Send + Default + 'static {
+    /// Convert from a sample in the range [-1.0, 1.0]
+    fn from_f32(s: f32) -> Self;
+}
+
+impl OutputSample for i16 {
+    fn from_f32(s: f32) -> i16 {
+        let s = s.max(-1.).min(1.);
+
+        (s * i16::max_value() as f32) as i16
+    }
+}
+
+impl OutputSample for f32 {
+    fn from_f32(s: f32) -> f32 {
+        s
+    }
+}
+
+impl<T: OutputSample> Resampler<T> {
     pub fn new(source: Receiver<SampleBuffer>, rate: u32) -> Resampler<T> {
         let async = Arc::new(Async::new(rate));
 
@@ -36,24 +59,24 @@ where
 
         Resampler {
             async: async,
-            last_sample: Default::default(),
+            last_frame: Default::default(),
         }
     }
 
     pub fn fill_buf(&mut self, buf: &mut [T]) {
         let mut atomic = self.async.atomic.lock().unwrap();
 
-        atomic.out_samples += buf.len() as u32;
+        atomic.out_samples += (buf.len() / AUDIO_CHANNELS) as u32;
 
-        for sample in buf.iter_mut() {
-            let new_sample = match atomic.fifo.pop() {
-                Some(s) => s,
-                // Fifo is empty, duplicate the last sample
-                None => self.last_sample,
+        for frame in buf.chunks_mut(AUDIO_CHANNELS) {
+            let new_frame = match atomic.fifo.pop() {
+                Some(f) => f,
+                // Fifo is empty, duplicate the last frame
+                None => self.last_frame,
             };
 
-            self.last_sample = new_sample;
-            *sample = new_sample;
+            self.last_frame = new_frame;
+            frame.copy_from_slice(&new_frame[..frame.len()]);
         }
 
         // Notify the writer that we made some room in the FIFO
@@ -67,8 +90,8 @@ where
 
 /// Part of the `ASync` state that must be accessed atomically
 struct Atomic<T: Send> {
-    fifo: Fifo<T>,
-    /// Number of samples requested by the backend since the last
+    fifo: Fifo<Frame<T>>,
+    /// Number of frames requested by the backend since the last
     /// adjustment (even missed samples in case of empty FIFO
     /// count!). Used to compute the average output sample rate.
     out_samples: u32,
@@ -76,6 +99,9 @@ struct Atomic<T: Send> {
     /// rate. Will be adjusted at runtime based on the computed
     /// average sample rate
     ratio: f32,
+    /// Emulation speed relative to the real hardware. The samples
+    /// are consumed that much faster, which shifts their pitch.
+    speed: f32,
     /// Resampling ratio estimation state machine
     training: Training,
 }
@@ -89,10 +115,7 @@ pub struct Async<T: Send> {
     stall: Condvar,
 }
 
-impl<T> Async<T>
-where
-    T: Copy + Send + Default + Integer + FromPrimitive + 'static,
-{
+impl<T: OutputSample> Async<T> {
     fn new(rate: u32) -> Async<T> {
         // Initial educated guess for the sampling ratio. This is just
         // used while starting up, it'll be replaced by the measured
@@ -103,6 +126,7 @@ where
             fifo: Fifo::new(),
             out_samples: 0,
             ratio: ratio,
+            speed: 1.,
             training: Training::Init(2),
         };
 
@@ -113,7 +137,7 @@ where
     }
 
     /// Adjust the resampling ratio. `in_samples` is the number of
-    /// samples generated by the emulator since the last adjustment
+    /// frames generated by the emulator since the last adjustment
     /// (counting even the samples potentially dropped in case of a
     /// FIFO overflow). This call resets the internal sample counter.
     pub fn adjust_resampling(&self, in_samples: u32) {
@@ -124,8 +148,9 @@ where
         // Reset the atomic counter
         atomic.out_samples = 0;
 
-        // Compute the resampling factor since the last adjust
-        let r = in_samples as f32 / out_samples as f32;
+        // Compute the resampling factor since the last adjust, at
+        // normal speed
+        let r = in_samples as f32 / atomic.speed / out_samples as f32;
 
         match atomic.training {
             Training::Init(c) => {
@@ -164,6 +189,16 @@ where
             }
         }
     }
+
+    /// Set the emulation `speed` relative to the real hardware. This
+    /// call resets the internal sample counter, the next adjustment
+    /// must only account for the samples generated after it.
+    pub fn set_speed(&self, speed: f32) {
+        let mut atomic = self.atomic.lock().unwrap();
+
+        atomic.speed = speed;
+        atomic.out_samples = 0;
+    }
 }
 
 /// Sample Rate training
@@ -1,10 +1,9 @@
 //! Asynchronous worker thread doing the actual resampling
 
-use super::Async;
+use super::{Async, OutputSample};
 
-use num::{Bounded, FromPrimitive, Integer};
-use spu::{SampleBuffer, SAMPLE_MAX};
-use std::default::Default;
+use spu::sinc::SincResampler;
+use spu::{SampleBuffer, AUDIO_CHANNELS};
 use std::sync::mpsc::Receiver;
 use std::sync::Arc;
 
@@ -14,27 +13,32 @@ pub struct AsyncResampler<T: Send> {
     source: Receiver<SampleBuffer>,
     /// Asynchronous FIFO used to send samples to the backend
     async: Arc<Async<T>>,
-    /// offset into the next buffer
-    offset: f32,
+    /// Windowed-sinc filter
+    sinc: SincResampler,
+    /// Emulation speed the filter was computed for
+    speed: f32,
 }
 
-impl<T> AsyncResampler<T>
-where
-    T: Copy + Send + Default + Integer + Bounded + FromPrimitive + 'static,
-{
+impl<T: OutputSample> AsyncResampler<T> {
     pub fn new(source: Receiver<SampleBuffer>, async: Arc<Async<T>>) -> AsyncResampler<T> {
+        let ratio = async.atomic.lock().unwrap().ratio;
+
         AsyncResampler {
             source: source,
             async: async,
-            offset: 0.,
+            sinc: SincResampler::new(AUDIO_CHANNELS, ratio),
+            speed: 1.,
         }
     }
 
     pub fn resample(&mut self) {
-        let range: T = T::max_value();
-        let sample_max = T::from_u8(SAMPLE_MAX).unwrap();
+        let scale = 1. / -(i16::min_value() as f32);
 
         while let Ok(buf) = self.source.recv() {
+            for frame in buf.chunks(AUDIO_CHANNELS) {
+                self.sinc.push(&[frame[0] as f32 * scale, frame[1] as f32 * scale]);
+            }
+
             let mut atomic = self.async.atomic.lock().unwrap();
 
             // Adapt the resampling ratio based on the current FIFO
@@ -45,21 +49,22 @@ where
             let adj = (fifo_depth - (2 * atomic.fifo.len()) as f32) / fifo_depth;
             let adj = 1. + adj * DEVIATION;
 
-            let factor = atomic.ratio / adj;
+            let factor = atomic.ratio * atomic.speed / adj;
 
-            let mut pos = self.offset;
+            // Fast forward raises the pitch, the filter must follow
+            // to avoid aliasing
+            if atomic.speed != self.speed {
+                self.speed = atomic.speed;
+                self.sinc.set_ratio(atomic.ratio * self.speed);
+            }
 
-            while (pos as usize) < buf.len() {
-                // Nearest resampling
-                let sample = buf[pos as usize];
+            let mut out = [0.; AUDIO_CHANNELS];
 
-                // Convert u8 sample to the target range by
-                // "upscaling" it.
-                let sample = T::from_u8(sample).unwrap();
-                let sample = sample * (range / sample_max);
+            while self.sinc.next(factor, &mut out) {
+                let frame = [T::from_f32(out[0]), T::from_f32(out[1])];
 
-                // Push the sample in the target FIFO
-                while let Err(_) = atomic.fifo.push(sample) {
+                // Push the frame in the target FIFO
+                while let Err(_) = atomic.fifo.push(frame) {
                     // Destination FIFO is full, notify the reader
                     // thread that there's something to read...
                     self.async.stall.notify_one();
@@ -67,14 +72,8 @@ where
                     // ... and wait for it to make some room
                     atomic = self.async.stall.wait(atomic).unwrap();
                 }
-
-                // Move on to the next sample
-                pos += factor;
             }
 
-            // Update offset into the next buffer
-            self.offset = pos - buf.len() as f32;
-
             // Notify the reader that we put samples in the FIFO
             self.async.stall.notify_one();
         }
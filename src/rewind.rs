@@ -0,0 +1,332 @@
+//! Rewind buffer
+//!
+//! A snapshot of the whole machine (see `GameBoy::snapshot`) is taken
+//! every few frames. Only the most recent one is kept whole, the older
+//! ones are stored as the difference with the snapshot that follows
+//! them: the two states are XORed together, which leaves mostly zeros
+//! since few bytes change in a handful of frames, and the runs of
+//! zeros are squeezed out. Going back in time undoes the deltas one by
+//! one, from the newest to the oldest, which means the oldest ones can
+//! be dropped whenever the buffer grows past its budget. The picture
+//! on screen isn't part of the machine state, it's appended to each
+//! snapshot so that it can be shown again without running the
+//! emulation.
+//!
+//! A delta is encoded as the length of the older state followed by a
+//! sequence of `<zeros> <length> <bytes>` chunks: `zeros` bytes are
+//! identical in both states then `length` bytes differ by `bytes`.
+//! Numbers are stored as LEB128 varints.
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::io::Result as IoResult;
+
+use gameboy::{GameBoy, CYCLES_PER_FRAME};
+use gpu::Color;
+
+/// Runs of identical bytes shorter than this are stored along with
+/// the surrounding differences, starting a new chunk would cost more
+const MIN_ZERO_RUN: usize = 4;
+
+/// Size of the picture appended to the snapshots: 160x144 RGB555
+/// pixels
+const FRAME_BYTES: usize = 160 * 144 * 2;
+
+/// History of the machine states
+pub struct Rewind {
+    /// Number of system clock ticks between two snapshots
+    interval: u64,
+    /// Maximum number of bytes used by the deltas
+    budget: usize,
+    /// Most recent snapshot, `None` until the first one is taken
+    current: Option<Vec<u8>>,
+    /// Clock tick count when `current` was taken
+    current_cycles: u64,
+    /// Deltas going from each snapshot to the previous one, oldest
+    /// first
+    deltas: VecDeque<Vec<u8>>,
+    /// Total size of `deltas`
+    size: usize,
+}
+
+impl Rewind {
+    /// Create an empty history taking a snapshot every `frames`
+    /// frames and using at most `budget` bytes for the older ones
+    pub fn new(frames: u32, budget: usize) -> Rewind {
+        Rewind {
+            interval: cmp::max(frames, 1) as u64 * CYCLES_PER_FRAME,
+            budget: budget,
+            current: None,
+            current_cycles: 0,
+            deltas: VecDeque::new(),
+            size: 0,
+        }
+    }
+
+    /// Take a snapshot of `gb` if enough time has passed since the
+    /// last one. Must be called regularly while the emulation runs.
+    pub fn record(&mut self, gb: &GameBoy) {
+        let cycles = gb.cycles();
+
+        // The clock also goes back when a state is loaded
+        if self.current.is_some()
+            && cycles >= self.current_cycles
+            && cycles - self.current_cycles < self.interval
+        {
+            return;
+        }
+
+        let mut state = gb.snapshot();
+
+        for line in gb.frame().iter() {
+            for &color in line.iter() {
+                let rgb = color.rgb555();
+
+                state.push(rgb as u8);
+                state.push((rgb >> 8) as u8);
+            }
+        }
+
+        if let Some(previous) = self.current.take() {
+            let delta = encode_delta(&previous, &state);
+
+            self.size += delta.len();
+            self.deltas.push_back(delta);
+
+            while self.size > self.budget {
+                match self.deltas.pop_front() {
+                    Some(d) => self.size -= d.len(),
+                    None => break,
+                }
+            }
+        }
+
+        self.current = Some(state);
+        self.current_cycles = cycles;
+    }
+
+    /// Bring `gb` back to the most recent snapshot, picture included
+    /// (see `GameBoy::redraw`), and make the one before it the next
+    /// to be restored. Once the oldest snapshot is reached it's
+    /// restored again each time. Returns `false` if there's no
+    /// snapshot at all.
+    pub fn step_back(&mut self, gb: &mut GameBoy) -> IoResult<bool> {
+        let state = match self.current.take() {
+            Some(s) => s,
+            None => return Ok(false),
+        };
+
+        {
+            let (snapshot, pixels) = state.split_at(state.len() - FRAME_BYTES);
+
+            let mut frame = [[Color::from_rgb555(0); 160]; 144];
+
+            for (i, p) in pixels.chunks(2).enumerate() {
+                frame[i / 160][i % 160] = Color::from_rgb555(p[0] as u16 | (p[1] as u16) << 8);
+            }
+
+            if let Err(e) = gb.restore(snapshot) {
+                // Keep the history, the error isn't going away
+                self.current = Some(state);
+                return Err(e);
+            }
+
+            gb.set_frame(&frame);
+        }
+
+        self.current = Some(match self.deltas.pop_back() {
+            Some(delta) => {
+                self.size -= delta.len();
+                apply_delta(&delta, &state)
+            }
+            None => state,
+        });
+        self.current_cycles = gb.cycles();
+
+        Ok(true)
+    }
+
+    /// Return the number of snapshots in the history
+    pub fn snapshots(&self) -> usize {
+        match self.current {
+            Some(_) => self.deltas.len() + 1,
+            None => 0,
+        }
+    }
+
+    /// Return the number of bytes used by the older snapshots
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+/// Encode the difference between the `old` and `new` states
+fn encode_delta(old: &[u8], new: &[u8]) -> Vec<u8> {
+    let len = cmp::max(old.len(), new.len());
+    let diff = |i: usize| old.get(i).cloned().unwrap_or(0) ^ new.get(i).cloned().unwrap_or(0);
+    let zeros_at = |i: usize| (i..cmp::min(i + MIN_ZERO_RUN, len)).all(|i| diff(i) == 0);
+
+    let mut out = Vec::new();
+
+    write_varint(&mut out, old.len());
+
+    let mut i = 0;
+
+    while i < len {
+        let start = i;
+
+        while i < len && diff(i) == 0 {
+            i += 1;
+        }
+
+        if i == len {
+            break;
+        }
+
+        write_varint(&mut out, i - start);
+
+        let start = i;
+
+        while i < len && !zeros_at(i) {
+            i += 1;
+        }
+
+        write_varint(&mut out, i - start);
+
+        for j in start..i {
+            out.push(diff(j));
+        }
+    }
+
+    out
+}
+
+/// Rebuild the older state from `delta` and the `new` state it was
+/// computed against
+fn apply_delta(delta: &[u8], new: &[u8]) -> Vec<u8> {
+    let mut pos = 0;
+
+    let old_len = read_varint(delta, &mut pos);
+
+    let mut old = new.to_vec();
+    old.resize(cmp::max(old_len, new.len()), 0);
+
+    let mut i = 0;
+
+    while pos < delta.len() {
+        i += read_varint(delta, &mut pos);
+
+        let len = read_varint(delta, &mut pos);
+
+        for (o, d) in old[i..i + len].iter_mut().zip(&delta[pos..pos + len]) {
+            *o ^= *d;
+        }
+
+        i += len;
+        pos += len;
+    }
+
+    old.truncate(old_len);
+
+    old
+}
+
+fn write_varint(out: &mut Vec<u8>, mut v: usize) {
+    while v >= 0x80 {
+        out.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+
+    out.push(v as u8);
+}
+
+fn read_varint(buf: &[u8], pos: &mut usize) -> usize {
+    let mut v = 0;
+    let mut shift = 0;
+
+    loop {
+        let b = buf[*pos];
+
+        *pos += 1;
+        v |= ((b & 0x7f) as usize) << shift;
+        shift += 7;
+
+        if b & 0x80 == 0 {
+            return v;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply_delta, encode_delta, Rewind};
+    use cartridge::Cartridge;
+    use gameboy::{CpuCore, GameBoy, CYCLES_PER_FRAME};
+
+    #[test]
+    fn delta_round_trip() {
+        let new: Vec<u8> = (0..1000).map(|i| (i * 7) as u8).collect();
+
+        let mut old = new.clone();
+        old[3] ^= 0xff;
+        old[500] = 0;
+        old[501] = 1;
+        old[998] = 42;
+
+        let delta = encode_delta(&old, &new);
+
+        assert!(delta.len() < 20);
+        assert!(apply_delta(&delta, &new) == old);
+
+        // Not all states have the same size
+        let short = old[..600].to_vec();
+
+        assert!(apply_delta(&encode_delta(&short, &new), &new) == short);
+        assert!(apply_delta(&encode_delta(&new, &short), &short) == new);
+    }
+
+    #[test]
+    fn rewind() {
+        // The bootrom scrolling the logo is plenty to change the state
+        let mut gb = GameBoy::headless(Cartridge::from_vec(vec![0; 0x8000]), CpuCore::Gamedorp);
+        let mut rewind = Rewind::new(2, 1 << 20);
+        let mut states = Vec::new();
+
+        for _ in 0..20 {
+            // `run_frame` returns early when the LCD is switched on
+            gb.run_cycles(CYCLES_PER_FRAME);
+            rewind.record(&gb);
+
+            if rewind.snapshots() > states.len() {
+                states.push((gb.snapshot(), *gb.frame()));
+            }
+        }
+
+        assert!(rewind.snapshots() == 10);
+
+        for &(ref state, ref frame) in states.iter().rev() {
+            assert!(rewind.step_back(&mut gb).unwrap());
+            assert!(gb.snapshot() == *state);
+            // The picture comes back too
+            assert!(gb.frame()[..] == frame[..]);
+
+            // Running after a step must not disturb the history
+            gb.run_frame();
+        }
+
+        // Stuck on the oldest state
+        assert!(rewind.step_back(&mut gb).unwrap());
+        assert!(gb.snapshot() == states[0].0);
+
+        // Over budget, only the most recent snapshots are kept
+        let mut rewind = Rewind::new(1, 1);
+
+        for _ in 0..5 {
+            gb.run_frame();
+            rewind.record(&gb);
+        }
+
+        assert!(rewind.snapshots() == 1);
+        assert!(rewind.size() == 0);
+    }
+}
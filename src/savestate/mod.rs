@@ -0,0 +1,223 @@
+//! Save state serialization.
+//!
+//! A save state is a flat little-endian binary dump of the whole
+//! machine. Each stateful component implements `Snapshot` and is
+//! responsible for writing and reading back its own fields, in the
+//! same order. The layout is only guaranteed to be stable for a given
+//! `VERSION`: any change to what a component serializes must bump it
+//! so that old states are rejected instead of being misinterpreted.
+
+use std::io::{Error, ErrorKind, Result as IoResult};
+use std::path::{Path, PathBuf};
+
+/// Magic string found at the beginning of every save state file
+pub const MAGIC: &'static [u8; 8] = b"GBRSSTAT";
+
+/// Version of the save state format
+pub const VERSION: u32 = 9;
+
+/// Interface implemented by every piece of emulated hardware holding
+/// state that needs to be saved.
+pub trait Snapshot {
+    /// Serialize the state into `w`
+    fn snapshot(&self, w: &mut StateWriter);
+
+    /// Restore the state previously saved by `snapshot`. If an error
+    /// is returned the object may have been partially restored.
+    fn restore(&mut self, r: &mut StateReader) -> IoResult<()>;
+}
+
+/// Buffer accumulating a serialized state
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    /// Create an empty writer
+    pub fn new() -> StateWriter {
+        StateWriter { buf: Vec::new() }
+    }
+
+    /// Append a single byte
+    pub fn write_u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    /// Append a little-endian 16bit value
+    pub fn write_u16(&mut self, v: u16) {
+        self.write_u8(v as u8);
+        self.write_u8((v >> 8) as u8);
+    }
+
+    /// Append a little-endian 32bit value
+    pub fn write_u32(&mut self, v: u32) {
+        self.write_u16(v as u16);
+        self.write_u16((v >> 16) as u16);
+    }
+
+    /// Append a little-endian 64bit value
+    pub fn write_u64(&mut self, v: u64) {
+        self.write_u32(v as u32);
+        self.write_u32((v >> 32) as u32);
+    }
+
+    /// Append a signed 32bit value
+    pub fn write_i32(&mut self, v: i32) {
+        self.write_u32(v as u32);
+    }
+
+    /// Append a boolean as a single byte
+    pub fn write_bool(&mut self, v: bool) {
+        self.write_u8(v as u8);
+    }
+
+    /// Append a slice of bytes. The length is not stored, the reader
+    /// must know how many bytes to expect.
+    pub fn write_bytes(&mut self, v: &[u8]) {
+        self.buf.extend_from_slice(v);
+    }
+
+    /// Return the serialized state
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// Cursor used to read back a serialized state
+pub struct StateReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    /// Create a reader for the state `buf`
+    pub fn new(buf: &'a [u8]) -> StateReader<'a> {
+        StateReader { buf: buf, pos: 0 }
+    }
+
+    /// Read a single byte
+    pub fn read_u8(&mut self) -> IoResult<u8> {
+        match self.buf.get(self.pos) {
+            Some(&b) => {
+                self.pos += 1;
+                Ok(b)
+            }
+            None => Err(Error::new(ErrorKind::UnexpectedEof, "truncated save state")),
+        }
+    }
+
+    /// Read a little-endian 16bit value
+    pub fn read_u16(&mut self) -> IoResult<u16> {
+        let lo = try!(self.read_u8()) as u16;
+        let hi = try!(self.read_u8()) as u16;
+
+        Ok((hi << 8) | lo)
+    }
+
+    /// Read a little-endian 32bit value
+    pub fn read_u32(&mut self) -> IoResult<u32> {
+        let lo = try!(self.read_u16()) as u32;
+        let hi = try!(self.read_u16()) as u32;
+
+        Ok((hi << 16) | lo)
+    }
+
+    /// Read a little-endian 64bit value
+    pub fn read_u64(&mut self) -> IoResult<u64> {
+        let lo = try!(self.read_u32()) as u64;
+        let hi = try!(self.read_u32()) as u64;
+
+        Ok((hi << 32) | lo)
+    }
+
+    /// Read a signed 32bit value
+    pub fn read_i32(&mut self) -> IoResult<i32> {
+        self.read_u32().map(|v| v as i32)
+    }
+
+    /// Read a boolean. Anything other than 0 or 1 is rejected.
+    pub fn read_bool(&mut self) -> IoResult<bool> {
+        match try!(self.read_u8()) {
+            0 => Ok(false),
+            1 => Ok(true),
+            b => invalid(&format!("invalid boolean 0x{:02x}", b)),
+        }
+    }
+
+    /// Return the number of bytes left to read
+    pub fn remaining(&self) -> usize {
+        self.buf.len() - self.pos
+    }
+
+    /// Fill `out` with the next `out.len()` bytes
+    pub fn read_bytes(&mut self, out: &mut [u8]) -> IoResult<()> {
+        let end = self.pos + out.len();
+
+        if end > self.buf.len() {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "truncated save state"));
+        }
+
+        out.copy_from_slice(&self.buf[self.pos..end]);
+        self.pos = end;
+
+        Ok(())
+    }
+
+    /// Return `true` if the whole state has been consumed
+    pub fn is_empty(&self) -> bool {
+        self.pos == self.buf.len()
+    }
+}
+
+/// Return an `InvalidData` error with the given description
+pub fn invalid<T>(what: &str) -> IoResult<T> {
+    Err(Error::new(
+        ErrorKind::InvalidData,
+        format!("corrupted save state: {}", what),
+    ))
+}
+
+/// Return the path of the save state for numbered `slot` of the ROM
+/// at `rom_path`: `<rom>.ss<slot>`, next to the ROM and its `.sav`.
+pub fn slot_path(rom_path: &Path, slot: u8) -> PathBuf {
+    let mut path = rom_path.to_path_buf();
+
+    path.set_extension(format!("ss{}", slot));
+
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{StateReader, StateWriter};
+
+    #[test]
+    fn round_trip() {
+        let mut w = StateWriter::new();
+
+        w.write_u8(0xab);
+        w.write_u16(0x1234);
+        w.write_u32(0xdeadbeef);
+        w.write_u64(0x0123456789abcdef);
+        w.write_i32(-42);
+        w.write_bool(true);
+        w.write_bytes(&[1, 2, 3]);
+
+        let state = w.into_bytes();
+        let mut r = StateReader::new(&state);
+
+        assert!(r.read_u8().unwrap() == 0xab);
+        assert!(r.read_u16().unwrap() == 0x1234);
+        assert!(r.read_u32().unwrap() == 0xdeadbeef);
+        assert!(r.read_u64().unwrap() == 0x0123456789abcdef);
+        assert!(r.read_i32().unwrap() == -42);
+        assert!(r.read_bool().unwrap() == true);
+
+        let mut bytes = [0; 3];
+        r.read_bytes(&mut bytes).unwrap();
+        assert!(bytes == [1, 2, 3]);
+
+        assert!(r.is_empty());
+        assert!(r.read_u8().is_err());
+    }
+}
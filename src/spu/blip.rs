@@ -0,0 +1,174 @@
+//! Band-limited synthesis
+//!
+//! The sounds switch instantly between a handful of levels. Sampling
+//! those square edges at the output rate folds all the harmonics
+//! above the Nyquist frequency back into the audible range, which is
+//! very noticeable on high-pitched notes.
+//!
+//! Instead we track the level of each output at the system clock
+//! resolution and every time it changes we add a band-limited step
+//! (BLEP) at the exact position of the change. In practice we
+//! accumulate the derivative of the step, a windowed sinc impulse,
+//! into a short buffer which is integrated when the output samples
+//! are generated.
+//!
+//! The integrated signal then goes through a high-pass filter
+//! emulating the capacitor on the Game Boy's audio output, which
+//! removes the DC offset of the sound DACs.
+
+use spu::sinc::windowed_sinc;
+
+/// Number of output samples affected by a step. Half of it is the
+/// latency of the synthesis, in samples.
+const WIDTH: usize = 16;
+
+/// Cutoff frequency of the steps, relative to the Nyquist frequency
+/// of the output
+const CUTOFF: f32 = 0.9;
+
+/// Charge factor of the high-pass filter capacitor for each system
+/// clock tick
+const HIGHPASS_CHARGE: f32 = 0.999958;
+
+/// Band-limited synthesizer for a set of channels
+pub struct Synth {
+    /// Number of system clock ticks per output sample, which is also
+    /// the number of possible positions for a step
+    phases: u32,
+    /// One band-limited impulse of `WIDTH` taps for each phase
+    kernel: Vec<f32>,
+    /// Position of the next output sample in the channels' `deltas`
+    index: usize,
+    /// Charge factor of the high-pass capacitor for each output
+    /// sample
+    charge: f32,
+    /// Per-channel state
+    channels: Vec<Channel>,
+}
+
+/// State of a single output channel
+#[derive(Clone, Copy)]
+struct Channel {
+    /// Current level
+    level: i32,
+    /// Circular buffer of accumulated impulses for the next `WIDTH`
+    /// output samples
+    deltas: [f32; WIDTH],
+    /// Integrated signal
+    sum: f32,
+    /// High-pass filter capacitor
+    capacitor: f32,
+}
+
+impl Synth {
+    /// Create a synthesizer for `channels` channels generating one
+    /// output sample every `phases` system clock ticks
+    pub fn new(phases: u32, channels: usize) -> Synth {
+        let mut kernel = Vec::with_capacity(phases as usize * WIDTH);
+
+        for p in 0..phases {
+            // Position of the step between the previous sample and
+            // the next one
+            let offset = p as f32 / phases as f32;
+
+            let start = kernel.len();
+
+            for i in 0..WIDTH {
+                let x = i as f32 + 1. - offset - (WIDTH / 2) as f32;
+
+                kernel.push(windowed_sinc(x, (WIDTH / 2) as f32, CUTOFF));
+            }
+
+            // Normalize the impulse so that the step reaches exactly
+            // its target level once integrated
+            let sum: f32 = kernel[start..].iter().sum();
+
+            for tap in &mut kernel[start..] {
+                *tap /= sum;
+            }
+        }
+
+        Synth {
+            phases: phases,
+            kernel: kernel,
+            index: 0,
+            charge: HIGHPASS_CHARGE.powi(phases as i32),
+            channels: vec![
+                Channel {
+                    level: 0,
+                    deltas: [0.; WIDTH],
+                    sum: 0.,
+                    capacitor: 0.,
+                };
+                channels
+            ],
+        }
+    }
+
+    /// Change the level of `channel` `phase` system clock ticks
+    /// after the last output sample
+    pub fn set_level(&mut self, channel: usize, level: i32, phase: u32) {
+        let c = &mut self.channels[channel];
+
+        if level == c.level {
+            return;
+        }
+
+        let delta = (level - c.level) as f32;
+
+        c.level = level;
+
+        let phase = (phase % self.phases) as usize;
+        let impulse = &self.kernel[phase * WIDTH..(phase + 1) * WIDTH];
+
+        for (i, tap) in impulse.iter().enumerate() {
+            c.deltas[(self.index + i) % WIDTH] += delta * tap;
+        }
+    }
+
+    /// Generate the next output sample for all channels into
+    /// `frame`. The values are in the same unit as the levels,
+    /// centered around 0.
+    pub fn sample(&mut self, frame: &mut [f32]) {
+        for (c, out) in self.channels.iter_mut().zip(frame.iter_mut()) {
+            c.sum += c.deltas[self.index];
+            c.deltas[self.index] = 0.;
+
+            *out = c.sum - c.capacitor;
+            c.capacitor = c.sum - *out * self.charge;
+        }
+
+        self.index = (self.index + 1) % WIDTH;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Synth, WIDTH};
+
+    #[test]
+    fn band_limited_step() {
+        let mut synth = Synth::new(95, 2);
+        let mut frame = [0.; 2];
+
+        synth.set_level(0, 100, 40);
+
+        let out: Vec<f32> = (0..WIDTH * 2)
+            .map(|_| {
+                synth.sample(&mut frame);
+                frame[0]
+            })
+            .collect();
+
+        // The step is smooth and settles on its level, only slowly
+        // drained by the high-pass filter
+        assert!(out[0].abs() < 1.);
+        assert!(out[WIDTH / 2 - 1] > 1. && out[WIDTH / 2 - 1] < 99.);
+        assert!(out[WIDTH] > 95. && out[WIDTH] < 100.);
+        assert!(out[WIDTH + 1] < out[WIDTH]);
+
+        // The other channel doesn't move
+        synth.sample(&mut frame);
+        assert!(frame[1] == 0.);
+    }
+}
@@ -1,6 +1,9 @@
 //! Envelope function used by sounds 1, 2 and 4
 
-use spu::{Sample, SOUND_MAX};
+use std::io::Result as IoResult;
+
+use savestate::{Snapshot, StateReader, StateWriter};
+use spu::{Level, SOUND_MAX};
 
 #[derive(Clone, Copy)]
 pub struct Envelope {
@@ -55,7 +58,7 @@ impl Envelope {
         }
     }
 
-    pub fn into_sample(&self) -> Sample {
+    pub fn into_sample(&self) -> Level {
         self.volume.into_sample()
     }
 
@@ -65,6 +68,26 @@ impl Envelope {
     }
 }
 
+impl Snapshot for Envelope {
+    /// The register value already contains the current volume, only
+    /// the step counter needs to be saved on top of it.
+    fn snapshot(&self, w: &mut StateWriter) {
+        w.write_u8(self.into_reg());
+        w.write_u32(self.counter);
+    }
+
+    fn restore(&mut self, r: &mut StateReader) -> IoResult<()> {
+        *self = Envelope::from_reg(try!(r.read_u8()));
+        self.counter = try!(r.read_u32());
+
+        if self.step_duration != 0 {
+            self.counter %= self.step_duration;
+        }
+
+        Ok(())
+    }
+}
+
 // Sound envelopes can become louder or quieter
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub enum EnvelopeDirection {
@@ -94,11 +117,11 @@ impl Volume {
         v
     }
 
-    /// Convert from 4-bit volume value to Sample range
-    fn into_sample(self) -> Sample {
+    /// Convert from 4-bit volume value to DAC level
+    fn into_sample(self) -> Level {
         let Volume(v) = self;
 
-        v as Sample
+        v as Level
     }
 
     fn up(&mut self) {
@@ -1,8 +1,11 @@
 //! Game Boy sound 4 generates noise from a Linear Feedback Shift
 //! Register.
 
+use std::io::Result as IoResult;
+
+use savestate::{Snapshot, StateReader, StateWriter};
 use spu::envelope::Envelope;
-use spu::{Mode, Sample};
+use spu::{Level, Mode};
 
 pub struct LfsrWave {
     /// True if the wave is generating samples
@@ -51,7 +54,7 @@ impl LfsrWave {
         self.lfsr.step();
     }
 
-    pub fn sample(&self) -> Sample {
+    pub fn sample(&self) -> Level {
         if !self.running {
             return 0;
         }
@@ -111,6 +114,28 @@ impl LfsrWave {
     }
 }
 
+impl Snapshot for LfsrWave {
+    fn snapshot(&self, w: &mut StateWriter) {
+        w.write_bool(self.running);
+        self.lfsr.snapshot(w);
+        self.start_envelope.snapshot(w);
+        self.envelope.snapshot(w);
+        self.mode.snapshot(w);
+        w.write_u32(self.remaining);
+    }
+
+    fn restore(&mut self, r: &mut StateReader) -> IoResult<()> {
+        self.running = try!(r.read_bool());
+        try!(self.lfsr.restore(r));
+        try!(self.start_envelope.restore(r));
+        try!(self.envelope.restore(r));
+        try!(self.mode.restore(r));
+        self.remaining = try!(r.read_u32());
+
+        Ok(())
+    }
+}
+
 #[derive(Clone, Copy)]
 pub struct Lfsr {
     register: u16,
@@ -180,3 +205,21 @@ impl Lfsr {
         };
     }
 }
+
+impl Snapshot for Lfsr {
+    /// The width and clock are rebuilt from the NR43 value, only the
+    /// shift register and counter are saved on top of it.
+    fn snapshot(&self, w: &mut StateWriter) {
+        w.write_u8(self.reg);
+        w.write_u16(self.register);
+        w.write_u32(self.counter);
+    }
+
+    fn restore(&mut self, r: &mut StateReader) -> IoResult<()> {
+        *self = Lfsr::from_reg(try!(r.read_u8()));
+        self.register = try!(r.read_u16());
+        self.counter = try!(r.read_u32()) % self.step_duration;
+
+        Ok(())
+    }
+}
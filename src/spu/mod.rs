@@ -1,16 +1,25 @@
 //! Game Boy sound emulation
 
+use std::io::Result as IoResult;
+use std::path::Path;
 use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
 
+use savestate::{self, Snapshot, StateReader, StateWriter};
+
+use spu::blip::Synth;
 use spu::envelope::Envelope;
 use spu::lfsr_wave::{Lfsr, LfsrWave};
 use spu::ram_wave::{OutputLevel, RamWave};
+use spu::recorder::Recorder;
 use spu::rectangle_wave::{DutyCycle, RectangleWave, Sweep};
 
+mod blip;
 mod envelope;
 mod lfsr_wave;
 mod ram_wave;
 mod rectangle_wave;
+mod recorder;
+pub mod sinc;
 
 /// Sound Processing Unit state.
 pub struct Spu {
@@ -21,10 +30,20 @@ pub struct Spu {
     /// Channel used to send the generated audio samples to the
     /// backend.
     output: SyncSender<SampleBuffer>,
-    /// Current sample buffer
+    /// Current sample buffer, interleaved left and right samples
     buffer: SampleBuffer,
     /// Position in the sample buffer
     position: usize,
+    /// `true` if the samples are not sent to the backend, used when
+    /// the emulation runs too fast for them to be played
+    muted: bool,
+    /// Band-limited synthesizer generating the samples from the
+    /// output levels
+    synth: Synth,
+    /// Levels of the 4 sounds during the last clock tick
+    levels: [Level; 4],
+    /// Audio recorder, if a recording is in progress
+    recorder: Option<Recorder>,
     /// Sound 1, rectangular wave with envelope function and
     /// frequency sweep
     sound1: RectangleWave,
@@ -48,8 +67,12 @@ impl Spu {
             enabled: false,
             divider: 0,
             output: tx,
-            buffer: [0; SAMPLES_PER_BUFFER],
+            buffer: [0; SAMPLES_PER_BUFFER * AUDIO_CHANNELS],
             position: 0,
+            muted: false,
+            synth: Synth::new(SAMPLER_DIVIDER, AUDIO_CHANNELS),
+            levels: [0; 4],
+            recorder: None,
             sound1: RectangleWave::new(),
             sound2: RectangleWave::new(),
             sound3: RamWave::new(),
@@ -63,6 +86,7 @@ impl Spu {
 
     pub fn step(&mut self) {
         if !self.enabled {
+            self.step_disabled();
             return;
         }
 
@@ -71,6 +95,32 @@ impl Spu {
         self.sound3.step();
         self.sound4.step();
 
+        self.step_sampler();
+
+        self.update_levels();
+    }
+
+    /// Called instead of `step` while the system clock is stopped by
+    /// the STOP instruction. The sounds are frozen but the output is
+    /// still sampled so that the recording stays in sync with the
+    /// emulation.
+    pub fn step_stopped(&mut self) {
+        if self.enabled {
+            self.step_sampler();
+        } else {
+            self.step_disabled();
+        }
+    }
+
+    fn step_disabled(&mut self) {
+        // The recording keeps going so that it stays in sync with the
+        // emulation
+        if let Some(ref mut recorder) = self.recorder {
+            recorder.step_silent();
+        }
+    }
+
+    fn step_sampler(&mut self) {
         if self.divider == 0 {
             self.divider = SAMPLER_DIVIDER;
 
@@ -80,7 +130,8 @@ impl Spu {
         self.divider -= 1;
     }
 
-    fn sample(&mut self) {
+    /// Feed the current sound levels to the synthesizer
+    fn update_levels(&mut self) {
         let sounds = [
             self.sound1.sample(),
             self.sound2.sample(),
@@ -88,22 +139,98 @@ impl Spu {
             self.sound4.sample(),
         ];
 
-        // For now let's just add both outputs
-        let sample = self.so1.sample(sounds) + self.so2.sample(sounds);
+        if sounds != self.levels {
+            self.levels = sounds;
+            self.mix_levels();
+
+            let phase = self.phase();
+
+            if let Some(ref mut recorder) = self.recorder {
+                recorder.set_levels(sounds, phase);
+            }
+        }
+    }
+
+    /// Return the number of clock ticks since the last sample
+    fn phase(&self) -> u32 {
+        SAMPLER_DIVIDER - 1 - self.divider
+    }
+
+    /// Recompute the output levels from the sound levels. Must be
+    /// called when the sound outputs are reconfigured.
+    fn mix_levels(&mut self) {
+        let phase = self.phase();
 
-        self.output_sample(sample);
+        // SO2 drives the left speaker, SO1 the right one
+        let left = self.so2.level(self.levels);
+        let right = self.so1.level(self.levels);
+
+        self.synth.set_level(0, left as i32, phase);
+        self.synth.set_level(1, right as i32, phase);
+    }
+
+    fn sample(&mut self) {
+        let mut frame = [0.; AUDIO_CHANNELS];
+
+        self.synth.sample(&mut frame);
+
+        let left = to_sample(frame[0], OUTPUT_LEVEL_MAX);
+        let right = to_sample(frame[1], OUTPUT_LEVEL_MAX);
+
+        if let Some(ref mut recorder) = self.recorder {
+            recorder.record([left, right]);
+        }
+
+        self.output_frame(left, right);
+    }
+
+    /// Start recording the audio output to the WAV file at `path`,
+    /// see `Recorder::new`. A recording already in progress is
+    /// stopped first.
+    pub fn start_recording(&mut self, path: &Path, stems: bool, rate: Option<u32>) -> IoResult<()> {
+        try!(self.stop_recording());
+
+        let mut recorder = try!(Recorder::new(path, stems, rate));
+
+        recorder.set_levels(self.levels, self.phase());
+
+        self.recorder = Some(recorder);
+
+        Ok(())
+    }
+
+    /// Stop the recording in progress, if any
+    pub fn stop_recording(&mut self) -> IoResult<()> {
+        match self.recorder.take() {
+            Some(recorder) => recorder.finish(),
+            None => Ok(()),
+        }
+    }
+
+    /// Return `true` if a recording is in progress
+    pub fn recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    /// Stop (`true`) or resume sending the samples to the backend.
+    /// Recordings are not affected.
+    pub fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
     }
 
     /// Handle sample buffering and sending them through the
     /// asynchronous channel.
-    fn output_sample(&mut self, sample: Sample) {
-        self.buffer[self.position] = sample;
+    fn output_frame(&mut self, left: Sample, right: Sample) {
+        self.buffer[self.position] = left;
+        self.buffer[self.position + 1] = right;
 
-        self.position += 1;
+        self.position += AUDIO_CHANNELS;
 
         if self.position == self.buffer.len() {
             // Buffer filled, send it over and reset the position
-            if let Err(e) = self.output.try_send(self.buffer) {
+            if self.muted {
+                // Nobody's listening
+            } else if let Err(e) = self.output.try_send(self.buffer) {
                 match e {
                     TrySendError::Full(_) => error!(
                         "Sound channel is full, dropping {} samples",
@@ -423,8 +550,8 @@ impl Spu {
 
         let index = index * 2;
 
-        let s0 = (val >> 4) as Sample;
-        let s1 = (val & 0xf) as Sample;
+        let s0 = (val >> 4) as Level;
+        let s1 = (val & 0xf) as Level;
 
         self.sound3.set_ram_sample(index, s0);
         self.sound3.set_ram_sample(index + 1, s1);
@@ -528,6 +655,8 @@ impl Spu {
 
         self.so1.set_volume(OutputVolume::from_field(val & 0xf));
         self.so2.set_volume(OutputVolume::from_field(val >> 4));
+
+        self.mix_levels();
     }
 
     /// Retreive sound output mixer register
@@ -546,6 +675,8 @@ impl Spu {
 
         self.so1.set_mixer(Mixer::from_field(val & 0xf));
         self.so2.set_mixer(Mixer::from_field(val >> 4));
+
+        self.mix_levels();
     }
 
     /// Get global sound enable and sound status
@@ -579,6 +710,49 @@ impl Spu {
 
         self.so1 = SoundOutput::new();
         self.so2 = SoundOutput::new();
+
+        self.mix_levels();
+    }
+}
+
+impl Snapshot for Spu {
+    /// The sample buffer is not part of the saved state, it only
+    /// holds output that hasn't been sent to the backend yet.
+    fn snapshot(&self, w: &mut StateWriter) {
+        w.write_bool(self.enabled);
+        w.write_u32(self.divider);
+        self.sound1.snapshot(w);
+        self.sound2.snapshot(w);
+        self.sound3.snapshot(w);
+        self.sound4.snapshot(w);
+        w.write_u8(self.nr50());
+        w.write_u8(self.nr51());
+    }
+
+    fn restore(&mut self, r: &mut StateReader) -> IoResult<()> {
+        self.enabled = try!(r.read_bool());
+        self.divider = try!(r.read_u32());
+        if self.divider >= SAMPLER_DIVIDER {
+            return savestate::invalid("SPU divider out of range");
+        }
+
+        try!(self.sound1.restore(r));
+        try!(self.sound2.restore(r));
+        try!(self.sound3.restore(r));
+        try!(self.sound4.restore(r));
+
+        let nr50 = try!(r.read_u8());
+        self.so1.set_volume(OutputVolume::from_field(nr50 & 0xf));
+        self.so2.set_volume(OutputVolume::from_field(nr50 >> 4));
+
+        let nr51 = try!(r.read_u8());
+        self.so1.set_mixer(Mixer::from_field(nr51 & 0xf));
+        self.so2.set_mixer(Mixer::from_field(nr51 >> 4));
+
+        self.update_levels();
+        self.mix_levels();
+
+        Ok(())
     }
 }
 
@@ -589,6 +763,22 @@ pub enum Mode {
     Counter = 1,
 }
 
+impl Snapshot for Mode {
+    fn snapshot(&self, w: &mut StateWriter) {
+        w.write_u8(*self as u8);
+    }
+
+    fn restore(&mut self, r: &mut StateReader) -> IoResult<()> {
+        *self = match try!(r.read_u8()) {
+            0 => Mode::Continuous,
+            1 => Mode::Counter,
+            _ => return savestate::invalid("sound mode"),
+        };
+
+        Ok(())
+    }
+}
+
 /// The Game Boy has two sound outputs: SO0 and SO1
 struct SoundOutput {
     /// Sound mixer for this output
@@ -604,7 +794,9 @@ impl SoundOutput {
         }
     }
 
-    fn sample(&self, sounds: [Sample; 4]) -> Sample {
+    /// Return the level of this output, between 0 and
+    /// `OUTPUT_LEVEL_MAX`
+    fn level(&self, sounds: [Level; 4]) -> u16 {
         let mixed = self.mixer.mix(sounds);
 
         self.volume.process(mixed)
@@ -658,7 +850,7 @@ impl Mixer {
         f
     }
 
-    fn mix(self, sounds: [Sample; 4]) -> Sample {
+    fn mix(self, sounds: [Level; 4]) -> Level {
         let mut r = 0;
 
         for i in 0..4 {
@@ -677,7 +869,8 @@ impl Mixer {
 struct OutputVolume {
     /// Not used for now
     vin: bool,
-    /// Sound volume divider, between 1 (full volume) and 8(min)
+    /// Sound volume, between 0 (1/8 of the full volume) and 7
+    /// (full volume)
     level: u8,
 }
 
@@ -689,21 +882,23 @@ impl OutputVolume {
 
         OutputVolume {
             vin: field & 8 != 0,
-            level: 8 - (field & 7),
+            level: field & 7,
         }
     }
 
     fn into_field(self) -> u8 {
-        ((self.vin as u8) << 3) | (8 - self.level)
+        ((self.vin as u8) << 3) | self.level
     }
 
-    fn process(self, s: Sample) -> Sample {
-        s / self.level as Sample
+    /// Scale the mixed level `s` by the volume. The result is 8
+    /// times bigger than `s` at full volume.
+    fn process(self, s: Level) -> u16 {
+        s as u16 * (self.level as u16 + 1)
     }
 }
 
-/// Return the number of sound samples that are generated during a
-/// period of `steps` SysClk ticks.
+/// Return the number of stereo sound frames that are generated
+/// during a period of `steps` SysClk ticks.
 pub fn samples_per_steps(steps: u32) -> u32 {
     steps / SAMPLER_DIVIDER
 }
@@ -711,11 +906,27 @@ pub fn samples_per_steps(steps: u32) -> u32 {
 /// Each sound uses a 4bit DAC which means it they can only output
 /// 16 sound levels each. There are 4 channels in total which means
 /// that the sum is in the range [0, 60], so a u8 is plenty enough.
-pub type Sample = u8;
+pub type Level = u8;
+
+/// Signed 16 bit audio sample sent to the backend
+pub type Sample = i16;
+
+/// Convert the output `v` of a synthesizer to a sample, `max` being
+/// the level giving a full scale sample
+fn to_sample(v: f32, max: u16) -> Sample {
+    let v = v * Sample::max_value() as f32 / max as f32;
+
+    v.max(Sample::min_value() as f32).min(Sample::max_value() as f32) as Sample
+}
 
-pub type SampleBuffer = [Sample; SAMPLES_PER_BUFFER];
+/// Batch of samples sent to the audio backend. Contains
+/// `SAMPLES_PER_BUFFER` frames made of one left and one right sample.
+pub type SampleBuffer = [Sample; SAMPLES_PER_BUFFER * AUDIO_CHANNELS];
 
-/// We buffer the sound samples before we send them to the next
+/// Number of interleaved channels in a `SampleBuffer`
+pub const AUDIO_CHANNELS: usize = 2;
+
+/// We buffer the sound frames before we send them to the next
 /// stage. Bigger buffers will reduce the contention on the channel
 /// but it will also increase latency.
 pub const SAMPLES_PER_BUFFER: usize = 0x100;
@@ -730,6 +941,10 @@ pub const SAMPLES_PER_BUFFER: usize = 0x100;
 /// frequency.
 const SAMPLER_DIVIDER: u32 = 95;
 
+/// Sample rate of the audio stream generated by the SPU, in Hz. The
+/// exact rate is 4194304 / 95, about 44150.57Hz, it's rounded down
+/// here and in the header of the WAV recordings, which makes them
+/// drift about 46ms per hour of recording compared to the emulation.
 pub const SAMPLE_RATE: u32 = ::GAMEBOY_SYSTEM_CLOCK_FREQUENCY as u32 / SAMPLER_DIVIDER;
 
 /// Depth of the channel between the Spu and the audio
@@ -742,11 +957,11 @@ pub const SAMPLE_RATE: u32 = ::GAMEBOY_SYSTEM_CLOCK_FREQUENCY as u32 / SAMPLER_D
 const CHANNEL_DEPTH: usize = 4;
 
 /// Maximum possible volume for a single sound
-const SOUND_MAX: Sample = 15;
+const SOUND_MAX: Level = 15;
 
-/// Maximum possible value for a sample. There are 4 sounds on two
-/// channels.
-pub const SAMPLE_MAX: Sample = SOUND_MAX * 4 * 2;
+/// Maximum possible level of an output. There are 4 sounds mixed
+/// into each output, then scaled by at most 8 by the output volume.
+const OUTPUT_LEVEL_MAX: u16 = SOUND_MAX as u16 * 4 * 8;
 
 #[cfg(test)]
 mod tests {
@@ -903,4 +1118,29 @@ mod tests {
         readback_test! {nr50, set_nr50, 0x00}
         readback_test! {nr51, set_nr51, 0x00}
     }
+
+    #[test]
+    fn stereo_output() {
+        use spu::{Mixer, OutputVolume, SoundOutput};
+
+        let sounds = [15, 10, 0, 4];
+
+        let mut left = SoundOutput::new();
+        let mut right = SoundOutput::new();
+
+        // NR51 = 0x2d: sound 2 on the left, sounds 1, 3 and 4 on the
+        // right
+        left.set_mixer(Mixer::from_field(0x2));
+        right.set_mixer(Mixer::from_field(0xd));
+
+        // NR50 = 0x73: full volume on the left, half on the right
+        left.set_volume(OutputVolume::from_field(0x7));
+        right.set_volume(OutputVolume::from_field(0x3));
+
+        assert!(left.level(sounds) == 80);
+        assert!(right.level(sounds) == 76);
+
+        right.set_volume(OutputVolume::from_field(0x0));
+        assert!(right.level(sounds) == 19);
+    }
 }
@@ -1,6 +1,9 @@
 //! Game Boy sound 3 generates a sound from samples stored in RAM
 
-use spu::{Mode, Sample};
+use std::io::Result as IoResult;
+
+use savestate::{self, Snapshot, StateReader, StateWriter};
+use spu::{Level, Mode};
 
 pub struct RamWave {
     /// True if the sound is generating samples
@@ -19,7 +22,7 @@ pub struct RamWave {
     /// Play mode (continuous or counter)
     mode: Mode,
     /// Custom sample RAM, 32 samples
-    samples: [Sample; 32],
+    samples: [Level; 32],
     /// Currently played sample
     index: u8,
 }
@@ -77,7 +80,7 @@ impl RamWave {
         self.counter -= 1;
     }
 
-    pub fn sample(&self) -> Sample {
+    pub fn sample(&self) -> Level {
         if !self.running {
             return 0;
         }
@@ -89,11 +92,11 @@ impl RamWave {
         self.output_level.process(sample)
     }
 
-    pub fn ram_sample(&self, index: u8) -> Sample {
+    pub fn ram_sample(&self, index: u8) -> Level {
         self.samples[index as usize]
     }
 
-    pub fn set_ram_sample(&mut self, index: u8, s: Sample) {
+    pub fn set_ram_sample(&mut self, index: u8, s: Level) {
         self.samples[index as usize] = s;
     }
 
@@ -152,6 +155,44 @@ impl RamWave {
     }
 }
 
+impl Snapshot for RamWave {
+    fn snapshot(&self, w: &mut StateWriter) {
+        w.write_bool(self.running);
+        w.write_bool(self.enabled);
+        w.write_u32(self.remaining);
+        w.write_u8(self.output_level.into_field());
+        w.write_u16(self.divider);
+        w.write_u16(self.counter);
+        self.mode.snapshot(w);
+        w.write_bytes(&self.samples);
+        w.write_u8(self.index);
+    }
+
+    fn restore(&mut self, r: &mut StateReader) -> IoResult<()> {
+        self.running = try!(r.read_bool());
+        self.enabled = try!(r.read_bool());
+        self.remaining = try!(r.read_u32());
+
+        let level = try!(r.read_u8());
+        if level > 3 {
+            return savestate::invalid("sound 3 output level");
+        }
+        self.output_level = OutputLevel::from_field(level);
+
+        self.divider = try!(r.read_u16());
+        self.counter = try!(r.read_u16());
+        try!(self.mode.restore(r));
+        try!(r.read_bytes(&mut self.samples));
+        self.index = try!(r.read_u8());
+
+        if self.divider >= 0x800 || self.index as usize >= self.samples.len() {
+            return savestate::invalid("sound 3 state");
+        }
+
+        Ok(())
+    }
+}
+
 /// The wave data can be didived before being sent out
 #[derive(Clone, Copy)]
 pub enum OutputLevel {
@@ -180,7 +221,7 @@ impl OutputLevel {
         self as u8
     }
 
-    fn process(self, sample: Sample) -> Sample {
+    fn process(self, sample: Level) -> Level {
         match self {
             OutputLevel::Mute => 0,
             OutputLevel::Full => sample,
@@ -0,0 +1,306 @@
+//! Audio recording
+//!
+//! The recorder taps the stereo stream generated by the SPU and
+//! writes it to a WAV file, either at the native sample rate or
+//! resampled to a more common one. It can also record each of the
+//! four sounds into a separate "stem" file, before they're mixed and
+//! scaled by the output volume.
+
+use std::fs::File;
+use std::io::{BufWriter, Error, Result as IoResult};
+use std::path::{Path, PathBuf};
+
+use spu::blip::Synth;
+use spu::sinc::SincResampler;
+use spu::{to_sample, Level, Sample, AUDIO_CHANNELS, SAMPLER_DIVIDER, SAMPLE_RATE, SOUND_MAX};
+use wav::WavWriter;
+
+type WavFile = WavWriter<BufWriter<File>>;
+
+/// Audio recorder state
+pub struct Recorder {
+    /// Stereo mix
+    mix: WavFile,
+    /// Stem files, one per sound. Empty if stems aren't recorded.
+    stems: Vec<WavFile>,
+    /// Band-limited synthesizer for the stems
+    stem_synth: Synth,
+    /// Resampling filters, `None` when recording at the native rate
+    resampling: Option<Resampling>,
+    /// First error encountered while writing, reported by `finish`
+    error: Option<Error>,
+    /// Clock ticks since the last silent frame while the SPU is
+    /// disabled
+    silent_ticks: u32,
+}
+
+/// Resampling state for the mix and the stems
+struct Resampling {
+    /// Native sample rate / recording sample rate
+    ratio: f32,
+    mix: SincResampler,
+    stems: SincResampler,
+}
+
+impl Recorder {
+    /// Create a recorder writing to the WAV file at `path`. `rate` is
+    /// the sample rate of the recording, `None` to use the native
+    /// rate of the SPU. If `stems` is true the sounds are also
+    /// recorded separately, see `stem_path`.
+    pub fn new(path: &Path, stems: bool, rate: Option<u32>) -> IoResult<Recorder> {
+        let out_rate = rate.unwrap_or(SAMPLE_RATE);
+
+        let mix = try!(create(path, AUDIO_CHANNELS as u16, out_rate));
+
+        let mut stem_files = Vec::new();
+
+        if stems {
+            for sound in 0..4 {
+                stem_files.push(try!(create(&stem_path(path, sound), 1, out_rate)));
+            }
+        }
+
+        let resampling = rate.map(|rate| {
+            let ratio = SAMPLE_RATE as f32 / rate as f32;
+
+            Resampling {
+                ratio: ratio,
+                mix: SincResampler::new(AUDIO_CHANNELS, ratio),
+                stems: SincResampler::new(4, ratio),
+            }
+        });
+
+        Ok(Recorder {
+            mix: mix,
+            stems: stem_files,
+            stem_synth: Synth::new(SAMPLER_DIVIDER, 4),
+            resampling: resampling,
+            error: None,
+            silent_ticks: 0,
+        })
+    }
+
+    /// Update the levels of the four sounds `phase` system clock
+    /// ticks after the last sample
+    pub fn set_levels(&mut self, sounds: [Level; 4], phase: u32) {
+        if self.stems.is_empty() {
+            return;
+        }
+
+        for (i, &level) in sounds.iter().enumerate() {
+            self.stem_synth.set_level(i, level as i32, phase);
+        }
+    }
+
+    /// Record the next output frame
+    pub fn record(&mut self, frame: [Sample; AUDIO_CHANNELS]) {
+        let mut stems = [0.; 4];
+
+        if !self.stems.is_empty() {
+            self.stem_synth.sample(&mut stems);
+        }
+
+        let r = match self.resampling {
+            None => Recorder::write_frame(&mut self.mix, &mut self.stems, &frame, &stems),
+            Some(ref mut resampling) => {
+                let input = [frame[0] as f32, frame[1] as f32];
+
+                resampling.mix.push(&input);
+                resampling.stems.push(&stems);
+
+                let mut r = Ok(());
+                let mut out = [0.; AUDIO_CHANNELS];
+                let mut out_stems = [0.; 4];
+
+                while resampling.mix.next(resampling.ratio, &mut out) {
+                    resampling.stems.next(resampling.ratio, &mut out_stems);
+
+                    let out = [clamp(out[0]), clamp(out[1])];
+
+                    r = r.and(Recorder::write_frame(
+                        &mut self.mix,
+                        &mut self.stems,
+                        &out,
+                        &out_stems,
+                    ));
+                }
+
+                r
+            }
+        };
+
+        if let Err(e) = r {
+            if self.error.is_none() {
+                error!("Audio recording failed: {}", e);
+                self.error = Some(e);
+            }
+        }
+    }
+
+    /// Called at each clock tick while the SPU is disabled, records
+    /// a silent frame at the sample rate
+    pub fn step_silent(&mut self) {
+        if self.silent_ticks == 0 {
+            self.set_levels([0; 4], 0);
+        }
+
+        self.silent_ticks += 1;
+
+        if self.silent_ticks == SAMPLER_DIVIDER {
+            self.silent_ticks = 0;
+            self.record([0; AUDIO_CHANNELS]);
+        }
+    }
+
+    fn write_frame(
+        mix: &mut WavFile,
+        stem_files: &mut [WavFile],
+        frame: &[Sample],
+        stems: &[f32],
+    ) -> IoResult<()> {
+        try!(mix.write_samples(frame));
+
+        for (file, &stem) in stem_files.iter_mut().zip(stems) {
+            // Use the same gain as the mix at full volume, where
+            // the 4 sounds add up
+            let s = to_sample(stem, SOUND_MAX as u16 * 4);
+
+            try!(file.write_samples(&[s]));
+        }
+
+        Ok(())
+    }
+
+    /// Finalize the WAV files. Returns the first error encountered
+    /// during the recording, if any.
+    pub fn finish(self) -> IoResult<()> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+
+        try!(self.mix.finish());
+
+        for stem in self.stems {
+            try!(stem.finish());
+        }
+
+        Ok(())
+    }
+}
+
+/// Return the path of the stem file for `sound` (between 0 and 3)
+/// when recording to `path`: "song.wav" gives "song-sound1.wav" to
+/// "song-sound4.wav".
+pub fn stem_path(path: &Path, sound: usize) -> PathBuf {
+    let stem = path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+
+    path.with_file_name(format!("{}-sound{}.wav", stem, sound + 1))
+}
+
+fn create(path: &Path, channels: u16, rate: u32) -> IoResult<WavFile> {
+    let file = try!(File::create(path));
+
+    WavWriter::new(BufWriter::new(file), channels, rate)
+}
+
+/// Convert a resampled value back to a sample
+fn clamp(v: f32) -> Sample {
+    v.round().max(Sample::min_value() as f32).min(Sample::max_value() as f32) as Sample
+}
+
+#[cfg(test)]
+mod tests {
+    use super::stem_path;
+    use spu::{Spu, SAMPLER_DIVIDER, SAMPLE_RATE};
+    use std::fs;
+
+    /// Return the sample rate, channel count and sample frames of a
+    /// WAV file written by the recorder
+    fn read_wav(path: &::std::path::Path) -> (u32, u16, Vec<Vec<i16>>) {
+        let data = fs::read(path).unwrap();
+
+        let u16_at = |i: usize| data[i] as u16 | (data[i + 1] as u16) << 8;
+        let u32_at = |i: usize| u16_at(i) as u32 | (u16_at(i + 2) as u32) << 16;
+
+        let channels = u16_at(22);
+        let rate = u32_at(24);
+        let size = u32_at(40) as usize;
+
+        assert!(data.len() == 44 + size);
+
+        let frames = data[44..]
+            .chunks(channels as usize * 2)
+            .map(|f| f.chunks(2).map(|s| (s[0] as u16 | (s[1] as u16) << 8) as i16).collect())
+            .collect();
+
+        (rate, channels, frames)
+    }
+
+    #[test]
+    fn resampled_stems() {
+        let dir = ::std::env::temp_dir().join(format!("gb-rs-recorder-{}", ::std::process::id()));
+        let path = dir.join("tone.wav");
+        let (mut spu, _rx) = Spu::new();
+
+        fs::create_dir_all(&dir).unwrap();
+
+        spu.set_muted(true);
+        spu.set_nr52(0x80);
+        spu.set_nr50(0x77);
+        spu.set_nr51(0xff);
+        // Sound 1 at full volume, about 440Hz
+        spu.set_nr11(0x80);
+        spu.set_nr12(0xf0);
+        spu.set_nr13(0xd6);
+        spu.set_nr14(0x86);
+
+        spu.start_recording(&path, true, Some(22050)).unwrap();
+
+        // 2000 native frames of tone, then 2000 with the SPU off
+        for _ in 0..SAMPLER_DIVIDER * 2000 {
+            spu.step();
+        }
+
+        spu.set_nr52(0);
+
+        for _ in 0..SAMPLER_DIVIDER * 2000 {
+            spu.step();
+        }
+
+        spu.stop_recording().unwrap();
+
+        let wavs: Vec<_> = Some(path.clone())
+            .into_iter()
+            .chain((0..4).map(|s| stem_path(&path, s)))
+            .map(|f| read_wav(&f))
+            .collect();
+
+        let _ = fs::remove_dir_all(&dir);
+
+        // The SPU being off doesn't stop the recording. Give or take a
+        // few frames depending on the position of the resampler.
+        let expected = 4000 * 22050 / SAMPLE_RATE as i32;
+
+        for (i, &(rate, channels, ref frames)) in wavs.iter().enumerate() {
+            assert!(rate == 22050);
+            assert!(channels == if i == 0 { 2 } else { 1 });
+            assert!((frames.len() as i32 - expected).abs() <= 8);
+        }
+
+        let loud = |frames: &[Vec<i16>]| frames.iter().any(|f| f.iter().any(|&s| s.abs() > 1000));
+        // The high-pass filter of the stems takes a while to settle
+        let quiet = |frames: &[Vec<i16>]| frames.iter().all(|f| f.iter().all(|&s| s.abs() < 64));
+
+        let (_, _, ref mix) = wavs[0];
+        let (_, _, ref sound1) = wavs[1];
+        let half = mix.len() / 2;
+
+        assert!(loud(&mix[..half]) && loud(&sound1[..half]));
+        // Silent frames are recorded while NR52 is off
+        assert!(quiet(&mix[half * 7 / 4..]) && quiet(&sound1[half * 7 / 4..]));
+
+        for &(_, _, ref frames) in &wavs[2..] {
+            assert!(quiet(frames));
+        }
+    }
+}
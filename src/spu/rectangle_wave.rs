@@ -2,8 +2,11 @@
 //! with an envelope function. Channel 1 can also sweep through a
 //! frequency range.
 
+use std::io::Result as IoResult;
+
+use savestate::{self, Snapshot, StateReader, StateWriter};
 use spu::envelope::Envelope;
-use spu::{Mode, Sample};
+use spu::{Level, Mode};
 
 pub struct RectangleWave {
     /// True if the sound is generating samples
@@ -87,7 +90,7 @@ impl RectangleWave {
         self.counter -= 1;
     }
 
-    pub fn sample(&self) -> Sample {
+    pub fn sample(&self) -> Level {
         if !self.running {
             return 0;
         }
@@ -170,6 +173,44 @@ impl RectangleWave {
     }
 }
 
+impl Snapshot for RectangleWave {
+    fn snapshot(&self, w: &mut StateWriter) {
+        w.write_bool(self.running);
+        w.write_u8(self.duty.into_field());
+        w.write_u16(self.counter);
+        w.write_u16(self.divider);
+        w.write_u8(self.phase);
+        self.start_envelope.snapshot(w);
+        self.envelope.snapshot(w);
+        self.mode.snapshot(w);
+        w.write_u32(self.remaining);
+        self.sweep.snapshot(w);
+    }
+
+    fn restore(&mut self, r: &mut StateReader) -> IoResult<()> {
+        self.running = try!(r.read_bool());
+
+        let duty = try!(r.read_u8());
+        if duty > 3 {
+            return savestate::invalid("duty cycle");
+        }
+        self.duty = DutyCycle::from_field(duty);
+
+        self.counter = try!(r.read_u16());
+        self.divider = try!(r.read_u16());
+        self.phase = try!(r.read_u8());
+        if self.divider >= 0x800 || self.phase >= 8 {
+            return savestate::invalid("rectangle wave frequency");
+        }
+
+        try!(self.start_envelope.restore(r));
+        try!(self.envelope.restore(r));
+        try!(self.mode.restore(r));
+        self.remaining = try!(r.read_u32());
+        self.sweep.restore(r)
+    }
+}
+
 /// Rectangular wave duty cycle.
 #[derive(Clone, Copy)]
 pub enum DutyCycle {
@@ -293,6 +334,24 @@ impl Sweep {
     }
 }
 
+impl Snapshot for Sweep {
+    fn snapshot(&self, w: &mut StateWriter) {
+        w.write_u8(self.into_reg());
+        w.write_u32(self.counter);
+    }
+
+    fn restore(&mut self, r: &mut StateReader) -> IoResult<()> {
+        *self = Sweep::from_reg(try!(r.read_u8()));
+        self.counter = try!(r.read_u32());
+
+        if self.step_duration != 0 {
+            self.counter %= self.step_duration;
+        }
+
+        Ok(())
+    }
+}
+
 // Sound envelopes can become louder or quieter
 #[derive(Clone, Copy, PartialEq, Eq)]
 enum SweepDirection {
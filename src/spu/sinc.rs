@@ -0,0 +1,203 @@
+//! Windowed-sinc resampling
+//!
+//! Each output frame is interpolated from the `TAPS` surrounding
+//! input frames using the precomputed filter for the nearest of
+//! `PHASES` possible fractional positions (polyphase filter).
+
+use std::f32::consts::PI;
+
+/// Number of input frames used to compute each output frame
+const TAPS: usize = 16;
+
+/// Number of precomputed fractional positions between two input
+/// frames
+const PHASES: usize = 256;
+
+/// Cutoff frequency of the filter relative to the Nyquist frequency
+/// of the input
+const CUTOFF: f32 = 0.9;
+
+/// Sinc function with a `cutoff` frequency relative to the Nyquist
+/// frequency, attenuated by a Blackman window spanning
+/// [-half_width, half_width]
+pub fn windowed_sinc(x: f32, half_width: f32, cutoff: f32) -> f32 {
+    if x.abs() >= half_width {
+        return 0.;
+    }
+
+    let sinc = match x == 0. {
+        true => 1.,
+        false => (PI * cutoff * x).sin() / (PI * cutoff * x),
+    };
+
+    let t = x / half_width;
+    let window = 0.42 + 0.5 * (PI * t).cos() + 0.08 * (2. * PI * t).cos();
+
+    cutoff * sinc * window
+}
+
+/// Polyphase resampler for interleaved frames of `channels` samples
+pub struct SincResampler {
+    /// Number of samples per frame
+    channels: usize,
+    /// Input frames not consumed yet, including the ones still needed
+    /// by the filter
+    input: Vec<f32>,
+    /// Position of the next output frame in `input`, relative to the
+    /// first tap of the filter
+    pos: f32,
+    /// `TAPS` filter coefficients for each phase
+    kernel: Vec<f32>,
+}
+
+impl SincResampler {
+    /// Create a resampler for the given `ratio` (input rate / output
+    /// rate). The ratio can be adjusted afterwards but it's used to
+    /// select the cutoff frequency of the filter.
+    pub fn new(channels: usize, ratio: f32) -> SincResampler {
+        SincResampler {
+            channels: channels,
+            input: vec![0.; TAPS * channels],
+            pos: 0.,
+            kernel: kernel(ratio),
+        }
+    }
+
+    /// Recompute the filter for a new `ratio`. Only needed when the
+    /// ratio changes significantly, the queued input is kept.
+    #[cfg_attr(not(feature = "sdl"), allow(dead_code))]
+    pub fn set_ratio(&mut self, ratio: f32) {
+        self.kernel = kernel(ratio);
+    }
+
+    /// Queue an input frame
+    pub fn push(&mut self, frame: &[f32]) {
+        // Drop the frames we're done with
+        let consumed = self.pos as usize;
+
+        if consumed > 0 {
+            self.input.drain(..consumed * self.channels);
+            self.pos -= consumed as f32;
+        }
+
+        self.input.extend_from_slice(&frame[..self.channels]);
+    }
+
+    /// Compute the next output frame into `out` if enough input frames
+    /// have been queued, then move forward by `ratio` input frames.
+    /// Returns `false` if more input is needed.
+    pub fn next(&mut self, ratio: f32, out: &mut [f32]) -> bool {
+        let index = self.pos as usize;
+
+        if (index + TAPS) * self.channels > self.input.len() {
+            return false;
+        }
+
+        let phase = ((self.pos - index as f32) * PHASES as f32) as usize;
+
+        let taps = &self.kernel[phase * TAPS..(phase + 1) * TAPS];
+        let input = &self.input[index * self.channels..(index + TAPS) * self.channels];
+
+        for o in out[..self.channels].iter_mut() {
+            *o = 0.;
+        }
+
+        for (tap, frame) in taps.iter().zip(input.chunks(self.channels)) {
+            for (o, s) in out.iter_mut().zip(frame) {
+                *o += tap * s;
+            }
+        }
+
+        self.pos += ratio;
+
+        true
+    }
+}
+
+/// Compute the filter coefficients of every phase for the given
+/// `ratio` (input rate / output rate)
+fn kernel(ratio: f32) -> Vec<f32> {
+    // When downsampling we must also filter out the frequencies
+    // above the output Nyquist frequency
+    let cutoff = CUTOFF * (1. / ratio).min(1.);
+
+    let mut kernel = Vec::with_capacity(PHASES * TAPS);
+
+    for p in 0..PHASES {
+        let offset = p as f32 / PHASES as f32;
+
+        let start = kernel.len();
+
+        for i in 0..TAPS {
+            let x = i as f32 - (TAPS / 2 - 1) as f32 - offset;
+
+            kernel.push(windowed_sinc(x, (TAPS / 2) as f32, cutoff));
+        }
+
+        // Unity gain
+        let sum: f32 = kernel[start..].iter().sum();
+
+        for tap in &mut kernel[start..] {
+            *tap /= sum;
+        }
+    }
+
+    kernel
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SincResampler;
+
+    #[test]
+    fn resample_constant() {
+        let mut r = SincResampler::new(2, 2.);
+        let mut out = [0.; 2];
+        let mut count = 0;
+
+        for _ in 0..1000 {
+            r.push(&[0.5, -0.25]);
+
+            while r.next(2., &mut out) {
+                count += 1;
+
+                // Wait for the filter to be past the initial silence
+                if count > 16 {
+                    assert!((out[0] - 0.5).abs() < 0.001);
+                    assert!((out[1] + 0.25).abs() < 0.001);
+                }
+            }
+        }
+
+        assert!(count == 501);
+    }
+
+    #[test]
+    fn change_ratio() {
+        let mut r = SincResampler::new(1, 1.);
+        let mut out = [0.];
+        let mut count = 0;
+
+        for i in 0..1000 {
+            // Switch to fast forward halfway through, the queued input
+            // is kept
+            if i == 500 {
+                r.set_ratio(4.);
+            }
+
+            let ratio = if i < 500 { 1. } else { 4. };
+
+            r.push(&[0.5]);
+
+            while r.next(ratio, &mut out) {
+                count += 1;
+
+                if count > 16 {
+                    assert!((out[0] - 0.5).abs() < 0.001);
+                }
+            }
+        }
+
+        assert!(count == 501 + 125);
+    }
+}
@@ -0,0 +1,350 @@
+//! Headless runner for test ROMs
+//!
+//! Two conventions are supported to detect the result of a test:
+//!
+//! * Blargg's tests print their result on the serial port, ending
+//!   with "Passed" or "Failed".
+//! * Mooneye's tests execute `LD B, B` once done, with the Fibonacci
+//!   sequence 3, 5, 8, 13, 21, 34 in B, C, D, E, H and L if they
+//!   passed or 0x42 in all of them if they failed.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::fs;
+use std::io::Result as IoResult;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use cartridge::Cartridge;
+use cpu::Registers;
+use gameboy::{CpuCore, GameBoy, CYCLES_PER_FRAME};
+use io::serial::SerialLink;
+use GAMEBOY_SYSTEM_CLOCK_FREQUENCY;
+
+/// Maximum emulated time a test can run for before we give up
+const TIMEOUT_SECONDS: u64 = 120;
+
+/// Once a test reported a failure we keep collecting its output
+/// until it's been quiet for that many system clock ticks (one
+/// second), Blargg's tests print the details after "Failed"
+const OUTPUT_SETTLE_CYCLES: u64 = GAMEBOY_SYSTEM_CLOCK_FREQUENCY;
+
+/// Result of a test ROM
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// The test passed
+    Passed,
+    /// The test failed. Contains the serial output, if any.
+    Failed(String),
+    /// The test didn't report anything in time
+    Timeout,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Outcome::Passed => write!(f, "passed"),
+            Outcome::Failed(ref output) => match output.is_empty() {
+                true => write!(f, "failed"),
+                false => write!(f, "failed: {}", output.trim()),
+            },
+            Outcome::Timeout => write!(f, "timeout"),
+        }
+    }
+}
+
+/// Serial link recording everything the ROM sends
+struct Capture(Rc<RefCell<Vec<u8>>>);
+
+impl SerialLink for Capture {
+    fn start_transfer(&mut self, byte: u8) {
+        self.0.borrow_mut().push(byte);
+    }
+
+    fn reply(&mut self) -> Option<u8> {
+        Some(0xff)
+    }
+
+    fn poll(&mut self, _: Option<u8>) -> Option<u8> {
+        None
+    }
+}
+
+/// Run the test ROM at `path` and return its outcome
+pub fn run(path: &Path, core: CpuCore) -> IoResult<Outcome> {
+    let cart = try!(Cartridge::from_path(path));
+    let gb = GameBoy::headless(cart, core);
+
+    Ok(run_gameboy(gb, TIMEOUT_SECONDS * GAMEBOY_SYSTEM_CLOCK_FREQUENCY))
+}
+
+/// Run `gb` until the test reports its result or `timeout` system
+/// clock ticks have elapsed
+fn run_gameboy(mut gb: GameBoy, timeout: u64) -> Outcome {
+    let serial = Rc::new(RefCell::new(Vec::new()));
+    let mut audio = Vec::new();
+    let mut elapsed = 0;
+    let mut next_check = 0;
+    // Length of the output and time it last changed once the test
+    // reported a failure
+    let mut failed: Option<(usize, u64)> = None;
+
+    gb.set_serial_link(Box::new(Capture(serial.clone())));
+
+    while elapsed < timeout {
+        let pc = gb.registers().pc;
+        let op = gb.interconnect().peek_byte(pc);
+
+        elapsed += gb.step_instruction();
+
+        // LD B, B
+        if op == 0x40 {
+            if let Some(outcome) = mooneye_outcome(&gb.registers()) {
+                return outcome;
+            }
+        }
+
+        if elapsed >= next_check {
+            let output = String::from_utf8_lossy(&serial.borrow()).into_owned();
+
+            if output.contains("Passed") {
+                return Outcome::Passed;
+            }
+
+            failed = match failed {
+                Some((len, _)) if len != output.len() => Some((output.len(), elapsed)),
+                Some((_, since)) if elapsed - since >= OUTPUT_SETTLE_CYCLES => {
+                    return Outcome::Failed(output);
+                }
+                None if output.contains("Failed") => Some((output.len(), elapsed)),
+                f => f,
+            };
+
+            // Nobody's listening
+            audio.clear();
+            gb.drain_audio(&mut audio);
+
+            next_check += CYCLES_PER_FRAME;
+        }
+    }
+
+    match failed {
+        Some(_) => Outcome::Failed(String::from_utf8_lossy(&serial.borrow()).into_owned()),
+        None => Outcome::Timeout,
+    }
+}
+
+/// Check the registers for the Mooneye result convention
+fn mooneye_outcome(r: &Registers) -> Option<Outcome> {
+    match (r.bc, r.de, r.hl) {
+        (0x0305, 0x080d, 0x1522) => Some(Outcome::Passed),
+        (0x4242, 0x4242, 0x4242) => Some(Outcome::Failed(String::new())),
+        _ => None,
+    }
+}
+
+/// Run all the ROMs (`.gb` and `.gbc` files) found in `dir` and its
+/// subdirectories, in alphabetical order. The ROMs that can't be
+/// loaded are reported as failed.
+pub fn run_dir(dir: &Path, core: CpuCore) -> IoResult<Vec<(PathBuf, Outcome)>> {
+    let mut roms = Vec::new();
+
+    try!(find_roms(dir, &mut roms));
+
+    roms.sort();
+
+    let mut results = Vec::with_capacity(roms.len());
+
+    for rom in roms {
+        let outcome = match run(&rom, core) {
+            Ok(o) => o,
+            Err(e) => Outcome::Failed(format!("couldn't load ROM: {}", e)),
+        };
+
+        results.push((rom, outcome));
+    }
+
+    Ok(results)
+}
+
+fn find_roms(dir: &Path, roms: &mut Vec<PathBuf>) -> IoResult<()> {
+    for entry in try!(fs::read_dir(dir)) {
+        let path = try!(entry).path();
+
+        if path.is_dir() {
+            try!(find_roms(&path, roms));
+            continue;
+        }
+
+        let is_rom = match path.extension().and_then(|e| e.to_str()) {
+            Some("gb") | Some("gbc") => true,
+            _ => false,
+        };
+
+        if is_rom {
+            roms.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+/// Format the results returned by `run_dir`
+pub fn report(results: &[(PathBuf, Outcome)]) -> String {
+    let mut s = String::new();
+    let mut passed = 0;
+
+    for &(ref path, ref outcome) in results {
+        if *outcome == Outcome::Passed {
+            passed += 1;
+        }
+
+        s += &format!("{}: {}\n", path.display(), outcome);
+    }
+
+    s += &format!("{}/{} passed\n", passed, results.len());
+
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::path::PathBuf;
+
+    use super::{run, run_dir, run_gameboy, Outcome};
+    use gameboy::{self, CpuCore, GameBoy};
+
+    fn gameboy(code: &[u8]) -> GameBoy {
+        gameboy::test_gameboy(code, CpuCore::Gamedorp)
+    }
+
+    #[test]
+    fn mooneye_convention() {
+        let gb = gameboy(&[
+            // LD BC, 0x0305; LD DE, 0x080d; LD HL, 0x1522
+            0x01, 0x05, 0x03, 0x11, 0x0d, 0x08, 0x21, 0x22, 0x15,
+            // LD B, B
+            0x40,
+        ]);
+
+        assert!(run_gameboy(gb, 10000) == Outcome::Passed);
+
+        // JR -2
+        assert!(run_gameboy(gameboy(&[0x18, 0xfe]), 10000) == Outcome::Timeout);
+    }
+
+    #[test]
+    fn serial_output() {
+        let gb = gameboy(&[
+            // 0100: LD HL, 0x0120
+            0x21, 0x20, 0x01,
+            // 0103: LD A, [HL+]; OR A; JR Z, +14
+            0x2a, 0xb7, 0x28, 0x0e,
+            // 0107: LDH [SB], A; LD A, 0x81; LDH [SC], A
+            0xe0, 0x01, 0x3e, 0x81, 0xe0, 0x02,
+            // 010d: LDH A, [SC]; BIT 7, A; JR NZ, -6
+            0xf0, 0x02, 0xcb, 0x7f, 0x20, 0xfa,
+            // 0113: JR -18
+            0x18, 0xee,
+            // 0115: JR -2
+            0x18, 0xfe,
+            0, 0, 0, 0, 0, 0, 0, 0, 0,
+            // 0120
+            b'F', b'a', b'i', b'l', b'e', b'd', b' ', b'#', b'1', 0,
+        ]);
+
+        // The details printed after "Failed" are kept
+        assert!(run_gameboy(gb, 0x800000) == Outcome::Failed("Failed #1".into()));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn unreadable_rom() {
+        let dir = env::temp_dir().join(format!("gb-rs-testrom-{}", ::std::process::id()));
+        let _ = ::std::fs::create_dir(&dir);
+
+        // Dangling link, the ROM can't be opened
+        let _ = ::std::os::unix::fs::symlink(dir.join("missing"), dir.join("bad.gb"));
+
+        let results = run_dir(&dir, CpuCore::Gamedorp).unwrap();
+
+        let _ = ::std::fs::remove_dir_all(&dir);
+
+        assert!(results.len() == 1);
+        match results[0].1 {
+            Outcome::Failed(_) => (),
+            ref o => panic!("{}", o),
+        }
+    }
+
+    /// Directory containing the test ROMs, can be overridden with the
+    /// `GB_TEST_ROMS` environment variable
+    fn rom_dir() -> PathBuf {
+        env::var_os("GB_TEST_ROMS")
+            .map(PathBuf::from)
+            .unwrap_or(PathBuf::from("test-roms"))
+    }
+
+    fn check(rom: &str) {
+        let path = rom_dir().join(rom);
+
+        match run(&path, CpuCore::Gamedorp).unwrap_or_else(|e| panic!("{}: {}", path.display(), e)) {
+            Outcome::Passed => (),
+            o => panic!("{}: {}", rom, o),
+        }
+    }
+
+    /// Generate one test case per ROM. The ROMs aren't distributed
+    /// with the emulator, run them with `cargo test -- --ignored`
+    macro_rules! test_roms {
+        ($($name:ident: $path:expr,)*) => {
+            $(
+                #[test]
+                #[ignore]
+                fn $name() {
+                    check($path);
+                }
+            )*
+        }
+    }
+
+    test_roms! {
+        blargg_cpu_instrs_01: "blargg/cpu_instrs/individual/01-special.gb",
+        blargg_cpu_instrs_02: "blargg/cpu_instrs/individual/02-interrupts.gb",
+        blargg_cpu_instrs_03: "blargg/cpu_instrs/individual/03-op sp,hl.gb",
+        blargg_cpu_instrs_04: "blargg/cpu_instrs/individual/04-op r,imm.gb",
+        blargg_cpu_instrs_05: "blargg/cpu_instrs/individual/05-op rp.gb",
+        blargg_cpu_instrs_06: "blargg/cpu_instrs/individual/06-ld r,r.gb",
+        blargg_cpu_instrs_07: "blargg/cpu_instrs/individual/07-jr,jp,call,ret,rst.gb",
+        blargg_cpu_instrs_08: "blargg/cpu_instrs/individual/08-misc instrs.gb",
+        blargg_cpu_instrs_09: "blargg/cpu_instrs/individual/09-op r,r.gb",
+        blargg_cpu_instrs_10: "blargg/cpu_instrs/individual/10-bit ops.gb",
+        blargg_cpu_instrs_11: "blargg/cpu_instrs/individual/11-op a,(hl).gb",
+        blargg_instr_timing: "blargg/instr_timing/instr_timing.gb",
+        blargg_mem_timing: "blargg/mem_timing/mem_timing.gb",
+        mooneye_daa: "mooneye/acceptance/instr/daa.gb",
+        mooneye_reg_f: "mooneye/acceptance/bits/reg_f.gb",
+        mooneye_ei_sequence: "mooneye/acceptance/ei_sequence.gb",
+        mooneye_div_timing: "mooneye/acceptance/div_timing.gb",
+        mooneye_div_write: "mooneye/acceptance/timer/div_write.gb",
+        mooneye_tim00: "mooneye/acceptance/timer/tim00.gb",
+        mooneye_tim01: "mooneye/acceptance/timer/tim01.gb",
+        mooneye_tim10: "mooneye/acceptance/timer/tim10.gb",
+        mooneye_tim11: "mooneye/acceptance/timer/tim11.gb",
+        mooneye_tim00_div_trigger: "mooneye/acceptance/timer/tim00_div_trigger.gb",
+        mooneye_tim01_div_trigger: "mooneye/acceptance/timer/tim01_div_trigger.gb",
+        mooneye_tim10_div_trigger: "mooneye/acceptance/timer/tim10_div_trigger.gb",
+        mooneye_tim11_div_trigger: "mooneye/acceptance/timer/tim11_div_trigger.gb",
+        mooneye_rapid_toggle: "mooneye/acceptance/timer/rapid_toggle.gb",
+        mooneye_tima_reload: "mooneye/acceptance/timer/tima_reload.gb",
+        mooneye_tima_write_reloading: "mooneye/acceptance/timer/tima_write_reloading.gb",
+        mooneye_tma_write_reloading: "mooneye/acceptance/timer/tma_write_reloading.gb",
+        mooneye_oam_dma_basic: "mooneye/acceptance/oam_dma/basic.gb",
+        mooneye_oam_dma_reg_read: "mooneye/acceptance/oam_dma/reg_read.gb",
+        mooneye_oam_dma_sources: "mooneye/acceptance/oam_dma/sources-GS.gb",
+        mooneye_oam_dma_start: "mooneye/acceptance/oam_dma_start.gb",
+        mooneye_oam_dma_restart: "mooneye/acceptance/oam_dma_restart.gb",
+        mooneye_oam_dma_timing: "mooneye/acceptance/oam_dma_timing.gb",
+    }
+}
@@ -0,0 +1,162 @@
+//! Display keeping a copy of the last complete frame
+//!
+//! `FrameBuffer` sits between the GPU and the actual display: it
+//! forwards everything and copies the frame being drawn when it's
+//! flipped, so that the picture on screen can be retrieved at any
+//! time through a `Screen` handle and saved as a PNG file. The copy
+//! the GPU keeps (see `GameBoy::frame`) is overwritten line by line
+//! while the next frame is drawn.
+
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{BufWriter, Result as IoResult, Write};
+use std::path::Path;
+use std::rc::Rc;
+
+use gpu::Color;
+use png;
+use ui::Display;
+
+/// Pixels of a GB screen, line by line
+pub type Frame = [[Color; 160]; 144];
+
+/// Display wrapper capturing the frames sent to `display`
+pub struct FrameBuffer {
+    display: Box<Display>,
+    /// Frame being drawn
+    current: Frame,
+    /// Last complete frame, shared with the `Screen` handles
+    last: Rc<RefCell<Frame>>,
+}
+
+impl FrameBuffer {
+    /// Wrap `display`
+    pub fn new(display: Box<Display>) -> FrameBuffer {
+        let white = Color::from_rgb888(0xff, 0xff, 0xff);
+
+        FrameBuffer {
+            display: display,
+            current: [[white; 160]; 144],
+            last: Rc::new(RefCell::new([[white; 160]; 144])),
+        }
+    }
+
+    /// Return a handle to the last complete frame. It remains valid
+    /// once the `FrameBuffer` has been handed to the emulator.
+    pub fn screen(&self) -> Screen {
+        Screen {
+            frame: self.last.clone(),
+        }
+    }
+}
+
+impl Display for FrameBuffer {
+    fn clear(&mut self) {
+        self.display.clear();
+    }
+
+    fn set_pixel(&mut self, x: u32, y: u32, color: Color) {
+        self.current[y as usize][x as usize] = color;
+        self.display.set_pixel(x, y, color);
+    }
+
+    fn flip(&mut self) {
+        *self.last.borrow_mut() = self.current;
+        self.display.flip();
+    }
+}
+
+/// Handle to the last frame shown by a `FrameBuffer`
+#[derive(Clone)]
+pub struct Screen {
+    frame: Rc<RefCell<Frame>>,
+}
+
+impl Screen {
+    /// Return a copy of the last complete frame
+    pub fn frame(&self) -> Frame {
+        *self.frame.borrow()
+    }
+
+    /// Save the last complete frame to `path`, each GB pixel
+    /// becoming a `scale`x`scale` square
+    pub fn save_png(&self, path: &Path, scale: u32) -> IoResult<()> {
+        let mut out = BufWriter::new(try!(File::create(path)));
+
+        try!(write_png(&mut out, &self.frame(), scale));
+
+        out.flush()
+    }
+}
+
+/// Encode `frame` as a PNG picture, each GB pixel becoming a
+/// `scale`x`scale` square
+pub fn write_png<W: Write>(out: &mut W, frame: &Frame, scale: u32) -> IoResult<()> {
+    let scale = ::std::cmp::max(scale, 1);
+
+    png::write_rgb(out, 160 * scale, 144 * scale, &rgb(frame, scale))
+}
+
+/// Return the pixels of `frame` line by line, 3 bytes per pixel,
+/// each GB pixel becoming a `scale`x`scale` square
+pub fn rgb(frame: &Frame, scale: u32) -> Vec<u8> {
+    let scale = ::std::cmp::max(scale, 1) as usize;
+    let mut rgb = Vec::with_capacity(160 * 144 * 3 * scale * scale);
+
+    for line in frame.iter() {
+        let start = rgb.len();
+
+        for &color in line.iter() {
+            let (r, g, b) = color.rgb888();
+
+            for _ in 0..scale {
+                rgb.extend_from_slice(&[r, g, b]);
+            }
+        }
+
+        // Repeat the whole line to scale vertically
+        let end = rgb.len();
+
+        for _ in 1..scale {
+            rgb.extend_from_within(start..end);
+        }
+    }
+
+    rgb
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{write_png, FrameBuffer};
+    use cartridge::Cartridge;
+    use gameboy::{CpuCore, GameBoy};
+    use ui::dummy::DummyDisplay;
+
+    #[test]
+    fn capture() {
+        let fb = FrameBuffer::new(Box::new(DummyDisplay));
+        let screen = fb.screen();
+        let mut gb = GameBoy::new(Cartridge::from_vec(vec![0; 0x8000]), Box::new(fb), CpuCore::Gamedorp);
+
+        // Run until the bootrom has drawn the logo
+        for _ in 0..60 {
+            gb.run_frame();
+        }
+
+        // The GPU is done with the frame right when `run_frame`
+        // returns
+        let frame = screen.frame();
+
+        assert!(frame[..] == gb.frame()[..]);
+
+        let mut native = Vec::new();
+        let mut scaled = Vec::new();
+
+        write_png(&mut native, &frame, 1).unwrap();
+        write_png(&mut scaled, &frame, 3).unwrap();
+
+        // Width and height in the IHDR chunk
+        assert!(native[16..24] == [0, 0, 0, 160, 0, 0, 0, 144]);
+        assert!(scaled[16..24] == [0, 0, 1, 224, 0, 0, 1, 176]);
+    }
+}
@@ -0,0 +1,450 @@
+//! Input bindings: which keys, controller buttons and axes drive the
+//! GB buttons and the emulator hotkeys.
+//!
+//! Bindings can be changed with a config file made of sections like:
+//!
+//! ```text
+//! # Comments start with '#'
+//! [default]
+//! a = key:X, button:b
+//! b = key:Z, button:a
+//! up = key:Up, button:dpup, axis:lefty-
+//! next-palette = key:F10, button:guide
+//!
+//! # Only used when the cartridge title matches
+//! [rom "TETRIS"]
+//! a = key:Space, axis:righttrigger+@4000
+//! ```
+//!
+//! Each entry replaces all the bindings of an action, the ones of
+//! the `[rom "<title>"]` section matching the cartridge override the
+//! `[default]` ones, which themselves override the built-in defaults
+//! listed in `DEFAULT_CONFIG`. An action can have any number of
+//! comma separated bindings:
+//!
+//! * `key:<name>` for a keyboard key using its SDL name ("Left Alt",
+//!   "Return", "F1"...). `key:Shift+<name>` only triggers while
+//!   shift is held. Bindings without it ignore shift, except for
+//!   hotkeys when another hotkey is bound to the same key with
+//!   shift.
+//! * `button:<name>` for a game controller button using its SDL name
+//!   ("a", "start", "dpup"...).
+//! * `axis:<name>+` or `axis:<name>-` for a game controller axis
+//!   ("leftx", "lefttrigger"...) moved in the positive or negative
+//!   direction past the dead zone, which can be changed by appending
+//!   `@<deadzone>` (between 0 and 32767).
+//!
+//! An empty entry (`select =`) unbinds the action.
+
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Result as IoResult};
+use std::path::Path;
+
+use ui::{ButtonState, Buttons, Event};
+
+/// Built-in bindings
+pub const DEFAULT_CONFIG: &'static str = "\
+[default]
+up = key:Up, button:dpup, axis:lefty-
+down = key:Down, button:dpdown, axis:lefty+
+left = key:Left, button:dpleft, axis:leftx-
+right = key:Right, button:dpright, axis:leftx+
+# The GB has A and B swapped compared to most controllers
+a = key:Left Alt, button:b
+b = key:Left Ctrl, button:a
+start = key:Return, button:start
+select = key:Right Shift, button:back
+power-off = key:Escape
+next-palette = key:F10
+audio-recording = key:F11
+video-recording = key:Shift+F11
+pause = key:P
+frame-advance = key:N
+fast-forward = key:Tab, axis:righttrigger+
+slow-motion = key:M
+rewind = key:Backspace, axis:lefttrigger+
+screenshot = key:F12
+screenshot-scaled = key:Shift+F12
+load-state-1 = key:F1
+load-state-2 = key:F2
+load-state-3 = key:F3
+load-state-4 = key:F4
+load-state-5 = key:F5
+load-state-6 = key:F6
+load-state-7 = key:F7
+load-state-8 = key:F8
+load-state-9 = key:F9
+save-state-1 = key:Shift+F1
+save-state-2 = key:Shift+F2
+save-state-3 = key:Shift+F3
+save-state-4 = key:Shift+F4
+save-state-5 = key:Shift+F5
+save-state-6 = key:Shift+F6
+save-state-7 = key:Shift+F7
+save-state-8 = key:Shift+F8
+save-state-9 = key:Shift+F9
+";
+
+/// The controller axes move in a range from -32768 to +32767. To
+/// avoid spurious events the dead zone says how far from 0 an axis
+/// has to move for us to register the event.
+pub const DEFAULT_DEAD_ZONE: i16 = 10_000;
+
+/// Something the user can trigger
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Up direction
+    Up,
+    /// Down direction
+    Down,
+    /// Left direction
+    Left,
+    /// Right direction
+    Right,
+    /// A button
+    A,
+    /// B button
+    B,
+    /// Start button
+    Start,
+    /// Select button
+    Select,
+    /// Hotkey sending `Event` when pressed. The ones meant to be held
+    /// also send an event when released (see `Event::released`).
+    Hotkey(Event),
+}
+
+impl Action {
+    /// Parse the name of an action in the config file
+    pub fn from_name(name: &str) -> Option<Action> {
+        let action = match name {
+            "up" => Action::Up,
+            "down" => Action::Down,
+            "left" => Action::Left,
+            "right" => Action::Right,
+            "a" => Action::A,
+            "b" => Action::B,
+            "start" => Action::Start,
+            "select" => Action::Select,
+            "power-off" => Action::Hotkey(Event::PowerOff),
+            "next-palette" => Action::Hotkey(Event::NextPalette),
+            "audio-recording" => Action::Hotkey(Event::ToggleAudioRecording),
+            "video-recording" => Action::Hotkey(Event::ToggleVideoRecording),
+            "pause" => Action::Hotkey(Event::TogglePause),
+            "frame-advance" => Action::Hotkey(Event::FrameAdvance),
+            "fast-forward" => Action::Hotkey(Event::FastForward(true)),
+            "slow-motion" => Action::Hotkey(Event::SlowMotion),
+            "rewind" => Action::Hotkey(Event::Rewind(true)),
+            "screenshot" => Action::Hotkey(Event::Screenshot(false)),
+            "screenshot-scaled" => Action::Hotkey(Event::Screenshot(true)),
+            _ => {
+                let slot = |prefix: &str| match name.starts_with(prefix) {
+                    true => name[prefix.len()..].parse::<u8>().ok().and_then(|s| match s {
+                        1...9 => Some(s),
+                        _ => None,
+                    }),
+                    false => None,
+                };
+
+                if let Some(s) = slot("load-state-") {
+                    Action::Hotkey(Event::LoadState(s))
+                } else if let Some(s) = slot("save-state-") {
+                    Action::Hotkey(Event::SaveState(s))
+                } else {
+                    return None;
+                }
+            }
+        };
+
+        Some(action)
+    }
+
+    /// Return the state of the GB button driven by this action, if
+    /// it's not a hotkey
+    pub fn button(self, buttons: &mut Buttons) -> Option<&mut ButtonState> {
+        let b = match self {
+            Action::Up => &mut buttons.up,
+            Action::Down => &mut buttons.down,
+            Action::Left => &mut buttons.left,
+            Action::Right => &mut buttons.right,
+            Action::A => &mut buttons.a,
+            Action::B => &mut buttons.b,
+            Action::Start => &mut buttons.start,
+            Action::Select => &mut buttons.select,
+            Action::Hotkey(_) => return None,
+        };
+
+        Some(b)
+    }
+}
+
+/// A physical input bound to an action. The names are the ones used
+/// by SDL, they're resolved by the frontend.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Binding {
+    /// Keyboard key, optionally combined with shift
+    Key {
+        /// Name of the key
+        name: String,
+        /// `true` if shift must be held
+        shift: bool,
+    },
+    /// Game controller button
+    Button(String),
+    /// Game controller axis
+    Axis {
+        /// Name of the axis
+        name: String,
+        /// `true` if the binding triggers in the positive direction
+        positive: bool,
+        /// Distance from the center the axis has to move past
+        dead_zone: i16,
+    },
+}
+
+impl Binding {
+    /// Parse a binding in the config file
+    fn parse(s: &str) -> Result<Binding, String> {
+        let (kind, name) = match s.find(':') {
+            Some(p) => (s[..p].trim(), s[p + 1..].trim()),
+            None => return Err(format!("expected 'key:', 'button:' or 'axis:' in '{}'", s)),
+        };
+
+        if name.is_empty() {
+            return Err(format!("missing name in '{}'", s));
+        }
+
+        match kind {
+            "key" => {
+                let (name, shift) = match name.get(..6) {
+                    Some(p) if p.eq_ignore_ascii_case("shift+") && name.len() > 6 => (&name[6..], true),
+                    _ => (name, false),
+                };
+
+                Ok(Binding::Key {
+                    name: name.into(),
+                    shift: shift,
+                })
+            }
+            "button" => Ok(Binding::Button(name.into())),
+            "axis" => {
+                let (name, dead_zone) = match name.find('@') {
+                    Some(p) => match name[p + 1..].parse::<i16>() {
+                        Ok(d) if d >= 0 => (&name[..p], d),
+                        _ => return Err(format!("invalid dead zone in '{}'", s)),
+                    },
+                    None => (name, DEFAULT_DEAD_ZONE),
+                };
+
+                let positive = match name.chars().last() {
+                    Some('+') => true,
+                    Some('-') => false,
+                    _ => return Err(format!("expected '+' or '-' after the axis in '{}'", s)),
+                };
+
+                Ok(Binding::Axis {
+                    name: name[..name.len() - 1].into(),
+                    positive: positive,
+                    dead_zone: dead_zone,
+                })
+            }
+            _ => Err(format!("unknown input type '{}'", kind)),
+        }
+    }
+}
+
+/// Bindings of all the actions
+#[derive(Clone, Debug)]
+pub struct Bindings {
+    bindings: Vec<(Action, Vec<Binding>)>,
+}
+
+impl Bindings {
+    /// Return the built-in bindings
+    pub fn defaults() -> Bindings {
+        let empty = Bindings { bindings: Vec::new() };
+
+        Bindings::parse_over(empty, DEFAULT_CONFIG, None).unwrap()
+    }
+
+    /// Load the bindings defined in the config file at `path` for the
+    /// cartridge titled `title`
+    pub fn load(path: &Path, title: Option<&str>) -> IoResult<Bindings> {
+        let mut config = String::new();
+
+        try!(try!(File::open(path)).read_to_string(&mut config));
+
+        Bindings::parse(&config, title).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+
+    /// Parse the bindings defined in `config` on top of the built-in
+    /// ones, using the overrides for the cartridge titled `title`
+    pub fn parse(config: &str, title: Option<&str>) -> Result<Bindings, String> {
+        Bindings::parse_over(Bindings::defaults(), config, title)
+    }
+
+    /// Parse the bindings defined in `config` on top of `bindings`
+    fn parse_over(mut bindings: Bindings, config: &str, title: Option<&str>) -> Result<Bindings, String> {
+        // Overrides for the cartridge, applied once the whole file
+        // has been parsed
+        let mut overrides = Vec::new();
+
+        // `None` while in a section for another cartridge
+        let mut section: Option<&mut Vec<(Action, Vec<Binding>)>> = None;
+        let mut in_section = false;
+
+        for (n, line) in config.lines().enumerate() {
+            let line = match line.find('#') {
+                Some(p) => &line[..p],
+                None => line,
+            };
+            let line = line.trim();
+
+            if line.is_empty() {
+                continue;
+            }
+
+            let error = |msg: &str| format!("line {}: {}", n + 1, msg);
+
+            if line.starts_with('[') {
+                if !line.ends_with(']') {
+                    return Err(error("invalid section header"));
+                }
+
+                let name = line[1..line.len() - 1].trim();
+
+                section = if name == "default" {
+                    Some(&mut bindings.bindings)
+                } else if name.starts_with("rom ") {
+                    let rom = name[4..].trim();
+
+                    if rom.len() < 2 || !rom.starts_with('"') || !rom.ends_with('"') {
+                        return Err(error("expected [rom \"<title>\"]"));
+                    }
+
+                    match title.map_or(false, |t| t.eq_ignore_ascii_case(&rom[1..rom.len() - 1])) {
+                        true => Some(&mut overrides),
+                        false => None,
+                    }
+                } else {
+                    return Err(error("unknown section"));
+                };
+
+                in_section = true;
+                continue;
+            }
+
+            if !in_section {
+                return Err(error("bindings outside of a section"));
+            }
+
+            let (key, value) = match line.find('=') {
+                Some(p) => (line[..p].trim(), line[p + 1..].trim()),
+                None => return Err(error("expected 'action = bindings'")),
+            };
+
+            let action = match Action::from_name(key) {
+                Some(a) => a,
+                None => return Err(error(&format!("unknown action '{}'", key))),
+            };
+
+            let mut inputs = Vec::new();
+
+            for b in value.split(',').map(|b| b.trim()).filter(|b| !b.is_empty()) {
+                match Binding::parse(b) {
+                    Ok(b) => inputs.push(b),
+                    Err(e) => return Err(error(&e)),
+                }
+            }
+
+            if let Some(ref mut section) = section {
+                set(section, action, inputs);
+            }
+        }
+
+        for (action, inputs) in overrides {
+            set(&mut bindings.bindings, action, inputs);
+        }
+
+        Ok(bindings)
+    }
+
+    /// Return all the actions with their bindings
+    pub fn actions(&self) -> &[(Action, Vec<Binding>)] {
+        &self.bindings
+    }
+}
+
+/// Replace the bindings of `action` in `bindings`
+fn set(bindings: &mut Vec<(Action, Vec<Binding>)>, action: Action, inputs: Vec<Binding>) {
+    match bindings.iter_mut().find(|&&mut (a, _)| a == action) {
+        Some(&mut (_, ref mut b)) => *b = inputs,
+        None => bindings.push((action, inputs)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Action, Binding, Bindings};
+    use ui::Event;
+
+    fn bindings(b: &Bindings, action: Action) -> Vec<Binding> {
+        b.actions().iter().find(|&&(a, _)| a == action).unwrap().1.clone()
+    }
+
+    fn key(name: &str, shift: bool) -> Binding {
+        Binding::Key {
+            name: name.into(),
+            shift: shift,
+        }
+    }
+
+    #[test]
+    fn defaults() {
+        let b = Bindings::defaults();
+
+        assert!(b.actions().len() == 8 + 11 + 18);
+        assert!(bindings(&b, Action::Hotkey(Event::SaveState(3))) == vec![key("F3", true)]);
+        assert!(bindings(&b, Action::Hotkey(Event::FastForward(true)))[0] == key("Tab", false));
+        assert!(bindings(&b, Action::Left)[2] == Binding::Axis {
+            name: "leftx".into(),
+            positive: false,
+            dead_zone: 10_000,
+        });
+    }
+
+    #[test]
+    fn config_file() {
+        let config = "# Test\n\
+                      [default]\n\
+                      a = key:X, button:b # comment\n\
+                      select =\n\
+                      [rom \"TETRIS\"]\n\
+                      a = key:Space, axis:righttrigger+@4000\n\
+                      [rom \"OTHER\"]\n\
+                      b = key:Y\n";
+
+        let b = Bindings::parse(config, Some("Tetris")).unwrap();
+
+        assert!(bindings(&b, Action::A) == vec![
+            key("Space", false),
+            Binding::Axis {
+                name: "righttrigger".into(),
+                positive: true,
+                dead_zone: 4000,
+            },
+        ]);
+        assert!(bindings(&b, Action::Select).is_empty());
+        assert!(bindings(&b, Action::B) == bindings(&Bindings::defaults(), Action::B));
+
+        let b = Bindings::parse(config, None).unwrap();
+
+        assert!(bindings(&b, Action::A) == vec![key("X", false), Binding::Button("b".into())]);
+
+        assert!(Bindings::parse("a = key:X", None).is_err());
+        assert!(Bindings::parse("[default]\nfoo = key:X", None).is_err());
+        assert!(Bindings::parse("[default]\na = pedal:X", None).is_err());
+        assert!(Bindings::parse("[default]\na = axis:leftx", None).is_err());
+        assert!(Bindings::parse("[rom TETRIS]\na = key:X", None).is_err());
+    }
+}
@@ -1,6 +1,11 @@
 //! User Interface. Objects used to display the GB Screen, get user
 //! input etc...
 
+pub mod framebuffer;
+pub mod input;
+
+/// SDL2 frontend
+#[cfg(feature = "sdl")]
 pub mod sdl2;
 
 /// GB screen. Screen resolution is always 160x144
@@ -19,15 +24,62 @@ pub trait Audio {
     /// generated by the emulator since the last adjustment
     /// (`in_samples`).
     fn adjust_resampling(&mut self, in_samples: u32);
+    /// Tell the backend that the emulator runs at `speed` times the
+    /// normal speed so that it can play the samples faster or slower
+    /// instead of falling behind or running dry.
+    fn set_speed(&mut self, speed: f32);
+}
+
+/// Force feedback interface used to reproduce the rumble motor of
+/// some cartridges
+pub trait Rumble {
+    /// Turn the rumble on or off
+    fn set_rumble(&mut self, on: bool);
 }
 
 /// Special events that need to be handled synchronously (instead of
 /// waiting for the GB program to come check the INPUT register)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Event {
     /// No event
     None,
     /// Shutdown the emulator
     PowerOff,
+    /// Save the machine state in the given numbered slot
+    SaveState(u8),
+    /// Restore the machine state from the given numbered slot
+    LoadState(u8),
+    /// Switch to the next DMG color palette
+    NextPalette,
+    /// Start or stop recording the audio output
+    ToggleAudioRecording,
+    /// Start or stop recording the video and audio output
+    ToggleVideoRecording,
+    /// Pause or resume the emulation
+    TogglePause,
+    /// Pause the emulation and run it until the next vblank
+    FrameAdvance,
+    /// Fast forward pressed (`true`) or released (`false`)
+    FastForward(bool),
+    /// Switch to the next slow motion speed
+    SlowMotion,
+    /// Rewind pressed (`true`) or released (`false`)
+    Rewind(bool),
+    /// Save the current frame to a PNG file, at the window's upscale
+    /// factor if `true` or at the native resolution otherwise
+    Screenshot(bool),
+}
+
+impl Event {
+    /// Return the event sent when the hotkey sending this one is
+    /// released, if it's meant to be held
+    pub fn released(self) -> Option<Event> {
+        match self {
+            Event::FastForward(true) => Some(Event::FastForward(false)),
+            Event::Rewind(true) => Some(Event::Rewind(false)),
+            _ => None,
+        }
+    }
 }
 
 /// Description of a button's state
@@ -40,6 +92,7 @@ pub enum ButtonState {
 }
 
 impl ButtonState {
+    /// Return `true` if the button is pushed down
     pub fn is_down(self) -> bool {
         match self {
             ButtonState::Down => true,
@@ -51,13 +104,21 @@ impl ButtonState {
 /// State of all the GB buttons
 #[derive(Debug, Clone, Copy)]
 pub struct Buttons {
+    /// Up direction
     pub up: ButtonState,
+    /// Down direction
     pub down: ButtonState,
+    /// Left direction
     pub left: ButtonState,
+    /// Right direction
     pub right: ButtonState,
+    /// A button
     pub a: ButtonState,
+    /// B button
     pub b: ButtonState,
+    /// Start button
     pub start: ButtonState,
+    /// Select button
     pub select: ButtonState,
     /// State of the interrupt that occurs at the moment a button is
     /// pressed
@@ -65,6 +126,7 @@ pub struct Buttons {
 }
 
 impl Buttons {
+    /// Create a new `Buttons` with all buttons in `default_state`
     pub fn new(default_state: ButtonState) -> Buttons {
         Buttons {
             a: default_state,
@@ -80,13 +142,13 @@ impl Buttons {
     }
 }
 
-#[cfg(test)]
 pub mod dummy {
-    //! Dummy implementations of the user interface for use in tests
-    //! and benchmarks
-
-    use std::cell::Cell;
+    //! Dummy implementations of the user interface for headless use,
+    //! tests and benchmarks
 
+    /// Display that discards everything. The GPU keeps a copy of the
+    /// current frame so it can still be retrieved through
+    /// `GameBoy::frame`.
     pub struct DummyDisplay;
 
     impl super::Display for DummyDisplay {
@@ -96,20 +158,4 @@ pub mod dummy {
 
         fn flip(&mut self) {}
     }
-
-    pub struct DummyController {
-        buttons: Cell<super::Buttons>,
-    }
-
-    impl DummyController {
-        pub fn new() -> DummyController {
-            DummyController {
-                buttons: Cell::new(super::Buttons::new(super::ButtonState::Up)),
-            }
-        }
-
-        pub fn buttons(&self) -> &Cell<super::Buttons> {
-            &self.buttons
-        }
-    }
 }
@@ -25,12 +25,15 @@ impl AudioCallback for Reader {
     }
 }
 
+/// SDL2 audio backend, plays the samples generated by the SPU
 pub struct Audio {
     dev: AudioDevice<Reader>,
     async: Arc<Async<Sample>>,
 }
 
 impl Audio {
+    /// Open the audio device. The samples received from `channel`
+    /// are resampled to the device sample rate.
     pub fn new(channel: Receiver<::spu::SampleBuffer>, sdl2: &Sdl) -> Audio {
         let resampler = Resampler::new(channel, SAMPLE_RATE);
 
@@ -40,7 +43,7 @@ impl Audio {
 
         let spec = AudioSpecDesired {
             freq: Some(SAMPLE_RATE as i32),
-            channels: Some(1),
+            channels: Some(::spu::AUDIO_CHANNELS as u8),
             samples: Some(::spu::SAMPLES_PER_BUFFER as u16),
         };
 
@@ -56,6 +59,7 @@ impl Audio {
         }
     }
 
+    /// Start playback
     pub fn start(&self) {
         self.dev.resume();
     }
@@ -65,10 +69,14 @@ impl ::ui::Audio for Audio {
     fn adjust_resampling(&mut self, in_samples: u32) {
         self.async.adjust_resampling(in_samples);
     }
+
+    fn set_speed(&mut self, speed: f32) {
+        self.async.set_speed(speed);
+    }
 }
 
-// Use 8bit sound samples
-type Sample = u8;
+// Use signed 16bit sound samples
+type Sample = i16;
 
 /// Audio output sample rate
 const SAMPLE_RATE: u32 = 44_100;
@@ -2,20 +2,34 @@ use std::cell::Cell;
 
 use sdl2::controller::{Axis, Button, GameController};
 use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
+use sdl2::haptic::Haptic;
+use sdl2::keyboard::{self, Keycode};
 use sdl2::Sdl;
 
+use ui::input::{Action, Binding, Bindings};
 use ui::ButtonState;
 
+/// Keyboard and game controller input
 pub struct Controller {
     buttons: Cell<::ui::Buttons>,
     #[allow(dead_code)]
     controller: Option<GameController>,
-    x_axis_state: Cell<AxisState>,
-    y_axis_state: Cell<AxisState>,
+    /// Haptic device of the game controller, if it supports it
+    haptic: Option<Haptic>,
+    /// `true` if the haptic device is currently rumbling
+    rumbling: bool,
+    /// Keys bound to an action, with the state of shift they expect
+    keys: Vec<Bound<(Keycode, bool)>>,
+    /// Game controller buttons bound to an action
+    controller_buttons: Vec<Bound<Button>>,
+    /// Game controller axes bound to an action, with their direction
+    /// and dead zone
+    axes: Vec<Bound<(Axis, bool, i16)>>,
 }
 
 impl Controller {
+    /// Create a new Controller, opening the first game controller
+    /// found
     pub fn new(sdl2: &Sdl) -> Controller {
         // Attempt to add a game controller
 
@@ -32,6 +46,7 @@ impl Controller {
         };
 
         let mut controller = None;
+        let mut haptic = None;
 
         // For now we just take the first controller we manage to open
         // (if any)
@@ -44,6 +59,7 @@ impl Controller {
                         // exit the loop
                         info!("Successfully opened \"{}\"\n", c.name());
                         controller = Some(c);
+                        haptic = open_haptic(sdl2, id);
                         break;
                     }
                     Err(e) => info!("failed: {:?}\n", e),
@@ -58,14 +74,56 @@ impl Controller {
             None => print!("No controller found"),
         }
 
-        Controller {
+        let mut c = Controller {
             buttons: Cell::new(::ui::Buttons::new(ButtonState::Up)),
             controller: controller,
-            x_axis_state: Cell::new(AxisState::Neutral),
-            y_axis_state: Cell::new(AxisState::Neutral),
+            haptic: haptic,
+            rumbling: false,
+            keys: Vec::new(),
+            controller_buttons: Vec::new(),
+            axes: Vec::new(),
+        };
+
+        c.set_bindings(&Bindings::defaults()).unwrap();
+
+        c
+    }
+
+    /// Replace the input bindings. If some of the names can't be
+    /// resolved an error is returned and the current bindings are
+    /// kept.
+    pub fn set_bindings(&mut self, bindings: &Bindings) -> Result<(), String> {
+        let mut keys = Vec::new();
+        let mut buttons = Vec::new();
+        let mut axes = Vec::new();
+
+        for &(action, ref inputs) in bindings.actions() {
+            for input in inputs {
+                match *input {
+                    Binding::Key { ref name, shift } => match Keycode::from_name(name) {
+                        Some(key) => keys.push(Bound::new((key, shift), action)),
+                        None => return Err(format!("unknown key '{}'", name)),
+                    },
+                    Binding::Button(ref name) => match Button::from_string(name) {
+                        Some(button) => buttons.push(Bound::new(button, action)),
+                        None => return Err(format!("unknown controller button '{}'", name)),
+                    },
+                    Binding::Axis { ref name, positive, dead_zone } => match Axis::from_string(name) {
+                        Some(axis) => axes.push(Bound::new((axis, positive, dead_zone), action)),
+                        None => return Err(format!("unknown controller axis '{}'", name)),
+                    },
+                }
+            }
         }
+
+        self.keys = keys;
+        self.controller_buttons = buttons;
+        self.axes = axes;
+
+        Ok(())
     }
 
+    /// Process the pending SDL2 events
     pub fn update(&self, sdl2: &Sdl) -> ::ui::Event {
         let mut event = ::ui::Event::None;
 
@@ -77,145 +135,204 @@ impl Controller {
         for e in event_pump.poll_iter() {
             match e {
                 Event::KeyDown {
-                    keycode: Some(Keycode::Escape),
+                    keycode: Some(key),
+                    keymod,
+                    repeat,
                     ..
-                } => event = ::ui::Event::PowerOff,
-                Event::KeyDown { keycode: key, .. } => {
-                    if let Some(key) = key {
-                        self.update_key(key, ButtonState::Down)
+                } => {
+                    let shift = keymod.intersects(keyboard::LSHIFTMOD | keyboard::RSHIFTMOD);
+
+                    // Hotkeys bound without shift ignore it, unless
+                    // they'd shadow one bound with it
+                    let shift_hotkey = shift
+                        && self.keys.iter().any(|b| match b.action {
+                            Action::Hotkey(_) => b.input == (key, true),
+                            _ => false,
+                        });
+
+                    for b in self.keys.iter().filter(|b| b.input.0 == key) {
+                        let (_, with_shift) = b.input;
+
+                        match b.action {
+                            Action::Hotkey(_) => {
+                                if with_shift == shift_hotkey && !repeat {
+                                    if let Some(e) = b.press() {
+                                        event = e;
+                                    }
+                                }
+                            }
+                            _ => {
+                                if shift || !with_shift {
+                                    b.active.set(true);
+                                }
+                            }
+                        }
                     }
                 }
-                Event::KeyUp { keycode: key, .. } => {
-                    if let Some(key) = key {
-                        self.update_key(key, ButtonState::Up)
+                Event::KeyUp {
+                    keycode: Some(key), ..
+                } => {
+                    for b in self.keys.iter().filter(|b| b.input.0 == key) {
+                        if let Some(e) = b.release() {
+                            event = e;
+                        }
                     }
                 }
                 Event::ControllerButtonDown { button, .. } => {
-                    self.update_button(button, ButtonState::Down)
+                    for b in self.controller_buttons.iter().filter(|b| b.input == button) {
+                        if let Some(e) = b.press() {
+                            event = e;
+                        }
+                    }
                 }
                 Event::ControllerButtonUp { button, .. } => {
-                    self.update_button(button, ButtonState::Up)
+                    for b in self.controller_buttons.iter().filter(|b| b.input == button) {
+                        if let Some(e) = b.release() {
+                            event = e;
+                        }
+                    }
                 }
                 Event::ControllerAxisMotion {
                     axis, value: val, ..
-                } => self.update_axis(axis, val),
+                } => {
+                    for b in self.axes.iter().filter(|b| b.input.0 == axis) {
+                        let (_, positive, dead_zone) = b.input;
+
+                        let active = match positive {
+                            true => val > dead_zone,
+                            false => val < -dead_zone,
+                        };
+
+                        if !active {
+                            if let Some(e) = b.release() {
+                                event = e;
+                            }
+                        } else if !b.active.get() {
+                            if let Some(e) = b.press() {
+                                event = e;
+                            }
+                        }
+                    }
+                }
                 Event::Quit { .. } => event = ::ui::Event::PowerOff,
                 _ => (),
             }
         }
 
+        self.update_buttons();
+
         event
     }
 
-    pub fn buttons(&self) -> &Cell<::ui::Buttons> {
-        &self.buttons
-    }
+    /// Recompute the state of the GB buttons: a button is down if any
+    /// of its bindings is active
+    fn update_buttons(&self) {
+        let mut buttons = ::ui::Buttons::new(ButtonState::Up);
 
-    /// Update key state. For now keybindings are hardcoded.
-    fn update_key(&self, key: Keycode, state: ButtonState) {
-        let mut b = self.buttons.get();
-
-        match key {
-            Keycode::Up => b.up = state,
-            Keycode::Down => b.down = state,
-            Keycode::Left => b.left = state,
-            Keycode::Right => b.right = state,
-            Keycode::LAlt => b.a = state,
-            Keycode::LCtrl => b.b = state,
-            Keycode::Return => b.start = state,
-            Keycode::RShift => b.select = state,
-            _ => (),
-        }
+        buttons.interrupt = self.buttons.get().interrupt;
 
-        self.buttons.set(b);
-    }
+        let active = self.keys.iter().filter(|b| b.active.get()).map(|b| b.action)
+            .chain(self.controller_buttons.iter().filter(|b| b.active.get()).map(|b| b.action))
+            .chain(self.axes.iter().filter(|b| b.active.get()).map(|b| b.action));
 
-    /// Same as update_key but for controller buttons
-    fn update_button(&self, button: Button, state: ButtonState) {
-        let mut b = self.buttons.get();
-
-        match button {
-            //gameboy has a nd b swapped
-            Button::B => b.a = state,
-            Button::A => b.b = state,
-            Button::DPadLeft => b.left = state,
-            Button::DPadRight => b.right = state,
-            Button::DPadUp => b.up = state,
-            Button::DPadDown => b.down = state,
-            Button::Start => b.start = state,
-            Button::Back => b.select = state,
-            _ => (),
+        for action in active {
+            if let Some(state) = action.button(&mut buttons) {
+                *state = ButtonState::Down;
+            }
         }
 
-        self.buttons.set(b);
+        self.buttons.set(buttons);
     }
 
-    /// Map left stick X/Y to directional buttons
-    fn update_axis(&self, axis: Axis, val: i16) {
-        let mut b = self.buttons.get();
-
-        let state = AxisState::from_value(val);
+    /// Return the current state of the GB buttons
+    pub fn buttons(&self) -> &Cell<::ui::Buttons> {
+        &self.buttons
+    }
 
-        match axis {
-            Axis::LeftX => {
-                if state != self.x_axis_state.get() {
-                    self.x_axis_state.set(state);
+    /// Start or stop the game controller's rumble. Does nothing if
+    /// the controller doesn't support it.
+    pub fn set_rumble(&mut self, on: bool) {
+        if on == self.rumbling {
+            return;
+        }
 
-                    b.left = state.down_if_negative();
-                    b.right = state.down_if_positive();
-                }
+        if let Some(ref mut haptic) = self.haptic {
+            if on {
+                haptic.rumble_play(RUMBLE_STRENGTH, RUMBLE_INFINITY);
+            } else {
+                haptic.rumble_stop();
             }
-            Axis::LeftY => {
-                if state != self.y_axis_state.get() {
-                    self.y_axis_state.set(state);
+        }
 
-                    b.up = state.down_if_negative();
-                    b.down = state.down_if_positive();
-                }
-            }
-            _ => (),
+        self.rumbling = on;
+    }
+}
+
+/// Attempt to open the haptic device of joystick `id`
+fn open_haptic(sdl2: &Sdl, id: u32) -> Option<Haptic> {
+    let subsystem = match sdl2.haptic() {
+        Ok(s) => s,
+        Err(e) => {
+            info!("Haptic subsystem unavailable: {}\n", e);
+            return None;
         }
+    };
 
-        self.buttons.set(b);
+    match subsystem.open_from_joystick_id(id) {
+        Ok(h) => {
+            info!("Rumble support enabled\n");
+            Some(h)
+        }
+        Err(e) => {
+            info!("Joystick {} has no haptic support: {:?}\n", id, e);
+            None
+        }
     }
 }
 
-#[derive(Clone, Copy, PartialEq, Eq)]
-enum AxisState {
-    Neutral,
-    Negative,
-    Positive,
+/// Input bound to an action
+struct Bound<T> {
+    input: T,
+    action: Action,
+    /// `true` while the input is pressed
+    active: Cell<bool>,
 }
 
-impl AxisState {
-    fn from_value(val: i16) -> AxisState {
-        if val > AXIS_DEAD_ZONE {
-            AxisState::Positive
-        } else if val < -AXIS_DEAD_ZONE {
-            AxisState::Negative
-        } else {
-            AxisState::Neutral
+impl<T> Bound<T> {
+    fn new(input: T, action: Action) -> Bound<T> {
+        Bound {
+            input: input,
+            action: action,
+            active: Cell::new(false),
         }
     }
 
-    fn down_if_negative(self) -> ButtonState {
-        if self == AxisState::Negative {
-            ButtonState::Down
-        } else {
-            ButtonState::Up
+    /// Mark the input as pressed. Returns the event to send if it's
+    /// bound to a hotkey.
+    fn press(&self) -> Option<::ui::Event> {
+        self.active.set(true);
+
+        match self.action {
+            Action::Hotkey(e) => Some(e),
+            _ => None,
         }
     }
 
-    fn down_if_positive(self) -> ButtonState {
-        if self == AxisState::Positive {
-            ButtonState::Down
-        } else {
-            ButtonState::Up
+    /// Mark the input as released. Returns the event to send if it
+    /// was holding a hotkey down.
+    fn release(&self) -> Option<::ui::Event> {
+        let was_active = self.active.replace(false);
+
+        match self.action {
+            Action::Hotkey(e) if was_active => e.released(),
+            _ => None,
         }
     }
 }
 
-/// The controller axis moves in a range from -32768 to +32767. To
-/// avoid spurious events this constant says how far from 0 the axis
-/// has to move for us to register the event.
-const AXIS_DEAD_ZONE: i16 = 10_000;
+/// Strength of the controller rumble, between 0 and 1
+const RUMBLE_STRENGTH: f32 = 0.75;
+
+/// Rumble duration used to keep rumbling until explicitly stopped
+/// (`SDL_HAPTIC_INFINITY`)
+const RUMBLE_INFINITY: u32 = 4294967295;
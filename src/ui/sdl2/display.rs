@@ -6,12 +6,15 @@ use sdl2::Sdl;
 
 use gpu::Color;
 
+/// SDL2 window displaying the GB screen
 pub struct Display {
     renderer: Canvas<Window>,
     upscale: u8,
 }
 
 impl Display {
+    /// Open a new window, `upscale` times bigger than the GB
+    /// screen
     pub fn new(sdl2: &Sdl, upscale: u8, fullscreen:bool) -> Display {
         let xres = 160 * upscale as u32;
         let yres = 144 * upscale as u32;
@@ -57,12 +60,8 @@ impl ::ui::Display for Display {
     }
 
     fn set_pixel(&mut self, x: u32, y: u32, color: Color) {
-        let color = match color {
-            Color::Black => SDL_Color::RGB(0x00, 0x00, 0x00),
-            Color::DarkGrey => SDL_Color::RGB(0x55, 0x55, 0x55),
-            Color::LightGrey => SDL_Color::RGB(0xab, 0xab, 0xab),
-            Color::White => SDL_Color::RGB(0xff, 0xff, 0xff),
-        };
+        let (r, g, b) = color.rgb888();
+        let color = SDL_Color::RGB(r, g, b);
 
         self.renderer.set_draw_color(color);
 
@@ -10,12 +10,15 @@ mod audio;
 mod controller;
 mod display;
 
+/// SDL2 context shared by the display, audio and controller
 pub struct Context {
+    /// SDL2 library handle
     pub sdl2: Sdl,
     controller: controller::Controller,
 }
 
 impl Context {
+    /// Initialize SDL2 and open the game controller (if any)
     pub fn new() -> Context {
         let sdl2 = ::sdl2::init().unwrap();
 
@@ -27,15 +30,29 @@ impl Context {
         }
     }
 
+    /// Open a new window, `upscale` times bigger than the GB screen
     pub fn new_display(&self, upscale: u8, fullscreen: bool) -> display::Display {
         display::Display::new(&self.sdl2, upscale, fullscreen)
     }
 
+    /// Return the current state of the GB buttons
     pub fn buttons(&self) -> &Cell<::ui::Buttons> {
         self.controller.buttons()
     }
 
+    /// Replace the input bindings, see `Controller::set_bindings`
+    pub fn set_bindings(&mut self, bindings: &::ui::input::Bindings) -> Result<(), String> {
+        self.controller.set_bindings(bindings)
+    }
+
+    /// Process the pending SDL2 events and update the buttons state
     pub fn update_buttons(&self) -> ::ui::Event {
         self.controller.update(&self.sdl2)
     }
 }
+
+impl ::ui::Rumble for Context {
+    fn set_rumble(&mut self, on: bool) {
+        self.controller.set_rumble(on);
+    }
+}
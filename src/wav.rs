@@ -0,0 +1,107 @@
+//! Minimal WAV file writer for 16 bit PCM audio
+
+use std::io::{Result as IoResult, Seek, SeekFrom, Write};
+
+/// Size of the RIFF header up to the start of the sample data
+const HEADER_SIZE: u32 = 44;
+
+/// Write 16 bit PCM samples to a WAV file. The chunk sizes are only
+/// known once all the samples have been written, `finish` must be
+/// called to fill them in.
+pub struct WavWriter<W: Write + Seek> {
+    out: W,
+    /// Number of bytes of sample data written so far
+    data_size: u32,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    /// Write the header for a stream of `channels` interleaved
+    /// channels at `rate` Hz
+    pub fn new(mut out: W, channels: u16, rate: u32) -> IoResult<WavWriter<W>> {
+        let block_align = channels * 2;
+
+        try!(out.write_all(b"RIFF"));
+        // RIFF chunk size, filled by `finish`
+        try!(write_u32(&mut out, HEADER_SIZE - 8));
+        try!(out.write_all(b"WAVE"));
+
+        try!(out.write_all(b"fmt "));
+        try!(write_u32(&mut out, 16));
+        // PCM
+        try!(write_u16(&mut out, 1));
+        try!(write_u16(&mut out, channels));
+        try!(write_u32(&mut out, rate));
+        // Byte rate
+        try!(write_u32(&mut out, rate * block_align as u32));
+        try!(write_u16(&mut out, block_align));
+        // Bits per sample
+        try!(write_u16(&mut out, 16));
+
+        try!(out.write_all(b"data"));
+        // Data chunk size, filled by `finish`
+        try!(write_u32(&mut out, 0));
+
+        Ok(WavWriter {
+            out: out,
+            data_size: 0,
+        })
+    }
+
+    /// Append interleaved samples
+    pub fn write_samples(&mut self, samples: &[i16]) -> IoResult<()> {
+        for &s in samples {
+            try!(write_u16(&mut self.out, s as u16));
+        }
+
+        self.data_size += samples.len() as u32 * 2;
+
+        Ok(())
+    }
+
+    /// Fill in the chunk sizes and return the underlying writer
+    pub fn finish(mut self) -> IoResult<W> {
+        try!(self.out.seek(SeekFrom::Start(4)));
+        try!(write_u32(&mut self.out, HEADER_SIZE - 8 + self.data_size));
+
+        try!(self.out.seek(SeekFrom::Start(HEADER_SIZE as u64 - 4)));
+        try!(write_u32(&mut self.out, self.data_size));
+
+        try!(self.out.seek(SeekFrom::End(0)));
+        try!(self.out.flush());
+
+        Ok(self.out)
+    }
+}
+
+fn write_u16<W: Write>(w: &mut W, v: u16) -> IoResult<()> {
+    w.write_all(&[v as u8, (v >> 8) as u8])
+}
+
+fn write_u32<W: Write>(w: &mut W, v: u32) -> IoResult<()> {
+    w.write_all(&[v as u8, (v >> 8) as u8, (v >> 16) as u8, (v >> 24) as u8])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WavWriter;
+    use std::io::Cursor;
+
+    #[test]
+    fn header() {
+        let mut wav = WavWriter::new(Cursor::new(Vec::new()), 2, 44100).unwrap();
+
+        wav.write_samples(&[1, -1, 0x1234, 0]).unwrap();
+
+        let data = wav.finish().unwrap().into_inner();
+
+        assert!(data.len() == 44 + 8);
+        assert!(&data[0..4] == b"RIFF");
+        assert!(data[4..8] == [44, 0, 0, 0]);
+        assert!(&data[8..16] == b"WAVEfmt ");
+        // Channels and sample rate
+        assert!(data[22..28] == [2, 0, 0x44, 0xac, 0, 0]);
+        assert!(&data[36..40] == b"data");
+        assert!(data[40..44] == [8, 0, 0, 0]);
+        assert!(data[44..50] == [1, 0, 0xff, 0xff, 0x34, 0x12]);
+    }
+}
@@ -0,0 +1,117 @@
+//! Minimal YUV4MPEG2 (Y4M) video writer
+//!
+//! Y4M is a raw video format: a one line text header giving the size
+//! and frame rate followed by each frame as uncompressed Y, Cb and Cr
+//! planes. Most video tools (ffmpeg, mpv, x264...) read it directly.
+//! The pictures are converted from RGB using the BT.601 coefficients
+//! and "studio" range (16 to 235 for luma) these tools assume, and
+//! the chroma planes are kept at full resolution (4:4:4) so that the
+//! sharp edges of the GB pixels don't bleed into each other.
+
+use std::io::{Result as IoResult, Write};
+
+/// Write RGB frames to a Y4M stream
+pub struct Y4mWriter<W: Write> {
+    out: W,
+    width: usize,
+    height: usize,
+    /// Number of frames written so far
+    frames: u64,
+}
+
+impl<W: Write> Y4mWriter<W> {
+    /// Write the header for a `width`x`height` video running at
+    /// `rate_num / rate_den` frames per second
+    pub fn new(mut out: W, width: u32, height: u32, rate_num: u32, rate_den: u32) -> IoResult<Y4mWriter<W>> {
+        // Progressive, square pixels
+        try!(write!(
+            out,
+            "YUV4MPEG2 W{} H{} F{}:{} Ip A1:1 C444\n",
+            width, height, rate_num, rate_den
+        ));
+
+        Ok(Y4mWriter {
+            out: out,
+            width: width as usize,
+            height: height as usize,
+            frames: 0,
+        })
+    }
+
+    /// Append a frame. `rgb` contains the pixels line by line, 3
+    /// bytes per pixel.
+    pub fn write_frame(&mut self, rgb: &[u8]) -> IoResult<()> {
+        let pixels = self.width * self.height;
+
+        assert!(rgb.len() == pixels * 3);
+
+        let mut planes = vec![0; pixels * 3];
+
+        for (i, p) in rgb.chunks(3).enumerate() {
+            let (y, cb, cr) = ycbcr(p[0], p[1], p[2]);
+
+            planes[i] = y;
+            planes[pixels + i] = cb;
+            planes[pixels * 2 + i] = cr;
+        }
+
+        try!(self.out.write_all(b"FRAME\n"));
+        try!(self.out.write_all(&planes));
+
+        self.frames += 1;
+
+        Ok(())
+    }
+
+    /// Return the number of frames written so far
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// Flush the stream and return the underlying writer
+    pub fn finish(mut self) -> IoResult<W> {
+        try!(self.out.flush());
+
+        Ok(self.out)
+    }
+}
+
+/// Convert a full range RGB color to studio range Y, Cb and Cr
+fn ycbcr(r: u8, g: u8, b: u8) -> (u8, u8, u8) {
+    let (r, g, b) = (r as i32, g as i32, b as i32);
+
+    let y = 16 + ((66 * r + 129 * g + 25 * b + 128) >> 8);
+    let cb = 128 + ((-38 * r - 74 * g + 112 * b + 128) >> 8);
+    let cr = 128 + ((112 * r - 94 * g - 18 * b + 128) >> 8);
+
+    (y as u8, cb as u8, cr as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ycbcr, Y4mWriter};
+
+    #[test]
+    fn colors() {
+        assert!(ycbcr(0xff, 0xff, 0xff) == (235, 128, 128));
+        assert!(ycbcr(0, 0, 0) == (16, 128, 128));
+        assert!(ycbcr(0xff, 0, 0) == (82, 90, 240));
+        assert!(ycbcr(0, 0, 0xff) == (41, 240, 110));
+    }
+
+    #[test]
+    fn stream() {
+        let mut y4m = Y4mWriter::new(Vec::new(), 2, 1, 262144, 4389).unwrap();
+
+        y4m.write_frame(&[0xff, 0xff, 0xff, 0, 0, 0]).unwrap();
+        y4m.write_frame(&[0, 0, 0, 0xff, 0xff, 0xff]).unwrap();
+
+        assert!(y4m.frames() == 2);
+
+        let out = y4m.finish().unwrap();
+        let header = b"YUV4MPEG2 W2 H1 F262144:4389 Ip A1:1 C444\n";
+
+        assert!(out[..header.len()] == header[..]);
+        assert!(out[header.len()..] == *b"FRAME\n\xeb\x10\x80\x80\x80\x80FRAME\n\x10\xeb\x80\x80\x80\x80");
+    }
+}